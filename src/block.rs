@@ -0,0 +1,183 @@
+// Cache of pre-decoded basic blocks. A block is a straight run of
+// instructions inside one page that ends at the first branch, jump or fence;
+// ecall and ebreak form blocks of their own so drivers can intercept them.
+// A 32-bit instruction that straddles two pages, which compressed code
+// allows, is left out of every block and decoded afresh each time.
+// Blocks are trusted only while the bus still has their page marked with the
+// stamp it gave out when they were translated: any write to the page drops
+// the mark, and the page's blocks are translated again the next time one of
+// them is entered. Harts sharing a bus each keep their own cache, the stamps
+// tell them apart from pages another hart translated again after a write.
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::sync::Arc;
+
+use crate::cpu::{decode, Exec};
+use crate::isa::Isa;
+use crate::memory::BUS;
+use crate::opcode::{B_TYPE, CSR, FENCE, JAL, JALR};
+use crate::rvc;
+
+pub const MAX_BLOCK_LEN: usize = 64;
+const PAGE_SHIFT: u32 = 12;
+
+#[derive(Debug)]
+pub struct Block {
+    pub start: u32,
+    pub instrs: Vec<(u32, Exec)>,
+}
+
+// block lookups happen on every taken branch, a multiplicative hash of the
+// pc is plenty and far cheaper than the default SipHash
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PcHasher(u64);
+
+impl Hasher for PcHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 = (self.0 << 8 | *b as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        }
+    }
+    fn write_u32(&mut self, n: u32) {
+        self.0 = (n as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+}
+
+pub type PcMap<V> = HashMap<u32, V, BuildHasherDefault<PcHasher>>;
+
+#[derive(Debug, Clone, Default)]
+pub struct BlockCache {
+    blocks: PcMap<Arc<Block>>,
+    // page number -> its code stamp and the start addresses of its blocks
+    pages: PcMap<(u64, Vec<u32>)>,
+    // block being executed, the index of its next instruction and where that
+    // is
+    current: Option<(Arc<Block>, usize, u32)>,
+    // bus code_writes when `current` was entered
+    generation: u64,
+}
+
+impl BlockCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // the decoded instruction at `pc`, None when there is no RAM at pc
+    pub fn lookup(&mut self, pc: u32, bus: &mut BUS, isa: &Isa) -> Option<(u32, Exec)> {
+        if let Some((block, idx, next)) = &mut self.current {
+            if *next == pc && *idx < block.instrs.len() && bus.code_writes() == self.generation {
+                let decoded = block.instrs[*idx];
+                *idx += 1;
+                *next = pc.wrapping_add(rvc::len(decoded.0));
+                return Some(decoded);
+            }
+        }
+        let Some(block) = self.enter(pc, bus, isa) else {
+            self.current = None;
+            let instr = fetch(pc, bus, isa)?;
+            return Some((instr, decode(instr, isa)));
+        };
+        let decoded = block.instrs[0];
+        self.current = Some((block, 1, pc.wrapping_add(rvc::len(decoded.0))));
+        self.generation = bus.code_writes();
+        Some(decoded)
+    }
+
+    // number of blocks currently translated
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // forget every translation, e.g. on fence.i
+    pub fn flush(&mut self) {
+        self.blocks.clear();
+        self.pages.clear();
+        self.current = None;
+    }
+
+    // the block starting at `pc`, translated if needed
+    pub fn enter(&mut self, pc: u32, bus: &mut BUS, isa: &Isa) -> Option<Arc<Block>> {
+        let page = pc >> PAGE_SHIFT;
+        let stamp = bus.code_stamp(pc);
+        match self.pages.get(&page) {
+            Some((seen, _)) if *seen == stamp && stamp != 0 => {
+                if let Some(block) = self.blocks.get(&pc) {
+                    return Some(block.clone());
+                }
+            }
+            Some(_) => {
+                for start in self.pages.remove(&page).unwrap().1 {
+                    self.blocks.remove(&start);
+                }
+            }
+            None => (),
+        }
+        let block = Arc::new(translate(pc, bus, isa)?);
+        let stamp = match stamp {
+            0 => bus.mark_code(pc),
+            _ => stamp,
+        };
+        self.blocks.insert(pc, block.clone());
+        self.pages
+            .entry(page)
+            .or_insert((stamp, Vec::new()))
+            .1
+            .push(pc);
+        Some(block)
+    }
+}
+
+// the instruction at `pc`, compressed ones in the low half
+pub(crate) fn fetch(pc: u32, bus: &BUS, isa: &Isa) -> Option<u32> {
+    let low = bus.load_bytes(pc, 2)?;
+    let low = u16::from_le_bytes(low.try_into().unwrap()) as u32;
+    if isa.has('c') && rvc::is_compressed(low) {
+        return Some(low);
+    }
+    let bytes = bus.load_bytes(pc, 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn translate(start: u32, bus: &BUS, isa: &Isa) -> Option<Block> {
+    let mut instrs = Vec::new();
+    let mut pc = start;
+    while instrs.len() < MAX_BLOCK_LEN {
+        let Some(instr) = fetch(pc, bus, isa) else {
+            break;
+        };
+        let len = rvc::len(instr);
+        if is_system(instr) && !instrs.is_empty()
+            || pc.wrapping_add(len - 1) >> PAGE_SHIFT != pc >> PAGE_SHIFT
+        {
+            break;
+        }
+        instrs.push((instr, decode(instr, isa)));
+        pc = pc.wrapping_add(len);
+        if ends_block(instr) || pc >> PAGE_SHIFT != start >> PAGE_SHIFT {
+            break;
+        }
+    }
+    match instrs.is_empty() {
+        true => None,
+        false => Some(Block { start, instrs }),
+    }
+}
+
+// control leaves the straight line, or the instruction may change what the
+// following code means (fence.i, traps)
+fn ends_block(instr: u32) -> bool {
+    matches!(rvc::expand(instr) & 0x7f, B_TYPE | JAL | JALR | FENCE | CSR)
+}
+
+// ecall and ebreak, c.ebreak among them
+fn is_system(instr: u32) -> bool {
+    let instr = rvc::expand(instr);
+    instr & 0x7f == CSR && (instr >> 12) & 0x7 == 0
+}
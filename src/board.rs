@@ -0,0 +1,725 @@
+// Machine descriptions. A board file says what the machine looks like instead
+// of the built-in constants: ISA, harts, where RAM and ROM sit and what they
+// start out holding, the devices and where execution starts.
+//
+//   isa = "rv32ia_zicsr"
+//   harts = 2
+//   misaligned = "trap"
+//   reset_vector = 0x1000
+//
+//   [[rom]]
+//   base = 0x1000
+//   size = 0x1000
+//   image = "boot.bin"
+//
+//   [[ram]]
+//   base = 0x80000000
+//   size = 0x100000
+//
+//   [[ram]]
+//   base = 0x40000000
+//   size = 0x40000000
+//   sparse = true
+//
+//   [[ram]]
+//   base = 0x20000000
+//   size = 0x1000000
+//   file = "nvram.bin"
+//
+//   [[device]]
+//   type = "clint"
+//   base = 0x2000000
+//   virtual_time = true
+//
+//   [[device]]
+//   type = "plic"
+//   base = 0xc000000
+//
+//   [[device]]
+//   type = "uart"
+//   base = 0x10000000
+//   irq = 1
+//
+//   [[device]]
+//   type = "virtio-blk"
+//   base = 0x10001000
+//   image = "rootfs.img"
+//   mode = "overlay"
+//
+//   [[device]]
+//   type = "virtio-rng"
+//   base = 0x10002000
+//   seed = 42
+//
+//   [[device]]
+//   type = "sifive-gpio"
+//   base = 0x10012000
+//   input = [{ at = 100000, pin = 3, level = true }]
+//   log = "gpio.log"
+//
+//   [[device]]
+//   type = "sifive-i2c"
+//   base = 0x10016000
+//   target = [{ address = 0x48, registers = [0x19, 0x80] }]
+//
+// Every hart starts in machine mode with its hartid in a0 and a1 pointing at
+// the machine's device tree, which sits at the top of the first RAM, right
+// above the stack.
+//
+// Boot puts firmware, a kernel and an initrd into a board the way QEMU's
+// -bios, -kernel, -initrd and -append do, so OpenSBI's fw_jump finds the
+// kernel where it jumps to and the kernel finds its initrd through /chosen.
+// The harts take traps and interrupts in machine and supervisor mode, so
+// fw_jump can set itself up and drop into an S-mode payload, and translate
+// addresses with Sv32, so that payload can be a Linux kernel that turns on
+// paging.
+//
+// Every problem found is reported against the key it is about, e.g.
+// "ram[1].base: overlaps ram[0]". Image, file, dump and log paths are
+// relative to the board file.
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::clint::{Clint, CLINT_SIZE};
+use crate::cpu::{Misaligned, CPU};
+use crate::device::{Device, Mapped};
+use crate::elf::ELF;
+use crate::fdt::{self, Chosen};
+use crate::framebuffer::{Framebuffer, Mode, PixelFormat};
+use crate::goldfish_rtc::{Rtc, RTC_SIZE};
+use crate::gpio::{self, Gpio, PinChange, GPIO_SIZE};
+use crate::i2c::{I2c, Registers, Target, I2C_SIZE};
+use crate::isa::{self, Isa};
+use crate::memory::{Backing, BUS};
+use crate::plic::{Plic, NUM_SOURCES, PLIC_SIZE};
+use crate::spi::{self, Spi, SPI_SIZE};
+use crate::syscon::{Syscon, SYSCON_SIZE};
+use crate::uart::{Uart, UART_SIZE};
+use crate::virtio::{VirtioMmio, VIRTIO_SIZE};
+use crate::virtio_blk::{Disk, DiskMode};
+use crate::virtio_console::Console;
+use crate::virtio_net::{self, Net};
+use crate::virtio_rng::Rng;
+
+// MMIO devices a board can place, the size of their register window (0 when
+// the configuration decides) and how to make one; a device that cannot be
+// made names the key at fault
+type NewDevice = fn(&DeviceConfig) -> Result<Box<dyn Device>, (&'static str, String)>;
+const DEVICES: &[(&str, u32, NewDevice)] = &[
+    ("clint", CLINT_SIZE, |config| {
+        Ok(Box::new(match config.virtual_time {
+            true => Clint::virtual_time(),
+            false => Clint::new(),
+        }))
+    }),
+    ("framebuffer", 0, |config| {
+        let mut framebuffer = Framebuffer::new(config.mode());
+        if let Some(path) = &config.dump {
+            framebuffer = framebuffer.with_dumps(path, config.dump_every);
+        }
+        Ok(Box::new(framebuffer))
+    }),
+    ("goldfish-rtc", RTC_SIZE, |config| {
+        Ok(Box::new(match config.epoch {
+            Some(epoch) => Rtc::virtual_time(epoch),
+            None => Rtc::host(),
+        }))
+    }),
+    ("plic", PLIC_SIZE, |_| Ok(Box::new(Plic::new()))),
+    ("sifive-gpio", GPIO_SIZE, |config| {
+        if let Some(change) = config.input.iter().find(|change| change.pin >= 32) {
+            return Err(("input", format!("there is no pin {}", change.pin)));
+        }
+        let mut script = gpio::Script::new(config.input.clone());
+        if let Some(path) = &config.log {
+            script = script
+                .with_log(path)
+                .map_err(|e| ("log", format!("{}: {}", path.display(), e)))?;
+        }
+        Ok(Box::new(Gpio::new(Box::new(script))))
+    }),
+    ("sifive-i2c", I2C_SIZE, |config| {
+        if let Some(target) = config.target.iter().find(|target| target.address > 0x7f) {
+            return Err((
+                "target",
+                format!("{:#x} is no 7-bit address", target.address),
+            ));
+        }
+        let targets = Registers::new(config.target.clone());
+        Ok(Box::new(I2c::new(Box::new(targets))))
+    }),
+    ("sifive-spi", SPI_SIZE, |config| {
+        let script = spi::Script::new(&config.replies);
+        Ok(Box::new(Spi::new(Box::new(script))))
+    }),
+    ("syscon", SYSCON_SIZE, |_| Ok(Box::new(Syscon::new()))),
+    ("uart", UART_SIZE, |_| Ok(Box::new(Uart::new()))),
+    ("virtio-blk", VIRTIO_SIZE, |config| {
+        let path = config
+            .image
+            .as_ref()
+            .ok_or(("image", "a disk needs an image".into()))?;
+        let disk = Disk::open(path, config.mode)
+            .map_err(|e| ("image", format!("{}: {}", path.display(), e)))?;
+        Ok(Box::new(VirtioMmio::new(Box::new(disk), config.legacy)))
+    }),
+    ("virtio-console", VIRTIO_SIZE, |config| {
+        let console = match &config.socket {
+            Some(path) => Console::socket(path)
+                .map_err(|e| ("socket", format!("{}: {}", path.display(), e)))?,
+            None => Console::stdio(),
+        };
+        Ok(Box::new(VirtioMmio::new(Box::new(console), config.legacy)))
+    }),
+    ("virtio-net", VIRTIO_SIZE, |config| {
+        let (Some(path), Some(peer)) = (&config.socket, &config.peer) else {
+            return Err((
+                "socket",
+                "a network device needs a socket and a peer".into(),
+            ));
+        };
+        let mut net =
+            Net::bind(path, peer).map_err(|e| ("socket", format!("{}: {}", path.display(), e)))?;
+        if let Some(mac) = &config.mac {
+            net = net.with_mac(virtio_net::parse_mac(mac).map_err(|e| ("mac", e))?);
+        }
+        Ok(Box::new(VirtioMmio::new(Box::new(net), config.legacy)))
+    }),
+    ("virtio-rng", VIRTIO_SIZE, |config| {
+        let rng = match config.seed {
+            Some(seed) => Rng::new(seed),
+            None => Rng::from_host_time(),
+        };
+        Ok(Box::new(VirtioMmio::new(Box::new(rng), config.legacy)))
+    }),
+];
+
+// virtio-mmio windows of the virt machine, where --drive and friends go:
+// VIRTIO_SLOTS of them from VIRTIO_BASE on, with interrupts 1 and up
+pub const VIRTIO_BASE: u32 = 0x10001000;
+const VIRTIO_SLOTS: u32 = 8;
+
+// where --framebuffer goes on the virt machine, where QEMU would have PCI
+// memory
+pub const FRAMEBUFFER_BASE: u32 = 0x40000000;
+
+// QEMU's virt machine as far as there are devices for it, `--machine virt`
+pub const VIRT: &str = r#"
+    bootargs = "console=ttyS0"
+
+    [[ram]]
+    base = 0x80000000
+    size = 0x8000000
+    sparse = true
+
+    [[device]]
+    type = "syscon"
+    base = 0x100000
+
+    [[device]]
+    type = "goldfish-rtc"
+    base = 0x101000
+    irq = 11
+
+    [[device]]
+    type = "clint"
+    base = 0x2000000
+
+    [[device]]
+    type = "plic"
+    base = 0xc000000
+
+    [[device]]
+    type = "uart"
+    base = 0x10000000
+    irq = 10
+"#;
+
+// where fw_jump jumps to on rv32, relative to the start of RAM
+pub const KERNEL_OFFSET: u32 = 0x400000;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Board {
+    #[serde(default = "default_isa")]
+    pub isa: String,
+    #[serde(default = "default_harts")]
+    pub harts: u32,
+    // what loads and stores off their alignment do: allow, trap or emulate
+    #[serde(default)]
+    pub misaligned: Misaligned,
+    // where every hart starts, the start of the first RAM by default
+    pub reset_vector: Option<u32>,
+    pub ram: Vec<Region>,
+    #[serde(default)]
+    pub rom: Vec<Region>,
+    #[serde(default)]
+    pub device: Vec<DeviceConfig>,
+    // kernel command line, /chosen/bootargs in the device tree
+    #[serde(default)]
+    pub bootargs: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Region {
+    pub base: u32,
+    pub size: u32,
+    // raw binary copied to the start of the region
+    pub image: Option<PathBuf>,
+    // RAM: pages come into being when the guest first touches them
+    #[serde(default)]
+    pub sparse: bool,
+    // RAM: a host file mapped into the region, which holds what the guest
+    // wrote after the run
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub base: u32,
+    pub irq: Option<u32>,
+    // virtio devices: the legacy interface instead of the modern one
+    #[serde(default)]
+    pub legacy: bool,
+    // virtio-blk: the disk image and how it is used
+    pub image: Option<PathBuf>,
+    #[serde(default)]
+    pub mode: DiskMode,
+    // virtio-console: Unix socket to connect to instead of stdio;
+    // virtio-net: datagram socket to bind, frames go to the peer's
+    pub socket: Option<PathBuf>,
+    pub peer: Option<PathBuf>,
+    // virtio-net: "52:54:00:12:34:56"
+    pub mac: Option<String>,
+    // virtio-rng: for the same bytes on every run
+    pub seed: Option<u64>,
+    // goldfish-rtc: seconds since 1970 at the first instruction, for a time
+    // that follows the instruction count instead of the host clock
+    pub epoch: Option<u64>,
+    // clint: mtime from the instruction count instead of the host clock
+    #[serde(default)]
+    pub virtual_time: bool,
+    // framebuffer: resolution, 640x480 x8r8g8b8 by default, and where frames
+    // are written, when asked and every `dump_every` instructions
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub format: Option<PixelFormat>,
+    pub dump: Option<PathBuf>,
+    pub dump_every: Option<u64>,
+    // sifive-gpio: pin levels over time, and a file the outputs are logged to
+    #[serde(default)]
+    pub input: Vec<PinChange>,
+    pub log: Option<PathBuf>,
+    // sifive-spi: the bytes the device on the bus answers with, in order
+    #[serde(default)]
+    pub replies: Vec<u8>,
+    // sifive-i2c: register file targets on the bus
+    #[serde(default)]
+    pub target: Vec<Target>,
+}
+
+impl DeviceConfig {
+    pub fn mode(&self) -> Mode {
+        Mode {
+            width: self.width.unwrap_or(640),
+            height: self.height.unwrap_or(480),
+            format: self.format.unwrap_or_default(),
+        }
+    }
+
+    // size of the window on the bus, None for an unknown device or a frame
+    // bigger than the address space
+    fn window_size(&self) -> Option<u32> {
+        let (_, size, _) = DEVICES.iter().find(|(kind, ..)| *kind == self.kind)?;
+        match self.kind.as_str() {
+            "framebuffer" => self.mode().window_size(),
+            _ => Some(*size),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BoardError {
+    Io(io::Error),
+    // not TOML, or a key that has no business there
+    Parse(toml::de::Error),
+    // a value that makes no sense for a machine
+    Invalid { key: String, msg: String },
+}
+
+impl fmt::Display for BoardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BoardError::Io(e) => write!(f, "{}", e),
+            BoardError::Parse(e) => write!(f, "{}", e),
+            BoardError::Invalid { key, msg } => write!(f, "{}: {}", key, msg),
+        }
+    }
+}
+
+impl std::error::Error for BoardError {}
+
+fn invalid(key: impl Into<String>, msg: impl Into<String>) -> BoardError {
+    BoardError::Invalid {
+        key: key.into(),
+        msg: msg.into(),
+    }
+}
+
+// what goes into memory before the first instruction
+#[derive(Debug, Clone, Default)]
+pub struct Boot {
+    // firmware ELF, e.g. OpenSBI's fw_jump.elf, started at its entry
+    pub bios: Option<PathBuf>,
+    // kernel Image or ELF, at KERNEL_OFFSET behind firmware and started at
+    // the start of RAM without
+    pub kernel: Option<PathBuf>,
+    // initial ramdisk, right below the device tree
+    pub initrd: Option<PathBuf>,
+    // kernel command line instead of the board's bootargs
+    pub append: Option<String>,
+}
+
+fn read(key: &str, path: &Path) -> Result<Vec<u8>, BoardError> {
+    std::fs::read(path).map_err(|e| invalid(key, format!("{}: {}", path.display(), e)))
+}
+
+// the loaded range of an ELF or raw image at `addr`, and where it starts
+fn load(bus: &mut BUS, key: &str, path: &Path, addr: u32) -> Result<(u32, u32, u32), BoardError> {
+    let data = read(key, path)?;
+    let too_big = || invalid(key, format!("{} does not fit into RAM", path.display()));
+    if !data.starts_with(b"\x7fELF") {
+        bus.store_bytes(addr, &data).ok_or_else(too_big)?;
+        let end = u32::try_from(addr as u64 + data.len() as u64).map_err(|_| too_big())?;
+        return Ok((addr, end, addr));
+    }
+    let image = ELF::new(&path.to_string_lossy())
+        .read_image()
+        .map_err(|e| invalid(key, e.to_string()))?;
+    let (mut start, mut end) = (u32::MAX, 0);
+    for seg in &image.segments {
+        bus.store_bytes(seg.vaddr, &seg.data).ok_or_else(too_big)?;
+        start = start.min(seg.vaddr);
+        // read_image() turned down segments that wrap around
+        end = end.max(seg.vaddr + seg.memsz);
+    }
+    Ok((start, end, image.entry))
+}
+
+fn default_isa() -> String {
+    isa::DEFAULT.to_string()
+}
+
+fn default_harts() -> u32 {
+    1
+}
+
+impl Board {
+    pub fn from_file(path: &Path) -> Result<Self, BoardError> {
+        let text = std::fs::read_to_string(path).map_err(BoardError::Io)?;
+        let mut board = Board::parse(&text)?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let regions = board.ram.iter_mut().chain(board.rom.iter_mut());
+        let images = regions
+            .flat_map(|region| [&mut region.image, &mut region.file])
+            .chain(
+                board
+                    .device
+                    .iter_mut()
+                    .flat_map(|device| [&mut device.image, &mut device.dump, &mut device.log]),
+            );
+        for image in images.flatten() {
+            *image = dir.join(&*image);
+        }
+        Ok(board)
+    }
+
+    pub fn virt() -> Self {
+        Board::parse(VIRT).unwrap()
+    }
+
+    pub fn parse(text: &str) -> Result<Self, BoardError> {
+        let board: Board = toml::from_str(text).map_err(BoardError::Parse)?;
+        board.validate()?;
+        Ok(board)
+    }
+
+    // put a device where the configuration says
+    pub fn add_device(&mut self, device: DeviceConfig) -> Result<(), BoardError> {
+        self.device.push(device);
+        self.validate()
+    }
+
+    // put a virtio device into the first free virtio-mmio window
+    pub fn add_virtio(&mut self, mut device: DeviceConfig) -> Result<(), BoardError> {
+        let slot = (0..VIRTIO_SLOTS)
+            .find(|slot| {
+                let base = VIRTIO_BASE + slot * VIRTIO_SIZE;
+                !self.device.iter().any(|device| device.base == base)
+            })
+            .ok_or_else(|| invalid("device", "every virtio-mmio window is taken"))?;
+        device.base = VIRTIO_BASE + slot * VIRTIO_SIZE;
+        if self.device.iter().any(|device| device.kind == "plic") {
+            device.irq = Some(slot + 1);
+        }
+        self.device.push(device);
+        self.validate()
+    }
+
+    pub fn reset_vector(&self) -> u32 {
+        self.reset_vector.unwrap_or(self.ram[0].base)
+    }
+
+    fn validate(&self) -> Result<(), BoardError> {
+        Isa::parse(&self.isa).map_err(|e| invalid("isa", e.to_string()))?;
+        if self.harts == 0 {
+            return Err(invalid("harts", "a machine needs at least one hart"));
+        }
+        if self.ram.is_empty() {
+            return Err(invalid("ram", "a machine needs RAM"));
+        }
+
+        // every range on the bus, to look for overlaps
+        let mut ranges: Vec<(String, u64, u64)> = Vec::new();
+        for (name, regions) in [("ram", &self.ram), ("rom", &self.rom)] {
+            for (i, region) in regions.iter().enumerate() {
+                let key = format!("{}[{}]", name, i);
+                if region.size == 0 {
+                    return Err(invalid(format!("{}.size", key), "must not be 0"));
+                }
+                let end = region.base as u64 + region.size as u64;
+                if end > 1 << 32 {
+                    return Err(invalid(
+                        format!("{}.size", key),
+                        "runs past the end of the address space",
+                    ));
+                }
+                if name == "rom" && (region.sparse || region.file.is_some()) {
+                    return Err(invalid(key, "only RAM can be sparse or backed by a file"));
+                }
+                if region.sparse && region.file.is_some() {
+                    return Err(invalid(
+                        format!("{}.sparse", key),
+                        "a file backed region is sparse already",
+                    ));
+                }
+                ranges.push((key, region.base as u64, end));
+            }
+        }
+        let mut irqs: Vec<(u32, String)> = Vec::new();
+        for (i, device) in self.device.iter().enumerate() {
+            let key = format!("device[{}]", i);
+            if !DEVICES.iter().any(|(kind, ..)| *kind == device.kind) {
+                return Err(invalid(
+                    format!("{}.type", key),
+                    format!("unknown device `{}`", device.kind),
+                ));
+            }
+            if device.kind == "framebuffer" {
+                let mode = device.mode();
+                for (name, value) in [("width", mode.width), ("height", mode.height)] {
+                    if value == 0 {
+                        return Err(invalid(format!("{}.{}", key, name), "must not be 0"));
+                    }
+                }
+            }
+            let Some(size) = device.window_size() else {
+                return Err(invalid(
+                    format!("{}.width", key),
+                    "the frame does not fit into the address space",
+                ));
+            };
+            if let Some(irq) = device.irq {
+                if irq == 0 || irq >= NUM_SOURCES {
+                    return Err(invalid(
+                        format!("{}.irq", key),
+                        format!("must be 1 to {}", NUM_SOURCES - 1),
+                    ));
+                }
+                if !self.device.iter().any(|device| device.kind == "plic") {
+                    return Err(invalid(
+                        format!("{}.irq", key),
+                        "there is no plic to route it to",
+                    ));
+                }
+                if let Some((_, other)) = irqs.iter().find(|(used, _)| *used == irq) {
+                    return Err(invalid(
+                        format!("{}.irq", key),
+                        format!("{} is already taken by {}", irq, other),
+                    ));
+                }
+                irqs.push((irq, key.clone()));
+            }
+            let end = device.base as u64 + size as u64;
+            if end > 1 << 32 {
+                return Err(invalid(
+                    format!("{}.base", key),
+                    "runs past the end of the address space",
+                ));
+            }
+            ranges.push((key, device.base as u64, end));
+        }
+        for (i, (key, base, end)) in ranges.iter().enumerate() {
+            if let Some((other, _, _)) = ranges[..i].iter().find(|(_, b, e)| base < e && b < end) {
+                return Err(invalid(
+                    format!("{}.base", key),
+                    format!("overlaps {}", other),
+                ));
+            }
+        }
+
+        let reset = self.reset_vector() as u64;
+        let bootable = self.ram.iter().chain(&self.rom).any(|region| {
+            (region.base as u64..region.base as u64 + region.size as u64).contains(&reset)
+        });
+        if !bootable {
+            return Err(invalid(
+                "reset_vector",
+                format!("{:#x} is neither in RAM nor in ROM", reset),
+            ));
+        }
+        Ok(())
+    }
+
+    // bus with the board's memory, images loaded
+    pub fn bus(&self) -> Result<BUS, BoardError> {
+        let mut bus = BUS::new();
+        for (name, regions) in [("ram", &self.ram), ("rom", &self.rom)] {
+            for (i, region) in regions.iter().enumerate() {
+                let mut data = match (&region.file, region.sparse) {
+                    (Some(path), _) => Backing::file(path, region.size).map_err(|e| {
+                        invalid(
+                            format!("{}[{}].file", name, i),
+                            format!("{}: {}", path.display(), e),
+                        )
+                    })?,
+                    (None, true) => Backing::sparse(region.size)
+                        .map_err(|e| invalid(format!("{}[{}].sparse", name, i), e.to_string()))?,
+                    (None, false) => vec![0; region.size as usize].into(),
+                };
+                if let Some(path) = &region.image {
+                    let key = format!("{}[{}].image", name, i);
+                    let image = std::fs::read(path)
+                        .map_err(|e| invalid(&key, format!("{}: {}", path.display(), e)))?;
+                    if image.len() > data.len() {
+                        return Err(invalid(
+                            key,
+                            format!(
+                                "{} does not fit into {:#x} bytes",
+                                path.display(),
+                                region.size
+                            ),
+                        ));
+                    }
+                    data[..image.len()].copy_from_slice(&image);
+                    data.touch(0, image.len());
+                }
+                match (name, i) {
+                    ("ram", 0) => bus.replace_ram(region.base, data),
+                    _ => bus.add_region(region.base, data, name == "rom"),
+                }
+            }
+        }
+        for (i, device) in self.device.iter().enumerate() {
+            let (_, _, new) = DEVICES
+                .iter()
+                .find(|(kind, ..)| *kind == device.kind)
+                .unwrap();
+            let new =
+                new(device).map_err(|(key, msg)| invalid(format!("device[{}].{}", i, key), msg))?;
+            bus.add_device(Mapped {
+                base: device.base,
+                size: device.window_size().unwrap(),
+                irq: device.irq,
+                device: new,
+            });
+        }
+        Ok(bus)
+    }
+
+    // hart 0 at reset, the device tree in memory
+    pub fn cpu(&self) -> Result<CPU, BoardError> {
+        self.boot(&Boot::default()).map(|(cpu, _)| cpu)
+    }
+
+    // hart 0 about to run what `boot` loaded, and the device tree it is given
+    pub fn boot(&self, boot: &Boot) -> Result<(CPU, Vec<u8>), BoardError> {
+        let mut cpu = CPU::new();
+        cpu.set_isa(Isa::parse(&self.isa).map_err(|e| invalid("isa", e.to_string()))?);
+        cpu.misaligned = self.misaligned;
+        cpu.bus = self.bus()?;
+        cpu.pc = self.reset_vector();
+
+        let ram = &self.ram[0];
+        if let Some(path) = &boot.bios {
+            (_, _, cpu.pc) = load(&mut cpu.bus, "bios", path, ram.base)?;
+        }
+        // nothing of the kernel may be overwritten by the initrd
+        let mut kernel_end = 0;
+        if let Some(path) = &boot.kernel {
+            let addr = match boot.bios {
+                Some(_) => ram
+                    .base
+                    .checked_add(KERNEL_OFFSET)
+                    .ok_or_else(|| invalid("kernel", "does not fit into RAM"))?,
+                None => ram.base,
+            };
+            let (_, end, entry) = load(&mut cpu.bus, "kernel", path, addr)?;
+            if boot.bios.is_none() {
+                cpu.pc = entry;
+            }
+            kernel_end = end;
+        }
+        let initrd = match &boot.initrd {
+            Some(path) => Some(read("initrd", path)?),
+            None => None,
+        };
+
+        // the initrd goes right below the device tree, whose size does not
+        // depend on where the initrd is
+        let mut chosen = Chosen {
+            bootargs: boot.append.as_deref().unwrap_or(&self.bootargs),
+            initrd: initrd.as_ref().map(|_| (0, 0)),
+        };
+        let len = fdt::machine(&cpu.bus, self.harts, cpu.isa(), &chosen).len() as u64;
+        let end = ram.base as u64 + ram.size as u64;
+        if len > ram.size as u64 {
+            return Err(invalid("ram[0].size", "no room for the device tree"));
+        }
+        let addr = ((end - len) & !7) as u32;
+        if let Some(data) = &initrd {
+            let start = (addr as u64)
+                .checked_sub(data.len() as u64)
+                .map(|start| start & !0xfff)
+                .filter(|start| *start >= ram.base as u64 && *start >= kernel_end as u64)
+                .ok_or_else(|| {
+                    invalid(
+                        "initrd",
+                        "does not fit between the kernel and the device tree",
+                    )
+                })? as u32;
+            cpu.bus.store_bytes(start, data).ok_or_else(|| {
+                invalid(
+                    "initrd",
+                    "does not fit between the kernel and the device tree",
+                )
+            })?;
+            chosen.initrd = Some((start, start + data.len() as u32));
+        }
+        let dtb = fdt::machine(&cpu.bus, self.harts, cpu.isa(), &chosen);
+        cpu.bus
+            .store_bytes(addr, &dtb)
+            .ok_or_else(|| invalid("ram[0].size", "no room for the device tree"))?;
+        cpu.xregs.regs[10] = cpu.hartid();
+        cpu.xregs.regs[11] = addr;
+        cpu.xregs.regs[2] = addr;
+        Ok((cpu, dtb))
+    }
+}
@@ -0,0 +1,161 @@
+// SiFive compatible core-local interruptor: a software interrupt bit
+// (msip) and a timer compare register (mtimecmp) per hart, and mtime, which
+// counts at fdt::TIMEBASE_FREQ from host time or, on virtual time, from the
+// bus's instruction count at goldfish_rtc::NS_PER_INSTR, like the RTC with an
+// epoch. A hart has its machine
+// software interrupt pending while its msip is set and its timer interrupt
+// once mtime reaches its mtimecmp; a mtimecmp never written is never
+// reached.
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::time::Instant;
+
+use crate::csr::{IRQ_M_SOFT, IRQ_M_TIMER};
+use crate::device::Device;
+use crate::fdt::{self, Fdt};
+use crate::goldfish_rtc::NS_PER_INSTR;
+use crate::snapshot::{read_map, read_u64, write_map};
+
+pub const CLINT_SIZE: u32 = 0x10000;
+
+const MSIP: u32 = 0x0;
+const MTIMECMP: u32 = 0x4000;
+const MTIME: u32 = 0xbff8;
+
+#[derive(Debug, Clone, Copy)]
+enum Clock {
+    // host time since this instant
+    Host(Instant),
+    Virtual,
+}
+
+#[derive(Debug, Clone)]
+pub struct Clint {
+    // msip and mtimecmp words by offset
+    regs: HashMap<u32, u32>,
+    clock: Clock,
+    // bus clock at the current access
+    instret: u64,
+    // added to the clock's ticks, so the guest can set mtime
+    mtime_offset: u64,
+}
+
+impl Clint {
+    fn with_clock(clock: Clock) -> Self {
+        Clint {
+            regs: HashMap::new(),
+            clock,
+            instret: 0,
+            mtime_offset: 0,
+        }
+    }
+
+    pub fn new() -> Self {
+        Clint::with_clock(Clock::Host(Instant::now()))
+    }
+
+    // mtime 0 at the first instruction, following the instruction count
+    pub fn virtual_time() -> Self {
+        Clint::with_clock(Clock::Virtual)
+    }
+
+    pub fn mtime(&self) -> u64 {
+        let ns = match self.clock {
+            Clock::Host(start) => start.elapsed().as_nanos(),
+            Clock::Virtual => self.instret as u128 * NS_PER_INSTR as u128,
+        };
+        let ticks = ns * fdt::TIMEBASE_FREQ as u128 / 1_000_000_000;
+        (ticks as u64).wrapping_add(self.mtime_offset)
+    }
+
+    fn mtimecmp(&self, hart: u32) -> u64 {
+        let word = |offset| self.regs.get(&offset).copied().unwrap_or(u32::MAX) as u64;
+        let offset = MTIMECMP + 8 * hart;
+        word(offset) | word(offset + 4) << 32
+    }
+
+    fn set_mtime(&mut self, mtime: u64) {
+        self.mtime_offset = self
+            .mtime_offset
+            .wrapping_add(mtime.wrapping_sub(self.mtime()));
+    }
+}
+
+impl Default for Clint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for Clint {
+    fn load(&mut self, offset: u32, _size: u32) -> u32 {
+        match offset {
+            MTIME => self.mtime() as u32,
+            o if o == MTIME + 4 => (self.mtime() >> 32) as u32,
+            o if (MTIMECMP..MTIME).contains(&o) => {
+                self.regs.get(&offset).copied().unwrap_or(u32::MAX)
+            }
+            _ => self.regs.get(&offset).copied().unwrap_or(0),
+        }
+    }
+
+    fn store(&mut self, offset: u32, _size: u32, value: u32) {
+        let mtime = self.mtime();
+        match offset {
+            MTIME => self.set_mtime(mtime & !0xffff_ffff | value as u64),
+            o if o == MTIME + 4 => self.set_mtime(mtime & 0xffff_ffff | (value as u64) << 32),
+            // only bit 0 of msip is there
+            o if (MSIP..MTIMECMP).contains(&o) => {
+                self.regs.insert(offset, value & 1);
+            }
+            _ => {
+                self.regs.insert(offset, value);
+            }
+        }
+    }
+
+    fn clock(&mut self, instret: u64) {
+        self.instret = instret;
+    }
+
+    // mtime goes on from where it was, on either clock
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        write_map(w, &self.regs)?;
+        w.write_all(&self.mtime().to_le_bytes())
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.regs = read_map(r)?;
+        let mtime = read_u64(r)?;
+        self.set_mtime(mtime);
+        Ok(())
+    }
+
+    fn interrupts(&self, hart: u32) -> u32 {
+        let soft = self
+            .regs
+            .get(&(MSIP + 4 * hart))
+            .is_some_and(|msip| *msip != 0);
+        let timer = self.mtime() >= self.mtimecmp(hart);
+        (soft as u32) << IRQ_M_SOFT | (timer as u32) << IRQ_M_TIMER
+    }
+
+    fn clone_box(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+
+    fn fdt_name(&self) -> &'static str {
+        "clint"
+    }
+
+    fn fdt_props(&self, fdt: &mut Fdt, harts: u32) {
+        fdt.prop_strs("compatible", &["sifive,clint0", "riscv,clint0"]);
+        let cells: Vec<u32> = (0..harts)
+            .flat_map(|hart| {
+                let intc = fdt::intc_phandle(hart);
+                [intc, IRQ_M_SOFT, intc, IRQ_M_TIMER]
+            })
+            .collect();
+        fdt.prop_cells("interrupts-extended", &cells);
+    }
+}
@@ -0,0 +1,114 @@
+// Code coverage of the guest: how often each instruction address executed and
+// how often each conditional branch went either way.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::dwarf::LineTable;
+use crate::elf::Symbols;
+use crate::memory::BUS;
+use crate::opcode::B_TYPE;
+use crate::rvc;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BranchCount {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+// source line -> (hits, branch outcomes in address order)
+type LineCounts = BTreeMap<u32, (u64, Vec<Option<BranchCount>>)>;
+
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    hits: BTreeMap<u32, u64>,
+    branches: BTreeMap<u32, BranchCount>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // record `instr` retired at `pc`, `next_pc` tells whether a branch was taken
+    pub fn retire(&mut self, pc: u32, instr: u32, next_pc: u32) {
+        *self.hits.entry(pc).or_insert(0) += 1;
+        if rvc::expand(instr) & 0x7f == B_TYPE {
+            let count = self.branches.entry(pc).or_default();
+            match next_pc == pc.wrapping_add(rvc::len(instr)) {
+                true => count.not_taken += 1,
+                false => count.taken += 1,
+            }
+        }
+    }
+
+    pub fn hits(&self, pc: u32) -> u64 {
+        self.hits.get(&pc).copied().unwrap_or(0)
+    }
+
+    pub fn branch(&self, pc: u32) -> Option<BranchCount> {
+        self.branches.get(&pc).copied()
+    }
+
+    // "pc <symbol> hits" per executed address, branches add their outcomes
+    pub fn report(&self, symbols: &Symbols) -> String {
+        let mut out = String::new();
+        for (pc, hits) in &self.hits {
+            write!(out, "{:#010x} <{}> {}", pc, symbols.describe(*pc), hits).unwrap();
+            if let Some(b) = self.branches.get(pc) {
+                write!(out, " taken {} not-taken {}", b.taken, b.not_taken).unwrap();
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    // lcov tracefile with line and branch records for every source line in
+    // the table, `bus` is read to find branches that never executed
+    pub fn lcov(&self, lines: &LineTable, bus: &BUS) -> String {
+        let mut files: BTreeMap<&str, LineCounts> = BTreeMap::new();
+        for row in lines.rows() {
+            let file = lines.files()[row.file].as_str();
+            let entry = files.entry(file).or_default().entry(row.line).or_default();
+            for addr in lines.range_of(row).step_by(4) {
+                entry.0 = entry.0.max(self.hits(addr));
+                let is_branch = bus
+                    .load_bytes(addr, 4)
+                    .is_some_and(|b| b[0] as u32 & 0x7f == B_TYPE);
+                if is_branch {
+                    entry.1.push(match self.hits(addr) {
+                        0 => None,
+                        _ => Some(self.branch(addr).unwrap_or_default()),
+                    });
+                }
+            }
+        }
+
+        let mut out = String::new();
+        for (file, file_lines) in files {
+            writeln!(out, "TN:\nSF:{}", file).unwrap();
+            let (mut brf, mut brh) = (0, 0);
+            for (line, (_, branches)) in &file_lines {
+                for (block, branch) in branches.iter().enumerate() {
+                    let counts = match branch {
+                        Some(b) => [b.taken.to_string(), b.not_taken.to_string()],
+                        None => ["-".to_string(), "-".to_string()],
+                    };
+                    for (i, count) in counts.iter().enumerate() {
+                        writeln!(out, "BRDA:{},{},{},{}", line, block, i, count).unwrap();
+                        brf += 1;
+                        if count != "-" && count != "0" {
+                            brh += 1;
+                        }
+                    }
+                }
+            }
+            writeln!(out, "BRF:{}\nBRH:{}", brf, brh).unwrap();
+            for (line, (hits, _)) in &file_lines {
+                writeln!(out, "DA:{},{}", line, hits).unwrap();
+            }
+            let hit = file_lines.values().filter(|(h, _)| *h > 0).count();
+            writeln!(out, "LF:{}\nLH:{}\nend_of_record", file_lines.len(), hit).unwrap();
+        }
+        out
+    }
+}
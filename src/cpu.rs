@@ -1,7 +1,153 @@
+use core::fmt;
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::block::{self, Block};
+use crate::coverage;
+use crate::csr;
 use crate::debug::REGS_NAMES;
+use crate::hooks::{HookList, Trap};
+use crate::isa::Isa;
 use crate::memory;
+use crate::mmu::{self, Access};
 use crate::opcode::*;
+use crate::profile;
 use crate::registers;
+use crate::rvc;
+use crate::semihosting;
+
+// executes one decoded instruction
+pub type Exec = fn(&mut CPU, u32);
+
+// exception causes that are no Fault
+pub const CAUSE_BREAKPOINT: u32 = 3;
+// from user mode, plus the privilege level it came from
+pub const CAUSE_ECALL: u32 = 8;
+
+// the order pending interrupts are taken in
+const IRQ_PRIORITY: [u32; 6] = [
+    csr::IRQ_M_EXT,
+    csr::IRQ_M_SOFT,
+    csr::IRQ_M_TIMER,
+    csr::IRQ_S_EXT,
+    csr::IRQ_S_SOFT,
+    csr::IRQ_S_TIMER,
+];
+
+// the privilege level a hart runs at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege {
+    // the level an mstatus.MPP or SPP field holds
+    pub fn from_bits(bits: u32) -> Self {
+        match bits {
+            0 => Privilege::User,
+            1 => Privilege::Supervisor,
+            _ => Privilege::Machine,
+        }
+    }
+}
+
+// an instruction the machine could not carry out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    IllegalInstruction(u32),
+    // nothing answers at the address
+    FetchAccess(u32),
+    LoadAccess(u32),
+    StoreAccess(u32),
+    // the page tables do not map the virtual address, or not for this
+    FetchPage(u32),
+    LoadPage(u32),
+    StorePage(u32),
+    // a jump to or fetch from an address that is no instruction boundary
+    FetchMisaligned(u32),
+    // loads, stores and AMOs off their natural alignment, see Misaligned
+    LoadMisaligned(u32),
+    StoreMisaligned(u32),
+}
+
+impl Fault {
+    // mcause of the exception the fault is
+    pub fn cause(&self) -> u32 {
+        match self {
+            Fault::FetchMisaligned(_) => 0,
+            Fault::FetchAccess(_) => 1,
+            Fault::IllegalInstruction(_) => 2,
+            Fault::LoadMisaligned(_) => 4,
+            Fault::LoadAccess(_) => 5,
+            Fault::StoreMisaligned(_) => 6,
+            Fault::StoreAccess(_) => 7,
+            Fault::FetchPage(_) => 12,
+            Fault::LoadPage(_) => 13,
+            Fault::StorePage(_) => 15,
+        }
+    }
+
+    // mtval: the faulting address, or the instruction that is illegal
+    pub fn tval(&self) -> u32 {
+        match *self {
+            Fault::IllegalInstruction(value)
+            | Fault::FetchAccess(value)
+            | Fault::LoadAccess(value)
+            | Fault::StoreAccess(value)
+            | Fault::FetchPage(value)
+            | Fault::LoadPage(value)
+            | Fault::StorePage(value)
+            | Fault::FetchMisaligned(value)
+            | Fault::LoadMisaligned(value)
+            | Fault::StoreMisaligned(value) => value,
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::IllegalInstruction(instr) => write!(f, "illegal instruction {:#010x}", instr),
+            Fault::FetchAccess(addr) => write!(f, "instruction fetch from {:#x} failed", addr),
+            Fault::LoadAccess(addr) => write!(f, "load from {:#x} failed", addr),
+            Fault::StoreAccess(addr) => write!(f, "store to {:#x} failed", addr),
+            Fault::FetchPage(addr) => write!(f, "page fault on instruction fetch from {:#x}", addr),
+            Fault::LoadPage(addr) => write!(f, "page fault on load from {:#x}", addr),
+            Fault::StorePage(addr) => write!(f, "page fault on store to {:#x}", addr),
+            Fault::FetchMisaligned(addr) => write!(f, "misaligned instruction address {:#x}", addr),
+            Fault::LoadMisaligned(addr) => write!(f, "misaligned load from {:#x}", addr),
+            Fault::StoreMisaligned(addr) => write!(f, "misaligned store to {:#x}", addr),
+        }
+    }
+}
+
+// what a load, store or AMO off its natural alignment does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Misaligned {
+    // the hardware handles it, in one access
+    #[default]
+    Allow,
+    // address-misaligned exception with the address in mtval
+    Trap,
+    // what M-mode firmware does for loads and stores: one byte at a time, so
+    // devices see byte accesses; AMOs still trap
+    Emulate,
+}
+
+impl Misaligned {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "allow" => Ok(Misaligned::Allow),
+            "trap" => Ok(Misaligned::Trap),
+            "emulate" => Ok(Misaligned::Emulate),
+            _ => Err(format!("`{}` is none of allow, trap and emulate", s)),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct CPU {
@@ -9,7 +155,59 @@ pub struct CPU {
     pub xregs: registers::XREGS,
     pub pc: u32,
 
+    // control and status registers, mhartid among them
+    pub csrs: csr::CSRS,
+
+    // machine mode out of reset, traps and xRET move it
+    pub privilege: Privilege,
+
+    // extensions the decoder accepts, change it with set_isa()
+    pub(crate) isa: Isa,
+
+    // how loads, stores and AMOs off their alignment are carried out
+    pub misaligned: Misaligned,
+
+    // physical address reserved by lr.w and the word it read there; sc.w
+    // only stores while memory still holds that word
+    pub reservation: Option<(u32, u32)>,
+
+    // why the last instruction could not complete, pc is left at it
+    pub fault: Option<Fault>,
+
+    // set by instructions that moved pc themselves
+    jumped: bool,
+
+    // the instruction trapped into a guest handler, pc is at the handler
+    pub(crate) trapped: bool,
+
+    // the bus's irq_changes() when the device lines were last sampled
+    irq_seen: u64,
+
+    // length of the instruction being executed, 2 for compressed ones
+    ilen: u32,
+
     pub bus: memory::BUS,
+
+    // print every executed instruction and its operands
+    pub trace: bool,
+
+    // serve semihosting requests made through ebreak
+    pub semihosting: Option<semihosting::Semihosting>,
+
+    // count retired instructions per pc and call stack
+    pub profiler: Option<profile::Profiler>,
+
+    // record executed addresses and branch outcomes
+    pub coverage: Option<coverage::Coverage>,
+
+    // pre-decoded basic blocks, by physical address
+    pub blocks: block::BlockCache,
+
+    // Sv32 translations, flushed by satp writes and sfence.vma
+    pub(crate) tlb: mmu::Tlb,
+
+    // embedder instrumentation, see Machine::add_hooks()
+    pub hooks: HookList,
 }
 
 impl CPU {
@@ -17,110 +215,591 @@ impl CPU {
         let mut cpu: CPU = CPU {
             xregs: registers::XREGS::new(),
             pc: memory::MEM_BASE,
+            csrs: csr::CSRS::new(0),
+            privilege: Privilege::Machine,
+            isa: Isa::default(),
+            misaligned: Misaligned::default(),
+            reservation: None,
+            fault: None,
+            jumped: false,
+            trapped: false,
+            irq_seen: u64::MAX,
+            ilen: 4,
             bus: memory::BUS::new(),
+            trace: false,
+            semihosting: None,
+            profiler: None,
+            coverage: None,
+            blocks: block::BlockCache::new(),
+            tlb: mmu::Tlb::new(),
+            hooks: HookList::default(),
         };
         cpu.xregs.regs[2] = memory::MEM_BASE + memory::MEM_SIZE; // Set stack pointer
         cpu.pc = memory::MEM_BASE;
+        cpu.csrs.csrs[csr::MISA as usize] = cpu.isa.misa();
         return cpu;
     }
 
-    pub fn fetch(&self) -> u32 {
-        let instr: u32 = self.bus.load(self.pc, 32);
-        return instr;
+    pub fn isa(&self) -> &Isa {
+        &self.isa
+    }
+
+    // run `isa` from now on, instructions of any other extension are illegal
+    pub fn set_isa(&mut self, isa: Isa) {
+        self.isa = isa;
+        self.csrs.csrs[csr::MISA as usize] = isa.misa();
+        self.blocks.flush();
+    }
+
+    pub fn hartid(&self) -> u32 {
+        self.csrs.load(csr::MHARTID)
+    }
+
+    // the instruction at pc, compressed ones in the low half, None when pc
+    // is not in memory or not mapped
+    pub fn fetch(&mut self) -> Option<u32> {
+        self.fetch_at(self.pc).ok()
+    }
+
+    // fetch() with the fault it fails with; each half of an instruction
+    // that straddles two pages has a mapping of its own
+    fn fetch_at(&mut self, pc: u32) -> Result<u32, Fault> {
+        let at = self.translate(pc, Access::Fetch)?;
+        if pc % mmu::PAGE_SIZE != mmu::PAGE_SIZE - 2 {
+            return block::fetch(at, &self.bus, &self.isa).ok_or(Fault::FetchAccess(pc));
+        }
+        let low = self.bus.try_load(at, 16).ok_or(Fault::FetchAccess(pc))?;
+        if self.isa.has('c') && rvc::is_compressed(low) {
+            return Ok(low);
+        }
+        let next = pc.wrapping_add(2);
+        let at = self.translate(next, Access::Fetch)?;
+        let high = self.bus.try_load(at, 16).ok_or(Fault::FetchAccess(next))?;
+        Ok(low | high << 16)
+    }
+
+    // whether the page tables are in the way of fetches, loads or stores
+    pub fn translates(&self) -> bool {
+        self.translating(Access::Fetch).is_some() || self.translating(Access::Load).is_some()
+    }
+
+    // the privilege level page permissions are checked at for `access`,
+    // None when addresses are physical: satp is Bare, or the access is made
+    // in machine mode, which loads and stores are not with mstatus.MPRV
+    fn translating(&self, access: Access) -> Option<Privilege> {
+        if self.csrs.load(csr::SATP) & csr::SATP_MODE == 0 {
+            return None;
+        }
+        let status = self.csrs.load(csr::MSTATUS);
+        let privilege = match access {
+            Access::Load | Access::Store
+                if self.privilege == Privilege::Machine && status & csr::MSTATUS_MPRV != 0 =>
+            {
+                Privilege::from_bits((status & csr::MSTATUS_MPP) >> csr::MSTATUS_MPP_SHIFT)
+            }
+            _ => self.privilege,
+        };
+        (privilege < Privilege::Machine).then_some(privilege)
+    }
+
+    // the physical address of `vaddr`, or the fault an `access` there
+    // raises
+    pub fn translate(&mut self, vaddr: u32, access: Access) -> Result<u32, Fault> {
+        let Some(privilege) = self.translating(access) else {
+            return Ok(vaddr);
+        };
+        let satp = self.csrs.load(csr::SATP);
+        let status = self.csrs.load(csr::MSTATUS);
+        self.tlb
+            .translate(&mut self.bus, satp, status, privilege, vaddr, access)
+    }
+
+    // report an instruction that completed at `pc` to the attached analyses,
+    // the driver calls this once self.pc points at the next instruction
+    pub fn retire(&mut self, pc: u32, instr: u32) {
+        self.bus.count_retired(1);
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.retire(pc, instr, self.pc);
+        }
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.retire(pc, instr, self.pc);
+        }
+        if !self.hooks.is_empty() {
+            self.hooks.retire(pc, instr);
+        }
+    }
+
+    // run the instruction at pc and move on to the next one, returns the
+    // number of instructions retired: 0 when it faulted. A trap taken
+    // counts as one, though nothing retires, so a hart trapping over and
+    // over still uses up its budget.
+    pub fn step(&mut self) -> u64 {
+        if self.take_interrupt() {
+            return 1;
+        }
+        let pc = self.pc;
+        self.trapped = false;
+        let Some((instr, exec)) = self.fetch_decoded() else {
+            return self.trapped as u64;
+        };
+        self.execute_decoded(instr, exec);
+        if self.trapped {
+            return 1;
+        }
+        if self.fault.is_some() {
+            return 0;
+        }
+        self.retire(pc, instr);
+        1
+    }
+
+    // run from pc to the end of its basic block, the same as calling step()
+    // that many times, and return the number of instructions retired
+    pub fn run_block(&mut self) -> u64 {
+        if self.take_interrupt() {
+            return 1;
+        }
+        let Some(block) = self.enter_block() else {
+            return self.step();
+        };
+        let generation = self.bus.code_writes();
+        let mut retired = 0;
+        for &(instr, exec) in block.instrs.iter() {
+            let pc = self.pc;
+            self.execute_decoded(instr, exec);
+            if self.trapped {
+                return retired + 1;
+            }
+            if self.fault.is_some() {
+                break;
+            }
+            self.retire(pc, instr);
+            retired += 1;
+            // a jump, a store that hit the rest of this block, or a device
+            // that powers the machine off
+            if self.pc != pc.wrapping_add(rvc::len(instr))
+                || self.bus.code_writes() != generation
+                || self.bus.power().is_some()
+            {
+                break;
+            }
+        }
+        retired
+    }
+
+    // the block at pc, found by the physical address it maps to; None when
+    // step() has to take the instruction, e.g. to raise its fetch fault.
+    // Blocks never cross a page, so the rest of one follows pc.
+    pub(crate) fn enter_block(&mut self) -> Option<Arc<Block>> {
+        let at = self.translate(self.pc, Access::Fetch).ok()?;
+        self.blocks.enter(at, &mut self.bus, &self.isa)
+    }
+
+    // the instruction at pc and its handler, from the block cache when
+    // possible, None with a fetch fault when pc is not in RAM or not mapped
+    pub fn fetch_decoded(&mut self) -> Option<(u32, Exec)> {
+        let pc = self.pc;
+        if !pc.is_multiple_of(self.isa.ialign()) {
+            self.raise(Fault::FetchMisaligned(pc));
+            return None;
+        }
+        let decoded = match self.translate(pc, Access::Fetch) {
+            Ok(_) if pc % mmu::PAGE_SIZE == mmu::PAGE_SIZE - 2 => self
+                .fetch_at(pc)
+                .map(|instr| (instr, decode(instr, &self.isa))),
+            Ok(at) => self
+                .blocks
+                .lookup(at, &mut self.bus, &self.isa)
+                .ok_or(Fault::FetchAccess(pc)),
+            Err(fault) => Err(fault),
+        };
+        decoded.map_err(|fault| self.raise(fault)).ok()
     }
 
     pub fn execute(&mut self, instr: u32) {
-        let opcode = instr & 0x7f;
-        let funct3 = (instr >> 12) & 0x7;
-        let funct7 = (instr >> 25) & 0x7f;
+        self.execute_decoded(instr, decode(instr, &self.isa));
+    }
+
+    // carry out one instruction and move pc past it, unless the instruction
+    // jumped or faulted
+    pub fn execute_decoded(&mut self, instr: u32, exec: Exec) {
         self.xregs.regs[0] = 0; // x0 hardwired to 0 at each cycle
+        self.fault = None;
+        self.jumped = false;
+        self.trapped = false;
+        self.ilen = rvc::len(instr);
+        exec(self, instr);
+        // whatever the instruction wrote to it
+        self.xregs.regs[0] = 0;
+        if !self.jumped && self.fault.is_none() {
+            self.pc = self.pc.wrapping_add(self.ilen);
+        }
+    }
 
-        match opcode {
-            LUI => exec_lui(self, instr),
-            AUIPC => exec_auipc(self, instr),
-            JAL => exec_jal(self, instr),
-            JALR => exec_jalr(self, instr),
-            B_TYPE => match funct3 {
-                BEQ => exec_beq(self, instr),
-                BNE => exec_bne(self, instr),
-                BLT => exec_blt(self, instr),
-                BGE => exec_bge(self, instr),
-                BLTU => exec_bltu(self, instr),
-                BGEU => exec_bgeu(self, instr),
-                _ => panic!(),
-            },
-            LOAD => match funct3 {
-                LB => exec_lb(self, instr),
-                LH => exec_lh(self, instr),
-                LW => exec_lw(self, instr),
-                LBU => exec_lbu(self, instr),
-                LHU => exec_lhu(self, instr),
-                LWU => exec_lwu(self, instr),
-                _ => panic!(),
-            },
-            S_TYPE => match funct3 {
-                SB => exec_sb(self, instr),
-                SH => exec_sh(self, instr),
-                SW => exec_sw(self, instr),
-                _ => panic!(),
+    // give up on the instruction at pc: the guest's handler takes over, or,
+    // with none set up, the hart stops there with the CSRs as taking the
+    // exception would leave them
+    fn raise(&mut self, fault: Fault) {
+        self.trap(Trap::Fault(fault));
+        if self.take_trap(fault.cause(), fault.tval(), false) {
+            return;
+        }
+        self.fault = Some(fault);
+        self.csrs.store(csr::MEPC, self.pc);
+        self.csrs.store(csr::MCAUSE, fault.cause());
+        self.csrs.store(csr::MTVAL, fault.tval());
+    }
+
+    // the privilege level and trap vector an exception or interrupt with
+    // `cause` goes to; None while that vector is 0, as nothing has set up
+    // a handler there
+    fn trap_target(&self, cause: u32, interrupt: bool) -> Option<(Privilege, u32)> {
+        let deleg = match interrupt {
+            true => self.csrs.load(csr::MIDELEG),
+            false => self.csrs.load(csr::MEDELEG),
+        };
+        let (to, tvec) = match self.privilege {
+            Privilege::Machine => (Privilege::Machine, csr::MTVEC),
+            _ if deleg >> cause & 1 != 0 => (Privilege::Supervisor, csr::STVEC),
+            _ => (Privilege::Machine, csr::MTVEC),
+        };
+        match self.csrs.load(tvec) {
+            0 => None,
+            tvec => Some((to, tvec)),
+        }
+    }
+
+    // whether an exception with `cause` raised now goes to a guest handler
+    pub fn has_handler(&self, cause: u32) -> bool {
+        self.trap_target(cause, false).is_some()
+    }
+
+    // move to another privilege level on a trap or xRET, telling the hooks
+    fn set_privilege(&mut self, to: Privilege) {
+        if to != self.privilege && !self.hooks.is_empty() {
+            self.hooks.privilege(self.privilege, to);
+        }
+        self.privilege = to;
+    }
+
+    // cause of an ecall at the current privilege level
+    pub fn ecall_cause(&self) -> u32 {
+        CAUSE_ECALL + self.privilege as u32
+    }
+
+    // enter the handler for `cause`, with pc as where to return to; false
+    // when there is none
+    fn take_trap(&mut self, cause: u32, tval: u32, interrupt: bool) -> bool {
+        let Some((to, tvec)) = self.trap_target(cause, interrupt) else {
+            return false;
+        };
+        let mcause = cause | (interrupt as u32) << 31;
+        let status = self.csrs.load(csr::MSTATUS);
+        let from = self.privilege as u32;
+        let status = match to {
+            Privilege::Machine => {
+                self.csrs.store(csr::MEPC, self.pc);
+                self.csrs.store(csr::MCAUSE, mcause);
+                self.csrs.store(csr::MTVAL, tval);
+                let mie = status & csr::MSTATUS_MIE != 0;
+                let status = status & !(csr::MSTATUS_MIE | csr::MSTATUS_MPP);
+                with_bit(status, csr::MSTATUS_MPIE, mie) | from << csr::MSTATUS_MPP_SHIFT
+            }
+            _ => {
+                self.csrs.store(csr::SEPC, self.pc);
+                self.csrs.store(csr::SCAUSE, mcause);
+                self.csrs.store(csr::STVAL, tval);
+                let sie = status & csr::MSTATUS_SIE != 0;
+                let status = with_bit(status & !csr::MSTATUS_SIE, csr::MSTATUS_SPIE, sie);
+                with_bit(status, csr::MSTATUS_SPP, from != 0)
+            }
+        };
+        self.csrs.store(csr::MSTATUS, status);
+        self.set_privilege(to);
+        // vectored mode sends interrupts to base + 4 * cause
+        let base = tvec & !3;
+        self.jump(match tvec & 1 != 0 && interrupt {
+            true => base.wrapping_add(4 * cause),
+            false => base,
+        });
+        self.trapped = true;
+        self.reservation = None;
+        true
+    }
+
+    // take the most urgent interrupt that is pending and enabled, before
+    // the instruction at pc; the device lines are sampled again whenever
+    // a device register was accessed or the devices were polled
+    pub fn take_interrupt(&mut self) -> bool {
+        if self.bus.irq_changes() != self.irq_seen {
+            self.irq_seen = self.bus.irq_changes();
+            self.csrs.lines = self.bus.interrupts(self.hartid());
+        }
+        let pending = self.csrs.read(csr::MIP) & self.csrs.load(csr::MIE);
+        if pending == 0 {
+            return false;
+        }
+        let status = self.csrs.load(csr::MSTATUS);
+        let mideleg = self.csrs.load(csr::MIDELEG);
+        // interrupts for a more privileged level are always enabled, those
+        // for the current one only with its xIE bit
+        let m_enabled = self.privilege < Privilege::Machine || status & csr::MSTATUS_MIE != 0;
+        let s_enabled = self.privilege < Privilege::Supervisor
+            || self.privilege == Privilege::Supervisor && status & csr::MSTATUS_SIE != 0;
+        let mut enabled = 0;
+        if m_enabled {
+            enabled |= pending & !mideleg;
+        }
+        if s_enabled {
+            enabled |= pending & mideleg;
+        }
+        let Some(&irq) = IRQ_PRIORITY.iter().find(|irq| enabled >> **irq & 1 != 0) else {
+            return false;
+        };
+        if self.trap_target(irq, true).is_none() {
+            return false;
+        }
+        self.trap(Trap::Interrupt(irq));
+        self.take_trap(irq, 0, true)
+    }
+
+    // tell the hooks the instruction at pc traps
+    fn trap(&mut self, trap: Trap) {
+        if !self.hooks.is_empty() {
+            self.hooks.trap(self.pc, trap);
+        }
+    }
+
+    // continue at `target` instead of the next instruction
+    pub fn jump(&mut self, target: u32) {
+        self.pc = target;
+        self.jumped = true;
+    }
+
+    // jump for jal, jalr and taken branches, which trap instead when
+    // `target` is no instruction boundary; false then
+    fn branch(&mut self, target: u32) -> bool {
+        if !target.is_multiple_of(self.isa.ialign()) {
+            self.raise(Fault::FetchMisaligned(target));
+            return false;
+        }
+        self.jump(target);
+        true
+    }
+
+    // read guest memory, faulting when nothing answers at addr or the page
+    // tables do not let the hart read it
+    pub fn load(&mut self, addr: u32, size: u32) -> Option<u32> {
+        let value = match self.misaligned {
+            _ if addr.is_multiple_of(size / 8) => self.read(addr, size),
+            // an access across two pages is split, they map apart
+            Misaligned::Allow if !crosses_page(addr, size) => self.read(addr, size),
+            Misaligned::Trap => {
+                self.raise(Fault::LoadMisaligned(addr));
+                return None;
+            }
+            // little endian, one byte after the other
+            Misaligned::Allow | Misaligned::Emulate => (0..size / 8).try_fold(0, |value, i| {
+                let byte = self.read(addr.wrapping_add(i), 8)?;
+                Ok(value | byte << (8 * i))
+            }),
+        };
+        match value {
+            Ok(value) if !self.hooks.is_empty() => self.hooks.mem_read(addr, size, value),
+            Ok(_) => (),
+            Err(fault) => self.raise(fault),
+        }
+        value.ok()
+    }
+
+    // write guest memory, faulting when nothing answers at addr or the page
+    // tables do not let the hart write it
+    pub fn store(&mut self, addr: u32, size: u32, value: u32) -> Option<()> {
+        let done = match self.misaligned {
+            _ if addr.is_multiple_of(size / 8) => self.write(addr, size, value),
+            Misaligned::Allow if !crosses_page(addr, size) => self.write(addr, size, value),
+            Misaligned::Trap => {
+                self.raise(Fault::StoreMisaligned(addr));
+                return None;
+            }
+            Misaligned::Allow | Misaligned::Emulate => (0..size / 8)
+                .try_for_each(|i| self.write(addr.wrapping_add(i), 8, value >> (8 * i) & 0xff)),
+        };
+        match done {
+            Ok(()) if !self.hooks.is_empty() => self.hooks.mem_write(addr, size, value),
+            Ok(()) => (),
+            Err(fault) => self.raise(fault),
+        }
+        done.ok()
+    }
+
+    // one access of load() and store(), at a virtual address
+    fn read(&mut self, addr: u32, size: u32) -> Result<u32, Fault> {
+        let at = self.translate(addr, Access::Load)?;
+        self.bus.read(at, size).ok_or(Fault::LoadAccess(addr))
+    }
+    fn write(&mut self, addr: u32, size: u32, value: u32) -> Result<(), Fault> {
+        let at = self.translate(addr, Access::Store)?;
+        self.bus
+            .write(at, size, value)
+            .ok_or(Fault::StoreAccess(addr))
+    }
+
+    // whether sc.w at physical `addr` would store: lr.w reserved it and
+    // nothing changed the word since, whichever hart or device had the bus
+    pub fn holds_reservation(&self, addr: u32) -> bool {
+        self.reservation
+            .is_some_and(|(at, word)| at == addr && self.bus.try_load(addr, 32) == Some(word))
+    }
+
+    // AMOs and lr/sc are never split up: off their alignment they trap
+    // unless the hardware handles it
+    fn atomic_aligned(&mut self, addr: u32, fault: Fault) -> bool {
+        if !addr.is_multiple_of(4) && self.misaligned != Misaligned::Allow {
+            self.raise(fault);
+            return false;
+        }
+        true
+    }
+}
+
+// whether a `size` bit access at `addr` touches two pages
+fn crosses_page(addr: u32, size: u32) -> bool {
+    addr % mmu::PAGE_SIZE + size / 8 > mmu::PAGE_SIZE
+}
+
+// `status` with `bit` set or cleared
+fn with_bit(status: u32, bit: u32, set: bool) -> u32 {
+    match set {
+        true => status | bit,
+        false => status & !bit,
+    }
+}
+
+// handler for `instr`, illegal unless `isa` has it
+pub fn decode(instr: u32, isa: &Isa) -> Exec {
+    if rvc::is_compressed(instr) && isa.has('c') {
+        return exec_compressed;
+    }
+    let opcode = instr & 0x7f;
+    let funct3 = (instr >> 12) & 0x7;
+    let funct7 = (instr >> 25) & 0x7f;
+
+    match opcode {
+        LUI => exec_lui,
+        AUIPC => exec_auipc,
+        JAL => exec_jal,
+        JALR => exec_jalr,
+        B_TYPE => match funct3 {
+            BEQ => exec_beq,
+            BNE => exec_bne,
+            BLT => exec_blt,
+            BGE => exec_bge,
+            BLTU => exec_bltu,
+            BGEU => exec_bgeu,
+            _ => exec_illegal,
+        },
+        LOAD => match funct3 {
+            LB => exec_lb,
+            LH => exec_lh,
+            LW => exec_lw,
+            LBU => exec_lbu,
+            LHU => exec_lhu,
+            LWU => exec_lwu,
+            _ => exec_illegal,
+        },
+        S_TYPE => match funct3 {
+            SB => exec_sb,
+            SH => exec_sh,
+            SW => exec_sw,
+            _ => exec_illegal,
+        },
+        I_TYPE => match funct3 {
+            ADDI => exec_addi,
+            SLLI => exec_slli,
+            SLTI => exec_slti,
+            SLTIU => exec_sltiu,
+            XORI => exec_xori,
+            SRI => match funct7 {
+                SRLI => exec_srli,
+                SRAI => exec_srai,
+                _ => exec_illegal,
             },
-            I_TYPE => match funct3 {
-                ADDI => exec_addi(self, instr),
-                SLLI => exec_slli(self, instr),
-                SLTI => exec_slti(self, instr),
-                SLTIU => exec_sltiu(self, instr),
-                XORI => exec_xori(self, instr),
-                SRI => match funct7 {
-                    SRLI => exec_srli(self, instr),
-                    SRAI => exec_srai(self, instr),
-                    _ => panic!(),
-                },
-                ORI => exec_ori(self, instr),
-                ANDI => exec_andi(self, instr),
-                _ => {
-                    panic!("malformed I type instruction");
-                }
+            ORI => exec_ori,
+            ANDI => exec_andi,
+            _ => exec_illegal,
+        },
+        R_TYPE if funct7 == MULDIV => match funct3 {
+            _ if !isa.has('m') => exec_illegal,
+            MUL => exec_mul,
+            MULH => exec_mulh,
+            MULHSU => exec_mulhsu,
+            MULHU => exec_mulhu,
+            DIV => exec_div,
+            DIVU => exec_divu,
+            REM => exec_rem,
+            _ => exec_remu,
+        },
+        R_TYPE => match funct3 {
+            ADDSUB => match funct7 {
+                ADD => exec_add,
+                SUB => exec_sub,
+                _ => exec_illegal,
             },
-            R_TYPE => match funct3 {
-                ADDSUB => match funct7 {
-                    ADD => exec_add(self, instr),
-                    SUB => exec_sub(self, instr),
-                    _ => (),
-                },
-                SLL => exec_sll(self, instr),
-                SLT => exec_slt(self, instr),
-                SLTU => exec_sltu(self, instr),
-                XOR => exec_xor(self, instr),
-                SR => match funct7 {
-                    SRL => exec_srl(self, instr),
-                    SRA => exec_sra(self, instr),
-                    _ => (),
-                },
-                OR => exec_or(self, instr),
-                AND => exec_and(self, instr),
-                _ => {
-                    panic!("malformed I type instruction");
-                }
+            SLL => exec_sll,
+            SLT => exec_slt,
+            SLTU => exec_sltu,
+            XOR => exec_xor,
+            SR => match funct7 {
+                SRL => exec_srl,
+                SRA => exec_sra,
+                _ => exec_illegal,
             },
-            FENCE => exec_fence(self, instr),
-            CSR => match (funct3) {
-                ECALL | EBREAK => match imm_i(instr) {
-                    0x0 => exec_ecall(self, instr),
-                    0x1 => exec_ebreak(self, instr),
-                    _ => (),
-                },
-                CSRRW => exec_csrrw(self, instr),
-                CSRRS => exec_csrrs(self, instr),
-                CSRRC => exec_csrrc(self, instr),
-                CSRRWI => exec_csrrwi(self, instr),
-                CSRRSI => exec_csrrsi(self, instr),
-                CSRRCI => exec_csrrci(self, instr),
-                _ => {
-                    panic!("malformed CSR instruction");
-                }
+            OR => exec_or,
+            AND => exec_and,
+            _ => exec_illegal,
+        },
+        FENCE => match funct3 {
+            FENCE_I if isa.zifencei => exec_fence_i,
+            FENCE_I => exec_illegal,
+            _ => exec_fence,
+        },
+        AMO if isa.has('a') => match (funct3, funct5(instr)) {
+            (AMO_W, LR) => exec_lr_w,
+            (AMO_W, SC) => exec_sc_w,
+            (AMO_W, AMOSWAP) => exec_amoswap_w,
+            (AMO_W, AMOADD) => exec_amoadd_w,
+            (AMO_W, AMOXOR) => exec_amoxor_w,
+            (AMO_W, AMOAND) => exec_amoand_w,
+            (AMO_W, AMOOR) => exec_amoor_w,
+            (AMO_W, AMOMIN) => exec_amomin_w,
+            (AMO_W, AMOMAX) => exec_amomax_w,
+            (AMO_W, AMOMINU) => exec_amominu_w,
+            (AMO_W, AMOMAXU) => exec_amomaxu_w,
+            _ => exec_illegal,
+        },
+        CSR => match funct3 {
+            ECALL | EBREAK => match imm_i(instr) as u32 & 0xfff {
+                _ if rd(instr) != 0 => exec_illegal,
+                _ if funct7 == SFENCE_VMA => exec_sfence_vma,
+                _ if rs1(instr) != 0 => exec_illegal,
+                0x0 => exec_ecall,
+                0x1 => exec_ebreak,
+                SRET => exec_sret,
+                WFI => exec_wfi,
+                MRET => exec_mret,
+                _ => exec_illegal,
             },
-            _ => panic!("invalid instr {}, opcode: {:b}", instr, opcode),
-        }
+            _ if !isa.zicsr => exec_illegal,
+            CSRRW => exec_csrrw,
+            CSRRS => exec_csrrs,
+            CSRRC => exec_csrrc,
+            CSRRWI => exec_csrrwi,
+            CSRRSI => exec_csrrsi,
+            CSRRCI => exec_csrrci,
+            _ => exec_illegal,
+        },
+        _ => exec_illegal,
     }
 }
 
@@ -138,112 +817,120 @@ pub fn exec_auipc(cpu: &mut CPU, instr: u32) {
 }
 pub fn exec_jal(cpu: &mut CPU, instr: u32) {
     let imm = imm_j(instr) as i32;
-    cpu.xregs.regs[rd(instr) as usize] = cpu.pc.wrapping_add(4);
-    cpu.pc = ((cpu.pc as i32).wrapping_add(imm)).wrapping_sub(4) as u32;
+    let link = cpu.pc.wrapping_add(cpu.ilen);
+    if cpu.branch((cpu.pc as i32).wrapping_add(imm) as u32) {
+        cpu.xregs.regs[rd(instr) as usize] = link;
+    }
 }
 pub fn exec_jalr(cpu: &mut CPU, instr: u32) {
     let imm = imm_i(instr) as i32;
-    cpu.xregs.regs[rd(instr) as usize] = cpu.pc + 4;
-    // ignore the last 1 bit with 0xfffffffe
-    cpu.pc = (cpu.xregs.regs[rs1(instr) as usize] as i32).wrapping_add(imm) as u32 & 0xfffffffe;
+    // ignore the last 1 bit with 0xfffffffe, rs1 is read before rd is written
+    let target = (cpu.xregs.regs[rs1(instr) as usize] as i32).wrapping_add(imm) as u32 & 0xfffffffe;
+    let link = cpu.pc.wrapping_add(cpu.ilen);
+    if cpu.branch(target) {
+        cpu.xregs.regs[rd(instr) as usize] = link;
+    }
 }
 pub fn exec_beq(cpu: &mut CPU, instr: u32) {
     let imm = imm_b(instr) as i32;
     if cpu.xregs.regs[rs1(instr) as usize] == cpu.xregs.regs[rs2(instr) as usize] {
-        cpu.pc = (cpu.pc as i32).wrapping_add(imm).wrapping_sub(4) as u32;
+        cpu.branch((cpu.pc as i32).wrapping_add(imm) as u32);
     }
 }
 pub fn exec_bne(cpu: &mut CPU, instr: u32) {
     let imm = imm_b(instr) as i32;
     dump_format_instr_b(cpu, instr);
     if cpu.xregs.regs[rs1(instr) as usize] != cpu.xregs.regs[rs2(instr) as usize] {
-        cpu.pc = (cpu.pc as i32).wrapping_add(imm).wrapping_sub(4) as u32;
+        cpu.branch((cpu.pc as i32).wrapping_add(imm) as u32);
     }
 }
 pub fn exec_blt(cpu: &mut CPU, instr: u32) {
     let imm = imm_b(instr) as i32;
     dump_format_instr_b(cpu, instr);
     if (cpu.xregs.regs[rs1(instr) as usize] as i32) < (cpu.xregs.regs[rs2(instr) as usize] as i32) {
-        cpu.pc = (cpu.pc as i32).wrapping_add(imm).wrapping_sub(4) as u32;
+        cpu.branch((cpu.pc as i32).wrapping_add(imm) as u32);
     }
 }
 pub fn exec_bge(cpu: &mut CPU, instr: u32) {
     let imm = imm_b(instr) as i32;
     if (cpu.xregs.regs[rs1(instr) as usize] as i32) >= (cpu.xregs.regs[rs2(instr) as usize] as i32)
     {
-        cpu.pc = (cpu.pc as i32).wrapping_add(imm).wrapping_sub(4) as u32;
+        cpu.branch((cpu.pc as i32).wrapping_add(imm) as u32);
     }
 }
 pub fn exec_bltu(cpu: &mut CPU, instr: u32) {
     let imm = imm_b(instr) as i32;
     if cpu.xregs.regs[rs1(instr) as usize] < cpu.xregs.regs[rs2(instr) as usize] {
-        cpu.pc = (cpu.pc as i32).wrapping_add(imm).wrapping_sub(4) as u32;
+        cpu.branch((cpu.pc as i32).wrapping_add(imm) as u32);
     }
 }
 pub fn exec_bgeu(cpu: &mut CPU, instr: u32) {
     let imm = imm_b(instr) as i32;
     if cpu.xregs.regs[rs1(instr) as usize] >= cpu.xregs.regs[rs2(instr) as usize] {
-        cpu.pc = (cpu.pc as i32).wrapping_add(imm).wrapping_sub(4) as u32;
+        cpu.branch((cpu.pc as i32).wrapping_add(imm) as u32);
     }
 }
 pub fn exec_lb(cpu: &mut CPU, instr: u32) {
     let imm = imm_i(instr) as i32;
-    let load_i8 = cpu.bus.load(
-        (cpu.xregs.regs[rs1(instr) as usize] as i32).wrapping_add(imm) as u32,
-        8,
-    ) as i32;
-    cpu.xregs.regs[rd(instr) as usize] = ((load_i8 << 26) >> 26) as u32;
+    let addr = (cpu.xregs.regs[rs1(instr) as usize] as i32).wrapping_add(imm) as u32;
+    let Some(load_i8) = cpu.load(addr, 8) else {
+        return;
+    };
+    cpu.xregs.regs[rd(instr) as usize] = (((load_i8 as i32) << 24) >> 24) as u32;
 }
 pub fn exec_lh(cpu: &mut CPU, instr: u32) {
     let imm = imm_i(instr) as i32;
-    let load_i16 = cpu.bus.load(
-        (cpu.xregs.regs[rs1(instr) as usize] as i32).wrapping_add(imm) as u32,
-        16,
-    ) as i32;
-    cpu.xregs.regs[rd(instr) as usize] = ((load_i16 << 16) >> 16) as u32;
+    let addr = (cpu.xregs.regs[rs1(instr) as usize] as i32).wrapping_add(imm) as u32;
+    let Some(load_i16) = cpu.load(addr, 16) else {
+        return;
+    };
+    cpu.xregs.regs[rd(instr) as usize] = (((load_i16 as i32) << 16) >> 16) as u32;
 }
 pub fn exec_lw(cpu: &mut CPU, instr: u32) {
     let imm = imm_i(instr) as i32;
-    cpu.xregs.regs[rd(instr) as usize] = cpu.bus.load(
-        (cpu.xregs.regs[rs1(instr) as usize] as i32).wrapping_add(imm) as u32,
-        32,
-    );
+    let addr = (cpu.xregs.regs[rs1(instr) as usize] as i32).wrapping_add(imm) as u32;
+    if let Some(val) = cpu.load(addr, 32) {
+        cpu.xregs.regs[rd(instr) as usize] = val;
+    }
 }
 pub fn exec_lbu(cpu: &mut CPU, instr: u32) {
     let imm = imm_i(instr) as u32;
-    cpu.xregs.regs[rd(instr) as usize] = cpu
-        .bus
-        .load(cpu.xregs.regs[rs1(instr) as usize].wrapping_add(imm), 8);
+    let addr = cpu.xregs.regs[rs1(instr) as usize].wrapping_add(imm);
+    if let Some(val) = cpu.load(addr, 8) {
+        cpu.xregs.regs[rd(instr) as usize] = val;
+    }
 }
 pub fn exec_lhu(cpu: &mut CPU, instr: u32) {
     let imm = imm_i(instr) as u32;
-    cpu.xregs.regs[rd(instr) as usize] = cpu
-        .bus
-        .load(cpu.xregs.regs[rs1(instr) as usize].wrapping_add(imm), 16);
+    let addr = cpu.xregs.regs[rs1(instr) as usize].wrapping_add(imm);
+    if let Some(val) = cpu.load(addr, 16) {
+        cpu.xregs.regs[rd(instr) as usize] = val;
+    }
 }
 pub fn exec_lwu(cpu: &mut CPU, instr: u32) {
     let imm = imm_i(instr) as u32;
-    cpu.xregs.regs[rd(instr) as usize] = cpu
-        .bus
-        .load(cpu.xregs.regs[rs1(instr) as usize].wrapping_add(imm), 32);
+    let addr = cpu.xregs.regs[rs1(instr) as usize].wrapping_add(imm);
+    if let Some(val) = cpu.load(addr, 32) {
+        cpu.xregs.regs[rd(instr) as usize] = val;
+    }
 }
 pub fn exec_sb(cpu: &mut CPU, instr: u32) {
     let imm = imm_s(instr) as i32;
     let addr = (cpu.xregs.regs[rs1(instr) as usize] as i32).wrapping_add(imm) as u32;
     let val = cpu.xregs.regs[rs2(instr) as usize] & std::u8::MAX as u32;
-    cpu.bus.store(addr, 8, val);
+    cpu.store(addr, 8, val);
 }
 pub fn exec_sh(cpu: &mut CPU, instr: u32) {
     let imm = imm_s(instr) as i32;
     let addr = (cpu.xregs.regs[rs1(instr) as usize] as i32).wrapping_add(imm) as u32;
     let val = cpu.xregs.regs[rs2(instr) as usize] & std::u16::MAX as u32;
-    cpu.bus.store(addr, 16, val);
+    cpu.store(addr, 16, val);
 }
 pub fn exec_sw(cpu: &mut CPU, instr: u32) {
     let imm = imm_s(instr) as i32;
     let addr = (cpu.xregs.regs[rs1(instr) as usize] as i32).wrapping_add(imm) as u32;
     let val = cpu.xregs.regs[rs2(instr) as usize] & std::u32::MAX as u32;
-    cpu.bus.store(addr, 32, val);
+    cpu.store(addr, 32, val);
 }
 pub fn exec_addi(cpu: &mut CPU, instr: u32) {
     let imm = imm_i(instr);
@@ -289,9 +976,8 @@ pub fn exec_srai(cpu: &mut CPU, instr: u32) {
         (cpu.xregs.regs[rs1(instr) as usize] as i32 >> (imm & 0x1f)) as u32;
 }
 pub fn exec_add(cpu: &mut CPU, instr: u32) {
-    cpu.xregs.regs[rd(instr) as usize] = (cpu.xregs.regs[rs1(instr) as usize] as i32
-        + cpu.xregs.regs[rs2(instr) as usize] as i32)
-        as u32;
+    cpu.xregs.regs[rd(instr) as usize] =
+        cpu.xregs.regs[rs1(instr) as usize].wrapping_add(cpu.xregs.regs[rs2(instr) as usize]);
 }
 pub fn exec_sub(cpu: &mut CPU, instr: u32) {
     dump_format_instr_r(cpu, instr);
@@ -300,9 +986,9 @@ pub fn exec_sub(cpu: &mut CPU, instr: u32) {
         as u32;
 }
 pub fn exec_sll(cpu: &mut CPU, instr: u32) {
-    cpu.xregs.regs[rd(instr) as usize] = ((cpu.xregs.regs[rs1(instr) as usize] as i32)
-        << cpu.xregs.regs[rs2(instr) as usize] as i32)
-        as u32;
+    // only the low 5 bits of rs2 are the shift amount
+    cpu.xregs.regs[rd(instr) as usize] =
+        cpu.xregs.regs[rs1(instr) as usize].wrapping_shl(cpu.xregs.regs[rs2(instr) as usize]);
 }
 pub fn exec_slt(cpu: &mut CPU, instr: u32) {
     cpu.xregs.regs[rd(instr) as usize] = ((cpu.xregs.regs[rs1(instr) as usize] as i32)
@@ -319,11 +1005,11 @@ pub fn exec_xor(cpu: &mut CPU, instr: u32) {
 }
 pub fn exec_srl(cpu: &mut CPU, instr: u32) {
     cpu.xregs.regs[rd(instr) as usize] =
-        cpu.xregs.regs[rs1(instr) as usize] >> cpu.xregs.regs[rs2(instr) as usize];
+        cpu.xregs.regs[rs1(instr) as usize].wrapping_shr(cpu.xregs.regs[rs2(instr) as usize]);
 }
 pub fn exec_sra(cpu: &mut CPU, instr: u32) {
-    cpu.xregs.regs[rd(instr) as usize] = ((cpu.xregs.regs[rs1(instr) as usize] as i32)
-        >> cpu.xregs.regs[rs2(instr) as usize] as i32)
+    cpu.xregs.regs[rd(instr) as usize] = (cpu.xregs.regs[rs1(instr) as usize] as i32)
+        .wrapping_shr(cpu.xregs.regs[rs2(instr) as usize])
         as u32;
 }
 pub fn exec_or(cpu: &mut CPU, instr: u32) {
@@ -334,18 +1020,261 @@ pub fn exec_and(cpu: &mut CPU, instr: u32) {
     cpu.xregs.regs[rd(instr) as usize] =
         cpu.xregs.regs[rs1(instr) as usize] & cpu.xregs.regs[rs2(instr) as usize];
 }
+// RV32M, division by zero and overflow give the results the spec lists
+// instead of trapping
+pub fn exec_mul(cpu: &mut CPU, instr: u32) {
+    let (a, b) = operands(cpu, instr);
+    cpu.xregs.regs[rd(instr) as usize] = a.wrapping_mul(b);
+}
+pub fn exec_mulh(cpu: &mut CPU, instr: u32) {
+    let (a, b) = operands(cpu, instr);
+    let product = a as i32 as i64 * b as i32 as i64;
+    cpu.xregs.regs[rd(instr) as usize] = (product >> 32) as u32;
+}
+pub fn exec_mulhsu(cpu: &mut CPU, instr: u32) {
+    let (a, b) = operands(cpu, instr);
+    let product = a as i32 as i64 * b as i64;
+    cpu.xregs.regs[rd(instr) as usize] = (product >> 32) as u32;
+}
+pub fn exec_mulhu(cpu: &mut CPU, instr: u32) {
+    let (a, b) = operands(cpu, instr);
+    let product = a as u64 * b as u64;
+    cpu.xregs.regs[rd(instr) as usize] = (product >> 32) as u32;
+}
+pub fn exec_div(cpu: &mut CPU, instr: u32) {
+    let (a, b) = operands(cpu, instr);
+    cpu.xregs.regs[rd(instr) as usize] = match b {
+        0 => u32::MAX,
+        _ => (a as i32).wrapping_div(b as i32) as u32,
+    };
+}
+pub fn exec_divu(cpu: &mut CPU, instr: u32) {
+    let (a, b) = operands(cpu, instr);
+    cpu.xregs.regs[rd(instr) as usize] = a.checked_div(b).unwrap_or(u32::MAX);
+}
+pub fn exec_rem(cpu: &mut CPU, instr: u32) {
+    let (a, b) = operands(cpu, instr);
+    cpu.xregs.regs[rd(instr) as usize] = match b {
+        0 => a,
+        _ => (a as i32).wrapping_rem(b as i32) as u32,
+    };
+}
+pub fn exec_remu(cpu: &mut CPU, instr: u32) {
+    let (a, b) = operands(cpu, instr);
+    cpu.xregs.regs[rd(instr) as usize] = a.checked_rem(b).unwrap_or(a);
+}
+fn operands(cpu: &CPU, instr: u32) -> (u32, u32) {
+    (
+        cpu.xregs.regs[rs1(instr) as usize],
+        cpu.xregs.regs[rs2(instr) as usize],
+    )
+}
+// RV32C, the full instruction does the work
+fn exec_compressed(cpu: &mut CPU, instr: u32) {
+    match rvc::expand(instr) {
+        0 => exec_illegal(cpu, instr),
+        full => decode(full, &cpu.isa)(cpu, full),
+    }
+}
 pub fn exec_fence(cpu: &mut CPU, instr: u32) {}
-pub fn exec_fence_i(cpu: &mut CPU, instr: u32) {}
-pub fn exec_ecall(cpu: &mut CPU, instr: u32) {}
-pub fn exec_ebreak(cpu: &mut CPU, instr: u32) {}
-pub fn exec_csrrw(cpu: &mut CPU, instr: u32) {}
-pub fn exec_csrrs(cpu: &mut CPU, instr: u32) {}
-pub fn exec_csrrc(cpu: &mut CPU, instr: u32) {}
-pub fn exec_csrrwi(cpu: &mut CPU, instr: u32) {}
-pub fn exec_csrrsi(cpu: &mut CPU, instr: u32) {}
-pub fn exec_csrrci(cpu: &mut CPU, instr: u32) {}
+pub fn exec_fence_i(cpu: &mut CPU, _instr: u32) {
+    cpu.blocks.flush();
+}
+fn exec_illegal(cpu: &mut CPU, instr: u32) {
+    cpu.raise(Fault::IllegalInstruction(instr));
+}
+pub fn exec_ecall(cpu: &mut CPU, _instr: u32) {
+    cpu.trap(Trap::Ecall);
+    cpu.take_trap(cpu.ecall_cause(), 0, false);
+}
+pub fn exec_ebreak(cpu: &mut CPU, _instr: u32) {
+    match cpu.semihosting.take() {
+        Some(mut sh) if semihosting::is_semihosting_call(cpu) => {
+            sh.call(cpu);
+            cpu.semihosting = Some(sh);
+        }
+        sh => {
+            cpu.semihosting = sh;
+            cpu.trap(Trap::Breakpoint);
+            cpu.take_trap(CAUSE_BREAKPOINT, cpu.pc, false);
+        }
+    }
+}
+// return from a machine mode trap to the level in mstatus.MPP
+pub fn exec_mret(cpu: &mut CPU, instr: u32) {
+    if cpu.privilege < Privilege::Machine {
+        return exec_illegal(cpu, instr);
+    }
+    let status = cpu.csrs.load(csr::MSTATUS);
+    let to = Privilege::from_bits((status & csr::MSTATUS_MPP) >> csr::MSTATUS_MPP_SHIFT);
+    // MIE takes MPIE, which is set, MPP goes to the lowest level
+    let mie = status & csr::MSTATUS_MPIE != 0;
+    let status = status & !csr::MSTATUS_MPP | csr::MSTATUS_MPIE;
+    let mut status = with_bit(status, csr::MSTATUS_MIE, mie);
+    if to != Privilege::Machine {
+        status &= !csr::MSTATUS_MPRV;
+    }
+    cpu.csrs.store(csr::MSTATUS, status);
+    cpu.set_privilege(to);
+    let mepc = cpu.csrs.load(csr::MEPC);
+    cpu.jump(mepc & !(cpu.isa.ialign() - 1));
+}
+// return from a supervisor trap to the level in mstatus.SPP
+pub fn exec_sret(cpu: &mut CPU, instr: u32) {
+    let status = cpu.csrs.load(csr::MSTATUS);
+    if cpu.privilege < Privilege::Supervisor
+        || cpu.privilege == Privilege::Supervisor && status & csr::MSTATUS_TSR != 0
+    {
+        return exec_illegal(cpu, instr);
+    }
+    let to = Privilege::from_bits((status & csr::MSTATUS_SPP != 0) as u32);
+    let sie = status & csr::MSTATUS_SPIE != 0;
+    let status = status & !(csr::MSTATUS_SPP | csr::MSTATUS_MPRV) | csr::MSTATUS_SPIE;
+    let status = with_bit(status, csr::MSTATUS_SIE, sie);
+    cpu.csrs.store(csr::MSTATUS, status);
+    cpu.set_privilege(to);
+    let sepc = cpu.csrs.load(csr::SEPC);
+    cpu.jump(sepc & !(cpu.isa.ialign() - 1));
+}
+// waiting is up to the guest's idle loop, the interrupt is taken before
+// the next instruction once the devices raise it
+pub fn exec_wfi(_cpu: &mut CPU, _instr: u32) {}
+// the whole TLB goes, whatever address and ASID rs1 and rs2 name
+pub fn exec_sfence_vma(cpu: &mut CPU, instr: u32) {
+    if cpu.privilege == Privilege::User || trapped_vm(cpu) {
+        return exec_illegal(cpu, instr);
+    }
+    cpu.tlb.flush();
+}
+// supervisor mode may not touch satp or sfence.vma under mstatus.TVM
+fn trapped_vm(cpu: &CPU) -> bool {
+    cpu.privilege == Privilege::Supervisor && cpu.csrs.load(csr::MSTATUS) & csr::MSTATUS_TVM != 0
+}
+// RV32A, atomic because a hart has the bus to itself while it runs
+pub fn exec_lr_w(cpu: &mut CPU, instr: u32) {
+    let addr = cpu.xregs.regs[rs1(instr) as usize];
+    if !cpu.atomic_aligned(addr, Fault::LoadMisaligned(addr)) {
+        return;
+    }
+    let Some(val) = cpu.load(addr, 32) else {
+        return;
+    };
+    cpu.xregs.regs[rd(instr) as usize] = val;
+    // the load just mapped addr, this cannot fail
+    let at = cpu.translate(addr, Access::Load).unwrap_or(addr);
+    cpu.reservation = Some((at, val));
+}
+pub fn exec_sc_w(cpu: &mut CPU, instr: u32) {
+    let addr = cpu.xregs.regs[rs1(instr) as usize];
+    if !cpu.atomic_aligned(addr, Fault::StoreMisaligned(addr)) {
+        return;
+    }
+    let at = match cpu.translate(addr, Access::Store) {
+        Ok(at) => at,
+        Err(fault) => return cpu.raise(fault),
+    };
+    let reserved = cpu.holds_reservation(at);
+    cpu.reservation = None;
+    if reserved
+        && cpu
+            .store(addr, 32, cpu.xregs.regs[rs2(instr) as usize])
+            .is_none()
+    {
+        return;
+    }
+    cpu.xregs.regs[rd(instr) as usize] = !reserved as u32;
+}
+pub fn exec_amoswap_w(cpu: &mut CPU, instr: u32) {
+    amo(cpu, instr, |_, src| src);
+}
+pub fn exec_amoadd_w(cpu: &mut CPU, instr: u32) {
+    amo(cpu, instr, u32::wrapping_add);
+}
+pub fn exec_amoxor_w(cpu: &mut CPU, instr: u32) {
+    amo(cpu, instr, |old, src| old ^ src);
+}
+pub fn exec_amoand_w(cpu: &mut CPU, instr: u32) {
+    amo(cpu, instr, |old, src| old & src);
+}
+pub fn exec_amoor_w(cpu: &mut CPU, instr: u32) {
+    amo(cpu, instr, |old, src| old | src);
+}
+pub fn exec_amomin_w(cpu: &mut CPU, instr: u32) {
+    amo(cpu, instr, |old, src| (old as i32).min(src as i32) as u32);
+}
+pub fn exec_amomax_w(cpu: &mut CPU, instr: u32) {
+    amo(cpu, instr, |old, src| (old as i32).max(src as i32) as u32);
+}
+pub fn exec_amominu_w(cpu: &mut CPU, instr: u32) {
+    amo(cpu, instr, u32::min);
+}
+pub fn exec_amomaxu_w(cpu: &mut CPU, instr: u32) {
+    amo(cpu, instr, u32::max);
+}
+// rd gets the old word at rs1, which is replaced by op(old, rs2)
+fn amo(cpu: &mut CPU, instr: u32, op: fn(u32, u32) -> u32) {
+    let addr = cpu.xregs.regs[rs1(instr) as usize];
+    let src = cpu.xregs.regs[rs2(instr) as usize];
+    if !cpu.atomic_aligned(addr, Fault::StoreMisaligned(addr)) {
+        return;
+    }
+    let Some(old) = cpu.load(addr, 32) else {
+        return;
+    };
+    if cpu.store(addr, 32, op(old, src)).is_some() {
+        cpu.xregs.regs[rd(instr) as usize] = old;
+    }
+}
+pub fn exec_csrrw(cpu: &mut CPU, instr: u32) {
+    let src = cpu.xregs.regs[rs1(instr) as usize];
+    csr_swap(cpu, instr, |_| Some(src));
+}
+pub fn exec_csrrs(cpu: &mut CPU, instr: u32) {
+    let mask = cpu.xregs.regs[rs1(instr) as usize];
+    csr_swap(cpu, instr, |old| (rs1(instr) != 0).then_some(old | mask));
+}
+pub fn exec_csrrc(cpu: &mut CPU, instr: u32) {
+    let mask = cpu.xregs.regs[rs1(instr) as usize];
+    csr_swap(cpu, instr, |old| (rs1(instr) != 0).then_some(old & !mask));
+}
+pub fn exec_csrrwi(cpu: &mut CPU, instr: u32) {
+    csr_swap(cpu, instr, |_| Some(rs1(instr)));
+}
+pub fn exec_csrrsi(cpu: &mut CPU, instr: u32) {
+    csr_swap(cpu, instr, |old| {
+        (rs1(instr) != 0).then_some(old | rs1(instr))
+    });
+}
+pub fn exec_csrrci(cpu: &mut CPU, instr: u32) {
+    csr_swap(cpu, instr, |old| {
+        (rs1(instr) != 0).then_some(old & !rs1(instr))
+    });
+}
+// rd gets the old value of the CSR, which then takes new(old) if there is
+// one; csr[9:8] is the lowest privilege level that may access it
+fn csr_swap(cpu: &mut CPU, instr: u32, new: impl FnOnce(u32) -> Option<u32>) {
+    if csr(instr) >> 8 & 0x3 > cpu.privilege as u32 || csr(instr) == csr::SATP && trapped_vm(cpu) {
+        return exec_illegal(cpu, instr);
+    }
+    let old = cpu.csrs.read(csr(instr));
+    let new = new(old);
+    if let Some(new) = new {
+        cpu.csrs.write(csr(instr), new);
+        // there are no ASIDs, a new satp is a new address space
+        if csr(instr) == csr::SATP {
+            cpu.tlb.flush();
+        }
+    }
+    if !cpu.hooks.is_empty() {
+        cpu.hooks.csr(csr(instr), old, new);
+    }
+    cpu.xregs.regs[rd(instr) as usize] = old;
+}
 
 fn dump_format_instr_r(cpu: &CPU, instr: u32) {
+    if !cpu.trace {
+        return;
+    }
     println!(
         "{}<- {}: {:#x}, {}: {:#x}",
         REGS_NAMES[rd(instr) as usize],
@@ -356,6 +1285,9 @@ fn dump_format_instr_r(cpu: &CPU, instr: u32) {
     );
 }
 fn dump_format_instr_i(cpu: &CPU, instr: u32) {
+    if !cpu.trace {
+        return;
+    }
     println!(
         "{}<- {}: {:#x}, imm: {:#x}",
         REGS_NAMES[rd(instr) as usize],
@@ -365,6 +1297,9 @@ fn dump_format_instr_i(cpu: &CPU, instr: u32) {
     );
 }
 fn dump_format_instr_s(cpu: &CPU, instr: u32) {
+    if !cpu.trace {
+        return;
+    }
     println!(
         "{}: {:#x}, {}: {:#x}, imm: {:#x}",
         REGS_NAMES[rs1(instr) as usize],
@@ -375,6 +1310,9 @@ fn dump_format_instr_s(cpu: &CPU, instr: u32) {
     );
 }
 fn dump_format_instr_load(cpu: &CPU, instr: u32) {
+    if !cpu.trace {
+        return;
+    }
     println!(
         "{}<- {}: {:#x}, imm: {:#x}",
         REGS_NAMES[rd(instr) as usize],
@@ -384,6 +1322,9 @@ fn dump_format_instr_load(cpu: &CPU, instr: u32) {
     );
 }
 fn dump_format_instr_b(cpu: &CPU, instr: u32) {
+    if !cpu.trace {
+        return;
+    }
     println!(
         "{}: {:#x}, {}: {:#x}, imm: {:#x}",
         REGS_NAMES[rs1(instr) as usize],
@@ -394,6 +1335,9 @@ fn dump_format_instr_b(cpu: &CPU, instr: u32) {
     );
 }
 fn dump_format_instr_j(cpu: &CPU, instr: u32) {
+    if !cpu.trace {
+        return;
+    }
     println!(
         "{}<- {:#x}, imm: {:#x}",
         REGS_NAMES[rd(instr) as usize],
@@ -402,6 +1346,9 @@ fn dump_format_instr_j(cpu: &CPU, instr: u32) {
     );
 }
 fn dump_format_instr_u(cpu: &CPU, instr: u32) {
+    if !cpu.trace {
+        return;
+    }
     println!(
         "{}<- {:#x}, imm: {:#x}",
         REGS_NAMES[rd(instr) as usize],
@@ -0,0 +1,158 @@
+// Control and status registers of one hart. load() and store() are the raw
+// registers; read() and write() are what a CSR instruction sees: the
+// supervisor registers that are views of the machine ones, and only the
+// bits that exist here.
+use core::fmt;
+
+pub const NUM_CSRS: usize = 4096;
+
+pub const SSTATUS: u32 = 0x100;
+pub const SIE: u32 = 0x104;
+pub const STVEC: u32 = 0x105;
+pub const SSCRATCH: u32 = 0x140;
+pub const SEPC: u32 = 0x141;
+pub const SCAUSE: u32 = 0x142;
+pub const STVAL: u32 = 0x143;
+pub const SIP: u32 = 0x144;
+pub const SATP: u32 = 0x180;
+pub const MSTATUS: u32 = 0x300;
+pub const MISA: u32 = 0x301;
+pub const MEDELEG: u32 = 0x302;
+pub const MIDELEG: u32 = 0x303;
+pub const MIE: u32 = 0x304;
+pub const MTVEC: u32 = 0x305;
+pub const MSCRATCH: u32 = 0x340;
+pub const MEPC: u32 = 0x341;
+pub const MCAUSE: u32 = 0x342;
+pub const MTVAL: u32 = 0x343;
+pub const MIP: u32 = 0x344;
+pub const MHARTID: u32 = 0xf14;
+
+// mstatus fields
+pub const MSTATUS_SIE: u32 = 1 << 1;
+pub const MSTATUS_MIE: u32 = 1 << 3;
+pub const MSTATUS_SPIE: u32 = 1 << 5;
+pub const MSTATUS_MPIE: u32 = 1 << 7;
+pub const MSTATUS_SPP: u32 = 1 << 8;
+pub const MSTATUS_MPP_SHIFT: u32 = 11;
+pub const MSTATUS_MPP: u32 = 3 << MSTATUS_MPP_SHIFT;
+pub const MSTATUS_MPRV: u32 = 1 << 17;
+pub const MSTATUS_SUM: u32 = 1 << 18;
+pub const MSTATUS_MXR: u32 = 1 << 19;
+pub const MSTATUS_TVM: u32 = 1 << 20;
+pub const MSTATUS_TSR: u32 = 1 << 22;
+// TW only holds what was written: wfi never waits
+const MSTATUS_WRITABLE: u32 = MSTATUS_SIE
+    | MSTATUS_MIE
+    | MSTATUS_SPIE
+    | MSTATUS_MPIE
+    | MSTATUS_SPP
+    | MSTATUS_MPP
+    | MSTATUS_MPRV
+    | MSTATUS_SUM
+    | MSTATUS_MXR
+    | MSTATUS_TVM
+    | 1 << 21
+    | MSTATUS_TSR;
+// what sstatus shows of mstatus: SIE, SPIE, SPP, SUM and MXR
+const SSTATUS_MASK: u32 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR;
+
+// satp fields, MODE 1 is Sv32; there are no ASIDs, that field reads 0
+pub const SATP_MODE: u32 = 1 << 31;
+pub const SATP_PPN: u32 = 0x3f_ffff;
+
+// interrupts, by their cause and bit in mip and mie
+pub const IRQ_S_SOFT: u32 = 1;
+pub const IRQ_M_SOFT: u32 = 3;
+pub const IRQ_S_TIMER: u32 = 5;
+pub const IRQ_M_TIMER: u32 = 7;
+pub const IRQ_S_EXT: u32 = 9;
+pub const IRQ_M_EXT: u32 = 11;
+// the supervisor interrupts, the only ones machine mode can delegate and
+// the ones software sets in mip
+const S_INTERRUPTS: u32 = 1 << IRQ_S_SOFT | 1 << IRQ_S_TIMER | 1 << IRQ_S_EXT;
+const INTERRUPTS: u32 = S_INTERRUPTS | 1 << IRQ_M_SOFT | 1 << IRQ_M_TIMER | 1 << IRQ_M_EXT;
+// an ecall from machine mode is never delegated
+const ECALL_FROM_M: u32 = 11;
+
+#[derive(Clone)]
+pub struct CSRS {
+    pub csrs: Box<[u32; NUM_CSRS]>,
+    // mip bits the devices drive, on top of the ones software sets
+    pub lines: u32,
+}
+
+impl CSRS {
+    pub fn new(hartid: u32) -> Self {
+        let mut csrs = CSRS {
+            csrs: Box::new([0; NUM_CSRS]),
+            lines: 0,
+        };
+        csrs.csrs[MHARTID as usize] = hartid;
+        csrs
+    }
+
+    pub fn load(&self, csr: u32) -> u32 {
+        self.csrs[csr as usize & (NUM_CSRS - 1)]
+    }
+
+    // writes to read-only CSRs (csr[11:10] == 0b11) are dropped, and so are
+    // writes to misa: extensions cannot be switched off at run time
+    pub fn store(&mut self, csr: u32, value: u32) {
+        if csr >> 10 & 0x3 != 0x3 && csr != MISA {
+            self.csrs[csr as usize & (NUM_CSRS - 1)] = value;
+        }
+    }
+
+    // the value a CSR instruction reads
+    pub fn read(&self, csr: u32) -> u32 {
+        let mideleg = self.load(MIDELEG);
+        match csr {
+            SSTATUS => self.load(MSTATUS) & SSTATUS_MASK,
+            SIE => self.load(MIE) & mideleg,
+            MIP => self.load(MIP) | self.lines,
+            SIP => (self.load(MIP) | self.lines) & mideleg,
+            _ => self.load(csr),
+        }
+    }
+
+    // what a CSR instruction writing `value` leaves in the registers
+    pub fn write(&mut self, csr: u32, value: u32) {
+        let mideleg = self.load(MIDELEG);
+        let merge = |old: u32, mask: u32| old & !mask | value & mask;
+        match csr {
+            MSTATUS => {
+                let mut value = value & MSTATUS_WRITABLE;
+                // 2 is no privilege level, MPP is WARL
+                if value & MSTATUS_MPP == 2 << MSTATUS_MPP_SHIFT {
+                    value &= !MSTATUS_MPP;
+                }
+                self.store(MSTATUS, value);
+            }
+            SSTATUS => self.store(MSTATUS, merge(self.load(MSTATUS), SSTATUS_MASK)),
+            MEDELEG => self.store(MEDELEG, value & !(1 << ECALL_FROM_M)),
+            MIDELEG => self.store(MIDELEG, value & S_INTERRUPTS),
+            MIE => self.store(MIE, value & INTERRUPTS),
+            SIE => self.store(MIE, merge(self.load(MIE), mideleg)),
+            MIP => self.store(MIP, value & S_INTERRUPTS),
+            SIP => self.store(MIP, merge(self.load(MIP), mideleg & 1 << IRQ_S_SOFT)),
+            // direct and vectored mode only
+            MTVEC | STVEC => self.store(csr, value & !2),
+            MEPC | SEPC => self.store(csr, value & !1),
+            SATP => self.store(SATP, value & (SATP_MODE | SATP_PPN)),
+            _ => self.store(csr, value),
+        }
+    }
+}
+
+impl fmt::Debug for CSRS {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_struct("csrs");
+        for (i, csr) in self.csrs.iter().enumerate() {
+            if *csr != 0 {
+                s.field(format!("csr[{i:#x}]").as_str(), csr);
+            }
+        }
+        s.finish()
+    }
+}
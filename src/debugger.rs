@@ -0,0 +1,241 @@
+// Interactive debugging of a bare-metal guest with reverse execution, shared
+// by the command line REPL and the GDB remote stub.
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Write};
+
+use crate::cpu::{Fault, CPU};
+use crate::debug::REGS_NAMES;
+use crate::replay::Replay;
+
+// why the guest stopped running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    Step,
+    Breakpoint,
+    Interrupted,
+    // went back to the first recorded instruction
+    Start,
+    Exited(i32),
+    // the instruction at pc cannot be carried out
+    Fault(Fault),
+}
+
+// how often a running guest checks for an interrupt request
+const INTERRUPT_POLL: u64 = 4096;
+
+pub struct Debugger {
+    pub cpu: CPU,
+    pub replay: Replay,
+    pub breakpoints: Vec<u32>,
+}
+
+impl Debugger {
+    pub fn new(cpu: CPU, interval: u64) -> Self {
+        let replay = Replay::new(&cpu, interval);
+        Debugger {
+            cpu,
+            replay,
+            breakpoints: Vec::new(),
+        }
+    }
+
+    fn exit_code(&self) -> Option<i32> {
+        self.cpu.semihosting.as_ref().and_then(|sh| sh.exit_code)
+    }
+
+    pub fn step(&mut self) -> Stop {
+        if let Some(code) = self.exit_code() {
+            return Stop::Exited(code);
+        }
+        self.replay.step(&mut self.cpu);
+        if let Some(fault) = self.cpu.fault.take() {
+            return Stop::Fault(fault);
+        }
+        match self.exit_code() {
+            Some(code) => Stop::Exited(code),
+            None => Stop::Step,
+        }
+    }
+
+    // run until a breakpoint, the guest exits or `interrupted` says so
+    pub fn cont(&mut self, interrupted: &mut dyn FnMut() -> bool) -> Stop {
+        loop {
+            let stop = self.step();
+            if stop != Stop::Step {
+                return stop;
+            }
+            if self.breakpoints.contains(&self.cpu.pc) {
+                return Stop::Breakpoint;
+            }
+            if self.replay.icount().is_multiple_of(INTERRUPT_POLL) && interrupted() {
+                return Stop::Interrupted;
+            }
+        }
+    }
+
+    pub fn reverse_step(&mut self) -> Stop {
+        self.rewound();
+        match self.replay.reverse_step(&mut self.cpu) {
+            true => Stop::Step,
+            false => Stop::Start,
+        }
+    }
+
+    pub fn reverse_cont(&mut self) -> Stop {
+        self.rewound();
+        match self
+            .replay
+            .reverse_continue(&mut self.cpu, &self.breakpoints)
+        {
+            true => Stop::Breakpoint,
+            false => Stop::Start,
+        }
+    }
+
+    // an exit request that is undone by going back has not happened yet
+    fn rewound(&mut self) {
+        if let Some(sh) = self.cpu.semihosting.as_mut() {
+            sh.exit_code = None;
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u32) {
+        if !self.breakpoints.contains(&addr) {
+            self.breakpoints.push(addr);
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u32) {
+        self.breakpoints.retain(|bp| *bp != addr);
+    }
+
+    // human readable answer to "who wrote this address last"
+    pub fn last_write(&mut self, addr: u32) -> String {
+        match self.replay.last_write(&mut self.cpu, addr) {
+            Some((n, pc)) => format!(
+                "{:#x} last written by instruction {} at pc {:#x}\n",
+                addr, n, pc
+            ),
+            None => format!("{:#x} not written since recording started\n", addr),
+        }
+    }
+
+    fn describe(&self, stop: Stop) -> String {
+        let reason = match stop {
+            Stop::Step => "",
+            Stop::Breakpoint => "breakpoint, ",
+            Stop::Interrupted => "interrupted, ",
+            Stop::Start => "start of recording, ",
+            Stop::Exited(code) => return format!("guest exited with code {}\n", code),
+            Stop::Fault(fault) => return format!("{} at pc {:#x}\n", fault, self.cpu.pc),
+        };
+        format!(
+            "{}pc {:#x}, instruction {}\n",
+            reason,
+            self.cpu.pc,
+            self.replay.icount()
+        )
+    }
+
+    // run one REPL command and return its output, None asks to quit
+    pub fn command(&mut self, line: &str) -> Option<String> {
+        let mut words = line.split_whitespace();
+        let cmd = words.next().unwrap_or("");
+        let arg = words.next();
+        let count = arg.and_then(|a| a.parse::<u64>().ok()).unwrap_or(1);
+        let addr = arg.and_then(parse_addr);
+        let out = match (cmd, addr) {
+            ("" | "#", _) => String::new(),
+            ("s" | "step", _) => {
+                let mut stop = Stop::Step;
+                for _ in 0..count {
+                    stop = self.step();
+                    if stop != Stop::Step {
+                        break;
+                    }
+                }
+                self.describe(stop)
+            }
+            ("c" | "continue", _) => {
+                let stop = self.cont(&mut || false);
+                self.describe(stop)
+            }
+            ("rs" | "reverse-step", _) => {
+                let mut stop = Stop::Step;
+                for _ in 0..count {
+                    stop = self.reverse_step();
+                    if stop != Stop::Step {
+                        break;
+                    }
+                }
+                self.describe(stop)
+            }
+            ("rc" | "reverse-continue", _) => {
+                let stop = self.reverse_cont();
+                self.describe(stop)
+            }
+            ("b" | "break", Some(addr)) => {
+                self.add_breakpoint(addr);
+                format!("breakpoint at {:#x}\n", addr)
+            }
+            ("d" | "delete", Some(addr)) => {
+                self.remove_breakpoint(addr);
+                String::new()
+            }
+            ("r" | "regs", _) => {
+                let mut out = format!("pc   {:#010x}\n", self.cpu.pc);
+                for (name, val) in REGS_NAMES.iter().zip(self.cpu.xregs.regs) {
+                    writeln!(out, "{:4} {:#010x}", name, val).unwrap();
+                }
+                out
+            }
+            ("x", Some(addr)) => match self.cpu.bus.load_bytes(addr, 4) {
+                Some(b) => format!(
+                    "{:#x}: {:#010x}\n",
+                    addr,
+                    u32::from_le_bytes(b.try_into().unwrap())
+                ),
+                None => format!("{:#x}: not mapped\n", addr),
+            },
+            ("lw" | "last-write", Some(addr)) => self.last_write(addr),
+            ("q" | "quit", _) => return None,
+            _ => HELP.to_string(),
+        };
+        Some(out)
+    }
+}
+
+const HELP: &str = "\
+s|step [n]            execute n instructions
+c|continue            run to the next breakpoint
+rs|reverse-step [n]   undo n instructions
+rc|reverse-continue   run backwards to the previous breakpoint
+b|break ADDR          set a breakpoint
+d|delete ADDR         remove a breakpoint
+r|regs                show the registers
+x ADDR                show the word at ADDR
+lw|last-write ADDR    find the last store to ADDR
+q|quit                leave the debugger
+";
+
+// "0x80000000" or plain hex
+pub fn parse_addr(s: &str) -> Option<u32> {
+    u32::from_str_radix(s.trim_start_matches("0x"), 16).ok()
+}
+
+// read commands from stdin until quit or end of input
+pub fn repl(dbg: &mut Debugger) -> io::Result<()> {
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        eprint!("(riscland) ");
+        io::stderr().flush()?;
+        let Some(line) = lines.next().transpose()? else {
+            return Ok(());
+        };
+        match dbg.command(&line) {
+            Some(out) => print!("{}", out),
+            None => return Ok(()),
+        }
+    }
+}
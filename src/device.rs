@@ -0,0 +1,107 @@
+// Memory mapped devices. A device sits on the bus at a base address and sees
+// accesses to its register window as offsets from that base. Register reads
+// may have side effects, so they go through the bus mutably. A device that
+// reads or writes guest memory by itself (DMA) does so in dma(), right after
+// the register store that started it, or when the bus polls its devices
+// every POLL_INTERVAL instructions, e.g. to hand on host input.
+//
+// Interrupts are levels: after every register access and poll the bus
+// hands the lines of the devices with an irq to the interrupt controller,
+// and a hart asks the devices which of its mip bits they raise.
+//
+// A snapshot holds what save() writes, for restore() of a device made from
+// the same board to take back; the snapshot module has helpers to read it.
+use core::fmt;
+use std::io::{self, Read, Write};
+
+use crate::fdt::Fdt;
+use crate::memory::BUS;
+
+// what a device can ask of the whole machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Power {
+    // stop with this exit code
+    Off(i32),
+    // start over from reset
+    Reset,
+}
+
+// instructions between two polls of the devices
+pub const POLL_INTERVAL: u64 = 10_000;
+
+pub trait Device: Send + fmt::Debug {
+    fn load(&mut self, offset: u32, size: u32) -> u32;
+
+    fn store(&mut self, offset: u32, size: u32, value: u32);
+
+    // guest memory work started by the last store or waiting for host
+    // input; `bus` has every memory but no devices
+    fn dma(&mut self, _bus: &mut BUS) {}
+
+    // the bus clock, instructions retired so far, handed over right before
+    // every register access and every poll
+    fn clock(&mut self, _instret: u64) {}
+
+    // level of the device's interrupt line, the PLIC source the board
+    // gives it
+    fn irq(&self) -> bool {
+        false
+    }
+
+    // the levels of every interrupt source, bit n for source n, for an
+    // interrupt controller to latch
+    fn irq_lines(&mut self, _lines: u32) {}
+
+    // the mip bits the device raises for hart `hart`
+    fn interrupts(&self, _hart: u32) -> u32 {
+        0
+    }
+
+    // a power-off or reset the last store asked for
+    fn power(&mut self) -> Option<Power> {
+        None
+    }
+
+    // the state the guest can see, leaving out what the configuration says
+    // and what is on the host side, e.g. a disk image
+    fn save(&self, _w: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    // take back what save() wrote, at the bus clock the snapshot was taken
+    fn restore(&mut self, _r: &mut dyn Read) -> io::Result<()> {
+        Ok(())
+    }
+
+    // copy for a cloned bus, e.g. a replay checkpoint
+    fn clone_box(&self) -> Box<dyn Device>;
+
+    // node name in the device tree, "serial" for serial@10000000
+    fn fdt_name(&self) -> &'static str;
+
+    // compatible and whatever else the guest driver needs; reg and the
+    // interrupt are already there
+    fn fdt_props(&self, fdt: &mut Fdt, harts: u32);
+}
+
+impl Clone for Box<dyn Device> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+// a device and where it sits on the bus
+#[derive(Debug, Clone)]
+pub struct Mapped {
+    pub base: u32,
+    pub size: u32,
+    // PLIC interrupt source
+    pub irq: Option<u32>,
+    pub device: Box<dyn Device>,
+}
+
+impl Mapped {
+    pub fn contains(&self, addr: u32, len: u32) -> bool {
+        addr >= self.base && (addr - self.base) as u64 + len as u64 <= self.size as u64
+    }
+}
@@ -0,0 +1,136 @@
+// Source line lookup from the DWARF .debug_line tables of an ELF file.
+use std::borrow::Cow;
+use std::path::PathBuf;
+
+use gimli::{EndianSlice, RunTimeEndian};
+use object::{Object, ObjectSection};
+
+// one row of the line table, `line == 0` marks the end of a sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineRow {
+    pub addr: u32,
+    pub file: usize,
+    pub line: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LineTable {
+    files: Vec<String>,
+    rows: Vec<LineRow>,
+}
+
+impl LineTable {
+    pub fn new(files: Vec<String>, mut rows: Vec<LineRow>) -> Self {
+        rows.sort_by_key(|r| (r.addr, r.line != 0));
+        LineTable { files, rows }
+    }
+
+    // an ELF without debug info gives an empty table
+    pub fn parse(data: &[u8]) -> Result<Self, gimli::Error> {
+        let file = object::File::parse(data).map_err(|_| gimli::Error::Io)?;
+        let endian = match file.is_little_endian() {
+            true => RunTimeEndian::Little,
+            false => RunTimeEndian::Big,
+        };
+        let load = |id: gimli::SectionId| -> Result<Cow<[u8]>, gimli::Error> {
+            Ok(match file.section_by_name(id.name()) {
+                Some(s) => s.uncompressed_data().unwrap_or(Cow::Borrowed(&[])),
+                None => Cow::Borrowed(&[]),
+            })
+        };
+        let sections = gimli::Dwarf::load(load)?;
+        let dwarf = sections.borrow(|s| EndianSlice::new(s, endian));
+
+        let mut files = Vec::new();
+        let mut rows = Vec::new();
+        let mut units = dwarf.units();
+        while let Some(header) = units.next()? {
+            let unit = dwarf.unit(header)?;
+            let Some(program) = unit.line_program.clone() else {
+                continue;
+            };
+            let comp_dir = unit
+                .comp_dir
+                .map(|d| PathBuf::from(d.to_string_lossy().into_owned()))
+                .unwrap_or_default();
+            // file indices of this unit mapped into `files`
+            let mut file_ids = Vec::new();
+            let mut program_rows = program.rows();
+            while let Some((header, row)) = program_rows.next_row()? {
+                if row.end_sequence() {
+                    rows.push(LineRow {
+                        addr: row.address() as u32,
+                        file: 0,
+                        line: 0,
+                    });
+                    continue;
+                }
+                let Some(line) = row.line() else {
+                    continue;
+                };
+                let idx = row.file_index() as usize;
+                if file_ids.len() <= idx {
+                    file_ids.resize(idx + 1, None);
+                }
+                if file_ids[idx].is_none() {
+                    let mut path = comp_dir.clone();
+                    if let Some(entry) = row.file(header) {
+                        if let Some(dir) = entry.directory(header) {
+                            let dir = dwarf.attr_string(&unit, dir)?;
+                            path.push(dir.to_string_lossy().as_ref());
+                        }
+                        let name = dwarf.attr_string(&unit, entry.path_name())?;
+                        path.push(name.to_string_lossy().as_ref());
+                    }
+                    let path = path.to_string_lossy().into_owned();
+                    let id = match files.iter().position(|f| *f == path) {
+                        Some(id) => id,
+                        None => {
+                            files.push(path);
+                            files.len() - 1
+                        }
+                    };
+                    file_ids[idx] = Some(id);
+                }
+                rows.push(LineRow {
+                    addr: row.address() as u32,
+                    file: file_ids[idx].unwrap(),
+                    line: line.get() as u32,
+                });
+            }
+        }
+        Ok(LineTable::new(files, rows))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = &LineRow> {
+        self.rows.iter().filter(|r| r.line != 0)
+    }
+
+    // (file, line) of the instruction at addr
+    pub fn lookup(&self, addr: u32) -> Option<(&str, u32)> {
+        let idx = self
+            .rows
+            .partition_point(|r| r.addr <= addr)
+            .checked_sub(1)?;
+        let row = self.rows[idx];
+        if row.line == 0 {
+            return None;
+        }
+        Some((&self.files[row.file], row.line))
+    }
+
+    // every instruction address between the rows mapped to this one
+    pub fn range_of(&self, row: &LineRow) -> std::ops::Range<u32> {
+        let next = self.rows.partition_point(|r| r.addr <= row.addr);
+        let end = self.rows.get(next).map_or(row.addr + 4, |r| r.addr);
+        row.addr..end
+    }
+}
@@ -1,6 +1,11 @@
-use object::{Object, ObjectSection};
+use object::elf::PT_LOAD;
+use object::read::elf::{ElfFile32, FileHeader, ProgramHeader};
+use object::{Architecture, Object, ObjectSection, ObjectSymbol, SectionKind, SymbolKind};
+use std::fmt;
 use std::fs;
 
+use crate::dwarf::LineTable;
+
 pub struct ELF {
     path: String,
 }
@@ -8,6 +13,73 @@ pub struct ELF {
 // for 32bit
 pub type INSTRUCTION = u32;
 
+// a PT_LOAD segment, bytes past data.len() up to memsz are zero filled
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub vaddr: u32,
+    pub memsz: u32,
+    pub data: Vec<u8>,
+}
+
+// everything a loader needs to place an executable in guest memory
+#[derive(Debug, Clone, Default)]
+pub struct Image {
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    // address of the program headers once loaded, for AT_PHDR
+    pub phdr: u32,
+    pub phent: u32,
+    pub phnum: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u32,
+    pub size: u32,
+}
+
+// function symbols sorted by address, for turning pcs into names
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+    list: Vec<Symbol>,
+}
+
+impl Symbols {
+    pub fn new(mut list: Vec<Symbol>) -> Self {
+        list.sort_by_key(|s| s.addr);
+        Symbols { list }
+    }
+
+    // the symbol covering addr and the offset into it, a zero sized symbol
+    // is taken to extend up to the next one
+    pub fn lookup(&self, addr: u32) -> Option<(&Symbol, u32)> {
+        let idx = self
+            .list
+            .partition_point(|s| s.addr <= addr)
+            .checked_sub(1)?;
+        let sym = &self.list[idx];
+        let off = addr - sym.addr;
+        if sym.size != 0 && off >= sym.size {
+            return None;
+        }
+        Some((sym, off))
+    }
+
+    // "name+0x10", or the bare address when no symbol covers it
+    pub fn describe(&self, addr: u32) -> String {
+        match self.lookup(addr) {
+            Some((sym, 0)) => sym.name.clone(),
+            Some((sym, off)) => format!("{}+{:#x}", sym.name, off),
+            None => format!("{:#x}", addr),
+        }
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&Symbol> {
+        self.list.iter().find(|s| s.name == name)
+    }
+}
+
 impl ELF {
     pub fn new(path: &str) -> Self {
         Self {
@@ -43,8 +115,115 @@ impl ELF {
 
         // return instrs;
     }
+
+    pub fn read_symbols(&self) -> Symbols {
+        let raw_file = fs::read(&self.path).expect("Failed to read ELF file");
+        let file_obj = object::File::parse(&*raw_file).expect("Failed to parse ELF file");
+        // hand written assembly leaves its labels untyped, so take anything
+        // defined in an executable section except the $x/$d mapping symbols
+        let text_sections: Vec<_> = file_obj
+            .sections()
+            .filter(|s| s.kind() == SectionKind::Text)
+            .map(|s| s.index())
+            .collect();
+        let list = file_obj
+            .symbols()
+            .filter(|s| {
+                matches!(
+                    s.kind(),
+                    SymbolKind::Text | SymbolKind::Label | SymbolKind::Unknown
+                )
+            })
+            .filter(|s| {
+                s.section_index()
+                    .is_some_and(|i| text_sections.contains(&i))
+            })
+            .filter(|s| !s.name().unwrap_or("$").starts_with('$'))
+            .filter_map(|s| {
+                Some(Symbol {
+                    name: s.name().ok().filter(|n| !n.is_empty())?.to_string(),
+                    addr: s.address() as u32,
+                    size: s.size() as u32,
+                })
+            })
+            .collect();
+        Symbols::new(list)
+    }
+
+    pub fn read_line_table(&self) -> LineTable {
+        let raw_file = fs::read(&self.path).expect("Failed to read ELF file");
+        LineTable::parse(&raw_file).expect("Failed to parse DWARF line info")
+    }
+
+    // the loadable segments of an RV32 executable, errors instead of panics
+    // since the file may come from anywhere
+    pub fn read_image(&self) -> Result<Image, ElfError> {
+        let error = |msg: &str| ElfError(format!("{}: {}", self.path, msg));
+        let raw_file = fs::read(&self.path).map_err(|e| error(&e.to_string()))?;
+        let file = object::File::parse(&*raw_file).map_err(|e| error(&e.to_string()))?;
+        if file.is_64() {
+            return Err(error("an RV64 program, riscland runs RV32 only: build it with -march=rv32gc -mabi=ilp32d"));
+        }
+        if file.architecture() != Architecture::Riscv32 {
+            return Err(error("not a RISC-V executable"));
+        }
+        let elf = ElfFile32::<object::Endianness>::parse(&*raw_file)
+            .map_err(|e| error(&e.to_string()))?;
+        let endian = elf.endian();
+        let header = elf.raw_header();
+        let phoff = header.e_phoff(endian) as u64;
+
+        let mut image = Image {
+            entry: header.e_entry(endian),
+            phent: header.e_phentsize(endian) as u32,
+            phnum: header.e_phnum(endian) as u32,
+            ..Default::default()
+        };
+        let phdrs = header
+            .program_headers(endian, &*raw_file)
+            .map_err(|e| error(&e.to_string()))?;
+        for phdr in phdrs {
+            if phdr.p_type(endian) != PT_LOAD {
+                continue;
+            }
+            let offset = phdr.p_offset(endian) as u64;
+            let filesz = phdr.p_filesz(endian) as u64;
+            let vaddr = phdr.p_vaddr(endian);
+            let memsz = phdr.p_memsz(endian);
+            if filesz > memsz as u64 || vaddr.checked_add(memsz).is_none() {
+                return Err(error(&format!(
+                    "segment at {:#x} does not fit into the address space",
+                    vaddr
+                )));
+            }
+            // the program headers are mapped by whichever segment covers them
+            if offset <= phoff && phoff < offset + filesz {
+                image.phdr = vaddr + (phoff - offset) as u32;
+            }
+            let data = phdr
+                .data(endian, &*raw_file)
+                .map_err(|_| error("segment data runs past the end of the file"))?;
+            image.segments.push(Segment {
+                vaddr,
+                memsz,
+                data: data.to_vec(),
+            });
+        }
+        Ok(image)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElfError(pub String);
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
 }
 
+impl std::error::Error for ElfError {}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -57,4 +236,13 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_read_symbols() {
+        let elf = super::ELF::new("./tests/rv32ui-p-auipc");
+        let symbols = elf.read_symbols();
+        let start = symbols.by_name("_start").expect("no _start symbol");
+        assert_eq!(symbols.describe(start.addr), "_start");
+        assert_eq!(symbols.lookup(start.addr).unwrap().1, 0);
+    }
 }
@@ -0,0 +1,232 @@
+// Flattened device trees, the way firmware and kernels learn what the machine
+// looks like. Fdt writes the blob (header, empty reservation map, structure
+// block, strings block, all big endian); machine() describes a bus with it:
+//
+//   / { chosen, memory@..., cpus { cpu@N { interrupt-controller } },
+//       soc { one node per device } }
+//
+// Phandles are fixed: the interrupt controller of hart N is N + 1, the
+// PLIC is PLIC_PHANDLE and the syscon SYSCON_PHANDLE.
+use std::collections::HashMap;
+
+use crate::isa::Isa;
+use crate::memory::BUS;
+
+const MAGIC: u32 = 0xd00dfeed;
+const VERSION: u32 = 17;
+const LAST_COMP_VERSION: u16 = 16;
+const HEADER_SIZE: usize = 40;
+// a single terminating entry
+const RSVMAP_SIZE: usize = 16;
+
+const BEGIN_NODE: u32 = 1;
+const END_NODE: u32 = 2;
+const PROP: u32 = 3;
+const END: u32 = 9;
+
+// mtime ticks per second, the CLINT counts at this rate
+pub const TIMEBASE_FREQ: u32 = 10_000_000;
+pub const PLIC_PHANDLE: u32 = 0x100;
+pub const SYSCON_PHANDLE: u32 = 0x101;
+
+// phandle of the interrupt controller in hart `hart`
+pub fn intc_phandle(hart: u32) -> u32 {
+    hart + 1
+}
+
+#[derive(Default)]
+pub struct Fdt {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    // property name -> offset in strings
+    names: HashMap<String, u32>,
+    depth: usize,
+}
+
+impl Fdt {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn begin_node(&mut self, name: &str) {
+        self.token(BEGIN_NODE);
+        self.structure.extend(name.as_bytes());
+        self.structure.push(0);
+        self.pad();
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        self.token(END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn prop(&mut self, name: &str, value: &[u8]) {
+        let nameoff = match self.names.get(name) {
+            Some(off) => *off,
+            None => {
+                let off = self.strings.len() as u32;
+                self.strings.extend(name.as_bytes());
+                self.strings.push(0);
+                self.names.insert(name.to_string(), off);
+                off
+            }
+        };
+        self.token(PROP);
+        self.structure.extend((value.len() as u32).to_be_bytes());
+        self.structure.extend(nameoff.to_be_bytes());
+        self.structure.extend(value);
+        self.pad();
+    }
+
+    pub fn prop_empty(&mut self, name: &str) {
+        self.prop(name, &[]);
+    }
+
+    pub fn prop_u32(&mut self, name: &str, value: u32) {
+        self.prop_cells(name, &[value]);
+    }
+
+    pub fn prop_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.prop(name, &value);
+    }
+
+    pub fn prop_str(&mut self, name: &str, value: &str) {
+        self.prop_strs(name, &[value]);
+    }
+
+    pub fn prop_strs(&mut self, name: &str, values: &[&str]) {
+        let mut value = Vec::new();
+        for s in values {
+            value.extend(s.as_bytes());
+            value.push(0);
+        }
+        self.prop(name, &value);
+    }
+
+    // the blob, once every node is closed
+    pub fn finish(mut self) -> Vec<u8> {
+        assert_eq!(self.depth, 0, "unclosed device tree node");
+        self.token(END);
+        let off_rsvmap = HEADER_SIZE;
+        let off_struct = off_rsvmap + RSVMAP_SIZE;
+        let off_strings = off_struct + self.structure.len();
+        let total = off_strings + self.strings.len();
+
+        let mut blob = Vec::with_capacity(total);
+        for word in [
+            MAGIC,
+            total as u32,
+            off_struct as u32,
+            off_strings as u32,
+            off_rsvmap as u32,
+            VERSION,
+            LAST_COMP_VERSION as u32,
+            // boot_cpuid_phys
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ] {
+            blob.extend(word.to_be_bytes());
+        }
+        blob.extend([0; RSVMAP_SIZE]);
+        blob.extend(&self.structure);
+        blob.extend(&self.strings);
+        blob
+    }
+
+    fn token(&mut self, token: u32) {
+        self.structure.extend(token.to_be_bytes());
+    }
+
+    fn pad(&mut self) {
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+}
+
+// what /chosen hands the kernel besides the console
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Chosen<'a> {
+    pub bootargs: &'a str,
+    // start and end of the initial ramdisk
+    pub initrd: Option<(u32, u32)>,
+}
+
+// device tree of a machine with `harts` harts running `isa` on `bus`
+pub fn machine(bus: &BUS, harts: u32, isa: &Isa, chosen: &Chosen) -> Vec<u8> {
+    let mut fdt = Fdt::new();
+    fdt.begin_node("");
+    fdt.prop_u32("#address-cells", 1);
+    fdt.prop_u32("#size-cells", 1);
+    fdt.prop_str("compatible", "riscland");
+    fdt.prop_str("model", "riscland");
+
+    fdt.begin_node("chosen");
+    fdt.prop_str("bootargs", chosen.bootargs);
+    if let Some((start, end)) = chosen.initrd {
+        fdt.prop_u32("linux,initrd-start", start);
+        fdt.prop_u32("linux,initrd-end", end);
+    }
+    if let Some(uart) = bus
+        .devices()
+        .iter()
+        .find(|dev| dev.device.fdt_name() == "serial")
+    {
+        fdt.prop_str("stdout-path", &format!("/soc/serial@{:x}", uart.base));
+    }
+    fdt.end_node();
+
+    for (base, size, read_only) in bus.memories() {
+        if !read_only && size != 0 {
+            fdt.begin_node(&format!("memory@{:x}", base));
+            fdt.prop_str("device_type", "memory");
+            fdt.prop_cells("reg", &[base, size]);
+            fdt.end_node();
+        }
+    }
+
+    fdt.begin_node("cpus");
+    fdt.prop_u32("#address-cells", 1);
+    fdt.prop_u32("#size-cells", 0);
+    fdt.prop_u32("timebase-frequency", TIMEBASE_FREQ);
+    for hart in 0..harts {
+        fdt.begin_node(&format!("cpu@{}", hart));
+        fdt.prop_str("device_type", "cpu");
+        fdt.prop_u32("reg", hart);
+        fdt.prop_str("status", "okay");
+        fdt.prop_str("compatible", "riscv");
+        fdt.prop_str("riscv,isa", &isa.to_string());
+        fdt.prop_str("mmu-type", "riscv,sv32");
+        fdt.begin_node("interrupt-controller");
+        fdt.prop_u32("#interrupt-cells", 1);
+        fdt.prop_empty("interrupt-controller");
+        fdt.prop_str("compatible", "riscv,cpu-intc");
+        fdt.prop_u32("phandle", intc_phandle(hart));
+        fdt.end_node();
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    fdt.begin_node("soc");
+    fdt.prop_u32("#address-cells", 1);
+    fdt.prop_u32("#size-cells", 1);
+    fdt.prop_str("compatible", "simple-bus");
+    fdt.prop_empty("ranges");
+    for dev in bus.devices() {
+        fdt.begin_node(&format!("{}@{:x}", dev.device.fdt_name(), dev.base));
+        fdt.prop_cells("reg", &[dev.base, dev.size]);
+        if let Some(irq) = dev.irq {
+            fdt.prop_u32("interrupt-parent", PLIC_PHANDLE);
+            fdt.prop_u32("interrupts", irq);
+        }
+        dev.device.fdt_props(&mut fdt, harts);
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    fdt.end_node();
+    fdt.finish()
+}
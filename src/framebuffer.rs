@@ -0,0 +1,297 @@
+// Linear framebuffer for a simple-framebuffer device tree node: the pixels
+// sit at the start of the window, rows of `stride` bytes, little endian
+// pixels in one of the formats the binding names. There is no display; a
+// frame is written to a PNG or PPM file, picked by the extension, when the
+// guest stores to FRAME_DUMP in the control page right after the pixels, or
+// at the first poll every `dump_every` instructions, so CI can look at what
+// was drawn. Frames are numbered, "fb.png" becomes fb-0000.png, fb-0001.png...
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::device::Device;
+use crate::fdt::Fdt;
+use crate::memory::BUS;
+use crate::snapshot::read_u64;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+// control page registers, from the end of the pixels rounded up to a page
+const FRAME_DUMP: u32 = 0x0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+pub enum PixelFormat {
+    #[serde(rename = "r5g6b5")]
+    Rgb565,
+    #[serde(rename = "r8g8b8")]
+    Rgb888,
+    #[default]
+    #[serde(rename = "x8r8g8b8")]
+    Xrgb8888,
+    #[serde(rename = "a8r8g8b8")]
+    Argb8888,
+    #[serde(rename = "x8b8g8r8")]
+    Xbgr8888,
+    #[serde(rename = "a8b8g8r8")]
+    Abgr8888,
+}
+
+const FORMATS: &[PixelFormat] = &[
+    PixelFormat::Rgb565,
+    PixelFormat::Rgb888,
+    PixelFormat::Xrgb8888,
+    PixelFormat::Argb8888,
+    PixelFormat::Xbgr8888,
+    PixelFormat::Abgr8888,
+];
+
+impl PixelFormat {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        FORMATS
+            .iter()
+            .find(|format| format.name() == s)
+            .copied()
+            .ok_or_else(|| format!("`{}` is no pixel format, e.g. x8r8g8b8", s))
+    }
+
+    // as in the simple-framebuffer binding
+    pub fn name(self) -> &'static str {
+        match self {
+            PixelFormat::Rgb565 => "r5g6b5",
+            PixelFormat::Rgb888 => "r8g8b8",
+            PixelFormat::Xrgb8888 => "x8r8g8b8",
+            PixelFormat::Argb8888 => "a8r8g8b8",
+            PixelFormat::Xbgr8888 => "x8b8g8r8",
+            PixelFormat::Abgr8888 => "a8b8g8r8",
+        }
+    }
+
+    pub fn bytes_per_pixel(self) -> u32 {
+        match self {
+            PixelFormat::Rgb565 => 2,
+            PixelFormat::Rgb888 => 3,
+            _ => 4,
+        }
+    }
+
+    // red, green and blue of the pixel at the start of `bytes`
+    fn rgb(self, bytes: &[u8]) -> [u8; 3] {
+        match self {
+            PixelFormat::Rgb565 => {
+                let p = u16::from_le_bytes([bytes[0], bytes[1]]);
+                let (r, g, b) = ((p >> 11) as u8, (p >> 5) as u8 & 0x3f, p as u8 & 0x1f);
+                [r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2]
+            }
+            PixelFormat::Rgb888 | PixelFormat::Xrgb8888 | PixelFormat::Argb8888 => {
+                [bytes[2], bytes[1], bytes[0]]
+            }
+            PixelFormat::Xbgr8888 | PixelFormat::Abgr8888 => [bytes[0], bytes[1], bytes[2]],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mode {
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+}
+
+impl Mode {
+    // "800x600", or with a format "800x600:r5g6b5"
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (size, format) = match s.split_once(':') {
+            Some((size, format)) => (size, PixelFormat::parse(format)?),
+            None => (s, PixelFormat::default()),
+        };
+        let bad = || format!("`{}` is no resolution, e.g. 640x480", size);
+        let (width, height) = size.split_once('x').ok_or_else(bad)?;
+        let mode = Mode {
+            width: width.parse().map_err(|_| bad())?,
+            height: height.parse().map_err(|_| bad())?,
+            format,
+        };
+        if mode.width == 0 || mode.height == 0 || mode.window_size().is_none() {
+            return Err(bad());
+        }
+        Ok(mode)
+    }
+
+    pub fn stride(&self) -> u32 {
+        self.width * self.format.bytes_per_pixel()
+    }
+
+    // pixels, then the control page; None when that does not fit the
+    // address space
+    pub fn window_size(&self) -> Option<u32> {
+        let pixels = self.width as u64 * self.format.bytes_per_pixel() as u64 * self.height as u64;
+        let size = pixels.div_ceil(PAGE_SIZE) * PAGE_SIZE + PAGE_SIZE;
+        u32::try_from(size).ok()
+    }
+
+    fn control(&self) -> u32 {
+        self.window_size().unwrap() - PAGE_SIZE as u32
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Framebuffer {
+    mode: Mode,
+    pixels: Vec<u8>,
+    // where frames go, numbered
+    dump: Option<PathBuf>,
+    dump_every: Option<u64>,
+    next_dump: u64,
+    frames: u32,
+    // bus clock at the last access or poll
+    instret: u64,
+}
+
+impl Framebuffer {
+    // the mode has to have a window_size()
+    pub fn new(mode: Mode) -> Self {
+        Framebuffer {
+            mode,
+            pixels: vec![0; (mode.stride() * mode.height) as usize],
+            dump: None,
+            dump_every: None,
+            next_dump: 0,
+            frames: 0,
+            instret: 0,
+        }
+    }
+
+    // write frames next to `path` when asked, and every `every` instructions
+    pub fn with_dumps(mut self, path: &Path, every: Option<u64>) -> Self {
+        self.dump = Some(path.to_path_buf());
+        self.dump_every = every.filter(|every| *every > 0);
+        self.next_dump = self.dump_every.unwrap_or(0);
+        self
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    // the frame as 8-bit RGB rows without padding
+    pub fn rgb(&self) -> Vec<u8> {
+        let bpp = self.mode.format.bytes_per_pixel() as usize;
+        let mut rgb = Vec::with_capacity((self.mode.width * self.mode.height * 3) as usize);
+        for row in self.pixels.chunks(self.mode.stride() as usize) {
+            for pixel in row.chunks(bpp) {
+                rgb.extend(self.mode.format.rgb(pixel));
+            }
+        }
+        rgb
+    }
+
+    pub fn write_ppm(&self, w: &mut impl Write) -> io::Result<()> {
+        write!(w, "P6\n{} {}\n255\n", self.mode.width, self.mode.height)?;
+        w.write_all(&self.rgb())
+    }
+
+    pub fn write_png(&self, w: &mut impl Write) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(w, self.mode.width, self.mode.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.rgb())?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    // PPM for a .ppm file, PNG for anything else
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("ppm") => self.write_ppm(&mut w)?,
+            _ => self.write_png(&mut w)?,
+        }
+        w.flush()
+    }
+
+    // the next numbered frame, a failed write is reported and not retried
+    fn dump_frame(&mut self) {
+        let Some(path) = &self.dump else {
+            return;
+        };
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let mut name = format!("{}-{:04}", stem, self.frames);
+        if let Some(ext) = path.extension() {
+            name = format!("{}.{}", name, ext.to_string_lossy());
+        }
+        let path = path.with_file_name(name);
+        self.frames += 1;
+        if let Err(e) = self.save(&path) {
+            eprintln!("framebuffer: {}: {}", path.display(), e);
+        }
+    }
+}
+
+impl Device for Framebuffer {
+    fn load(&mut self, offset: u32, size: u32) -> u32 {
+        let len = size as usize / 8;
+        match self.pixels.get(offset as usize..offset as usize + len) {
+            Some(bytes) => bytes
+                .iter()
+                .rev()
+                .fold(0, |value, byte| value << 8 | *byte as u32),
+            None => 0,
+        }
+    }
+
+    fn store(&mut self, offset: u32, size: u32, value: u32) {
+        if offset == self.mode.control() + FRAME_DUMP {
+            self.dump_frame();
+            return;
+        }
+        let len = size as usize / 8;
+        if let Some(bytes) = self.pixels.get_mut(offset as usize..offset as usize + len) {
+            bytes.copy_from_slice(&value.to_le_bytes()[..len]);
+        }
+    }
+
+    fn clock(&mut self, instret: u64) {
+        self.instret = instret;
+    }
+
+    fn dma(&mut self, _bus: &mut BUS) {
+        let Some(every) = self.dump_every else {
+            return;
+        };
+        if self.instret >= self.next_dump {
+            self.dump_frame();
+            self.next_dump = self.instret - self.instret % every + every;
+        }
+    }
+
+    // the frame, whose size the mode gives, and when the next dump is due
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(&self.pixels)?;
+        w.write_all(&self.next_dump.to_le_bytes())
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        r.read_exact(&mut self.pixels)?;
+        self.next_dump = read_u64(r)?;
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+
+    fn fdt_name(&self) -> &'static str {
+        "framebuffer"
+    }
+
+    fn fdt_props(&self, fdt: &mut Fdt, _harts: u32) {
+        fdt.prop_str("compatible", "simple-framebuffer");
+        fdt.prop_u32("width", self.mode.width);
+        fdt.prop_u32("height", self.mode.height);
+        fdt.prop_u32("stride", self.mode.stride());
+        fdt.prop_str("format", self.mode.format.name());
+    }
+}
@@ -0,0 +1,245 @@
+// GDB remote serial protocol stub, enough for `target remote` with
+// breakpoints, memory and register access and reverse execution:
+//
+//   (gdb) set architecture riscv:rv32
+//   (gdb) target remote :1234
+//   (gdb) reverse-stepi / reverse-continue
+//   (gdb) monitor last-write 0x80001000
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::cpu::Fault;
+use crate::debugger::{parse_addr, Debugger, Stop};
+
+// register numbers as gdb numbers them for riscv32
+const PC_REGNUM: usize = 32;
+
+// wait for gdb on `port` and serve it until it detaches or kills the guest
+pub fn serve(dbg: &mut Debugger, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("waiting for gdb on port {}", port);
+    let (mut stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    while let Some(packet) = recv(&mut stream)? {
+        let mut conn = stream.try_clone()?;
+        let reply = handle(dbg, &packet, &mut || interrupted(&mut conn));
+        let Some(reply) = reply else {
+            return Ok(());
+        };
+        send(&mut stream, &reply)?;
+        if packet.starts_with('D') {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+// answer one packet, None once the session is over
+pub fn handle(
+    dbg: &mut Debugger,
+    packet: &str,
+    interrupted: &mut dyn FnMut() -> bool,
+) -> Option<String> {
+    let (cmd, rest) = split_packet(packet);
+    let reply = match cmd {
+        "?" => "S05".to_string(),
+        "qSupported" => "PacketSize=4000;ReverseStep+;ReverseContinue+".to_string(),
+        "qAttached" => "1".to_string(),
+        "qRcmd" => monitor(dbg, rest.trim_start_matches(',')),
+        "H" | "D" => "OK".to_string(),
+        "k" => return None,
+        "g" => {
+            let mut out = String::new();
+            for reg in dbg.cpu.xregs.regs.iter().chain([dbg.cpu.pc].iter()) {
+                out.push_str(&hex(&reg.to_le_bytes()));
+            }
+            out
+        }
+        "G" => match unhex(rest) {
+            Some(bytes) if bytes.len() >= 4 * (PC_REGNUM + 1) => {
+                let mut words = bytes
+                    .chunks(4)
+                    .map(|w| u32::from_le_bytes(w.try_into().unwrap()));
+                for reg in dbg.cpu.xregs.regs.iter_mut() {
+                    *reg = words.next().unwrap();
+                }
+                dbg.cpu.pc = words.next().unwrap();
+                dbg.replay.diverge(&dbg.cpu);
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        },
+        "p" => match usize::from_str_radix(rest, 16) {
+            Ok(n) if n < PC_REGNUM => hex(&dbg.cpu.xregs.regs[n].to_le_bytes()),
+            Ok(PC_REGNUM) => hex(&dbg.cpu.pc.to_le_bytes()),
+            _ => "E01".to_string(),
+        },
+        "P" => {
+            let parsed = rest.split_once('=').and_then(|(n, v)| {
+                let n = usize::from_str_radix(n, 16).ok()?;
+                let v = u32::from_le_bytes(unhex(v)?.try_into().ok()?);
+                Some((n, v))
+            });
+            match parsed {
+                Some((n, v)) if n < PC_REGNUM => {
+                    dbg.cpu.xregs.regs[n] = v;
+                    dbg.replay.diverge(&dbg.cpu);
+                    "OK".to_string()
+                }
+                Some((PC_REGNUM, v)) => {
+                    dbg.cpu.pc = v;
+                    dbg.replay.diverge(&dbg.cpu);
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            }
+        }
+        "m" => {
+            let range = rest
+                .split_once(',')
+                .and_then(|(a, l)| Some((parse_addr(a)?, usize::from_str_radix(l, 16).ok()?)));
+            match range.and_then(|(addr, len)| dbg.cpu.bus.load_bytes(addr, len)) {
+                Some(bytes) => hex(bytes),
+                None => "E01".to_string(),
+            }
+        }
+        "M" => {
+            let write = rest.split_once(':').and_then(|(range, data)| {
+                let addr = parse_addr(range.split_once(',')?.0)?;
+                Some((addr, unhex(data)?))
+            });
+            match write.and_then(|(addr, data)| dbg.cpu.bus.store_bytes(addr, &data)) {
+                Some(()) => {
+                    dbg.replay.diverge(&dbg.cpu);
+                    "OK".to_string()
+                }
+                None => "E01".to_string(),
+            }
+        }
+        "Z" | "z" => {
+            let mut fields = rest.split(',');
+            let kind = fields.next();
+            match (kind, fields.next().and_then(parse_addr)) {
+                (Some("0" | "1"), Some(addr)) => {
+                    match cmd {
+                        "Z" => dbg.add_breakpoint(addr),
+                        _ => dbg.remove_breakpoint(addr),
+                    }
+                    "OK".to_string()
+                }
+                // watchpoints are not supported
+                _ => String::new(),
+            }
+        }
+        "s" => stop_reply(dbg.step()),
+        "c" => stop_reply(dbg.cont(interrupted)),
+        "bs" => stop_reply(dbg.reverse_step()),
+        "bc" => stop_reply(dbg.reverse_cont()),
+        _ => String::new(),
+    };
+    Some(reply)
+}
+
+// packet name and its arguments, unknown packets get an empty reply
+fn split_packet(packet: &str) -> (&str, &str) {
+    for name in ["qSupported", "qAttached", "qRcmd", "bs", "bc"] {
+        if let Some(rest) = packet.strip_prefix(name) {
+            return (name, rest);
+        }
+    }
+    match packet.chars().next() {
+        Some(c) if "?HDkgGpPmMZzsc".contains(c) => packet.split_at(1),
+        _ => (packet, ""),
+    }
+}
+
+fn stop_reply(stop: Stop) -> String {
+    match stop {
+        Stop::Step | Stop::Breakpoint => "S05".to_string(),
+        Stop::Interrupted => "S02".to_string(),
+        Stop::Start => "T05replaylog:begin;".to_string(),
+        Stop::Exited(code) => format!("W{:02x}", code as u8),
+        // SIGILL, SIGBUS and SIGSEGV
+        Stop::Fault(Fault::IllegalInstruction(_)) => "S04".to_string(),
+        Stop::Fault(
+            Fault::FetchMisaligned(_) | Fault::LoadMisaligned(_) | Fault::StoreMisaligned(_),
+        ) => "S07".to_string(),
+        Stop::Fault(_) => "S0b".to_string(),
+    }
+}
+
+// `monitor` commands, the reply is the hex encoded console output
+fn monitor(dbg: &mut Debugger, cmd: &str) -> String {
+    let Some(cmd) = unhex(cmd).and_then(|b| String::from_utf8(b).ok()) else {
+        return "E01".to_string();
+    };
+    let mut words = cmd.split_whitespace();
+    let out = match (words.next(), words.next().and_then(parse_addr)) {
+        (Some("lw" | "last-write"), Some(addr)) => dbg.last_write(addr),
+        _ => "usage: monitor last-write ADDR\n".to_string(),
+    };
+    hex(out.as_bytes())
+}
+
+// next packet payload, acknowledging it, None when gdb hung up
+fn recv(stream: &mut TcpStream) -> io::Result<Option<String>> {
+    let mut byte = [0];
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        if byte[0] == b'$' {
+            break;
+        }
+        // acks and stray interrupts while stopped
+    }
+    let mut payload = Vec::new();
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        if byte[0] == b'#' {
+            break;
+        }
+        payload.push(byte[0]);
+    }
+    let mut checksum = [0; 2];
+    stream.read_exact(&mut checksum)?;
+    stream.write_all(b"+")?;
+    Ok(Some(String::from_utf8_lossy(&payload).into_owned()))
+}
+
+fn send(stream: &mut TcpStream, payload: &str) -> io::Result<()> {
+    let sum = payload.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+    write!(stream, "${}#{:02x}", payload, sum)
+}
+
+// gdb sends a bare 0x03 to stop a running guest
+fn interrupted(stream: &mut TcpStream) -> bool {
+    let mut byte = [0];
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let got = matches!(stream.read(&mut byte), Ok(1) if byte[0] == 0x03);
+    let _ = stream.set_nonblocking(false);
+    got
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        write!(out, "{:02x}", b).unwrap();
+    }
+    out
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,184 @@
+// Goldfish RTC, the real time clock of QEMU's virt machine: nanoseconds since
+// 1970 in two 32-bit halves, reading TIME_LOW latches the high half for the
+// TIME_HIGH read that follows. Time comes from the host clock, or, with an
+// epoch, from the bus's instruction count at NS_PER_INSTR, so a run and its
+// snapshots and traces see the same time on every replay. The guest setting
+// the time moves an offset on top of either. A due alarm raises the
+// interrupt, if enabled, until the guest clears it; alarms are checked at
+// every access and poll.
+use std::io::{self, Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::device::Device;
+use crate::fdt::Fdt;
+use crate::snapshot::{read_bool, read_u32, read_u64};
+
+pub const RTC_SIZE: u32 = 0x1000;
+
+// virtual time a retired instruction takes, a 100 MIPS hart
+pub const NS_PER_INSTR: u64 = 10;
+
+const TIME_LOW: u32 = 0x00;
+const TIME_HIGH: u32 = 0x04;
+const ALARM_LOW: u32 = 0x08;
+const ALARM_HIGH: u32 = 0x0c;
+const IRQ_ENABLED: u32 = 0x10;
+const CLEAR_ALARM: u32 = 0x14;
+const ALARM_STATUS: u32 = 0x18;
+const CLEAR_INTERRUPT: u32 = 0x1c;
+
+#[derive(Debug, Clone, Copy)]
+enum Clock {
+    Host,
+    // nanoseconds since 1970 at instruction 0
+    Virtual { epoch: u64 },
+}
+
+#[derive(Debug, Clone)]
+pub struct Rtc {
+    clock: Clock,
+    // bus clock at the current access
+    instret: u64,
+    // what the guest set minus what the clock said then, wrapping
+    offset: u64,
+    // high half of the last TIME_LOW read
+    time_high: u32,
+    // high halves written ahead of TIME_LOW and ALARM_LOW
+    set_high: u32,
+    alarm_high: u32,
+    alarm: Option<u64>,
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+
+impl Rtc {
+    fn new(clock: Clock) -> Self {
+        Rtc {
+            clock,
+            instret: 0,
+            offset: 0,
+            time_high: 0,
+            set_high: 0,
+            alarm_high: 0,
+            alarm: None,
+            irq_enabled: false,
+            irq_pending: false,
+        }
+    }
+
+    pub fn host() -> Self {
+        Rtc::new(Clock::Host)
+    }
+
+    // `epoch` seconds since 1970 at the first instruction
+    pub fn virtual_time(epoch: u64) -> Self {
+        Rtc::new(Clock::Virtual {
+            epoch: epoch.saturating_mul(1_000_000_000),
+        })
+    }
+
+    fn clock_ns(&self) -> u64 {
+        match self.clock {
+            Clock::Host => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64,
+            Clock::Virtual { epoch } => epoch.wrapping_add(self.instret.wrapping_mul(NS_PER_INSTR)),
+        }
+    }
+
+    // nanoseconds since 1970 as the guest sees them
+    pub fn now(&self) -> u64 {
+        self.clock_ns().wrapping_add(self.offset)
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn check_alarm(&mut self) {
+        if self.alarm.is_some_and(|alarm| self.now() >= alarm) {
+            self.alarm = None;
+            self.irq_pending = self.irq_enabled;
+        }
+    }
+}
+
+impl Device for Rtc {
+    fn load(&mut self, offset: u32, _size: u32) -> u32 {
+        self.check_alarm();
+        match offset {
+            TIME_LOW => {
+                let now = self.now();
+                self.time_high = (now >> 32) as u32;
+                now as u32
+            }
+            TIME_HIGH => self.time_high,
+            IRQ_ENABLED => self.irq_enabled as u32,
+            ALARM_STATUS => self.alarm.is_some() as u32,
+            _ => 0,
+        }
+    }
+
+    fn store(&mut self, offset: u32, _size: u32, value: u32) {
+        match offset {
+            TIME_LOW => {
+                let set = (self.set_high as u64) << 32 | value as u64;
+                self.offset = set.wrapping_sub(self.clock_ns());
+            }
+            TIME_HIGH => self.set_high = value,
+            ALARM_LOW => self.alarm = Some((self.alarm_high as u64) << 32 | value as u64),
+            ALARM_HIGH => self.alarm_high = value,
+            IRQ_ENABLED => self.irq_enabled = value & 1 != 0,
+            CLEAR_ALARM => self.alarm = None,
+            CLEAR_INTERRUPT => self.irq_pending = false,
+            _ => (),
+        }
+        self.check_alarm();
+    }
+
+    fn clock(&mut self, instret: u64) {
+        self.instret = instret;
+        self.check_alarm();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    // the guest's time goes on from where it was, on either clock
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(&self.now().to_le_bytes())?;
+        for half in [self.time_high, self.set_high, self.alarm_high] {
+            w.write_all(&half.to_le_bytes())?;
+        }
+        w.write_all(&[self.alarm.is_some() as u8])?;
+        w.write_all(&self.alarm.unwrap_or(0).to_le_bytes())?;
+        w.write_all(&[self.irq_enabled as u8, self.irq_pending as u8])
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.offset = read_u64(r)?.wrapping_sub(self.clock_ns());
+        self.time_high = read_u32(r)?;
+        self.set_high = read_u32(r)?;
+        self.alarm_high = read_u32(r)?;
+        let armed = read_bool(r)?;
+        let alarm = read_u64(r)?;
+        self.alarm = armed.then_some(alarm);
+        self.irq_enabled = read_bool(r)?;
+        self.irq_pending = read_bool(r)?;
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+
+    fn fdt_name(&self) -> &'static str {
+        "rtc"
+    }
+
+    fn fdt_props(&self, fdt: &mut Fdt, _harts: u32) {
+        fdt.prop_str("compatible", "google,goldfish-rtc");
+    }
+}
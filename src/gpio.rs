@@ -0,0 +1,267 @@
+// SiFive GPIO block, 32 pins. A pin the guest drives (output_en) reads back
+// what it drives, any other pin what the world outside puts on it. That
+// world is a Pins: a Rust implementation a test holds on to, or a Script of
+// timed level changes from the board file that logs what the guest drives.
+// Time is the bus clock, so a script plays out the same on every run.
+// Edges and levels latch in the *_ip registers, a 1 written clears them; no
+// interrupt is delivered.
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use serde::Deserialize;
+
+use crate::device::Device;
+use crate::fdt::Fdt;
+use crate::memory::BUS;
+use crate::snapshot::{bad_state, read_u32};
+
+pub const GPIO_SIZE: u32 = 0x1000;
+
+const INPUT_VAL: u32 = 0x00;
+const INPUT_EN: u32 = 0x04;
+const OUTPUT_EN: u32 = 0x08;
+const OUTPUT_VAL: u32 = 0x0c;
+const RISE_IP: u32 = 0x1c;
+const FALL_IP: u32 = 0x24;
+const HIGH_IP: u32 = 0x2c;
+const LOW_IP: u32 = 0x34;
+const OUT_XOR: u32 = 0x40;
+const LAST_REG: u32 = OUT_XOR;
+
+// the world outside the pins
+pub trait Pins: Send + fmt::Debug {
+    // levels it puts on the pins at bus clock `instret`
+    fn input(&mut self, instret: u64) -> u32;
+
+    // the guest drives `value` on the pins in `enabled` from `instret` on
+    fn output(&mut self, instret: u64, value: u32, enabled: u32);
+
+    // state of the world outside for a snapshot, as Device::save() and
+    // restore()
+    fn save(&self, _w: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn restore(&mut self, _r: &mut dyn Read) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn Pins>;
+}
+
+impl Clone for Box<dyn Pins> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+// pin `pin` goes to `level` once `at` instructions are retired
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PinChange {
+    pub at: u64,
+    pub pin: u32,
+    pub level: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Script {
+    // by time
+    changes: Vec<PinChange>,
+    next: usize,
+    levels: u32,
+    // "instret value enabled" lines, hex, for every change of the outputs
+    log: Option<Arc<Mutex<File>>>,
+}
+
+impl Script {
+    pub fn new(mut changes: Vec<PinChange>) -> Self {
+        changes.sort_by_key(|change| change.at);
+        Script {
+            changes,
+            ..Script::default()
+        }
+    }
+
+    pub fn with_log(mut self, path: &Path) -> std::io::Result<Self> {
+        self.log = Some(Arc::new(Mutex::new(File::create(path)?)));
+        Ok(self)
+    }
+}
+
+impl Pins for Script {
+    fn input(&mut self, instret: u64) -> u32 {
+        while let Some(change) = self.changes.get(self.next).filter(|c| c.at <= instret) {
+            let bit = 1u32.checked_shl(change.pin).unwrap_or(0);
+            match change.level {
+                true => self.levels |= bit,
+                false => self.levels &= !bit,
+            }
+            self.next += 1;
+        }
+        self.levels
+    }
+
+    fn output(&mut self, instret: u64, value: u32, enabled: u32) {
+        if let Some(log) = &self.log {
+            let _ = writeln!(
+                log.lock().unwrap(),
+                "{} {:08x} {:08x}",
+                instret,
+                value,
+                enabled
+            );
+        }
+    }
+
+    // how far the script has played, so a snapshot from earlier plays it
+    // again
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(&(self.next as u32).to_le_bytes())?;
+        w.write_all(&self.levels.to_le_bytes())
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        let next = read_u32(r)? as usize;
+        if next > self.changes.len() {
+            return Err(bad_state("past the end of the script"));
+        }
+        self.next = next;
+        self.levels = read_u32(r)?;
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn Pins> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Gpio {
+    pins: Box<dyn Pins>,
+    // register words by offset / 4
+    regs: [u32; LAST_REG as usize / 4 + 1],
+    // pin levels at the last sample
+    levels: u32,
+    // what was last handed to pins.output()
+    driven: (u32, u32),
+    instret: u64,
+}
+
+impl Gpio {
+    pub fn new(pins: Box<dyn Pins>) -> Self {
+        Gpio {
+            pins,
+            regs: [0; LAST_REG as usize / 4 + 1],
+            levels: 0,
+            driven: (0, 0),
+            instret: 0,
+        }
+    }
+
+    fn reg(&self, offset: u32) -> u32 {
+        self.regs[offset as usize / 4]
+    }
+
+    fn reg_mut(&mut self, offset: u32) -> &mut u32 {
+        &mut self.regs[offset as usize / 4]
+    }
+
+    // what the guest drives, and on which pins
+    fn outputs(&self) -> (u32, u32) {
+        let enabled = self.reg(OUTPUT_EN);
+        (
+            (self.reg(OUTPUT_VAL) ^ self.reg(OUT_XOR)) & enabled,
+            enabled,
+        )
+    }
+
+    // look at the pins and latch edges and levels
+    fn sample(&mut self) {
+        let (value, enabled) = self.outputs();
+        let levels = value | self.pins.input(self.instret) & !enabled;
+        let (old, new) = (self.levels, levels);
+        *self.reg_mut(RISE_IP) |= new & !old;
+        *self.reg_mut(FALL_IP) |= old & !new;
+        *self.reg_mut(HIGH_IP) |= new;
+        *self.reg_mut(LOW_IP) |= !new;
+        self.levels = levels;
+    }
+}
+
+impl Device for Gpio {
+    fn load(&mut self, offset: u32, _size: u32) -> u32 {
+        if offset > LAST_REG || !offset.is_multiple_of(4) {
+            return 0;
+        }
+        self.sample();
+        match offset {
+            INPUT_VAL => self.levels & self.reg(INPUT_EN),
+            _ => self.reg(offset),
+        }
+    }
+
+    fn store(&mut self, offset: u32, _size: u32, value: u32) {
+        if offset > LAST_REG || !offset.is_multiple_of(4) {
+            return;
+        }
+        match offset {
+            INPUT_VAL => (),
+            RISE_IP | FALL_IP | HIGH_IP | LOW_IP => *self.reg_mut(offset) &= !value,
+            _ => *self.reg_mut(offset) = value,
+        }
+        let outputs = self.outputs();
+        if outputs != self.driven {
+            self.driven = outputs;
+            self.pins.output(self.instret, outputs.0, outputs.1);
+        }
+        self.sample();
+    }
+
+    fn clock(&mut self, instret: u64) {
+        self.instret = instret;
+    }
+
+    // catch edges between accesses too
+    fn dma(&mut self, _bus: &mut BUS) {
+        self.sample();
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        for word in self
+            .regs
+            .iter()
+            .chain([&self.levels, &self.driven.0, &self.driven.1])
+        {
+            w.write_all(&word.to_le_bytes())?;
+        }
+        self.pins.save(w)
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        for reg in self.regs.iter_mut() {
+            *reg = read_u32(r)?;
+        }
+        self.levels = read_u32(r)?;
+        self.driven = (read_u32(r)?, read_u32(r)?);
+        self.pins.restore(r)
+    }
+
+    fn clone_box(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+
+    fn fdt_name(&self) -> &'static str {
+        "gpio"
+    }
+
+    fn fdt_props(&self, fdt: &mut Fdt, _harts: u32) {
+        fdt.prop_str("compatible", "sifive,gpio0");
+        fdt.prop_empty("gpio-controller");
+        fdt.prop_u32("#gpio-cells", 2);
+        fdt.prop_u32("ngpios", 32);
+    }
+}
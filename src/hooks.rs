@@ -0,0 +1,98 @@
+// Instrumentation for embedders: a Hooks implementation registered on a
+// Machine is told about every instruction retired, data access, trap, CSR
+// access and privilege change of the hart, without touching the core. All
+// callbacks default to doing nothing, so a plugin only implements what it
+// looks at. With nothing registered the core pays one empty-list check per
+// event.
+//
+// Hooks see every instruction, so a machine with hooks runs the interpreter
+// and never the JIT. Copies of a hart, e.g. the other harts of an SMP machine
+// made from it, report to the same hooks.
+use core::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::cpu::Fault;
+pub use crate::cpu::Privilege;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+    // ecall at pc
+    Ecall,
+    // ebreak at pc that is no semihosting call
+    Breakpoint,
+    // the instruction at pc could not be carried out
+    Fault(Fault),
+    // interrupt with this cause taken before the instruction at pc
+    Interrupt(u32),
+}
+
+#[allow(unused_variables)]
+pub trait Hooks: Send {
+    // the instruction `instr` at `pc` completed
+    fn retire(&mut self, pc: u32, instr: u32) {}
+
+    // a load of `size` bits from `addr` returned `value`
+    fn mem_read(&mut self, addr: u32, size: u32, value: u32) {}
+
+    // a store of `size` bits wrote `value` to `addr`
+    fn mem_write(&mut self, addr: u32, size: u32, value: u32) {}
+
+    // the instruction at `pc` trapped, it does not retire
+    fn trap(&mut self, pc: u32, trap: Trap) {}
+
+    // a CSR instruction read `old` from `csr` and, unless it only reads,
+    // wrote `new`
+    fn csr(&mut self, csr: u32, old: u32, new: Option<u32>) {}
+
+    // a trap or an mret/sret moved the hart from one privilege level to
+    // another; the trap itself is reported first
+    fn privilege(&mut self, from: Privilege, to: Privilege) {}
+}
+
+// the hooks registered on one hart, shared with its copies
+#[derive(Default, Clone)]
+pub struct HookList(pub Vec<Arc<Mutex<dyn Hooks>>>);
+
+impl HookList {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn retire(&mut self, pc: u32, instr: u32) {
+        self.each(|hooks| hooks.retire(pc, instr));
+    }
+
+    pub fn mem_read(&mut self, addr: u32, size: u32, value: u32) {
+        self.each(|hooks| hooks.mem_read(addr, size, value));
+    }
+
+    pub fn mem_write(&mut self, addr: u32, size: u32, value: u32) {
+        self.each(|hooks| hooks.mem_write(addr, size, value));
+    }
+
+    pub fn trap(&mut self, pc: u32, trap: Trap) {
+        self.each(|hooks| hooks.trap(pc, trap));
+    }
+
+    pub fn csr(&mut self, csr: u32, old: u32, new: Option<u32>) {
+        self.each(|hooks| hooks.csr(csr, old, new));
+    }
+
+    pub fn privilege(&mut self, from: Privilege, to: Privilege) {
+        self.each(|hooks| hooks.privilege(from, to));
+    }
+
+    // in the order they were added; the harts of a threaded machine take
+    // turns
+    fn each(&mut self, mut f: impl FnMut(&mut dyn Hooks)) {
+        for hooks in &self.0 {
+            f(&mut *hooks.lock().unwrap());
+        }
+    }
+}
+
+impl fmt::Debug for HookList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} hooks", self.0.len())
+    }
+}
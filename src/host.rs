@@ -0,0 +1,281 @@
+// Host side of guest file descriptors, shared by the syscall emulation layers.
+// Errors are reported as Linux errno values, each layer decides how to hand
+// them back to the guest.
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const EPERM: i32 = 1;
+pub const ENOENT: i32 = 2;
+pub const EIO: i32 = 5;
+pub const EBADF: i32 = 9;
+pub const ENOMEM: i32 = 12;
+pub const EACCES: i32 = 13;
+pub const EFAULT: i32 = 14;
+pub const EINVAL: i32 = 22;
+pub const ENOTTY: i32 = 25;
+pub const ESPIPE: i32 = 29;
+pub const ENOSYS: i32 = 38;
+
+pub const SEEK_SET: u32 = 0;
+pub const SEEK_CUR: u32 = 1;
+pub const SEEK_END: u32 = 2;
+
+// st_mode of the terminal-like stdio streams
+const S_IFCHR: u32 = 0o020000;
+
+// ABI independent open(2) flags, every layer translates its own bits into these
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OpenFlags {
+    pub read: bool,
+    pub write: bool,
+    pub create: bool,
+    pub truncate: bool,
+    pub append: bool,
+    pub exclusive: bool,
+}
+
+// ABI independent stat(2) result
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stat {
+    pub dev: u64,
+    pub ino: u64,
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u64,
+    pub size: i64,
+    pub blksize: u32,
+    pub blocks: i64,
+    pub atime: (i64, u32),
+    pub mtime: (i64, u32),
+    pub ctime: (i64, u32),
+}
+
+impl Stat {
+    fn from_metadata(meta: &Metadata) -> Self {
+        Stat {
+            dev: meta.dev(),
+            ino: meta.ino(),
+            mode: meta.mode(),
+            nlink: meta.nlink() as u32,
+            uid: meta.uid(),
+            gid: meta.gid(),
+            rdev: meta.rdev(),
+            size: meta.size() as i64,
+            blksize: meta.blksize() as u32,
+            blocks: meta.blocks() as i64,
+            atime: (meta.atime(), meta.atime_nsec() as u32),
+            mtime: (meta.mtime(), meta.mtime_nsec() as u32),
+            ctime: (meta.ctime(), meta.ctime_nsec() as u32),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum HostFile {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+// a cloned file shares its offset with the original, like dup(2)
+impl Clone for HostFile {
+    fn clone(&self) -> Self {
+        match self {
+            HostFile::Stdin => HostFile::Stdin,
+            HostFile::Stdout => HostFile::Stdout,
+            HostFile::Stderr => HostFile::Stderr,
+            HostFile::File(f) => HostFile::File(f.try_clone().expect("failed to dup host file")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileTable {
+    files: Vec<Option<HostFile>>,
+}
+
+impl Default for FileTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileTable {
+    // fd 0, 1 and 2 are wired to the emulator's own stdio
+    pub fn new() -> Self {
+        FileTable {
+            files: vec![
+                Some(HostFile::Stdin),
+                Some(HostFile::Stdout),
+                Some(HostFile::Stderr),
+            ],
+        }
+    }
+
+    pub fn open(&mut self, path: &str, flags: OpenFlags, mode: u32) -> Result<u32, i32> {
+        let file = OpenOptions::new()
+            .read(flags.read)
+            .write(flags.write)
+            .append(flags.append)
+            .truncate(flags.truncate)
+            .create(flags.create && !flags.exclusive)
+            .create_new(flags.create && flags.exclusive)
+            .mode(mode)
+            .open(path)
+            .map_err(errno)?;
+        // reuse the lowest free descriptor like the kernel does
+        let fd = match self.files.iter().position(|f| f.is_none()) {
+            Some(fd) => fd,
+            None => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+        };
+        self.files[fd] = Some(HostFile::File(file));
+        Ok(fd as u32)
+    }
+
+    pub fn close(&mut self, fd: u32) -> Result<(), i32> {
+        match self.files.get_mut(fd as usize) {
+            Some(f @ Some(_)) => {
+                *f = None;
+                Ok(())
+            }
+            _ => Err(EBADF),
+        }
+    }
+
+    pub fn read(&mut self, fd: u32, len: usize) -> Result<Vec<u8>, i32> {
+        let mut buf = vec![0; len];
+        let n = match self.get(fd)? {
+            HostFile::Stdin => io::stdin().read(&mut buf),
+            HostFile::File(f) => f.read(&mut buf),
+            _ => return Err(EBADF),
+        }
+        .map_err(errno)?;
+        buf.truncate(n);
+        Ok(buf)
+    }
+
+    pub fn write(&mut self, fd: u32, buf: &[u8]) -> Result<usize, i32> {
+        let res = match self.get(fd)? {
+            HostFile::Stdout => io::stdout().write(buf).and_then(|n| {
+                io::stdout().flush()?;
+                Ok(n)
+            }),
+            HostFile::Stderr => io::stderr().write(buf),
+            HostFile::File(f) => f.write(buf),
+            HostFile::Stdin => return Err(EBADF),
+        };
+        res.map_err(errno)
+    }
+
+    pub fn seek(&mut self, fd: u32, offset: i64, whence: u32) -> Result<u64, i32> {
+        let pos = match whence {
+            SEEK_SET => SeekFrom::Start(offset as u64),
+            SEEK_CUR => SeekFrom::Current(offset),
+            SEEK_END => SeekFrom::End(offset),
+            _ => return Err(EINVAL),
+        };
+        match self.get(fd)? {
+            HostFile::File(f) => f.seek(pos).map_err(errno),
+            _ => Err(ESPIPE),
+        }
+    }
+
+    // read up to `len` bytes at `offset` without moving the file position
+    pub fn read_at(&mut self, fd: u32, offset: u64, len: usize) -> Result<Vec<u8>, i32> {
+        let HostFile::File(f) = self.get(fd)? else {
+            return Err(EINVAL);
+        };
+        let saved = f.stream_position().map_err(errno)?;
+        f.seek(SeekFrom::Start(offset)).map_err(errno)?;
+        let mut buf = Vec::with_capacity(len);
+        let res = Read::by_ref(f).take(len as u64).read_to_end(&mut buf);
+        f.seek(SeekFrom::Start(saved)).map_err(errno)?;
+        res.map_err(errno)?;
+        Ok(buf)
+    }
+
+    pub fn stat(&mut self, fd: u32) -> Result<Stat, i32> {
+        match self.get(fd)? {
+            HostFile::File(f) => Ok(Stat::from_metadata(&f.metadata().map_err(errno)?)),
+            _ => Ok(Stat {
+                mode: S_IFCHR | 0o620,
+                nlink: 1,
+                blksize: 1024,
+                ..Default::default()
+            }),
+        }
+    }
+
+    pub fn is_open(&self, fd: u32) -> bool {
+        matches!(self.files.get(fd as usize), Some(Some(_)))
+    }
+
+    pub fn is_tty(&self, fd: u32) -> bool {
+        matches!(
+            self.files.get(fd as usize),
+            Some(Some(HostFile::Stdin | HostFile::Stdout | HostFile::Stderr))
+        )
+    }
+
+    fn get(&mut self, fd: u32) -> Result<&mut HostFile, i32> {
+        match self.files.get_mut(fd as usize) {
+            Some(Some(f)) => Ok(f),
+            _ => Err(EBADF),
+        }
+    }
+}
+
+pub fn stat_path(path: &str) -> Result<Stat, i32> {
+    let meta = fs::metadata(path).map_err(errno)?;
+    Ok(Stat::from_metadata(&meta))
+}
+
+// wall clock as (seconds, nanoseconds) since the epoch
+pub fn now() -> (i64, u32) {
+    let d = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (d.as_secs() as i64, d.subsec_nanos())
+}
+
+// the host is Linux, so its errno values can be passed through as-is
+pub fn errno(e: io::Error) -> i32 {
+    e.raw_os_error().unwrap_or(EIO)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_write_seek_read() {
+        let path = std::env::temp_dir().join(format!("riscland-host-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut files = FileTable::new();
+        let flags = OpenFlags {
+            read: true,
+            write: true,
+            create: true,
+            truncate: true,
+            ..Default::default()
+        };
+        let fd = files.open(path, flags, 0o644).unwrap();
+        assert_eq!(fd, 3);
+        assert_eq!(files.write(fd, b"riscland").unwrap(), 8);
+        assert_eq!(files.read_at(fd, 4, 16).unwrap(), b"land");
+        assert_eq!(files.seek(fd, 0, SEEK_SET).unwrap(), 0);
+        assert_eq!(files.read(fd, 5).unwrap(), b"riscl");
+        assert_eq!(files.stat(fd).unwrap().size, 8);
+        files.close(fd).unwrap();
+        assert_eq!(files.close(fd), Err(EBADF));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,279 @@
+// SiFive I2C master, the OpenCores controller with registers 4 bytes apart.
+// Every command runs to completion as it is written: TIP never shows, IF
+// is set, and RxACK tells whether the addressed device acked. The devices on
+// the bus are an I2cBus: a Rust implementation a test fakes a sensor with,
+// or Registers from the board file, targets that answer with a register
+// file through a register pointer the way most sensors do.
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use serde::Deserialize;
+
+use crate::device::Device;
+use crate::fdt::Fdt;
+use crate::snapshot::{bad_state, read_bool, read_u32, read_u8};
+
+pub const I2C_SIZE: u32 = 0x1000;
+
+const PRER_LO: u32 = 0x00;
+const PRER_HI: u32 = 0x04;
+const CTR: u32 = 0x08;
+// TXR when written, RXR when read
+const DATA: u32 = 0x0c;
+// CR when written, SR when read
+const COMMAND: u32 = 0x10;
+
+const CTR_EN: u32 = 1 << 7;
+
+const CR_STA: u32 = 1 << 7;
+const CR_STO: u32 = 1 << 6;
+const CR_RD: u32 = 1 << 5;
+const CR_WR: u32 = 1 << 4;
+// NACK the byte read, the last of a read
+const CR_ACK: u32 = 1 << 3;
+const CR_IACK: u32 = 1 << 0;
+
+const SR_RXACK: u32 = 1 << 7;
+const SR_BUSY: u32 = 1 << 6;
+const SR_IF: u32 = 1 << 0;
+
+// the devices on the bus, told apart by their 7-bit address
+pub trait I2cBus: Send + fmt::Debug {
+    // a start condition and the address byte, whether a device acked
+    fn start(&mut self, addr: u8, read: bool) -> bool;
+
+    // a byte for the addressed device, whether it acked
+    fn write(&mut self, byte: u8) -> bool;
+
+    // a byte from the addressed device, `ack` when the master wants more
+    fn read(&mut self, ack: bool) -> u8;
+
+    fn stop(&mut self) {}
+
+    // state of the devices for a snapshot, as Device::save() and restore()
+    fn save(&self, _w: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn restore(&mut self, _r: &mut dyn Read) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn I2cBus>;
+}
+
+impl Clone for Box<dyn I2cBus> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+// a register file at `address`, e.g. a temperature sensor
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    pub address: u8,
+    pub registers: Vec<u8>,
+}
+
+// targets where the first byte written after the address sets the register
+// pointer, more bytes written store from there on and reads return from
+// there on, the pointer moving on with every byte
+#[derive(Debug, Clone, Default)]
+pub struct Registers {
+    targets: Vec<Target>,
+    // addressed target and whether the pointer is still to come
+    selected: Option<(usize, bool)>,
+    pointer: usize,
+}
+
+impl Registers {
+    pub fn new(targets: Vec<Target>) -> Self {
+        Registers {
+            targets,
+            ..Registers::default()
+        }
+    }
+}
+
+impl I2cBus for Registers {
+    fn start(&mut self, addr: u8, read: bool) -> bool {
+        let target = self.targets.iter().position(|t| t.address == addr);
+        self.selected = target.map(|t| (t, !read));
+        target.is_some()
+    }
+
+    fn write(&mut self, byte: u8) -> bool {
+        let Some((t, pointer_next)) = self.selected else {
+            return false;
+        };
+        if pointer_next {
+            self.pointer = byte as usize;
+            self.selected = Some((t, false));
+        } else if let Some(reg) = self.targets[t].registers.get_mut(self.pointer) {
+            *reg = byte;
+            self.pointer += 1;
+        }
+        true
+    }
+
+    fn read(&mut self, _ack: bool) -> u8 {
+        let Some((t, _)) = self.selected else {
+            return 0xff;
+        };
+        let byte = self.targets[t].registers.get(self.pointer).copied();
+        self.pointer += 1;
+        byte.unwrap_or(0xff)
+    }
+
+    fn stop(&mut self) {
+        self.selected = None;
+    }
+
+    // the register files, which the board gives their size, and where the
+    // transfer under way is
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        for target in &self.targets {
+            w.write_all(&target.registers)?;
+        }
+        let (target, pointer_next) = self.selected.unwrap_or((0, false));
+        w.write_all(&[self.selected.is_some() as u8, pointer_next as u8])?;
+        w.write_all(&(target as u32).to_le_bytes())?;
+        w.write_all(&(self.pointer as u32).to_le_bytes())
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        for target in self.targets.iter_mut() {
+            r.read_exact(&mut target.registers)?;
+        }
+        let selected = read_bool(r)?;
+        let pointer_next = read_bool(r)?;
+        let target = read_u32(r)? as usize;
+        if selected && target >= self.targets.len() {
+            return Err(bad_state("no such target"));
+        }
+        self.selected = selected.then_some((target, pointer_next));
+        self.pointer = read_u32(r)? as usize;
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn I2cBus> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct I2c {
+    bus: Box<dyn I2cBus>,
+    prescale: u32,
+    control: u32,
+    transmit: u8,
+    receive: u8,
+    status: u32,
+}
+
+impl I2c {
+    pub fn new(bus: Box<dyn I2cBus>) -> Self {
+        I2c {
+            bus,
+            prescale: 0xffff,
+            control: 0,
+            transmit: 0,
+            receive: 0,
+            status: 0,
+        }
+    }
+
+    fn command(&mut self, cr: u32) {
+        if cr & CR_IACK != 0 {
+            self.status &= !SR_IF;
+        }
+        if self.control & CTR_EN == 0 {
+            return;
+        }
+        let transfer = cr & (CR_STA | CR_RD | CR_WR) != 0;
+        let mut ack = true;
+        if cr & CR_WR != 0 {
+            ack = match cr & CR_STA {
+                0 => self.bus.write(self.transmit),
+                _ => {
+                    self.status |= SR_BUSY;
+                    self.bus.start(self.transmit >> 1, self.transmit & 1 != 0)
+                }
+            };
+        } else if cr & CR_RD != 0 {
+            self.receive = self.bus.read(cr & CR_ACK == 0);
+        }
+        if transfer {
+            match ack {
+                true => self.status &= !SR_RXACK,
+                false => self.status |= SR_RXACK,
+            }
+        }
+        if cr & CR_STO != 0 {
+            self.bus.stop();
+            self.status &= !SR_BUSY;
+        }
+        if transfer || cr & CR_STO != 0 {
+            self.status |= SR_IF;
+        }
+    }
+}
+
+impl Device for I2c {
+    fn load(&mut self, offset: u32, _size: u32) -> u32 {
+        match offset {
+            PRER_LO => self.prescale & 0xff,
+            PRER_HI => self.prescale >> 8,
+            CTR => self.control,
+            DATA => self.receive as u32,
+            COMMAND => self.status,
+            _ => 0,
+        }
+    }
+
+    fn store(&mut self, offset: u32, _size: u32, value: u32) {
+        let value = value & 0xff;
+        match offset {
+            PRER_LO => self.prescale = self.prescale & 0xff00 | value,
+            PRER_HI => self.prescale = self.prescale & 0xff | value << 8,
+            CTR => self.control = value,
+            DATA => self.transmit = value as u8,
+            COMMAND => self.command(value),
+            _ => (),
+        }
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        for word in [self.prescale, self.control, self.status] {
+            w.write_all(&word.to_le_bytes())?;
+        }
+        w.write_all(&[self.transmit, self.receive])?;
+        self.bus.save(w)
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.prescale = read_u32(r)?;
+        self.control = read_u32(r)?;
+        self.status = read_u32(r)?;
+        self.transmit = read_u8(r)?;
+        self.receive = read_u8(r)?;
+        self.bus.restore(r)
+    }
+
+    fn clone_box(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+
+    fn fdt_name(&self) -> &'static str {
+        "i2c"
+    }
+
+    fn fdt_props(&self, fdt: &mut Fdt, _harts: u32) {
+        fdt.prop_strs("compatible", &["sifive,i2c0", "opencores,i2c-ocores"]);
+        fdt.prop_u32("reg-shift", 2);
+        fdt.prop_u32("reg-io-width", 1);
+        fdt.prop_u32("#address-cells", 1);
+        fdt.prop_u32("#size-cells", 0);
+    }
+}
@@ -0,0 +1,155 @@
+// RISC-V ISA strings such as "rv32imac_zicsr_zifencei": the base, single
+// letter extensions in any order, optionally versioned ("a2p1"), then
+// multi-letter extensions separated by underscores. A string naming an
+// extension this core does not implement is rejected, so a guest never sees
+// misa promise instructions that would then trap.
+use core::fmt;
+
+// what a hart runs unless told otherwise: everything implemented
+pub const DEFAULT: &str = "rv32imac_zicsr_zifencei";
+
+// single letter extensions that have instructions here, in canonical order
+const LETTERS: &str = "imac";
+
+// misa.MXL for 32-bit harts
+const MXL_32: u32 = 1 << 30;
+// supervisor and user mode, which every hart has
+const MISA_S_U: u32 = 1 << ('s' as u32 - 'a' as u32) | 1 << ('u' as u32 - 'a' as u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Isa {
+    // single letter extensions as in misa, bit 0 is A
+    letters: u32,
+    pub zicsr: bool,
+    pub zifencei: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IsaError(pub String);
+
+impl fmt::Display for IsaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for IsaError {}
+
+impl Default for Isa {
+    fn default() -> Self {
+        Isa::parse(DEFAULT).unwrap()
+    }
+}
+
+impl Isa {
+    pub fn parse(s: &str) -> Result<Self, IsaError> {
+        let lower = s.to_ascii_lowercase();
+        let Some(rest) = lower.strip_prefix("rv32") else {
+            return Err(IsaError(format!("`{}` is not an rv32 ISA", s)));
+        };
+        let mut isa = Isa {
+            letters: 0,
+            zicsr: false,
+            zifencei: false,
+        };
+        let (single, multi) = match rest.find(['z', 's', 'x']) {
+            Some(i) => rest.split_at(i),
+            None => (rest, ""),
+        };
+
+        let mut chars = single.chars().peekable();
+        match chars.next() {
+            Some('i') => isa.add_letter('i')?,
+            // G is IMAFD plus Zicsr and Zifencei
+            Some('g') => {
+                return Err(IsaError(format!(
+                    "`{}`: g includes the f and d extensions, which are not implemented, \
+                     use rv32ima_zicsr_zifencei",
+                    s
+                )))
+            }
+            Some('e') => return Err(IsaError("the rv32e base is not implemented".to_string())),
+            _ => return Err(IsaError(format!("`{}` has no i, e or g base", s))),
+        }
+        while let Some(c) = chars.next() {
+            match c {
+                '_' => (),
+                // version of the extension before, e.g. 2p0
+                '0'..='9' => {
+                    while chars.next_if(char::is_ascii_digit).is_some() {}
+                    if chars.next_if_eq(&'p').is_some() {
+                        while chars.next_if(char::is_ascii_digit).is_some() {}
+                    }
+                }
+                'a'..='z' => isa.add_letter(c)?,
+                _ => return Err(IsaError(format!("unexpected `{}` in `{}`", c, s))),
+            }
+        }
+
+        for ext in multi.split('_').filter(|ext| !ext.is_empty()) {
+            let name = ext
+                .find(|c: char| c.is_ascii_digit())
+                .map_or(ext, |i| &ext[..i]);
+            let seen = match name {
+                "zicsr" => std::mem::replace(&mut isa.zicsr, true),
+                "zifencei" => std::mem::replace(&mut isa.zifencei, true),
+                _ => return Err(IsaError(format!("extension `{}` is not implemented", name))),
+            };
+            if seen {
+                return Err(IsaError(format!("extension `{}` given twice", name)));
+            }
+        }
+        Ok(isa)
+    }
+
+    fn add_letter(&mut self, letter: char) -> Result<(), IsaError> {
+        if !LETTERS.contains(letter) {
+            return Err(IsaError(format!(
+                "extension `{}` is not implemented",
+                letter
+            )));
+        }
+        let bit = 1 << (letter as u32 - 'a' as u32);
+        if self.letters & bit != 0 {
+            return Err(IsaError(format!("extension `{}` given twice", letter)));
+        }
+        self.letters |= bit;
+        Ok(())
+    }
+
+    // whether a single letter extension is enabled
+    pub fn has(&self, letter: char) -> bool {
+        letter.is_ascii_lowercase() && self.letters & 1 << (letter as u32 - 'a' as u32) != 0
+    }
+
+    // alignment of instruction addresses, 2 once compressed instructions
+    // are in
+    pub fn ialign(&self) -> u32 {
+        if self.has('c') {
+            2
+        } else {
+            4
+        }
+    }
+
+    // value of the misa CSR
+    pub fn misa(&self) -> u32 {
+        MXL_32 | MISA_S_U | self.letters
+    }
+}
+
+impl fmt::Display for Isa {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rv32")?;
+        for letter in LETTERS.chars().filter(|letter| self.has(*letter)) {
+            write!(f, "{}", letter)?;
+        }
+        if self.zicsr {
+            write!(f, "_zicsr")?;
+        }
+        if self.zifencei {
+            write!(f, "_zifencei")?;
+        }
+        Ok(())
+    }
+}
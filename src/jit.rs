@@ -0,0 +1,552 @@
+// Translation of hot basic blocks to host code with Cranelift.
+//
+// A block from the block cache that has run JIT_THRESHOLD times is compiled
+// up to its first instruction the JIT does not handle (fences, CSRs, ecall,
+// ebreak, M and compressed instructions). Generated code works on the
+// registers and RAM in place and gives up on any access outside RAM (MMIO),
+// on misaligned accesses and jumps, and on stores to pages that hold
+// translated code; those instructions, and everything not compiled, run in
+// the interpreter. Compiled code is tied to the cached block it came from,
+// so whatever invalidates the block invalidates the compiled code as well.
+// Nothing runs compiled while the hart translates addresses with Sv32.
+use std::mem::offset_of;
+use std::sync::Arc;
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, InstBuilder, MemFlags, Value};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_codegen::Context;
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Module};
+
+use crate::block::{Block, PcMap};
+use crate::cpu::CPU;
+use crate::opcode::*;
+
+pub const JIT_THRESHOLD: u32 = 16;
+
+// stale code is only freed by throwing the whole module away
+const MAX_COMPILED: usize = 16 * 1024;
+
+// what generated code gets to see of the machine
+#[repr(C)]
+struct Env {
+    regs: *mut u32,
+    ram: *mut u8,
+    ram_base: u32,
+    ram_len: u32,
+    code: *const u64,
+    code_len: u32,
+    // a byte per page of RAM, set by every store
+    touched: *mut u8,
+}
+
+// returns the next pc in the low and the retired count in the high half
+type BlockFn = unsafe extern "C" fn(*const Env) -> u64;
+
+struct Compiled {
+    block: Arc<Block>,
+    func: BlockFn,
+}
+
+pub struct Jit {
+    module: JITModule,
+    ctx: Context,
+    builder_ctx: FunctionBuilderContext,
+    compiled: PcMap<Compiled>,
+    // executions of blocks not compiled yet
+    counts: PcMap<u32>,
+    // functions in `module`, live or stale
+    defined: usize,
+    threshold: u32,
+    // run every compiled block in the interpreter too and compare
+    cross_check: bool,
+}
+
+impl Jit {
+    pub fn new() -> Self {
+        let module = new_module();
+        Jit {
+            ctx: module.make_context(),
+            module,
+            builder_ctx: FunctionBuilderContext::new(),
+            compiled: PcMap::default(),
+            counts: PcMap::default(),
+            defined: 0,
+            threshold: JIT_THRESHOLD,
+            cross_check: false,
+        }
+    }
+
+    // compile every block on first use and check it against the interpreter,
+    // panicking on the first difference
+    pub fn with_cross_check() -> Self {
+        Jit {
+            threshold: 1,
+            cross_check: true,
+            ..Jit::new()
+        }
+    }
+
+    // number of blocks with live compiled code
+    pub fn compiled(&self) -> usize {
+        self.compiled.len()
+    }
+
+    // run the block at pc, compiled if it is hot enough, and return the
+    // number of instructions retired
+    pub fn run(&mut self, cpu: &mut CPU) -> u64 {
+        if cpu.take_interrupt() {
+            return 1;
+        }
+        let pc = cpu.pc;
+        // too little RAM for the bounds checks in generated code, and
+        // generated code knows nothing of page tables
+        if cpu.bus.ram().1.len() < 4 || cpu.translates() {
+            return cpu.run_block();
+        }
+        let Some(block) = cpu.blocks.enter(pc, &mut cpu.bus, &cpu.isa) else {
+            return cpu.run_block();
+        };
+        let func = match self.compiled.get(&pc) {
+            Some(c) if Arc::ptr_eq(&c.block, &block) => Some(c.func),
+            _ => self.maybe_compile(pc, block),
+        };
+        let Some(func) = func else {
+            return cpu.run_block();
+        };
+        let reference = self.cross_check.then(|| cpu.clone());
+        let retired = call(func, cpu);
+        if retired == 0 {
+            // gave up on the very first instruction
+            return cpu.step();
+        }
+        if let Some(mut interp) = reference {
+            for _ in 0..retired {
+                interp.step();
+            }
+            check(pc, &interp, cpu);
+        }
+        // generated code does not go through retire()
+        cpu.bus.count_retired(retired);
+        retired
+    }
+
+    fn maybe_compile(&mut self, pc: u32, block: Arc<Block>) -> Option<BlockFn> {
+        self.compiled.remove(&pc);
+        let count = self.counts.entry(pc).or_insert(0);
+        *count += 1;
+        if *count < self.threshold {
+            return None;
+        }
+        self.counts.remove(&pc);
+        if self.defined >= MAX_COMPILED {
+            self.reset();
+        }
+        let func = self.compile(&block)?;
+        self.compiled.insert(pc, Compiled { block, func });
+        Some(func)
+    }
+
+    fn reset(&mut self) {
+        let module = std::mem::replace(&mut self.module, new_module());
+        self.compiled.clear();
+        self.defined = 0;
+        // SAFETY: nothing refers to the old code once `compiled` is empty
+        unsafe { module.free_memory() };
+    }
+
+    fn compile(&mut self, block: &Block) -> Option<BlockFn> {
+        let len = block
+            .instrs
+            .iter()
+            .take_while(|(instr, _)| supported(*instr))
+            .count();
+        if len == 0 {
+            return None;
+        }
+        let ptr = self.module.target_config().pointer_type();
+        let mut sig = self.module.make_signature();
+        sig.params.push(AbiParam::new(ptr));
+        sig.returns.push(AbiParam::new(types::I64));
+        let id = self.module.declare_anonymous_function(&sig).ok()?;
+
+        self.module.clear_context(&mut self.ctx);
+        self.ctx.func.signature = sig;
+        let mut b = FunctionBuilder::new(&mut self.ctx.func, &mut self.builder_ctx);
+        let entry = b.create_block();
+        b.append_block_params_for_function_params(entry);
+        b.switch_to_block(entry);
+        b.seal_block(entry);
+        let env = b.block_params(entry)[0];
+        let flags = MemFlags::trusted();
+        let mut t = Translator {
+            regs: b.ins().load(ptr, flags, env, offset_of!(Env, regs) as i32),
+            ram: b.ins().load(ptr, flags, env, offset_of!(Env, ram) as i32),
+            ram_base: b
+                .ins()
+                .load(types::I32, flags, env, offset_of!(Env, ram_base) as i32),
+            ram_len: b
+                .ins()
+                .load(types::I32, flags, env, offset_of!(Env, ram_len) as i32),
+            code: b.ins().load(ptr, flags, env, offset_of!(Env, code) as i32),
+            code_len: b
+                .ins()
+                .load(types::I32, flags, env, offset_of!(Env, code_len) as i32),
+            touched: b
+                .ins()
+                .load(ptr, flags, env, offset_of!(Env, touched) as i32),
+            ptr,
+            b,
+        };
+        let mut ended = false;
+        for (i, (instr, _)) in block.instrs[..len].iter().enumerate() {
+            let pc = block.start.wrapping_add(i as u32 * 4);
+            if t.instr(*instr, pc, i as u32) {
+                ended = true;
+                break;
+            }
+        }
+        if !ended {
+            let next = block.start.wrapping_add(len as u32 * 4);
+            let next = t.word(next);
+            t.exit(next, len as u32);
+        }
+        t.b.finalize();
+
+        self.module.define_function(id, &mut self.ctx).ok()?;
+        self.module.finalize_definitions().ok()?;
+        self.defined += 1;
+        let code = self.module.get_finalized_function(id);
+        // SAFETY: the function was just built with exactly this signature
+        Some(unsafe { std::mem::transmute::<*const u8, BlockFn>(code) })
+    }
+}
+
+impl Default for Jit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn new_module() -> JITModule {
+    let mut flags = settings::builder();
+    flags.set("opt_level", "speed").unwrap();
+    let isa = cranelift_native::builder()
+        .expect("host not supported by cranelift")
+        .finish(settings::Flags::new(flags))
+        .unwrap();
+    JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()))
+}
+
+fn call(func: BlockFn, cpu: &mut CPU) -> u64 {
+    // never read when there are no marks, but must not dangle either
+    static NO_CODE: u64 = 0;
+    let (ram, ram_base, ram_len, code, code_len, touched) = cpu.bus.raw_parts();
+    let env = Env {
+        regs: cpu.xregs.regs.as_mut_ptr(),
+        ram,
+        ram_base,
+        ram_len,
+        code: if code_len == 0 { &NO_CODE } else { code },
+        code_len,
+        touched,
+    };
+    // SAFETY: generated code only touches the registers, RAM within
+    // ram_len and code marks within code_len, all borrowed from `cpu`, and
+    // the touched marks of that RAM
+    let result = unsafe { func(&env) };
+    cpu.xregs.regs[0] = 0;
+    cpu.pc = result as u32;
+    result >> 32
+}
+
+fn check(pc: u32, interp: &CPU, jit: &CPU) {
+    let mut diffs = Vec::new();
+    if interp.pc != jit.pc {
+        diffs.push(format!(
+            "pc: interpreter {:#x}, jit {:#x}",
+            interp.pc, jit.pc
+        ));
+    }
+    for (i, (a, b)) in interp.xregs.regs.iter().zip(jit.xregs.regs).enumerate() {
+        if *a != b {
+            diffs.push(format!("x{}: interpreter {:#x}, jit {:#x}", i, a, b));
+        }
+    }
+    let (base, a) = interp.bus.ram();
+    let (_, b) = jit.bus.ram();
+    if let Some(off) = a.iter().zip(b).position(|(x, y)| x != y) {
+        diffs.push(format!("memory differs at {:#x}", base as usize + off));
+    }
+    if !diffs.is_empty() {
+        panic!("jit mismatch in block {:#x}:\n{}", pc, diffs.join("\n"));
+    }
+}
+
+// instructions with a translation, everything else ends the compiled code
+fn supported(instr: u32) -> bool {
+    let funct3 = (instr >> 12) & 0x7;
+    let funct7 = (instr >> 25) & 0x7f;
+    match instr & 0x7f {
+        // a target off a word boundary is the interpreter's to trap on
+        LUI | AUIPC | JALR => true,
+        JAL => imm_j(instr) & 2 == 0,
+        B_TYPE => !matches!(funct3, 2 | 3) && imm_b(instr) & 2 == 0,
+        LOAD => !matches!(funct3, LD | 7),
+        S_TYPE => matches!(funct3, SB | SH | SW),
+        I_TYPE => funct3 != SRI || matches!(funct7, SRLI | SRAI),
+        // M is left to the interpreter
+        R_TYPE if funct7 == MULDIV => false,
+        R_TYPE => !matches!(funct3, ADDSUB | SR) || matches!(funct7, ADD | SUB),
+        _ => false,
+    }
+}
+
+struct Translator<'a> {
+    b: FunctionBuilder<'a>,
+    ptr: types::Type,
+    regs: Value,
+    ram: Value,
+    ram_base: Value,
+    ram_len: Value,
+    code: Value,
+    code_len: Value,
+    touched: Value,
+}
+
+impl Translator<'_> {
+    fn word(&mut self, v: u32) -> Value {
+        self.b.ins().iconst(types::I32, v as i64)
+    }
+
+    fn get(&mut self, r: u32) -> Value {
+        match r {
+            0 => self.b.ins().iconst(types::I32, 0),
+            _ => self
+                .b
+                .ins()
+                .load(types::I32, MemFlags::trusted(), self.regs, r as i32 * 4),
+        }
+    }
+
+    fn set(&mut self, r: u32, v: Value) {
+        if r != 0 {
+            self.b
+                .ins()
+                .store(MemFlags::trusted(), v, self.regs, r as i32 * 4);
+        }
+    }
+
+    fn exit(&mut self, next_pc: Value, retired: u32) {
+        let pc = self.b.ins().uextend(types::I64, next_pc);
+        let packed = self.b.ins().bor_imm(pc, (retired as i64) << 32);
+        self.b.ins().return_(&[packed]);
+    }
+
+    // continue only when `ok`, otherwise hand instruction `idx` at `pc` to
+    // the interpreter
+    fn guard(&mut self, ok: Value, pc: u32, idx: u32) {
+        let cont = self.b.create_block();
+        let bail = self.b.create_block();
+        self.b.ins().brif(ok, cont, &[], bail, &[]);
+        self.b.switch_to_block(bail);
+        self.b.seal_block(bail);
+        let pc = self.word(pc);
+        self.exit(pc, idx);
+        self.b.switch_to_block(cont);
+        self.b.seal_block(cont);
+    }
+
+    // host address of `size` bytes at guest `addr`, bailing out unless all of
+    // them are RAM and `addr` is aligned to `size`
+    fn host_addr(&mut self, addr: Value, size: i64, pc: u32, idx: u32) -> (Value, Value) {
+        if size > 1 {
+            let low = self.b.ins().band_imm(addr, size - 1);
+            let ok = self.b.ins().icmp_imm(IntCC::Equal, low, 0);
+            self.guard(ok, pc, idx);
+        }
+        let off = self.b.ins().isub(addr, self.ram_base);
+        let limit = self.b.ins().iadd_imm(self.ram_len, -size);
+        let ok = self
+            .b
+            .ins()
+            .icmp(IntCC::UnsignedLessThanOrEqual, off, limit);
+        self.guard(ok, pc, idx);
+        let wide = self.b.ins().uextend(self.ptr, off);
+        (self.b.ins().iadd(self.ram, wide), off)
+    }
+
+    // translate one instruction, true when it ended the block
+    fn instr(&mut self, instr: u32, pc: u32, idx: u32) -> bool {
+        let funct3 = (instr >> 12) & 0x7;
+        let funct7 = (instr >> 25) & 0x7f;
+        let (rd, rs1, rs2) = (rd(instr), rs1(instr), rs2(instr));
+        let flags = MemFlags::new();
+        match instr & 0x7f {
+            LUI => {
+                let v = self.word(imm_u(instr));
+                self.set(rd, v);
+            }
+            AUIPC => {
+                let v = pc.wrapping_add(imm_u(instr));
+                let v = self.word(v);
+                self.set(rd, v);
+            }
+            JAL => {
+                let link = self.word(pc.wrapping_add(4));
+                self.set(rd, link);
+                let target = pc.wrapping_add(imm_j(instr));
+                let target = self.word(target);
+                self.exit(target, idx + 1);
+                return true;
+            }
+            JALR => {
+                let base = self.get(rs1);
+                let target = self.b.ins().iadd_imm(base, imm_i(instr) as i64);
+                let target = self.b.ins().band_imm(target, 0xfffffffe_u32 as i32 as i64);
+                let low = self.b.ins().band_imm(target, 2);
+                let ok = self.b.ins().icmp_imm(IntCC::Equal, low, 0);
+                self.guard(ok, pc, idx);
+                let link = self.word(pc.wrapping_add(4));
+                self.set(rd, link);
+                self.exit(target, idx + 1);
+                return true;
+            }
+            B_TYPE => {
+                let (a, b) = (self.get(rs1), self.get(rs2));
+                let cc = match funct3 {
+                    BEQ => IntCC::Equal,
+                    BNE => IntCC::NotEqual,
+                    BLT => IntCC::SignedLessThan,
+                    BGE => IntCC::SignedGreaterThanOrEqual,
+                    BLTU => IntCC::UnsignedLessThan,
+                    _ => IntCC::UnsignedGreaterThanOrEqual,
+                };
+                let taken = self.b.ins().icmp(cc, a, b);
+                let (yes, no) = (self.b.create_block(), self.b.create_block());
+                self.b.ins().brif(taken, yes, &[], no, &[]);
+                for (blk, next) in [
+                    (yes, pc.wrapping_add(imm_b(instr))),
+                    (no, pc.wrapping_add(4)),
+                ] {
+                    self.b.switch_to_block(blk);
+                    self.b.seal_block(blk);
+                    let next = self.word(next);
+                    self.exit(next, idx + 1);
+                }
+                return true;
+            }
+            LOAD => {
+                let base = self.get(rs1);
+                let addr = self.b.ins().iadd_imm(base, imm_i(instr) as i64);
+                let size = match funct3 {
+                    LB | LBU => 1,
+                    LH | LHU => 2,
+                    _ => 4,
+                };
+                let (host, _) = self.host_addr(addr, size, pc, idx);
+                let v = match funct3 {
+                    LB => self.b.ins().sload8(types::I32, flags, host, 0),
+                    LBU => self.b.ins().uload8(types::I32, flags, host, 0),
+                    LH => self.b.ins().sload16(types::I32, flags, host, 0),
+                    LHU => self.b.ins().uload16(types::I32, flags, host, 0),
+                    _ => self.b.ins().load(types::I32, flags, host, 0),
+                };
+                self.set(rd, v);
+            }
+            S_TYPE => {
+                let base = self.get(rs1);
+                let addr = self.b.ins().iadd_imm(base, imm_s(instr) as i32 as i64);
+                let size = match funct3 {
+                    SB => 1,
+                    SH => 2,
+                    _ => 4,
+                };
+                let (host, off) = self.host_addr(addr, size, pc, idx);
+                // the interpreter has to see stores to translated code; a
+                // store within one page never straddles two marks
+                let first = self.b.ins().ushr_imm(off, 12);
+                let last = self.b.ins().iadd_imm(off, size - 1);
+                let last = self.b.ins().ushr_imm(last, 12);
+                for page in [first, last] {
+                    let in_range = self
+                        .b
+                        .ins()
+                        .icmp(IntCC::UnsignedLessThan, page, self.code_len);
+                    let zero = self.b.ins().iconst(types::I32, 0);
+                    let page = self.b.ins().select(in_range, page, zero);
+                    let page = self.b.ins().uextend(self.ptr, page);
+                    let page = self.b.ins().ishl_imm(page, 3);
+                    let mark = self.b.ins().iadd(self.code, page);
+                    let mark = self.b.ins().load(types::I64, MemFlags::trusted(), mark, 0);
+                    let marked = self.b.ins().icmp_imm(IntCC::NotEqual, mark, 0);
+                    let hit = self.b.ins().band(marked, in_range);
+                    let ok = self.b.ins().icmp_imm(IntCC::Equal, hit, 0);
+                    self.guard(ok, pc, idx);
+                }
+                let one = self.b.ins().iconst(types::I8, 1);
+                for page in [first, last] {
+                    let page = self.b.ins().uextend(self.ptr, page);
+                    let mark = self.b.ins().iadd(self.touched, page);
+                    self.b.ins().store(MemFlags::trusted(), one, mark, 0);
+                }
+                let v = self.get(rs2);
+                match funct3 {
+                    SB => self.b.ins().istore8(flags, v, host, 0),
+                    SH => self.b.ins().istore16(flags, v, host, 0),
+                    _ => self.b.ins().store(flags, v, host, 0),
+                };
+            }
+            I_TYPE => {
+                let a = self.get(rs1);
+                let imm = imm_i(instr) as i64;
+                let v = match funct3 {
+                    ADDI => self.b.ins().iadd_imm(a, imm),
+                    SLTI => self.flag(IntCC::SignedLessThan, a, imm),
+                    SLTIU => self.flag(IntCC::UnsignedLessThan, a, imm),
+                    XORI => self.b.ins().bxor_imm(a, imm),
+                    ORI => self.b.ins().bor_imm(a, imm),
+                    ANDI => self.b.ins().band_imm(a, imm),
+                    SLLI => self.b.ins().ishl_imm(a, imm & 0x1f),
+                    _ => match funct7 {
+                        SRLI => self.b.ins().ushr_imm(a, imm & 0x1f),
+                        _ => self.b.ins().sshr_imm(a, imm & 0x1f),
+                    },
+                };
+                self.set(rd, v);
+            }
+            _ => {
+                // R_TYPE, shift amounts are masked to 5 bits like on RISC-V
+                let (a, b) = (self.get(rs1), self.get(rs2));
+                let v = match (funct3, funct7) {
+                    (ADDSUB, SUB) => self.b.ins().isub(a, b),
+                    (ADDSUB, _) => self.b.ins().iadd(a, b),
+                    (SLL, _) => self.b.ins().ishl(a, b),
+                    (SLT, _) => {
+                        let c = self.b.ins().icmp(IntCC::SignedLessThan, a, b);
+                        self.b.ins().uextend(types::I32, c)
+                    }
+                    (SLTU, _) => {
+                        let c = self.b.ins().icmp(IntCC::UnsignedLessThan, a, b);
+                        self.b.ins().uextend(types::I32, c)
+                    }
+                    (XOR, _) => self.b.ins().bxor(a, b),
+                    (SR, SRA) => self.b.ins().sshr(a, b),
+                    (SR, _) => self.b.ins().ushr(a, b),
+                    (OR, _) => self.b.ins().bor(a, b),
+                    _ => self.b.ins().band(a, b),
+                };
+                self.set(rd, v);
+            }
+        }
+        false
+    }
+
+    // 1 or 0 from comparing `a` with a sign extended immediate
+    fn flag(&mut self, cc: IntCC, a: Value, imm: i64) -> Value {
+        let c = self.b.ins().icmp_imm(cc, a, imm);
+        self.b.ins().uextend(types::I32, c)
+    }
+}
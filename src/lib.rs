@@ -1,6 +1,44 @@
+pub mod block;
+pub mod board;
+pub mod clint;
+pub mod coverage;
 pub mod cpu;
+pub mod csr;
 pub mod debug;
+pub mod debugger;
+pub mod device;
+pub mod dwarf;
 pub mod elf;
+pub mod fdt;
+pub mod framebuffer;
+pub mod gdb;
+pub mod goldfish_rtc;
+pub mod gpio;
+pub mod hooks;
+pub mod host;
+pub mod i2c;
+pub mod isa;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod linux;
+pub mod machine;
 pub mod memory;
+pub mod mmu;
 pub mod opcode;
+pub mod pk;
+pub mod plic;
+pub mod profile;
 pub mod registers;
+pub mod replay;
+pub mod rvc;
+pub mod semihosting;
+pub mod smp;
+pub mod snapshot;
+pub mod spi;
+pub mod syscon;
+pub mod uart;
+pub mod virtio;
+pub mod virtio_blk;
+pub mod virtio_console;
+pub mod virtio_net;
+pub mod virtio_rng;
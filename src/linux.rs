@@ -0,0 +1,589 @@
+// Linux user-mode emulation, qemu-user style: a statically linked RV32 program
+// runs without a kernel and every `ecall` is translated into a host operation.
+use crate::cpu::{Fault, CPU};
+use crate::elf::Image;
+use crate::host::{self, FileTable, OpenFlags, Stat};
+use crate::memory::BUS;
+use crate::pk;
+
+// user address space, the whole range is backed by guest RAM
+pub const USER_MEM_BASE: u32 = 0x0;
+pub const USER_MEM_SIZE: u32 = 0x1000_0000;
+pub const STACK_TOP: u32 = USER_MEM_BASE + USER_MEM_SIZE;
+pub const STACK_SIZE: u32 = 8 * 1024 * 1024;
+// anonymous mappings are handed out upwards from here, brk grows below it
+pub const MMAP_BASE: u32 = 0x0800_0000;
+pub const PAGE_SIZE: u32 = 4096;
+
+pub const ECALL_INSTR: u32 = 0x00000073;
+
+// syscall numbers from asm-generic/unistd.h
+pub const SYS_GETCWD: u32 = 17;
+pub const SYS_IOCTL: u32 = 29;
+pub const SYS_OPENAT: u32 = 56;
+pub const SYS_CLOSE: u32 = 57;
+pub const SYS_LLSEEK: u32 = 62;
+pub const SYS_READ: u32 = 63;
+pub const SYS_WRITE: u32 = 64;
+pub const SYS_READV: u32 = 65;
+pub const SYS_WRITEV: u32 = 66;
+pub const SYS_FSTATAT: u32 = 79;
+pub const SYS_FSTAT: u32 = 80;
+pub const SYS_EXIT: u32 = 93;
+pub const SYS_EXIT_GROUP: u32 = 94;
+pub const SYS_SET_TID_ADDRESS: u32 = 96;
+pub const SYS_SET_ROBUST_LIST: u32 = 99;
+pub const SYS_CLOCK_GETTIME: u32 = 113;
+pub const SYS_RT_SIGACTION: u32 = 134;
+pub const SYS_RT_SIGPROCMASK: u32 = 135;
+pub const SYS_UNAME: u32 = 160;
+pub const SYS_GETTIMEOFDAY: u32 = 169;
+pub const SYS_GETPID: u32 = 172;
+pub const SYS_GETPPID: u32 = 173;
+pub const SYS_GETUID: u32 = 174;
+pub const SYS_GETEUID: u32 = 175;
+pub const SYS_GETGID: u32 = 176;
+pub const SYS_GETEGID: u32 = 177;
+pub const SYS_GETTID: u32 = 178;
+pub const SYS_BRK: u32 = 214;
+pub const SYS_MUNMAP: u32 = 215;
+pub const SYS_MMAP: u32 = 222;
+pub const SYS_MPROTECT: u32 = 226;
+pub const SYS_MADVISE: u32 = 233;
+pub const SYS_GETRANDOM: u32 = 278;
+pub const SYS_STATX: u32 = 291;
+pub const SYS_CLOCK_GETTIME64: u32 = 403;
+
+// auxiliary vector keys
+const AT_NULL: u32 = 0;
+const AT_PHDR: u32 = 3;
+const AT_PHENT: u32 = 4;
+const AT_PHNUM: u32 = 5;
+const AT_PAGESZ: u32 = 6;
+const AT_ENTRY: u32 = 9;
+const AT_UID: u32 = 11;
+const AT_EUID: u32 = 12;
+const AT_GID: u32 = 13;
+const AT_EGID: u32 = 14;
+const AT_HWCAP: u32 = 16;
+const AT_CLKTCK: u32 = 17;
+const AT_RANDOM: u32 = 25;
+
+const AT_FDCWD: i32 = -100;
+const AT_EMPTY_PATH: u32 = 0x1000;
+
+// open(2) flags of the generic Linux ABI
+const O_ACCMODE: u32 = 0x3;
+const O_WRONLY: u32 = 0x1;
+const O_RDWR: u32 = 0x2;
+const O_CREAT: u32 = 0x40;
+const O_EXCL: u32 = 0x80;
+const O_TRUNC: u32 = 0x200;
+const O_APPEND: u32 = 0x400;
+
+const MAP_FIXED: u32 = 0x10;
+const MAP_ANONYMOUS: u32 = 0x20;
+
+const TIOCGWINSZ: u32 = 0x5413;
+
+// most iovecs readv and writev take
+const UIO_MAXIOV: u32 = 1024;
+
+pub(crate) const GUEST_PID: u32 = 1000;
+
+// which syscall convention the guest's ecalls follow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Abi {
+    Linux,
+    // riscv-pk as used by newlib/libgloss bare-metal programs
+    ProxyKernel,
+}
+
+pub struct Process {
+    pub cpu: CPU,
+    pub abi: Abi,
+    pub(crate) files: FileTable,
+    brk_start: u32,
+    brk: u32,
+    mmap_next: u32,
+    exit_code: Option<i32>,
+}
+
+impl Process {
+    // place `image` in a fresh address space and build the initial stack
+    pub fn new(image: &Image, argv: &[String], envp: &[String], abi: Abi) -> Result<Self, String> {
+        let mut cpu = CPU::new();
+        cpu.trace = false;
+        cpu.bus = BUS::with_memory(USER_MEM_BASE, USER_MEM_SIZE);
+        let mut end = 0;
+        for seg in &image.segments {
+            let seg_end = seg
+                .vaddr
+                .checked_add(seg.memsz)
+                .filter(|end| *end <= MMAP_BASE && seg.data.len() <= seg.memsz as usize);
+            let Some(seg_end) = seg_end else {
+                return Err(format!(
+                    "segment at {:#x} is outside of the user address space",
+                    seg.vaddr
+                ));
+            };
+            cpu.bus.store_bytes(seg.vaddr, &seg.data).unwrap();
+            end = end.max(seg_end);
+        }
+        let brk_start = page_align(end).unwrap();
+        let mut process = Process {
+            cpu,
+            abi,
+            files: FileTable::new(),
+            brk_start,
+            brk: brk_start,
+            mmap_next: MMAP_BASE,
+            exit_code: None,
+        };
+        process.cpu.pc = image.entry;
+        process.cpu.xregs.regs[2] = process.setup_stack(image, argv, envp)?;
+        Ok(process)
+    }
+
+    // run until the guest exits, returns its exit status
+    pub fn run(&mut self) -> i32 {
+        loop {
+            // ecall always starts a block of its own
+            let pc = self.cpu.pc;
+            if self.cpu.bus.try_load(pc, 32) == Some(ECALL_INSTR) {
+                self.syscall();
+                self.cpu.pc += 4;
+                self.cpu.retire(pc, ECALL_INSTR);
+            } else {
+                self.cpu.run_block();
+            }
+            if let Some(code) = self.exit_code {
+                return code;
+            }
+            // the kernel would kill the process with SIGILL or SIGSEGV
+            if let Some(fault) = self.cpu.fault {
+                eprintln!("{} at pc {:#x}", fault, self.cpu.pc);
+                return match fault {
+                    Fault::IllegalInstruction(_) => 128 + 4,
+                    _ => 128 + 11,
+                };
+            }
+        }
+    }
+
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    // argc, argv, envp and auxv as laid out by the kernel's ELF loader, an
+    // error when they take more than the stack
+    fn setup_stack(
+        &mut self,
+        image: &Image,
+        argv: &[String],
+        envp: &[String],
+    ) -> Result<u32, String> {
+        let too_big = || "arguments and environment do not fit on the stack".to_string();
+        let mut sp = STACK_TOP;
+        let mut push_str = |bus: &mut BUS, s: &str| {
+            sp = sp
+                .checked_sub(s.len() as u32 + 1)
+                .filter(|sp| STACK_TOP - sp < STACK_SIZE)?;
+            bus.store_bytes(sp, s.as_bytes())?;
+            bus.try_store(sp + s.len() as u32, 8, 0)?;
+            Some(sp)
+        };
+        let argv_ptrs: Vec<u32> = argv
+            .iter()
+            .map(|s| push_str(&mut self.cpu.bus, s))
+            .collect::<Option<_>>()
+            .ok_or_else(too_big)?;
+        let envp_ptrs: Vec<u32> = envp
+            .iter()
+            .map(|s| push_str(&mut self.cpu.bus, s))
+            .collect::<Option<_>>()
+            .ok_or_else(too_big)?;
+        sp &= !0xf;
+        sp -= 16;
+        let random = sp;
+        let seed = host::now();
+        for i in 0..4 {
+            let word =
+                (seed.1 ^ (seed.0 as u32)).rotate_left(i * 8) ^ 0x9e3779b9u32.wrapping_mul(i + 1);
+            self.cpu
+                .bus
+                .try_store(random + i * 4, 32, word)
+                .ok_or_else(too_big)?;
+        }
+
+        let auxv = [
+            (AT_PHDR, image.phdr),
+            (AT_PHENT, image.phent),
+            (AT_PHNUM, image.phnum),
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_ENTRY, image.entry),
+            (AT_UID, 0),
+            (AT_EUID, 0),
+            (AT_GID, 0),
+            (AT_EGID, 0),
+            (AT_HWCAP, 0),
+            (AT_CLKTCK, 100),
+            (AT_RANDOM, random),
+            (AT_NULL, 0),
+        ];
+        let mut words = vec![argv_ptrs.len() as u32];
+        words.extend(&argv_ptrs);
+        words.push(0);
+        words.extend(&envp_ptrs);
+        words.push(0);
+        for (key, val) in auxv {
+            words.push(key);
+            words.push(val);
+        }
+        sp = (sp - words.len() as u32 * 4) & !0xf;
+        if STACK_TOP - sp > STACK_SIZE {
+            return Err(too_big());
+        }
+        for (i, word) in words.iter().enumerate() {
+            self.cpu
+                .bus
+                .try_store(sp + i as u32 * 4, 32, *word)
+                .ok_or_else(too_big)?;
+        }
+        Ok(sp)
+    }
+
+    // service the ecall at pc, a7 holds the number and a0-a5 the arguments
+    pub fn syscall(&mut self) {
+        let regs = self.cpu.xregs.regs;
+        let nr = regs[17];
+        let args = [regs[10], regs[11], regs[12], regs[13], regs[14], regs[15]];
+        let res = match self.abi {
+            Abi::Linux => self.dispatch(nr, args),
+            Abi::ProxyKernel => pk::dispatch(self, nr, args),
+        };
+        let ret = match res {
+            Ok(val) => val,
+            Err(errno) => (-errno) as u32,
+        };
+        self.cpu.xregs.regs[10] = ret;
+    }
+
+    fn dispatch(&mut self, nr: u32, a: [u32; 6]) -> Result<u32, i32> {
+        match nr {
+            SYS_GETCWD => {
+                let cwd = std::env::current_dir().map_err(host::errno)?;
+                let mut cwd = cwd.to_string_lossy().into_owned().into_bytes();
+                cwd.push(0);
+                if cwd.len() > a[1] as usize {
+                    return Err(34); // ERANGE
+                }
+                self.write_mem(a[0], &cwd)?;
+                Ok(cwd.len() as u32)
+            }
+            SYS_IOCTL => match a[1] {
+                // report stdio as a terminal without a size
+                TIOCGWINSZ if a[0] <= 2 => {
+                    self.write_mem(a[2], &[0; 8])?;
+                    Ok(0)
+                }
+                _ => Err(host::ENOTTY),
+            },
+            SYS_OPENAT => {
+                let path = self.read_cstr(a[1])?;
+                if a[0] as i32 != AT_FDCWD && !path.starts_with('/') {
+                    return Err(host::ENOSYS);
+                }
+                self.files.open(&path, open_flags(a[2]), a[3])
+            }
+            SYS_CLOSE => {
+                // the emulator keeps its own stdio open
+                if a[0] <= 2 {
+                    return Ok(0);
+                }
+                self.files.close(a[0]).map(|_| 0)
+            }
+            SYS_LLSEEK => {
+                // _llseek(fd, offset_high, offset_low, *result, whence)
+                let offset = (((a[1] as u64) << 32) | a[2] as u64) as i64;
+                let pos = self.files.seek(a[0], offset, a[4])?;
+                self.write_mem(a[3], &pos.to_le_bytes())?;
+                Ok(0)
+            }
+            SYS_READ => {
+                self.check_mem(a[1], a[2])?;
+                let buf = self.files.read(a[0], a[2] as usize)?;
+                self.write_mem(a[1], &buf)?;
+                Ok(buf.len() as u32)
+            }
+            SYS_WRITE => {
+                let buf = self.read_mem(a[1], a[2])?;
+                self.files.write(a[0], &buf).map(|n| n as u32)
+            }
+            SYS_READV | SYS_WRITEV => {
+                let mut total: u32 = 0;
+                if a[2] > UIO_MAXIOV {
+                    return Err(host::EINVAL);
+                }
+                let iov = self.read_mem(a[1], a[2] * 8)?;
+                for entry in iov.chunks_exact(8) {
+                    let base = u32::from_le_bytes(entry[..4].try_into().unwrap());
+                    let len = u32::from_le_bytes(entry[4..].try_into().unwrap());
+                    let n = if nr == SYS_READV {
+                        self.check_mem(base, len)?;
+                        let buf = self.files.read(a[0], len as usize)?;
+                        self.write_mem(base, &buf)?;
+                        buf.len() as u32
+                    } else {
+                        let buf = self.read_mem(base, len)?;
+                        self.files.write(a[0], &buf)? as u32
+                    };
+                    total = total.checked_add(n).ok_or(host::EINVAL)?;
+                    if n < len {
+                        break;
+                    }
+                }
+                Ok(total)
+            }
+            SYS_FSTATAT => {
+                let st = self.stat_at(a[0], a[1], a[3])?;
+                self.write_mem(a[2], &stat64_bytes(&st))?;
+                Ok(0)
+            }
+            SYS_FSTAT => {
+                let st = self.files.stat(a[0])?;
+                self.write_mem(a[1], &stat64_bytes(&st))?;
+                Ok(0)
+            }
+            SYS_STATX => {
+                let st = self.stat_at(a[0], a[1], a[2])?;
+                self.write_mem(a[4], &statx_bytes(&st))?;
+                Ok(0)
+            }
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                self.exit(a[0] as i32);
+                Ok(0)
+            }
+            SYS_SET_TID_ADDRESS | SYS_GETPID | SYS_GETTID => Ok(GUEST_PID),
+            SYS_GETPPID => Ok(GUEST_PID - 1),
+            SYS_GETUID | SYS_GETEUID | SYS_GETGID | SYS_GETEGID => Ok(0),
+            SYS_SET_ROBUST_LIST | SYS_RT_SIGACTION | SYS_RT_SIGPROCMASK => Ok(0),
+            SYS_MPROTECT | SYS_MADVISE | SYS_MUNMAP => Ok(0),
+            SYS_CLOCK_GETTIME => {
+                let (sec, nsec) = host::now();
+                let mut buf = (sec as u32).to_le_bytes().to_vec();
+                buf.extend(nsec.to_le_bytes());
+                self.write_mem(a[1], &buf)?;
+                Ok(0)
+            }
+            SYS_CLOCK_GETTIME64 => {
+                let (sec, nsec) = host::now();
+                let mut buf = sec.to_le_bytes().to_vec();
+                buf.extend((nsec as u64).to_le_bytes());
+                self.write_mem(a[1], &buf)?;
+                Ok(0)
+            }
+            SYS_GETTIMEOFDAY => {
+                let (sec, nsec) = host::now();
+                let mut buf = (sec as u32).to_le_bytes().to_vec();
+                buf.extend((nsec / 1000).to_le_bytes());
+                if a[0] != 0 {
+                    self.write_mem(a[0], &buf)?;
+                }
+                Ok(0)
+            }
+            SYS_UNAME => {
+                let mut buf = Vec::new();
+                for field in ["Linux", "riscland", "6.1.0", "#1", "riscv32", "(none)"] {
+                    let mut f = [0u8; 65];
+                    f[..field.len()].copy_from_slice(field.as_bytes());
+                    buf.extend(f);
+                }
+                self.write_mem(a[0], &buf)?;
+                Ok(0)
+            }
+            SYS_BRK => self.brk(a[0]),
+            SYS_MMAP => self.mmap(a),
+            SYS_GETRANDOM => {
+                self.check_mem(a[0], a[1])?;
+                let buf = std::fs::File::open("/dev/urandom")
+                    .and_then(|f| {
+                        use std::io::Read;
+                        let mut buf = Vec::new();
+                        f.take(a[1] as u64).read_to_end(&mut buf)?;
+                        Ok(buf)
+                    })
+                    .map_err(host::errno)?;
+                self.write_mem(a[0], &buf)?;
+                Ok(buf.len() as u32)
+            }
+            _ => Err(host::ENOSYS),
+        }
+    }
+
+    pub(crate) fn exit(&mut self, code: i32) {
+        self.exit_code = Some(code);
+    }
+
+    // a failed brk returns the current break instead of an error
+    pub(crate) fn brk(&mut self, addr: u32) -> Result<u32, i32> {
+        if addr >= self.brk_start && addr < MMAP_BASE {
+            let old = self.brk;
+            self.brk = addr;
+            // memory given back and taken again must read as zero
+            if self.brk > old {
+                self.write_mem(old, &vec![0; (self.brk - old) as usize])?;
+            }
+        }
+        Ok(self.brk)
+    }
+
+    // mmap2(addr, len, prot, flags, fd, pgoff), mappings are never reclaimed
+    fn mmap(&mut self, a: [u32; 6]) -> Result<u32, i32> {
+        let len = match page_align(a[1]) {
+            Some(0) => return Err(host::EINVAL),
+            Some(len) => len,
+            None => return Err(host::ENOMEM),
+        };
+        let flags = a[3];
+        let addr = if flags & MAP_FIXED != 0 {
+            self.check_mem(a[0], len)?;
+            a[0]
+        } else {
+            let addr = self.mmap_next;
+            if addr
+                .checked_add(len)
+                .is_none_or(|end| end > STACK_TOP - STACK_SIZE)
+            {
+                return Err(host::ENOMEM);
+            }
+            self.mmap_next += len;
+            addr
+        };
+        self.write_mem(addr, &vec![0; len as usize])?;
+        if flags & MAP_ANONYMOUS == 0 {
+            let offset = a[5] as u64 * PAGE_SIZE as u64;
+            let data = self.files.read_at(a[4], offset, a[1] as usize)?;
+            self.write_mem(addr, &data)?;
+        }
+        Ok(addr)
+    }
+
+    fn stat_at(&mut self, dirfd: u32, path: u32, flags: u32) -> Result<Stat, i32> {
+        let path = self.read_cstr(path)?;
+        if path.is_empty() && flags & AT_EMPTY_PATH != 0 {
+            return self.files.stat(dirfd);
+        }
+        if dirfd as i32 != AT_FDCWD && !path.starts_with('/') {
+            return Err(host::ENOSYS);
+        }
+        host::stat_path(&path)
+    }
+
+    pub(crate) fn read_mem(&self, addr: u32, len: u32) -> Result<Vec<u8>, i32> {
+        self.cpu
+            .bus
+            .load_bytes(addr, len as usize)
+            .map(|b| b.to_vec())
+            .ok_or(host::EFAULT)
+    }
+
+    // EFAULT unless all of the `len` bytes at `addr` are guest memory, before
+    // anything of that size is allocated on the host
+    pub(crate) fn check_mem(&self, addr: u32, len: u32) -> Result<(), i32> {
+        self.cpu
+            .bus
+            .load_bytes(addr, len as usize)
+            .map(|_| ())
+            .ok_or(host::EFAULT)
+    }
+
+    pub(crate) fn write_mem(&mut self, addr: u32, data: &[u8]) -> Result<(), i32> {
+        self.cpu.bus.store_bytes(addr, data).ok_or(host::EFAULT)
+    }
+
+    pub(crate) fn read_cstr(&self, addr: u32) -> Result<String, i32> {
+        let mut bytes = Vec::new();
+        loop {
+            let b = addr
+                .checked_add(bytes.len() as u32)
+                .and_then(|addr| self.cpu.bus.load_bytes(addr, 1))
+                .ok_or(host::EFAULT)?[0];
+            if b == 0 {
+                break;
+            }
+            bytes.push(b);
+        }
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+// None when rounding up runs past the end of the address space
+pub(crate) fn page_align(addr: u32) -> Option<u32> {
+    Some(addr.checked_add(PAGE_SIZE - 1)? & !(PAGE_SIZE - 1))
+}
+
+fn open_flags(flags: u32) -> OpenFlags {
+    let acc = flags & O_ACCMODE;
+    OpenFlags {
+        read: acc != O_WRONLY,
+        write: acc == O_WRONLY || acc == O_RDWR,
+        create: flags & O_CREAT != 0,
+        truncate: flags & O_TRUNC != 0,
+        append: flags & O_APPEND != 0,
+        exclusive: flags & O_EXCL != 0,
+    }
+}
+
+// struct stat64 of asm-generic/stat.h, 104 bytes
+fn stat64_bytes(st: &Stat) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(104);
+    buf.extend(st.dev.to_le_bytes());
+    buf.extend(st.ino.to_le_bytes());
+    buf.extend(st.mode.to_le_bytes());
+    buf.extend(st.nlink.to_le_bytes());
+    buf.extend(st.uid.to_le_bytes());
+    buf.extend(st.gid.to_le_bytes());
+    buf.extend(st.rdev.to_le_bytes());
+    buf.extend(0u64.to_le_bytes());
+    buf.extend(st.size.to_le_bytes());
+    buf.extend(st.blksize.to_le_bytes());
+    buf.extend(0u32.to_le_bytes());
+    buf.extend(st.blocks.to_le_bytes());
+    for (sec, nsec) in [st.atime, st.mtime, st.ctime] {
+        buf.extend((sec as u32).to_le_bytes());
+        buf.extend(nsec.to_le_bytes());
+    }
+    buf.extend([0; 8]);
+    buf
+}
+
+// struct statx of linux/stat.h, 256 bytes
+fn statx_bytes(st: &Stat) -> Vec<u8> {
+    const STATX_BASIC_STATS: u32 = 0x7ff;
+    let mut buf = Vec::with_capacity(256);
+    buf.extend(STATX_BASIC_STATS.to_le_bytes());
+    buf.extend(st.blksize.to_le_bytes());
+    buf.extend(0u64.to_le_bytes());
+    buf.extend(st.nlink.to_le_bytes());
+    buf.extend(st.uid.to_le_bytes());
+    buf.extend(st.gid.to_le_bytes());
+    buf.extend((st.mode as u16).to_le_bytes());
+    buf.extend([0; 2]);
+    buf.extend(st.ino.to_le_bytes());
+    buf.extend(st.size.to_le_bytes());
+    buf.extend(st.blocks.to_le_bytes());
+    buf.extend(0u64.to_le_bytes());
+    // atime, btime, ctime, mtime
+    for (sec, nsec) in [st.atime, st.ctime, st.ctime, st.mtime] {
+        buf.extend(sec.to_le_bytes());
+        buf.extend(nsec.to_le_bytes());
+        buf.extend([0; 4]);
+    }
+    for dev in [st.rdev, st.dev] {
+        buf.extend(((dev >> 8) as u32 & 0xfff).to_le_bytes());
+        buf.extend((dev as u32 & 0xff).to_le_bytes());
+    }
+    buf.resize(256, 0);
+    buf
+}
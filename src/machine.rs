@@ -0,0 +1,153 @@
+// Embedding API: a host program drives the hart and is told why it stopped.
+//
+// A run never stops at the instruction it starts on, so after any halt the
+// caller deals with the cause, e.g. serves the ecall, and runs again to
+// carry on with that instruction. Once the guest has a trap handler for
+// them, ecalls, ebreaks and faults go there instead and the run goes on.
+use std::sync::{Arc, Mutex};
+
+use crate::block::MAX_BLOCK_LEN;
+use crate::cpu::{Fault, CAUSE_BREAKPOINT, CPU};
+use crate::device::{Power, POLL_INTERVAL};
+use crate::hooks::Hooks;
+#[cfg(feature = "jit")]
+use crate::jit::Jit;
+use crate::linux::ECALL_INSTR;
+use crate::semihosting::{self, EBREAK_INSTR};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HaltReason {
+    // pc is on a breakpoint, or on an ebreak that is no semihosting call
+    // and that the guest does not handle
+    Breakpoint,
+    // pc is on an ecall for the host to serve, the guest has no handler
+    Ecall,
+    // the guest asked to exit through semihosting or a power-off device
+    Exited(i32),
+    // a device asked for a reboot, which is up to the caller
+    Reset,
+    // the instruction at pc could not be carried out
+    Fault(Fault),
+    // the instruction budget is used up
+    Limit,
+    // the run_until() predicate holds
+    Condition,
+}
+
+pub struct Machine {
+    pub cpu: CPU,
+    pub breakpoints: Vec<u32>,
+    // instructions retired through this machine
+    pub retired: u64,
+    // when the devices are polled next
+    next_poll: u64,
+    // run hot blocks as host code when nothing needs to see every instruction
+    #[cfg(feature = "jit")]
+    pub jit: Option<Jit>,
+}
+
+impl Machine {
+    pub fn new(cpu: CPU) -> Self {
+        Machine {
+            cpu,
+            breakpoints: Vec::new(),
+            retired: 0,
+            next_poll: POLL_INTERVAL,
+            #[cfg(feature = "jit")]
+            jit: None,
+        }
+    }
+
+    // execute one instruction
+    pub fn step(&mut self) -> HaltReason {
+        self.run(1)
+    }
+
+    // execute up to `max` instructions
+    pub fn run(&mut self, max: u64) -> HaltReason {
+        self.run_inner(max, None)
+    }
+
+    // execute until `until` holds before an instruction
+    pub fn run_until(&mut self, mut until: impl FnMut(&CPU) -> bool) -> HaltReason {
+        self.run_inner(u64::MAX, Some(&mut until))
+    }
+
+    // report what the hart does to `hooks`, after the hooks added before
+    pub fn add_hooks(&mut self, hooks: impl Hooks + 'static) {
+        self.cpu.hooks.0.push(Arc::new(Mutex::new(hooks)));
+    }
+
+    pub fn exit_code(&self) -> Option<i32> {
+        self.cpu.semihosting.as_ref().and_then(|sh| sh.exit_code)
+    }
+
+    fn run_inner(
+        &mut self,
+        max: u64,
+        mut until: Option<&mut dyn FnMut(&CPU) -> bool>,
+    ) -> HaltReason {
+        let end = self.retired.saturating_add(max);
+        let mut first = true;
+        loop {
+            if let Some(code) = self.exit_code() {
+                return HaltReason::Exited(code);
+            }
+            if self.retired >= end {
+                return HaltReason::Limit;
+            }
+            if !first {
+                if self.breakpoints.contains(&self.cpu.pc) {
+                    return HaltReason::Breakpoint;
+                }
+                if until.as_mut().is_some_and(|until| until(&self.cpu)) {
+                    return HaltReason::Condition;
+                }
+                // both always start a block, so looking at block starts
+                // is enough on the fast path too
+                match self.cpu.bus.try_load(self.cpu.pc, 32) {
+                    Some(ECALL_INSTR) if !self.cpu.has_handler(self.cpu.ecall_cause()) => {
+                        return HaltReason::Ecall
+                    }
+                    Some(EBREAK_INSTR)
+                        if !self.cpu.has_handler(CAUSE_BREAKPOINT)
+                            && (self.cpu.semihosting.is_none()
+                                || !semihosting::is_semihosting_call(&self.cpu)) =>
+                    {
+                        return HaltReason::Breakpoint
+                    }
+                    _ => (),
+                }
+            }
+            first = false;
+            // whole blocks unless every instruction has to be looked at
+            let by_block = until.is_none()
+                && self.breakpoints.is_empty()
+                && end - self.retired >= MAX_BLOCK_LEN as u64;
+            self.retired += match by_block {
+                true => self.run_block(),
+                false => self.cpu.step(),
+            };
+            if self.retired >= self.next_poll {
+                self.cpu.bus.poll();
+                self.next_poll = self.retired + POLL_INTERVAL;
+            }
+            if let Some(fault) = self.cpu.fault.take() {
+                return HaltReason::Fault(fault);
+            }
+            match self.cpu.bus.take_power() {
+                Some(Power::Off(code)) => return HaltReason::Exited(code),
+                Some(Power::Reset) => return HaltReason::Reset,
+                None => (),
+            }
+        }
+    }
+
+    fn run_block(&mut self) -> u64 {
+        #[cfg(feature = "jit")]
+        if let Some(jit) = self.jit.as_mut().filter(|_| self.cpu.hooks.is_empty()) {
+            return jit.run(&mut self.cpu);
+        }
+        self.cpu.run_block()
+    }
+}
@@ -1,36 +1,580 @@
+use std::path::Path;
+
 use clap::Parser;
 
+use riscland::board::{Board, Boot, DeviceConfig, FRAMEBUFFER_BASE};
+use riscland::coverage::Coverage;
 use riscland::cpu;
+use riscland::debugger::{self, Debugger};
+use riscland::device::Power;
 use riscland::elf;
+use riscland::fdt;
+use riscland::framebuffer::Mode;
+use riscland::gdb;
+use riscland::isa::Isa;
+use riscland::linux;
+use riscland::machine::{HaltReason, Machine};
 use riscland::opcode::get_instr_name;
+use riscland::profile::Profiler;
+use riscland::replay;
+use riscland::semihosting::Semihosting;
+use riscland::smp::{self, SMP};
+use riscland::snapshot;
+use riscland::virtio_blk::DiskMode;
+
+// what the interpreter has to run for
+#[cfg(feature = "jit")]
+const JIT_CONFLICTS: [&str; 9] = [
+    "user", "pk", "trace", "profile", "coverage", "lcov", "debug", "gdb", "harts",
+];
 
 #[derive(Parser, Debug)]
 #[command(version)]
 struct Args {
-    // input binary file
-    #[arg(short, long)]
-    file: String,
+    // input binary file, copied to the start of RAM; a board file may bring
+    // its program in its images instead
+    #[arg(short, long, required_unless_present_any = ["bios", "kernel", "machine"],
+          conflicts_with_all = ["bios", "kernel"])]
+    file: Option<String>,
+
+    // build the machine from this board description instead of the
+    // defaults, `virt` for the built-in QEMU virt layout
+    #[arg(long, conflicts_with_all = ["user", "pk", "harts"])]
+    machine: Option<std::path::PathBuf>,
+
+    // firmware ELF started at its entry, e.g. OpenSBI's fw_jump.elf; the
+    // machine is virt unless --machine says otherwise
+    #[arg(long, conflicts_with_all = ["user", "pk", "harts"])]
+    bios: Option<std::path::PathBuf>,
+
+    // kernel Image or ELF, jumped to by the firmware or started directly
+    #[arg(long, conflicts_with_all = ["user", "pk", "harts"])]
+    kernel: Option<std::path::PathBuf>,
+
+    // initial ramdisk, e.g. a cpio archive
+    #[arg(long, requires = "kernel")]
+    initrd: Option<std::path::PathBuf>,
+
+    // kernel command line, instead of the machine's bootargs
+    #[arg(long, requires = "kernel")]
+    append: Option<String>,
+
+    // disk image behind a virtio block device, may be given more than once
+    #[arg(long, conflicts_with_all = ["user", "pk", "harts"])]
+    drive: Vec<std::path::PathBuf>,
+
+    // read-write, read-only, or overlay to keep the drives' writes in memory
+    #[arg(long, value_parser = DiskMode::parse, default_value = "read-write", requires = "drive")]
+    drive_mode: DiskMode,
+
+    // add a virtio console on `stdio` or on the Unix socket at this path
+    #[arg(long, conflicts_with_all = ["user", "pk", "harts"])]
+    console: Option<String>,
+
+    // add a virtio network device on a datagram socket bound here
+    #[arg(long, requires = "net_peer", conflicts_with_all = ["user", "pk", "harts"])]
+    net: Option<std::path::PathBuf>,
+
+    // socket of the other end of the link, e.g. another emulator's --net
+    #[arg(long, requires = "net")]
+    net_peer: Option<std::path::PathBuf>,
+
+    // MAC address of the network device
+    #[arg(long, requires = "net")]
+    mac: Option<String>,
+
+    // add a virtio entropy device
+    #[arg(long, conflicts_with_all = ["user", "pk", "harts"])]
+    rng: bool,
+
+    // seed of the entropy device, for reproducible runs
+    #[arg(long, conflicts_with_all = ["user", "pk", "harts"])]
+    rng_seed: Option<u64>,
+
+    // run the RTC on virtual time from this many seconds since 1970 on, and
+    // the CLINT's mtime too, following the instruction count so every run
+    // sees the same time
+    #[arg(long, conflicts_with_all = ["user", "pk", "harts"])]
+    rtc_epoch: Option<u64>,
+
+    // add a framebuffer, e.g. 640x480 or 320x240:r5g6b5
+    #[arg(long, value_parser = Mode::parse, conflicts_with_all = ["user", "pk", "harts"])]
+    framebuffer: Option<Mode>,
+
+    // write numbered frames next to this .png or .ppm file, whenever the
+    // guest asks
+    #[arg(long, requires = "framebuffer")]
+    fb_dump: Option<std::path::PathBuf>,
+
+    // also write a frame every this many instructions
+    #[arg(long, requires = "fb_dump")]
+    fb_dump_every: Option<u64>,
+
+    // write the machine's device tree blob to this file and exit
+    #[arg(long, conflicts_with_all = ["user", "pk"])]
+    dump_dtb: Option<String>,
+
+    // ISA string of the emulated core, e.g. rv32i_zicsr, other extensions'
+    // instructions are illegal; a machine file says it with `isa`
+    #[arg(long, value_parser = Isa::parse, conflicts_with = "machine")]
+    isa: Option<Isa>,
+
+    // what loads and stores off their alignment do: allow, trap, or emulate
+    // them byte by byte like M-mode firmware; the machine's choice otherwise
+    #[arg(long, value_parser = cpu::Misaligned::parse)]
+    misaligned: Option<cpu::Misaligned>,
+
+    // run a statically linked RV32 Linux program, syscalls are served by the
+    // host; the harts are 32-bit, so RV64 programs are refused
+    #[arg(long, requires = "file")]
+    user: bool,
+
+    // run a newlib program against riscv-pk style proxy syscalls
+    #[arg(long, conflicts_with = "user", requires = "file")]
+    pk: bool,
+
+    // serve semihosting requests made through ebreak
+    #[arg(long)]
+    semihosting: bool,
+
+    // print every executed instruction and its operands, one at a time;
+    // not for boards, whose console is stdout as well
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "machine", "bios", "kernel", "user", "pk", "semihosting", "debug", "gdb", "harts"
+        ]
+    )]
+    trace: bool,
+
+    // confine semihosting file access to this directory
+    #[arg(long, requires = "semihosting")]
+    semihosting_root: Option<std::path::PathBuf>,
+
+    // profile the guest and write its collapsed call stacks to this file
+    #[arg(long, requires = "file")]
+    profile: Option<String>,
+
+    // number of functions and pcs listed in the profile report
+    #[arg(long, default_value_t = 20, requires = "profile")]
+    profile_top: usize,
+
+    // write executed addresses and branch outcomes to this file
+    #[arg(long, requires = "file")]
+    coverage: Option<String>,
+
+    // write an lcov tracefile mapped to source lines through DWARF
+    #[arg(long, requires = "file")]
+    lcov: Option<String>,
+
+    // save the machine state to this file after --snapshot-at instructions
+    #[arg(long, requires = "snapshot_at", conflicts_with_all = ["user", "pk"])]
+    snapshot_save: Option<String>,
+
+    // number of retired instructions before the snapshot is taken
+    #[arg(long)]
+    snapshot_at: Option<u64>,
+
+    // start from a previously saved machine state instead of reset
+    #[arg(long, conflicts_with_all = ["user", "pk"])]
+    snapshot_restore: Option<String>,
+
+    // debug the guest from an interactive prompt, with reverse execution
+    #[arg(long, conflicts_with_all = ["user", "pk"])]
+    debug: bool,
+
+    // wait for gdb to connect on this port before running the guest
+    #[arg(long, conflicts_with_all = ["user", "pk", "debug"])]
+    gdb: Option<u16>,
+
+    // instructions between the checkpoints used to go back in time
+    #[arg(long, default_value_t = replay::DEFAULT_INTERVAL)]
+    replay_interval: u64,
+
+    // number of harts sharing the machine's memory
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..),
+          conflicts_with_all = ["user", "pk", "debug", "gdb", "snapshot_save"])]
+    harts: u32,
+
+    // instructions each hart runs before the next one gets its turn
+    #[arg(long, default_value_t = smp::DEFAULT_QUANTUM, value_parser = clap::value_parser!(u64).range(1..))]
+    quantum: u64,
+
+    // run every hart on its own host thread instead of round robin
+    #[arg(long)]
+    threaded: bool,
+
+    // run hot blocks as host code; generated code does not trace nor report
+    // to the analyses, and runs a single hart
+    #[cfg(feature = "jit")]
+    #[arg(long, conflicts_with_all = JIT_CONFLICTS)]
+    jit: bool,
+
+    // compile every block and check it against the interpreter
+    #[cfg(feature = "jit")]
+    #[arg(long, conflicts_with_all = JIT_CONFLICTS)]
+    jit_check: bool,
+
+    // arguments passed to the guest program, argv[0] is the file itself
+    #[arg(last = true)]
+    args: Vec<String>,
 }
 
 fn main() {
     let args = Args::parse();
-    let mut cpu = cpu::CPU::new();
-    let elf_file = elf::ELF::new(&args.file);
-    let file_bin = elf_file.read_instructions_to_end();
-    cpu.bus.init_memory(file_bin);
-    let mut cnt = 0;
-    loop {
-        let instr = cpu.fetch();
-        println!(
-            "cnt: {}, cpu.pc: {:#x}, instr: {:x}, name: {}",
-            cnt,
-            cpu.pc,
-            instr,
-            get_instr_name(instr),
-        );
-        cnt += 1;
-        cpu.execute(instr);
-        cpu.pc += 4;
-        // riscland::debug::dump_registers(&cpu);
+    if args.user || args.pk {
+        let file = args.file.clone().unwrap();
+        let image = elf::ELF::new(&file).read_image().unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+        let mut argv = vec![file];
+        argv.extend(args.args.iter().cloned());
+        let envp: Vec<String> = std::env::vars().map(|(k, v)| format!("{k}={v}")).collect();
+        let abi = match args.pk {
+            true => linux::Abi::ProxyKernel,
+            false => linux::Abi::Linux,
+        };
+        let mut process = linux::Process::new(&image, &argv, &envp, abi).unwrap_or_else(|e| {
+            eprintln!("{}: {}", argv[0], e);
+            std::process::exit(1);
+        });
+        if let Some(isa) = args.isa {
+            process.cpu.set_isa(isa);
+        }
+        if let Some(misaligned) = args.misaligned {
+            process.cpu.misaligned = misaligned;
+        }
+        attach_analyses(&mut process.cpu, &args);
+        let code = process.run();
+        finish_analyses(&process.cpu, &args);
+        std::process::exit(code);
+    }
+
+    let machine_path = match &args.machine {
+        Some(path) => Some(path.clone()),
+        None if wants_virt(&args) => Some("virt".into()),
+        None => None,
+    };
+    let board = machine_path.as_ref().map(|path| {
+        let board = match path.to_str() {
+            Some("virt") => Ok(Board::virt()),
+            _ => Board::from_file(path),
+        };
+        let mut board = board.unwrap_or_else(|e| {
+            eprintln!("{}: {}", path.display(), e);
+            std::process::exit(1);
+        });
+        // only the implicit virt board, a machine file has its own
+        if let Some(isa) = args.isa {
+            board.isa = isa.to_string();
+        }
+        for device in virtio_devices(&args) {
+            if let Err(e) = board.add_virtio(device) {
+                eprintln!("{}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+        if let Some(epoch) = args.rtc_epoch {
+            for device in &mut board.device {
+                match device.kind.as_str() {
+                    "goldfish-rtc" => device.epoch = Some(epoch),
+                    "clint" => device.virtual_time = true,
+                    _ => (),
+                }
+            }
+        }
+        if let Some(mode) = args.framebuffer {
+            let framebuffer = DeviceConfig {
+                kind: "framebuffer".to_string(),
+                base: FRAMEBUFFER_BASE,
+                width: Some(mode.width),
+                height: Some(mode.height),
+                format: Some(mode.format),
+                dump: args.fb_dump.clone(),
+                dump_every: args.fb_dump_every,
+                ..DeviceConfig::default()
+            };
+            if let Err(e) = board.add_device(framebuffer) {
+                eprintln!("{}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+        board
+    });
+    let harts = board.as_ref().map_or(args.harts, |board| board.harts);
+    if harts > 1 && (args.debug || args.gdb.is_some() || args.snapshot_save.is_some()) {
+        eprintln!("debugging and snapshots need a single hart");
+        std::process::exit(1);
+    }
+    #[cfg(feature = "jit")]
+    if harts > 1 && (args.jit || args.jit_check) {
+        eprintln!("the jit runs a single hart");
+        std::process::exit(1);
+    }
+    let file_bin = args
+        .file
+        .as_ref()
+        .map(|file| elf::ELF::new(file).read_instructions_to_end());
+    let (mut cpu, dtb) = match &board {
+        Some(board) => boot(
+            &args,
+            board,
+            machine_path.as_ref().unwrap(),
+            file_bin.as_deref(),
+        ),
+        None => {
+            let mut cpu = cpu::CPU::new();
+            cpu.set_isa(args.isa.unwrap_or_default());
+            cpu.bus.init_memory(file_bin.clone().unwrap());
+            let dtb = fdt::machine(&cpu.bus, harts, cpu.isa(), &fdt::Chosen::default());
+            (cpu, dtb)
+        }
+    };
+    if let Some(misaligned) = args.misaligned {
+        cpu.misaligned = misaligned;
+    }
+    cpu.trace = args.trace;
+    if let Some(path) = &args.dump_dtb {
+        std::fs::write(path, dtb).expect("failed to write device tree");
+        return;
+    }
+    if let Some(path) = &args.snapshot_restore {
+        if let Err(e) = snapshot::restore_file(&mut cpu, path) {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+    if args.semihosting {
+        // what the guest runs, or the board that has it in its images
+        let program = args.file.clone().or_else(|| {
+            let kernel = args.kernel.as_ref().or(args.bios.as_ref());
+            kernel
+                .or(machine_path.as_ref())
+                .map(|path| path.display().to_string())
+        });
+        let mut cmdline = vec![program.unwrap()];
+        cmdline.extend(args.args.iter().cloned());
+        cpu.semihosting = Some(Semihosting::new(
+            &cmdline.join(" "),
+            args.semihosting_root.clone(),
+        ));
+    }
+    attach_analyses(&mut cpu, &args);
+    if args.debug || args.gdb.is_some() {
+        let mut dbg = Debugger::new(cpu, args.replay_interval);
+        let result = match args.gdb {
+            Some(port) => gdb::serve(&mut dbg, port),
+            None => debugger::repl(&mut dbg),
+        };
+        if let Err(e) = result {
+            eprintln!("debugger: {}", e);
+        }
+        finish_analyses(&dbg.cpu, &args);
+        return;
+    }
+    if harts > 1 {
+        // tracing and the jit are single hart only
+        cpu.trace = false;
+        let mut machine = SMP::new(cpu, harts as usize);
+        machine.quantum = args.quantum;
+        loop {
+            match args.threaded {
+                true => machine.run_threaded(u64::MAX),
+                false => machine.run(u64::MAX),
+            };
+            if machine.bus.power() != Some(Power::Reset) {
+                break;
+            }
+            // only boards have a syscon to reset the machine with
+            let (mut cpu, _) = boot(
+                &args,
+                board.as_ref().unwrap(),
+                machine_path.as_ref().unwrap(),
+                file_bin.as_deref(),
+            );
+            cpu.trace = false;
+            cpu.semihosting = machine.harts[0].semihosting.take();
+            cpu.profiler = machine.harts[0].profiler.take();
+            cpu.coverage = machine.harts[0].coverage.take();
+            let quantum = machine.quantum;
+            machine = SMP::new(cpu, harts as usize);
+            machine.quantum = quantum;
+        }
+        let code = match machine.fault() {
+            Some((hartid, fault)) => {
+                eprintln!("hart {}: {}", hartid, fault);
+                1
+            }
+            None => machine.exit_code().unwrap_or(0),
+        };
+        // analyses only cover hart 0
+        finish_analyses(&machine.harts[0], &args);
+        std::process::exit(code);
+    }
+    let mut machine = Machine::new(cpu);
+    #[cfg(feature = "jit")]
+    if args.jit || args.jit_check {
+        machine.jit = Some(match args.jit_check {
+            true => riscland::jit::Jit::with_cross_check(),
+            false => riscland::jit::Jit::new(),
+        });
+    }
+    let mut snapshot_save = args.snapshot_save.as_ref().zip(args.snapshot_at);
+    let code = loop {
+        let reason = if machine.cpu.trace {
+            // a fetch that fails is reported by step()
+            let instr = machine.cpu.fetch().unwrap_or(0);
+            println!(
+                "cnt: {}, cpu.pc: {:#x}, instr: {:x}, name: {}",
+                machine.retired,
+                machine.cpu.pc,
+                instr,
+                get_instr_name(instr),
+            );
+            machine.step()
+        } else {
+            match snapshot_save {
+                Some((_, at)) => machine.run(at.saturating_sub(machine.retired)),
+                None => machine.run(u64::MAX),
+            }
+        };
+        if let Some((path, at)) = snapshot_save {
+            if machine.retired >= at {
+                snapshot::save_file(&machine.cpu, path).expect("failed to save snapshot");
+                snapshot_save = None;
+            }
+        }
+        match reason {
+            HaltReason::Exited(code) => break code,
+            HaltReason::Fault(fault) => {
+                eprintln!("{} at pc {:#x}", fault, machine.cpu.pc);
+                break 1;
+            }
+            HaltReason::Reset => {
+                // only boards have a syscon to reset the machine with; what
+                // watches the guest carries on
+                let (mut cpu, _) = boot(
+                    &args,
+                    board.as_ref().unwrap(),
+                    machine_path.as_ref().unwrap(),
+                    file_bin.as_deref(),
+                );
+                cpu.trace = machine.cpu.trace;
+                cpu.misaligned = machine.cpu.misaligned;
+                cpu.semihosting = machine.cpu.semihosting.take();
+                cpu.profiler = machine.cpu.profiler.take();
+                cpu.coverage = machine.cpu.coverage.take();
+                machine.cpu = cpu;
+            }
+            // bare-metal ecall and ebreak do nothing, run on past them
+            _ => (),
+        }
+    };
+    finish_analyses(&machine.cpu, &args);
+    std::process::exit(code);
+}
+
+// hart 0 of `board` at reset with everything loaded, and its device tree
+fn boot(args: &Args, board: &Board, path: &Path, file_bin: Option<&[u8]>) -> (cpu::CPU, Vec<u8>) {
+    let boot = Boot {
+        bios: args.bios.clone(),
+        kernel: args.kernel.clone(),
+        initrd: args.initrd.clone(),
+        append: args.append.clone(),
+    };
+    let (mut cpu, dtb) = board.boot(&boot).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path.display(), e);
+        std::process::exit(1);
+    });
+    // the program goes to the start of the first RAM
+    if let Some(file_bin) = file_bin {
+        if cpu.bus.store_bytes(board.ram[0].base, file_bin).is_none() {
+            eprintln!("{} does not fit into ram[0]", args.file.as_ref().unwrap());
+            std::process::exit(1);
+        }
+    }
+    (cpu, dtb)
+}
+
+// devices only a virt style machine has room for
+fn wants_virt(args: &Args) -> bool {
+    args.bios.is_some()
+        || args.kernel.is_some()
+        || args.rtc_epoch.is_some()
+        || args.framebuffer.is_some()
+        || !virtio_devices(args).is_empty()
+}
+
+// the virtio devices asked for on the command line
+fn virtio_devices(args: &Args) -> Vec<DeviceConfig> {
+    let mut devices: Vec<DeviceConfig> = args
+        .drive
+        .iter()
+        .map(|image| DeviceConfig {
+            kind: "virtio-blk".to_string(),
+            image: Some(image.clone()),
+            mode: args.drive_mode,
+            ..DeviceConfig::default()
+        })
+        .collect();
+    if let Some(console) = &args.console {
+        devices.push(DeviceConfig {
+            kind: "virtio-console".to_string(),
+            socket: (console != "stdio").then(|| console.into()),
+            ..DeviceConfig::default()
+        });
+    }
+    if let Some(socket) = &args.net {
+        devices.push(DeviceConfig {
+            kind: "virtio-net".to_string(),
+            socket: Some(socket.clone()),
+            peer: args.net_peer.clone(),
+            mac: args.mac.clone(),
+            ..DeviceConfig::default()
+        });
+    }
+    if args.rng || args.rng_seed.is_some() {
+        devices.push(DeviceConfig {
+            kind: "virtio-rng".to_string(),
+            seed: args.rng_seed,
+            ..DeviceConfig::default()
+        });
+    }
+    devices
+}
+
+fn attach_analyses(cpu: &mut cpu::CPU, args: &Args) {
+    if args.profile.is_some() {
+        let symbols = elf::ELF::new(args.file.as_ref().unwrap()).read_symbols();
+        cpu.profiler = Some(Profiler::new(symbols));
+    }
+    if args.coverage.is_some() || args.lcov.is_some() {
+        cpu.coverage = Some(Coverage::new());
+    }
+}
+
+// print the hot spots and write out the profile and coverage files
+fn finish_analyses(cpu: &cpu::CPU, args: &Args) {
+    if let (Some(profiler), Some(path)) = (&cpu.profiler, &args.profile) {
+        eprint!("{}", profiler.report(args.profile_top));
+        std::fs::write(path, profiler.collapsed()).expect("failed to write profile");
+    }
+    let Some(coverage) = &cpu.coverage else {
+        return;
+    };
+    let file = args.file.as_ref().unwrap();
+    let elf_file = elf::ELF::new(file);
+    if let Some(path) = &args.coverage {
+        let report = coverage.report(&elf_file.read_symbols());
+        std::fs::write(path, report).expect("failed to write coverage");
+    }
+    if let Some(path) = &args.lcov {
+        let lines = elf_file.read_line_table();
+        if lines.is_empty() {
+            eprintln!("warning: {} has no DWARF line info", file);
+        }
+        let lcov = coverage.lcov(&lines, &cpu.bus);
+        std::fs::write(path, lcov).expect("failed to write lcov");
     }
 }
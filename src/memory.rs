@@ -1,42 +1,541 @@
+use std::fs::OpenOptions;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use memmap2::{MmapMut, MmapOptions};
+
+use crate::device::{Mapped, Power};
+
 pub const MEM_BASE: u32 = 0x80000000; // defined in QEMU
 pub const MEM_SIZE: u32 = 1024 * 10;
 
+// granularity at which writes to translated code are tracked
+const CODE_PAGE_SHIFT: u32 = 12;
+
+// granularity of sparse copies
+const PAGE_SIZE: usize = 4096;
+
+// every bus starts its code_writes count far away from all others, so blocks
+// translated from one bus are never mistaken as current on another
+fn fresh_code_writes() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    NEXT.fetch_add(1 << 32, Ordering::Relaxed)
+}
+
 #[derive(Debug, Clone)]
 pub struct BUS {
-    mem: MEMORY,
+    // main RAM first, then the RAM and ROM regions a board adds
+    mems: Vec<MEMORY>,
+    // MMIO devices, reached through read() and write()
+    devices: Vec<Mapped>,
+    // bumped whenever a code page mark changes, also the source of the
+    // stamps
+    code_writes: u64,
+    // what a device asked of the machine, until the machine acts on it
+    power: Option<Power>,
+    // instructions retired by the harts on this bus, the clock devices
+    // with a virtual time run on
+    instret: u64,
+    // bumped whenever the interrupt lines may have changed
+    irq_changes: u64,
+    // address whose writes are counted in watch_hits, whoever makes them
+    watch: Option<u32>,
+    watch_hits: u64,
 }
 
 impl BUS {
     pub fn new() -> Self {
-        BUS { mem: MEMORY::new() }
+        BUS {
+            mems: vec![MEMORY::new()],
+            devices: Vec::new(),
+            code_writes: fresh_code_writes(),
+            power: None,
+            instret: 0,
+            irq_changes: 0,
+            watch: None,
+            watch_hits: 0,
+        }
+    }
+    // bus with a zeroed RAM of `size` bytes mapped at `base`
+    pub fn with_memory(base: u32, size: u32) -> Self {
+        BUS {
+            mems: vec![MEMORY::with_size(base, size)],
+            devices: Vec::new(),
+            code_writes: fresh_code_writes(),
+            power: None,
+            instret: 0,
+            irq_changes: 0,
+            watch: None,
+            watch_hits: 0,
+        }
+    }
+    // map `data` at `base` next to the main RAM, stores to it fault when
+    // it is read only
+    pub fn add_region(&mut self, base: u32, data: impl Into<Backing>, read_only: bool) {
+        self.mems.push(MEMORY {
+            mem: data.into(),
+            base,
+            read_only,
+            code: Vec::new(),
+        });
+    }
+    pub fn add_device(&mut self, device: Mapped) {
+        self.devices.push(device);
+    }
+    pub fn devices(&self) -> &[Mapped] {
+        &self.devices
     }
+    pub fn device_mut(&mut self, base: u32) -> Option<&mut Mapped> {
+        self.devices.iter_mut().find(|dev| dev.base == base)
+    }
+    // base, size and read-only flag of every memory, main RAM first
+    pub fn memories(&self) -> impl Iterator<Item = (u32, u32, bool)> + '_ {
+        self.mems
+            .iter()
+            .map(|mem| (mem.base, mem.mem.len() as u32, mem.read_only))
+    }
+    // guest load: memory, or else a device register
+    pub fn read(&mut self, addr: u32, size: u32) -> Option<u32> {
+        if let Some(value) = self.try_load(addr, size) {
+            return Some(value);
+        }
+        let dev = self
+            .devices
+            .iter_mut()
+            .find(|dev| dev.contains(addr, size / 8))?;
+        dev.device.clock(self.instret);
+        let value = dev.device.load(addr - dev.base, size);
+        // e.g. a claim at the PLIC
+        self.route_irqs();
+        Some(value)
+    }
+    // guest store: memory, or else a device register
+    pub fn write(&mut self, addr: u32, size: u32, value: u32) -> Option<()> {
+        if self.find(addr, size as usize / 8).is_some() {
+            return self.try_store(addr, size, value);
+        }
+        let i = self
+            .devices
+            .iter()
+            .position(|dev| dev.contains(addr, size / 8))?;
+        let dev = &mut self.devices[i];
+        dev.device.clock(self.instret);
+        dev.device.store(addr - dev.base, size, value);
+        if let Some(power) = dev.device.power() {
+            self.power = Some(power);
+        }
+        // the device gets the memories while it is off the bus
+        let mut devices = std::mem::take(&mut self.devices);
+        devices[i].device.dma(self);
+        self.devices = devices;
+        self.route_irqs();
+        Some(())
+    }
+    // the power-off or reset a device asked for, once
+    pub fn take_power(&mut self) -> Option<Power> {
+        self.power.take()
+    }
+    pub fn power(&self) -> Option<Power> {
+        self.power
+    }
+    pub fn instret(&self) -> u64 {
+        self.instret
+    }
+    pub fn count_retired(&mut self, n: u64) {
+        self.instret += n;
+    }
+    // where the clock stands after a snapshot restore
+    pub fn set_instret(&mut self, instret: u64) {
+        self.instret = instret;
+    }
+    // let every device do the guest memory work it has waiting
+    pub fn poll(&mut self) {
+        let mut devices = std::mem::take(&mut self.devices);
+        for dev in &mut devices {
+            dev.device.clock(self.instret);
+            dev.device.dma(self);
+        }
+        self.devices = devices;
+        self.route_irqs();
+    }
+    // hand the device interrupt lines to the interrupt controller
+    pub(crate) fn route_irqs(&mut self) {
+        let lines = self
+            .devices
+            .iter()
+            .filter(|dev| dev.device.irq())
+            .filter_map(|dev| dev.irq)
+            .fold(0, |lines, irq| lines | 1 << irq);
+        for dev in &mut self.devices {
+            dev.device.irq_lines(lines);
+        }
+        self.irq_changes += 1;
+    }
+    // changes whenever the interrupts() of a hart may have changed
+    pub fn irq_changes(&self) -> u64 {
+        self.irq_changes
+    }
+    // the mip bits the devices raise for hart `hart`
+    pub fn interrupts(&mut self, hart: u32) -> u32 {
+        let instret = self.instret;
+        self.devices
+            .iter_mut()
+            .map(|dev| {
+                dev.device.clock(instret);
+                dev.device.interrupts(hart)
+            })
+            .fold(0, |mip, bits| mip | bits)
+    }
+    // for the host, e.g. a test, to read memory it knows is there; what the
+    // guest does goes through try_load(), which can fail
     pub fn load(&self, addr: u32, size: u32) -> u32 {
-        return self.mem.clone().load(addr, size) as u32;
+        self.try_load(addr, size)
+            .unwrap_or_else(|| panic!("no memory at {:#x}", addr))
+    }
+    // load that reports addresses outside of memory as None instead of
+    // panicking
+    pub fn try_load(&self, addr: u32, size: u32) -> Option<u32> {
+        let mem = self.find(addr, size as usize / 8)?;
+        Some(self.mems[mem].load(addr, size))
+    }
+    pub fn try_store(&mut self, addr: u32, size: u32, value: u32) -> Option<()> {
+        let mem = self.find_writable(addr, size as usize / 8)?;
+        self.written(mem, addr, size / 8);
+        self.mems[mem].store(addr, size, value);
+        Some(())
     }
+    // the host's counterpart of try_store(), which may write read only
+    // memories too
     pub fn store(&mut self, addr: u32, size: u32, value: u32) {
-        self.mem.store(addr, size, value);
+        let mem = self
+            .find(addr, size as usize / 8)
+            .unwrap_or_else(|| panic!("no memory at {:#x}", addr));
+        self.written(mem, addr, size / 8);
+        self.mems[mem].store(addr, size, value);
     }
     pub fn init_memory(&mut self, buf: Vec<u8>) {
         if buf.len() > MEM_SIZE as usize {
             panic!("binary file is bigger than MEM_SIZE");
         }
-        self.mem.mem = buf.as_slice().try_into().expect("failed to read bin file");
+        self.mems[0].mem = buf.into();
+        self.mems[0].code.clear();
+        self.code_writes += 1;
+    }
+    // copy raw bytes into guest memory, None if any byte is out of range
+    // or read only
+    pub fn store_bytes(&mut self, addr: u32, data: &[u8]) -> Option<()> {
+        let mem = self.find_writable(addr, data.len())?;
+        let range = self.mems[mem].range(addr, data.len())?;
+        self.written(mem, addr, data.len() as u32);
+        self.mems[mem].mem[range].copy_from_slice(data);
+        Some(())
+    }
+    // read raw bytes from guest memory, None if any byte is out of range
+    pub fn load_bytes(&self, addr: u32, len: usize) -> Option<&[u8]> {
+        let mem = &self.mems[self.find(addr, len)?];
+        Some(&mem.mem[mem.range(addr, len)?])
+    }
+    // base address and contents of the main RAM
+    pub fn ram(&self) -> (u32, &[u8]) {
+        (self.mems[0].base, &self.mems[0].mem)
+    }
+    // base address and contents of memory `index`, in the order of
+    // memories()
+    pub fn memory(&self, index: usize) -> (u32, &[u8]) {
+        (self.mems[index].base, &self.mems[index].mem)
+    }
+    // swap in a whole new main RAM
+    pub fn replace_ram(&mut self, base: u32, data: impl Into<Backing>) {
+        self.mems[0] = MEMORY::from_data(base, data.into());
+        self.code_writes += 1;
+    }
+    // memory `index`, all zeros, if it is there with that shape, e.g. to
+    // restore a snapshot into; 0 is the main RAM
+    pub fn zeroed_memory(&mut self, index: usize, base: u32, size: u32) -> Option<&mut Backing> {
+        let mem = self
+            .mems
+            .get_mut(index)
+            .filter(|mem| mem.base == base && mem.mem.len() == size as usize)?;
+        mem.mem.clear();
+        mem.code.clear();
+        self.code_writes += 1;
+        Some(&mut mem.mem)
+    }
+
+    // stamp of the code translated from the page of `addr`, 0 when the page
+    // was written since or never translated
+    pub fn code_stamp(&self, addr: u32) -> u64 {
+        let Some(mem) = self.find(addr, 1) else {
+            return 0;
+        };
+        let mem = &self.mems[mem];
+        mem.code.get(mem.code_page(addr)).copied().unwrap_or(0)
+    }
+    // mark the page of `addr` as translated and return its new stamp
+    pub fn mark_code(&mut self, addr: u32) -> u64 {
+        self.code_writes += 1;
+        if let Some(mem) = self.find(addr, 1) {
+            let mem = &mut self.mems[mem];
+            let page = mem.code_page(addr);
+            if mem.code.len() <= page {
+                mem.code.resize(page + 1, 0);
+            }
+            mem.code[page] = self.code_writes;
+        }
+        self.code_writes
+    }
+    // main RAM, its code page marks and its touched pages as raw pointers
+    // for generated code, which must leave stores to marked pages to the
+    // interpreter and touch the pages it stores to
+    #[cfg(feature = "jit")]
+    pub(crate) fn raw_parts(&mut self) -> (*mut u8, u32, u32, *const u64, u32, *mut u8) {
+        let ram = &mut self.mems[0];
+        (
+            ram.mem.as_mut_ptr(),
+            ram.base,
+            ram.mem.len() as u32,
+            ram.code.as_ptr(),
+            ram.code.len() as u32,
+            ram.mem.touched_ptr(),
+        )
+    }
+
+    // count the writes to `addr` from now on, e.g. to find the last one
+    pub fn watch(&mut self, addr: Option<u32>) {
+        self.watch = addr;
+    }
+    // writes to the watched address so far: stores, DMA and host calls
+    pub fn watch_hits(&self) -> u64 {
+        self.watch_hits
+    }
+    // changes whenever translated code may have been overwritten
+    pub fn code_writes(&self) -> u64 {
+        self.code_writes
+    }
+    // index of the memory holding all `len` bytes at `addr`
+    fn find(&self, addr: u32, len: usize) -> Option<usize> {
+        self.mems
+            .iter()
+            .position(|mem| mem.range(addr, len).is_some())
+    }
+    fn find_writable(&self, addr: u32, len: usize) -> Option<usize> {
+        self.find(addr, len)
+            .filter(|mem| !self.mems[*mem].read_only)
+    }
+    fn written(&mut self, mem: usize, addr: u32, len: u32) {
+        if self.watch.is_some_and(|at| at.wrapping_sub(addr) < len) {
+            self.watch_hits += 1;
+        }
+        let mem = &mut self.mems[mem];
+        mem.mem.touch((addr - mem.base) as usize, len as usize);
+        if mem.code.is_empty() || len == 0 {
+            return;
+        }
+        let first = mem.code_page(addr);
+        let last = mem.code_page(addr.wrapping_add(len - 1));
+        for page in first..=last.min(mem.code.len() - 1) {
+            if mem.code[page] != 0 {
+                mem.code[page] = 0;
+                self.code_writes += 1;
+            }
+        }
+    }
+}
+
+// what holds the bytes of a memory. A sparse memory is an anonymous mapping
+// that the host fills in a page at a time as the guest touches it, so a big
+// address space costs only what is used; a file backed one maps a host file
+// shared, the file holds what the guest wrote. Copies, e.g. replay
+// checkpoints, are sparse and private, whatever the original was, and only
+// copy the pages written so far.
+#[derive(Debug)]
+pub struct Backing {
+    bytes: Bytes,
+    // a byte per page, set once anything but zeros may be in it
+    touched: Vec<u8>,
+}
+
+#[derive(Debug)]
+enum Bytes {
+    Owned(Vec<u8>),
+    Sparse(MmapMut),
+    File(MmapMut),
+}
+
+impl Backing {
+    pub fn sparse(size: u32) -> io::Result<Self> {
+        let map = MmapOptions::new().len(size as usize).map_anon()?;
+        Ok(Backing::with_bytes(Bytes::Sparse(map)))
+    }
+
+    // `path` grows to `size` bytes if it is shorter
+    pub fn file(path: &Path, size: u32) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        if file.metadata()?.len() < size as u64 {
+            file.set_len(size as u64)?;
+        }
+        // SAFETY: nothing else is to change the file while the machine runs
+        let map = unsafe { MmapOptions::new().len(size as usize).map_mut(&file)? };
+        let mut backing = Backing::with_bytes(Bytes::File(map));
+        // what the file held is the one thing not written through touch()
+        for i in 0..backing.touched.len() {
+            let page = &backing[i * PAGE_SIZE..((i + 1) * PAGE_SIZE).min(backing.len())];
+            backing.touched[i] = !is_zero(page) as u8;
+        }
+        Ok(backing)
+    }
+
+    fn with_bytes(bytes: Bytes) -> Self {
+        let mut backing = Backing {
+            bytes,
+            touched: Vec::new(),
+        };
+        backing.touched = vec![0; backing.len().div_ceil(PAGE_SIZE)];
+        backing
+    }
+
+    // note a write of `len` bytes at `offset`; whoever writes through
+    // DerefMut has to
+    pub fn touch(&mut self, offset: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        let last = ((offset + len - 1) / PAGE_SIZE).min(self.touched.len() - 1);
+        self.touched[offset / PAGE_SIZE..=last].fill(1);
+    }
+
+    // the page marks as a raw pointer for generated code
+    #[cfg(feature = "jit")]
+    fn touched_ptr(&mut self) -> *mut u8 {
+        self.touched.as_mut_ptr()
+    }
+
+    // pages that may have anything in them, by index
+    fn touched_pages(&self) -> impl Iterator<Item = std::ops::Range<usize>> + '_ {
+        let len = self.len();
+        self.touched
+            .iter()
+            .enumerate()
+            .filter(|(_, touched)| **touched != 0)
+            .map(move |(i, _)| i * PAGE_SIZE..((i + 1) * PAGE_SIZE).min(len))
+    }
+
+    // zero every page in use, without touching the others
+    fn clear(&mut self) {
+        let pages: Vec<_> = self.touched_pages().collect();
+        for page in pages {
+            self[page].fill(0);
+        }
+        self.touched.fill(0);
+    }
+}
+
+// whether `data` is all zeros, compared with a page of them so it is a
+// memcmp even in debug builds
+pub fn is_zero(data: &[u8]) -> bool {
+    static ZEROS: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+    data.chunks(PAGE_SIZE)
+        .all(|chunk| chunk == &ZEROS[..chunk.len()])
+}
+
+impl From<Vec<u8>> for Backing {
+    fn from(data: Vec<u8>) -> Self {
+        let mut backing = Backing::with_bytes(Bytes::Owned(data));
+        backing.touched.fill(1);
+        backing
+    }
+}
+
+impl Deref for Backing {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.bytes {
+            Bytes::Owned(data) => data,
+            Bytes::Sparse(map) | Bytes::File(map) => map,
+        }
+    }
+}
+
+impl DerefMut for Backing {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match &mut self.bytes {
+            Bytes::Owned(data) => data,
+            Bytes::Sparse(map) | Bytes::File(map) => map,
+        }
+    }
+}
+
+impl Clone for Backing {
+    fn clone(&self) -> Self {
+        let bytes = match &self.bytes {
+            Bytes::Owned(data) => Bytes::Owned(data.clone()),
+            // the host may be out of address space for another mapping
+            _ => match MmapOptions::new().len(self.len()).map_anon() {
+                Ok(map) => Bytes::Sparse(map),
+                Err(_) => Bytes::Owned(vec![0; self.len()]),
+            },
+        };
+        let mut copy = Backing {
+            bytes,
+            touched: self.touched.clone(),
+        };
+        if !matches!(self.bytes, Bytes::Owned(_)) {
+            for page in self.touched_pages() {
+                copy[page.clone()].copy_from_slice(&self[page]);
+            }
+        }
+        copy
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct MEMORY {
-    mem: Vec<u8>,
+    mem: Backing,
+    base: u32,
+    read_only: bool,
+    // per page, the stamp handed out when a block cache translated code from
+    // it, cleared to 0 by any write
+    code: Vec<u64>,
 }
 
 impl MEMORY {
     fn new() -> Self {
+        MEMORY::from_data(MEM_BASE, Vec::new().into())
+    }
+    fn with_size(base: u32, size: u32) -> Self {
+        MEMORY::from_data(base, vec![0; size as usize].into())
+    }
+    fn from_data(base: u32, mem: Backing) -> Self {
         MEMORY {
-            mem: Vec::<u8>::new(),
+            mem,
+            base,
+            read_only: false,
+            code: Vec::new(),
+        }
+    }
+
+    fn code_page(&self, addr: u32) -> usize {
+        ((addr - self.base) >> CODE_PAGE_SHIFT) as usize
+    }
+
+    fn range(&self, addr: u32, len: usize) -> Option<std::ops::Range<usize>> {
+        let start = addr.checked_sub(self.base)? as usize;
+        let end = start.checked_add(len)?;
+        if end > self.mem.len() {
+            return None;
         }
+        Some(start..end)
     }
 
-    fn load(self, addr: u32, size: u32) -> u32 {
+    fn load(&self, addr: u32, size: u32) -> u32 {
         match size {
             8 => return self.load8(addr),
             16 => return self.load16(addr),
@@ -56,23 +555,23 @@ impl MEMORY {
     }
 
     // load funcs
-    fn load8(self, addr: u32) -> u32 {
-        let index = (addr - MEM_BASE) as usize;
+    fn load8(&self, addr: u32) -> u32 {
+        let index = (addr - self.base) as usize;
         return self.mem[index] as u32;
     }
-    fn load16(self, addr: u32) -> u32 {
-        let index = (addr - MEM_BASE) as usize;
+    fn load16(&self, addr: u32) -> u32 {
+        let index = (addr - self.base) as usize;
         return self.mem[index] as u32 | ((self.mem[index + 1] as u32) << 8);
     }
-    fn load32(self, addr: u32) -> u32 {
-        let index = (addr - MEM_BASE) as usize;
+    fn load32(&self, addr: u32) -> u32 {
+        let index = (addr - self.base) as usize;
         return self.mem[index] as u32
             | ((self.mem[index + 1] as u32) << 8)
             | ((self.mem[index + 2] as u32) << 16)
             | ((self.mem[index + 3] as u32) << 24);
     }
     // fn load64(self, addr: u32) -> u32 {
-    //     let index = (addr - MEM_BASE) as usize;
+    //     let index = (addr - self.base) as usize;
     //     return self.mem[index] as u32
     //         | ((self.mem[index + 1] as u32) << 8)
     //         | ((self.mem[index + 2] as u32) << 16)
@@ -85,23 +584,23 @@ impl MEMORY {
 
     // store funcs
     fn store8(&mut self, addr: u32, value: u32) {
-        let index = (addr - MEM_BASE) as usize;
+        let index = (addr - self.base) as usize;
         self.mem[index] = (value & (std::u8::MAX as u32)) as u8;
     }
     fn store16(&mut self, addr: u32, value: u32) {
-        let index = (addr - MEM_BASE) as usize;
+        let index = (addr - self.base) as usize;
         self.mem[index] = (value & (std::u8::MAX as u32)) as u8;
         self.mem[index + 1] = ((value >> 8) & (std::u8::MAX as u32)) as u8;
     }
     fn store32(&mut self, addr: u32, value: u32) {
-        let index = (addr - MEM_BASE) as usize;
+        let index = (addr - self.base) as usize;
         self.mem[index] = (value & (std::u8::MAX as u32)) as u8;
         self.mem[index + 1] = ((value >> 8) & (std::u8::MAX as u32)) as u8;
         self.mem[index + 2] = ((value >> 16) & (std::u8::MAX as u32)) as u8;
         self.mem[index + 3] = ((value >> 24) & (std::u8::MAX as u32)) as u8;
     }
     // fn store64(&mut self, addr: u32, value: u32) {
-    //     let index = (addr - MEM_BASE) as usize;
+    //     let index = (addr - self.base) as usize;
     //     self.mem[index] = (value & (std::u8::MAX as u32)) as u8;
     //     self.mem[index + 1] = ((value >> 8) & (std::u8::MAX as u32)) as u8;
     //     self.mem[index + 2] = ((value >> 16) & (std::u8::MAX as u32)) as u8;
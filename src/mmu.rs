@@ -0,0 +1,167 @@
+// Sv32 address translation: the two-level page table walk from satp, and a
+// small direct-mapped TLB of the leaves it found. Permissions are checked
+// against the cached leaf on every access, so only satp writes and
+// sfence.vma flush the TLB; there are no ASIDs to tell address spaces
+// apart. The walk sets the accessed and dirty bits itself, as Svadu
+// hardware does, rather than raising a page fault for software to set them.
+use crate::cpu::{Fault, Privilege};
+use crate::csr;
+use crate::memory::BUS;
+
+pub const PAGE_SHIFT: u32 = 12;
+pub const PAGE_SIZE: u32 = 1 << PAGE_SHIFT;
+
+const TLB_SIZE: usize = 64;
+
+// page table entry bits
+const PTE_V: u32 = 1 << 0;
+const PTE_R: u32 = 1 << 1;
+const PTE_W: u32 = 1 << 2;
+const PTE_X: u32 = 1 << 3;
+const PTE_U: u32 = 1 << 4;
+const PTE_A: u32 = 1 << 6;
+const PTE_D: u32 = 1 << 7;
+
+// what a virtual address is translated for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Fetch,
+    Load,
+    // stores and AMOs
+    Store,
+}
+
+impl Access {
+    fn page_fault(self, vaddr: u32) -> Fault {
+        match self {
+            Access::Fetch => Fault::FetchPage(vaddr),
+            Access::Load => Fault::LoadPage(vaddr),
+            Access::Store => Fault::StorePage(vaddr),
+        }
+    }
+
+    // a page table entry outside of memory
+    fn access_fault(self, vaddr: u32) -> Fault {
+        match self {
+            Access::Fetch => Fault::FetchAccess(vaddr),
+            Access::Load => Fault::LoadAccess(vaddr),
+            Access::Store => Fault::StoreAccess(vaddr),
+        }
+    }
+}
+
+// a leaf, for the 4 KiB page at `vpn` even when it maps a megapage
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    vpn: u32,
+    pte: u32,
+    ppn: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Tlb {
+    entries: [Option<Entry>; TLB_SIZE],
+}
+
+impl Default for Tlb {
+    fn default() -> Self {
+        Tlb {
+            entries: [None; TLB_SIZE],
+        }
+    }
+}
+
+impl Tlb {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn flush(&mut self) {
+        self.entries = [None; TLB_SIZE];
+    }
+
+    // the physical address `vaddr` maps to through the page table satp
+    // points at, for an access at `privilege`; `status` is mstatus, for SUM
+    // and MXR
+    pub fn translate(
+        &mut self,
+        bus: &mut BUS,
+        satp: u32,
+        status: u32,
+        privilege: Privilege,
+        vaddr: u32,
+        access: Access,
+    ) -> Result<u32, Fault> {
+        let vpn = vaddr >> PAGE_SHIFT;
+        let offset = vaddr & (PAGE_SIZE - 1);
+        let slot = vpn as usize % TLB_SIZE;
+        if let Some(entry) = self.entries[slot].filter(|entry| entry.vpn == vpn) {
+            if !permits(entry.pte, status, privilege, access) {
+                return Err(access.page_fault(vaddr));
+            }
+            // the first store to a clean page goes back to the table
+            if access != Access::Store || entry.pte & PTE_D != 0 {
+                return Ok(entry.ppn << PAGE_SHIFT | offset);
+            }
+        }
+        let mut table = ((satp & csr::SATP_PPN) as u64) << PAGE_SHIFT;
+        for level in [1, 0] {
+            let index = (vpn >> (10 * level)) & 0x3ff;
+            let at = u32::try_from(table + 4 * index as u64).ok();
+            let Some((at, mut pte)) = at.and_then(|at| Some((at, bus.try_load(at, 32)?))) else {
+                return Err(access.access_fault(vaddr));
+            };
+            if pte & PTE_V == 0 || pte & (PTE_R | PTE_W) == PTE_W {
+                break;
+            }
+            let ppn = pte >> 10;
+            // a pointer to the next level
+            if pte & (PTE_R | PTE_X) == 0 {
+                table = (ppn as u64) << PAGE_SHIFT;
+                continue;
+            }
+            // megapages are aligned to 4 MiB
+            if !permits(pte, status, privilege, access) || level == 1 && ppn & 0x3ff != 0 {
+                break;
+            }
+            let used = match access {
+                Access::Store => PTE_A | PTE_D,
+                _ => PTE_A,
+            };
+            if pte & used != used {
+                pte |= used;
+                if bus.try_store(at, 32, pte).is_none() {
+                    return Err(access.access_fault(vaddr));
+                }
+            }
+            let ppn = match level {
+                1 => ppn | vpn & 0x3ff,
+                _ => ppn,
+            };
+            // a physical address above 4 GiB, which nothing answers at
+            if ppn >> (32 - PAGE_SHIFT) != 0 {
+                return Err(access.access_fault(vaddr));
+            }
+            self.entries[slot] = Some(Entry { vpn, pte, ppn });
+            return Ok(ppn << PAGE_SHIFT | offset);
+        }
+        Err(access.page_fault(vaddr))
+    }
+}
+
+// whether the leaf `pte` allows `access` at `privilege`; supervisor mode
+// reaches user pages only for loads and stores, and only with mstatus.SUM
+fn permits(pte: u32, status: u32, privilege: Privilege, access: Access) -> bool {
+    let user = pte & PTE_U != 0;
+    let allowed = match privilege {
+        Privilege::User => user,
+        _ => !user || access != Access::Fetch && status & csr::MSTATUS_SUM != 0,
+    };
+    allowed
+        && match access {
+            Access::Fetch => pte & PTE_X != 0,
+            // MXR makes executable pages readable
+            Access::Load => pte & PTE_R != 0 || status & csr::MSTATUS_MXR != 0 && pte & PTE_X != 0,
+            Access::Store => pte & PTE_W != 0,
+        }
+}
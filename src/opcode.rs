@@ -1,3 +1,5 @@
+use crate::rvc;
+
 pub const LUI: u32 = 0x37;
 pub const AUIPC: u32 = 0x17;
 
@@ -52,8 +54,34 @@ pub const SRL: u32 = 0x00;
 pub const SRA: u32 = 0x20;
 pub const OR: u32 = 0x6;
 pub const AND: u32 = 0x7;
+// funct7 of the M extension, funct3 picks the operation
+pub const MULDIV: u32 = 0x01;
+pub const MUL: u32 = 0x0;
+pub const MULH: u32 = 0x1;
+pub const MULHSU: u32 = 0x2;
+pub const MULHU: u32 = 0x3;
+pub const DIV: u32 = 0x4;
+pub const DIVU: u32 = 0x5;
+pub const REM: u32 = 0x6;
+pub const REMU: u32 = 0x7;
 
 pub const FENCE: u32 = 0x0f;
+pub const FENCE_I: u32 = 0x1;
+
+pub const AMO: u32 = 0x2f;
+pub const AMO_W: u32 = 0x2;
+// funct5 in bits 31..27, aq and rl below it are ignored
+pub const AMOADD: u32 = 0x00;
+pub const AMOSWAP: u32 = 0x01;
+pub const LR: u32 = 0x02;
+pub const SC: u32 = 0x03;
+pub const AMOXOR: u32 = 0x04;
+pub const AMOOR: u32 = 0x08;
+pub const AMOAND: u32 = 0x0c;
+pub const AMOMIN: u32 = 0x10;
+pub const AMOMAX: u32 = 0x14;
+pub const AMOMINU: u32 = 0x18;
+pub const AMOMAXU: u32 = 0x1c;
 
 // pub const I_TYPE_64: u32 = 0x1b;
 // pub const ADDIW: u32 = 0x0;
@@ -79,6 +107,11 @@ pub const FENCE: u32 = 0x0f;
 pub const CSR: u32 = 0x73;
 pub const ECALL: u32 = 0x00;
 pub const EBREAK: u32 = 0x00;
+// funct12 of the privileged instructions, funct7 of sfence.vma
+pub const SRET: u32 = 0x102;
+pub const WFI: u32 = 0x105;
+pub const MRET: u32 = 0x302;
+pub const SFENCE_VMA: u32 = 0x09;
 pub const CSRRW: u32 = 0x01;
 pub const CSRRS: u32 = 0x02;
 pub const CSRRC: u32 = 0x03;
@@ -96,6 +129,10 @@ pub fn rs2(instr: u32) -> u32 {
     return (instr >> 20) & 0x1f; // rs2 in bits 24..20
 }
 
+pub fn funct5(instr: u32) -> u32 {
+    instr >> 27 // funct5 in bits 31..27
+}
+
 pub fn shamt(instr: u32) -> u32 {
     // shamt[4:5] = imm[5:0]
     return (imm_i(instr) & 0x1f) as u32;
@@ -116,7 +153,7 @@ pub fn imm_b(instr: u32) -> u32 {
 
 pub fn imm_s(instr: u32) -> u32 {
     // imm[11:5] = inst[31:25], imm[4:0] = inst[11:7]
-    return ((instr & 0xfe000000) >> 20) as u32 | ((instr >> 7) & 0x1f) as u32;
+    return ((instr & 0xfe000000) as i32 >> 20) as u32 | ((instr >> 7) & 0x1f) as u32;
 }
 
 pub fn imm_i(instr: u32) -> i32 {
@@ -132,12 +169,15 @@ pub fn imm_u(instr: u32) -> u32 {
 pub fn imm_j(instr: u32) -> u32 {
     // imm[20|10:1|11|19:12] = inst[31|30:21|20|19:12]
     return (((instr & 0x80000000) as i32 >> 11) as u32)// imm[20]
-    | ((instr & 0x3ff00000) >> 20) as u32 // imm[10:1]
-    | ((instr & 0x80000) >> 9) as u32 // imm[11]
+    | ((instr >> 20) & 0x7fe) as u32 // imm[10:1]
+    | ((instr >> 9) & 0x800) as u32 // imm[11]
     | (instr & 0xff000) as u32; // imm[19:12]
 }
 
 pub fn get_instr_name(instr: u32) -> String {
+    if rvc::is_compressed(instr) {
+        return format!("c.{}", get_instr_name(rvc::expand(instr)));
+    }
     let opcode = instr & 0x7f;
     let funct3 = (instr >> 12) & 0x7;
     let funct7 = (instr >> 25) & 0x7f;
@@ -188,6 +228,16 @@ pub fn get_instr_name(instr: u32) -> String {
                 panic!("malformed I type instruction");
             }
         },
+        R_TYPE if funct7 == MULDIV => match funct3 {
+            MUL => "mul".to_string(),
+            MULH => "mulh".to_string(),
+            MULHSU => "mulhsu".to_string(),
+            MULHU => "mulhu".to_string(),
+            DIV => "div".to_string(),
+            DIVU => "divu".to_string(),
+            REM => "rem".to_string(),
+            _ => "remu".to_string(),
+        },
         R_TYPE => match funct3 {
             ADDSUB => match funct7 {
                 ADD => "add".to_string(),
@@ -210,11 +260,31 @@ pub fn get_instr_name(instr: u32) -> String {
             }
         },
         FENCE => "fence".to_string(),
+        AMO => match funct5(instr) {
+            AMOADD => "amoadd.w".to_string(),
+            AMOSWAP => "amoswap.w".to_string(),
+            LR => "lr.w".to_string(),
+            SC => "sc.w".to_string(),
+            AMOXOR => "amoxor.w".to_string(),
+            AMOOR => "amoor.w".to_string(),
+            AMOAND => "amoand.w".to_string(),
+            AMOMIN => "amomin.w".to_string(),
+            AMOMAX => "amomax.w".to_string(),
+            AMOMINU => "amominu.w".to_string(),
+            AMOMAXU => "amomaxu.w".to_string(),
+            _ => panic!("malformed AMO instruction"),
+        },
         CSR => match (funct3) {
             ECALL | EBREAK => match imm_i(instr) {
                 0x0 => "ecall".to_string(),
                 0x1 => "ebreak".to_string(),
-                _ => "not ECALL/EBREAK".to_string(),
+                _ if funct7 == SFENCE_VMA => "sfence.vma".to_string(),
+                imm => match imm as u32 & 0xfff {
+                    SRET => "sret".to_string(),
+                    WFI => "wfi".to_string(),
+                    MRET => "mret".to_string(),
+                    _ => "not ECALL/EBREAK".to_string(),
+                },
             },
             CSRRW => "csrrw".to_string(),
             CSRRS => "csrrs".to_string(),
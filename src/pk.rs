@@ -0,0 +1,185 @@
+// riscv-pk style proxy syscalls for newlib/libgloss programs. The guest is
+// loaded like a Linux user program, only the syscall table differs.
+use crate::host::{self, OpenFlags, Stat};
+use crate::linux::{Process, GUEST_PID};
+
+// syscall numbers from libgloss/riscv/machine/syscall.h
+pub const SYS_GETCWD: u32 = 17;
+pub const SYS_FACCESSAT: u32 = 48;
+pub const SYS_OPENAT: u32 = 56;
+pub const SYS_CLOSE: u32 = 57;
+pub const SYS_LSEEK: u32 = 62;
+pub const SYS_READ: u32 = 63;
+pub const SYS_WRITE: u32 = 64;
+pub const SYS_FSTATAT: u32 = 79;
+pub const SYS_FSTAT: u32 = 80;
+pub const SYS_EXIT: u32 = 93;
+pub const SYS_EXIT_GROUP: u32 = 94;
+pub const SYS_TIMES: u32 = 153;
+pub const SYS_GETTIMEOFDAY: u32 = 169;
+pub const SYS_GETPID: u32 = 172;
+pub const SYS_BRK: u32 = 214;
+pub const SYS_OPEN: u32 = 1024;
+pub const SYS_UNLINK: u32 = 1026;
+pub const SYS_ACCESS: u32 = 1033;
+pub const SYS_STAT: u32 = 1038;
+pub const SYS_LSTAT: u32 = 1039;
+
+const AT_FDCWD: i32 = -100;
+
+// open(2) flags of newlib's sys/_default_fcntl.h, not the Linux ones
+const O_ACCMODE: u32 = 0x3;
+const O_WRONLY: u32 = 0x1;
+const O_RDWR: u32 = 0x2;
+const O_APPEND: u32 = 0x8;
+const O_CREAT: u32 = 0x200;
+const O_TRUNC: u32 = 0x400;
+const O_EXCL: u32 = 0x800;
+
+// clock_t ticks per second reported by times()
+const CLOCKS_PER_SEC: u64 = 1_000_000;
+
+pub(crate) fn dispatch(p: &mut Process, nr: u32, a: [u32; 6]) -> Result<u32, i32> {
+    match nr {
+        SYS_EXIT | SYS_EXIT_GROUP => {
+            p.exit(a[0] as i32);
+            Ok(0)
+        }
+        SYS_READ => {
+            p.check_mem(a[1], a[2])?;
+            let buf = p.files.read(a[0], a[2] as usize)?;
+            p.write_mem(a[1], &buf)?;
+            Ok(buf.len() as u32)
+        }
+        SYS_WRITE => {
+            let buf = p.read_mem(a[1], a[2])?;
+            p.files.write(a[0], &buf).map(|n| n as u32)
+        }
+        SYS_OPEN => {
+            let path = p.read_cstr(a[0])?;
+            p.files.open(&path, open_flags(a[1]), a[2])
+        }
+        SYS_OPENAT => {
+            let path = p.read_path(a[0], a[1])?;
+            p.files.open(&path, open_flags(a[2]), a[3])
+        }
+        SYS_CLOSE => {
+            if a[0] <= 2 {
+                return Ok(0);
+            }
+            p.files.close(a[0]).map(|_| 0)
+        }
+        SYS_LSEEK => p
+            .files
+            .seek(a[0], a[1] as i32 as i64, a[2])
+            .map(|pos| pos as u32),
+        SYS_FSTAT => {
+            let st = p.files.stat(a[0])?;
+            p.write_mem(a[1], &kernel_stat_bytes(&st))?;
+            Ok(0)
+        }
+        SYS_STAT | SYS_LSTAT => {
+            let path = p.read_cstr(a[0])?;
+            let st = host::stat_path(&path)?;
+            p.write_mem(a[1], &kernel_stat_bytes(&st))?;
+            Ok(0)
+        }
+        SYS_FSTATAT => {
+            let path = p.read_path(a[0], a[1])?;
+            let st = host::stat_path(&path)?;
+            p.write_mem(a[2], &kernel_stat_bytes(&st))?;
+            Ok(0)
+        }
+        SYS_ACCESS | SYS_FACCESSAT => {
+            let path = match nr {
+                SYS_ACCESS => p.read_cstr(a[0])?,
+                _ => p.read_path(a[0], a[1])?,
+            };
+            host::stat_path(&path).map(|_| 0)
+        }
+        SYS_UNLINK => {
+            let path = p.read_cstr(a[0])?;
+            std::fs::remove_file(path).map_err(host::errno)?;
+            Ok(0)
+        }
+        SYS_GETCWD => {
+            let cwd = std::env::current_dir().map_err(host::errno)?;
+            let mut cwd = cwd.to_string_lossy().into_owned().into_bytes();
+            cwd.push(0);
+            if cwd.len() > a[1] as usize {
+                return Err(host::EINVAL);
+            }
+            p.write_mem(a[0], &cwd)?;
+            Ok(a[0])
+        }
+        SYS_GETTIMEOFDAY => {
+            // struct timeval with a 64bit time_t
+            let (sec, nsec) = host::now();
+            let mut buf = sec.to_le_bytes().to_vec();
+            buf.extend((nsec / 1000).to_le_bytes());
+            buf.extend([0; 4]);
+            p.write_mem(a[0], &buf)?;
+            Ok(0)
+        }
+        SYS_TIMES => {
+            let (sec, nsec) = host::now();
+            let ticks = (sec as u64 * CLOCKS_PER_SEC + nsec as u64 / 1000) as u32;
+            if a[0] != 0 {
+                let mut buf = ticks.to_le_bytes().to_vec();
+                buf.extend([0; 12]);
+                p.write_mem(a[0], &buf)?;
+            }
+            Ok(ticks)
+        }
+        SYS_GETPID => Ok(GUEST_PID),
+        SYS_BRK => p.brk(a[0]),
+        _ => Err(host::ENOSYS),
+    }
+}
+
+impl Process {
+    // only the current directory is supported as the base of relative paths
+    fn read_path(&self, dirfd: u32, path: u32) -> Result<String, i32> {
+        let path = self.read_cstr(path)?;
+        if dirfd as i32 != AT_FDCWD && !path.starts_with('/') {
+            return Err(host::EBADF);
+        }
+        Ok(path)
+    }
+}
+
+fn open_flags(flags: u32) -> OpenFlags {
+    let acc = flags & O_ACCMODE;
+    OpenFlags {
+        read: acc != O_WRONLY,
+        write: acc == O_WRONLY || acc == O_RDWR,
+        create: flags & O_CREAT != 0,
+        truncate: flags & O_TRUNC != 0,
+        append: flags & O_APPEND != 0,
+        exclusive: flags & O_EXCL != 0,
+    }
+}
+
+// struct kernel_stat of libgloss/riscv/kernel_stat.h, 128 bytes
+fn kernel_stat_bytes(st: &Stat) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(128);
+    buf.extend(st.dev.to_le_bytes());
+    buf.extend(st.ino.to_le_bytes());
+    buf.extend(st.mode.to_le_bytes());
+    buf.extend(st.nlink.to_le_bytes());
+    buf.extend(st.uid.to_le_bytes());
+    buf.extend(st.gid.to_le_bytes());
+    buf.extend(st.rdev.to_le_bytes());
+    buf.extend(0u64.to_le_bytes());
+    buf.extend(st.size.to_le_bytes());
+    buf.extend(st.blksize.to_le_bytes());
+    buf.extend(0u32.to_le_bytes());
+    buf.extend(st.blocks.to_le_bytes());
+    for (sec, nsec) in [st.atime, st.mtime, st.ctime] {
+        buf.extend(sec.to_le_bytes());
+        buf.extend(nsec.to_le_bytes());
+        buf.extend([0; 4]);
+    }
+    buf.extend([0; 8]);
+    buf
+}
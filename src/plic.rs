@@ -0,0 +1,146 @@
+// SiFive compatible platform-level interrupt controller with two contexts
+// per hart, machine mode and supervisor mode, as on QEMU's virt machine.
+// A source whose line is up becomes pending, a claim hands the most urgent
+// pending source enabled for the context over to it and completing it lets
+// the source become pending again. A context raises its hart's external
+// interrupt while a source enabled for it is pending with a priority above
+// its threshold.
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+use crate::csr::{IRQ_M_EXT, IRQ_S_EXT};
+use crate::device::Device;
+use crate::fdt::{self, Fdt, PLIC_PHANDLE};
+use crate::snapshot::{read_map, read_u32, write_map};
+
+pub const PLIC_SIZE: u32 = 0x600000;
+// sources 1 to NUM_SOURCES - 1, 0 means no interrupt
+pub const NUM_SOURCES: u32 = 32;
+
+const PENDING: u32 = 0x1000;
+const ENABLE_BASE: u32 = 0x2000;
+const ENABLE_STRIDE: u32 = 0x80;
+const CONTEXT_BASE: u32 = 0x200000;
+const CONTEXT_STRIDE: u32 = 0x1000;
+// offset of the claim/complete register in a context
+const CLAIM: u32 = 4;
+
+// contexts per hart, machine mode first
+const CONTEXTS: u32 = 2;
+
+#[derive(Debug, Clone, Default)]
+pub struct Plic {
+    // priority, enable and threshold words by offset
+    regs: HashMap<u32, u32>,
+    // sources waiting for a claim, bit n for source n
+    pending: u32,
+    // sources claimed and not completed yet
+    claimed: u32,
+}
+
+impl Plic {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn reg(&self, offset: u32) -> u32 {
+        self.regs.get(&offset).copied().unwrap_or(0)
+    }
+
+    // the pending source `context` would claim, with a priority above
+    // `threshold`
+    fn best(&self, context: u32, threshold: u32) -> Option<u32> {
+        let enabled = self.reg(ENABLE_BASE + context * ENABLE_STRIDE) & self.pending;
+        (1..NUM_SOURCES)
+            .filter(|source| enabled >> source & 1 != 0)
+            .map(|source| (self.reg(4 * source), source))
+            .filter(|(priority, _)| *priority > threshold)
+            // the highest priority, the lowest source among equals
+            .max_by_key(|(priority, source)| (*priority, !source))
+            .map(|(_, source)| source)
+    }
+
+    fn threshold(&self, context: u32) -> u32 {
+        self.reg(CONTEXT_BASE + context * CONTEXT_STRIDE)
+    }
+}
+
+impl Device for Plic {
+    fn load(&mut self, offset: u32, _size: u32) -> u32 {
+        match offset {
+            PENDING => self.pending,
+            o if (PENDING..PENDING + NUM_SOURCES / 8).contains(&o) => 0,
+            o if o >= CONTEXT_BASE && o % CONTEXT_STRIDE == CLAIM => {
+                let context = (o - CONTEXT_BASE) / CONTEXT_STRIDE;
+                let Some(source) = self.best(context, 0) else {
+                    return 0;
+                };
+                self.pending &= !(1 << source);
+                self.claimed |= 1 << source;
+                source
+            }
+            _ => self.reg(offset),
+        }
+    }
+
+    fn store(&mut self, offset: u32, _size: u32, value: u32) {
+        match offset {
+            o if (PENDING..PENDING + NUM_SOURCES / 8).contains(&o) => (),
+            o if o >= CONTEXT_BASE && o % CONTEXT_STRIDE == CLAIM => {
+                if value < NUM_SOURCES {
+                    self.claimed &= !(1 << value);
+                }
+            }
+            _ => {
+                self.regs.insert(offset, value);
+            }
+        }
+    }
+
+    fn irq_lines(&mut self, lines: u32) {
+        self.pending |= lines & !self.claimed & !1;
+    }
+
+    fn interrupts(&self, hart: u32) -> u32 {
+        let raised = |context| self.best(context, self.threshold(context)).is_some();
+        let context = hart * CONTEXTS;
+        (raised(context) as u32) << IRQ_M_EXT | (raised(context + 1) as u32) << IRQ_S_EXT
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        write_map(w, &self.regs)?;
+        w.write_all(&self.pending.to_le_bytes())?;
+        w.write_all(&self.claimed.to_le_bytes())
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.regs = read_map(r)?;
+        self.pending = read_u32(r)?;
+        self.claimed = read_u32(r)?;
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+
+    fn fdt_name(&self) -> &'static str {
+        "plic"
+    }
+
+    fn fdt_props(&self, fdt: &mut Fdt, harts: u32) {
+        fdt.prop_strs("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+        fdt.prop_u32("#address-cells", 0);
+        fdt.prop_u32("#interrupt-cells", 1);
+        fdt.prop_empty("interrupt-controller");
+        fdt.prop_u32("riscv,ndev", NUM_SOURCES - 1);
+        fdt.prop_u32("phandle", PLIC_PHANDLE);
+        let cells: Vec<u32> = (0..harts)
+            .flat_map(|hart| {
+                let intc = fdt::intc_phandle(hart);
+                [intc, IRQ_M_EXT, intc, IRQ_S_EXT]
+            })
+            .collect();
+        fdt.prop_cells("interrupts-extended", &cells);
+    }
+}
@@ -0,0 +1,169 @@
+// Instruction level profiler. Every retired instruction is counted against its
+// pc and against the current call stack, which is tracked from jal/jalr that
+// link through ra (or t0) and from `jalr x0, 0(ra)` returns.
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::elf::Symbols;
+use crate::opcode::{rd, rs1, JAL, JALR};
+use crate::rvc;
+
+// x1 and x5 are the link registers of the calling convention
+fn is_link(reg: u32) -> bool {
+    reg == 1 || reg == 5
+}
+
+// one node of the call tree, a function entered from its parent frame
+#[derive(Debug, Clone)]
+struct Frame {
+    func: u32,
+    parent: usize,
+    children: HashMap<u32, usize>,
+    count: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    symbols: Symbols,
+    pc_counts: HashMap<u32, u64>,
+    frames: Vec<Frame>,
+    current: usize,
+    total: u64,
+}
+
+impl Profiler {
+    pub fn new(symbols: Symbols) -> Self {
+        Profiler {
+            symbols,
+            pc_counts: HashMap::new(),
+            frames: vec![Frame {
+                func: 0,
+                parent: 0,
+                children: HashMap::new(),
+                count: 0,
+            }],
+            current: 0,
+            total: 0,
+        }
+    }
+
+    // account for `instr` retired at `pc`, `next_pc` is where execution went on
+    pub fn retire(&mut self, pc: u32, instr: u32, next_pc: u32) {
+        if self.total == 0 {
+            self.frames[0].func = self.func_of(pc);
+        }
+        self.total += 1;
+        *self.pc_counts.entry(pc).or_insert(0) += 1;
+        self.frames[self.current].count += 1;
+
+        let instr = rvc::expand(instr);
+        let opcode = instr & 0x7f;
+        if opcode != JAL && opcode != JALR {
+            return;
+        }
+        if is_link(rd(instr)) {
+            let func = self.func_of(next_pc);
+            self.current = self.enter(func);
+        } else if opcode == JALR && rd(instr) == 0 && is_link(rs1(instr)) {
+            self.current = self.frames[self.current].parent;
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn pc_count(&self, pc: u32) -> u64 {
+        self.pc_counts.get(&pc).copied().unwrap_or(0)
+    }
+
+    // instructions retired inside each function, hottest first
+    pub fn function_counts(&self) -> Vec<(String, u64)> {
+        let mut funcs: HashMap<String, u64> = HashMap::new();
+        for (pc, count) in &self.pc_counts {
+            let name = match self.symbols.lookup(*pc) {
+                Some((sym, _)) => sym.name.clone(),
+                None => format!("{:#x}", pc),
+            };
+            *funcs.entry(name).or_insert(0) += count;
+        }
+        let mut funcs: Vec<_> = funcs.into_iter().collect();
+        funcs.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        funcs
+    }
+
+    // top-N functions and pcs by retired instructions
+    pub fn report(&self, top: usize) -> String {
+        let pct = |n: u64| n as f64 * 100.0 / self.total.max(1) as f64;
+        let mut out = String::new();
+        writeln!(out, "instructions retired: {}", self.total).unwrap();
+        writeln!(out, "{:>8} {:>12}  function", "self%", "count").unwrap();
+        for (name, count) in self.function_counts().iter().take(top) {
+            writeln!(out, "{:>7.2}% {:>12}  {}", pct(*count), count, name).unwrap();
+        }
+        let mut pcs: Vec<_> = self.pc_counts.iter().collect();
+        pcs.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        writeln!(out, "{:>8} {:>12}  pc", "self%", "count").unwrap();
+        for (pc, count) in pcs.iter().take(top) {
+            let name = self.symbols.describe(**pc);
+            writeln!(
+                out,
+                "{:>7.2}% {:>12}  {:#x} <{}>",
+                pct(**count),
+                count,
+                pc,
+                name
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    // one "outer;inner;leaf count" line per distinct stack, the format read
+    // by flamegraph.pl and inferno
+    pub fn collapsed(&self) -> String {
+        let mut lines = Vec::new();
+        for (idx, frame) in self.frames.iter().enumerate() {
+            if frame.count == 0 {
+                continue;
+            }
+            let mut names = Vec::new();
+            let mut i = idx;
+            loop {
+                names.push(self.symbols.describe(self.frames[i].func));
+                if i == 0 {
+                    break;
+                }
+                i = self.frames[i].parent;
+            }
+            names.reverse();
+            lines.push(format!("{} {}", names.join(";"), frame.count));
+        }
+        lines.sort();
+        let mut out = lines.join("\n");
+        out.push('\n');
+        out
+    }
+
+    fn func_of(&self, pc: u32) -> u32 {
+        match self.symbols.lookup(pc) {
+            Some((sym, _)) => sym.addr,
+            None => pc,
+        }
+    }
+
+    fn enter(&mut self, func: u32) -> usize {
+        if let Some(idx) = self.frames[self.current].children.get(&func) {
+            return *idx;
+        }
+        let idx = self.frames.len();
+        self.frames.push(Frame {
+            func,
+            parent: self.current,
+            children: HashMap::new(),
+            count: 0,
+        });
+        self.frames[self.current].children.insert(func, idx);
+        idx
+    }
+}
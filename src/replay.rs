@@ -0,0 +1,196 @@
+// Time travel for bare-metal guests. A checkpoint of the machine is taken
+// every `interval` instructions; going back restores the nearest earlier
+// checkpoint and re-executes forward, which reproduces the past exactly as
+// long as the guest is deterministic. Host side effects of re-executed
+// instructions, e.g. semihosting console output, do happen again. The
+// devices are polled every POLL_INTERVAL recorded instructions, so their DMA
+// happens at the same points when the past is executed again.
+//
+// Only MAX_CHECKPOINTS are kept: once there are more, every other one is
+// dropped and the interval doubles, so a long recording costs as much memory
+// as a short one and going back gets slower instead.
+use crate::cpu::CPU;
+use crate::device::POLL_INTERVAL;
+use crate::snapshot;
+
+pub const DEFAULT_INTERVAL: u64 = 10_000;
+
+const MAX_CHECKPOINTS: usize = 32;
+
+#[derive(Debug, Clone)]
+pub struct Replay {
+    interval: u64,
+    // (instruction count, snapshot) in increasing count order
+    checkpoints: Vec<(u64, Vec<u8>)>,
+    // instructions executed since the recording started
+    icount: u64,
+}
+
+impl Replay {
+    // start recording at the current state of `cpu`
+    pub fn new(cpu: &CPU, interval: u64) -> Self {
+        let mut replay = Replay {
+            interval: interval.max(1),
+            checkpoints: Vec::new(),
+            icount: 0,
+        };
+        replay.checkpoint(cpu);
+        replay
+    }
+
+    pub fn icount(&self) -> u64 {
+        self.icount
+    }
+
+    // how many checkpoints are kept
+    pub fn checkpoints(&self) -> usize {
+        self.checkpoints.len()
+    }
+
+    // execute one instruction going forward, a faulting one does not count
+    pub fn step(&mut self, cpu: &mut CPU) {
+        let retired = cpu.step();
+        self.icount += retired;
+        if retired > 0 && self.icount.is_multiple_of(POLL_INTERVAL) {
+            cpu.bus.poll();
+        }
+        let recorded = self.checkpoints.last().map_or(0, |(n, _)| *n);
+        if self.icount.is_multiple_of(self.interval) && self.icount > recorded {
+            self.checkpoint(cpu);
+        }
+    }
+
+    // the state was changed behind the guest's back, e.g. a debugger wrote a
+    // register, so whatever was recorded after this point no longer happens
+    pub fn diverge(&mut self, cpu: &CPU) {
+        let icount = self.icount;
+        self.checkpoints.retain(|(n, _)| *n < icount);
+        self.checkpoint(cpu);
+    }
+
+    // move to the state after `target` instructions, forward or backward
+    pub fn seek(&mut self, cpu: &mut CPU, target: u64) {
+        if target < self.icount {
+            self.restore(cpu, self.checkpoint_before(target));
+        }
+        quietly(cpu, |cpu| {
+            while self.icount < target {
+                self.step(cpu);
+            }
+        });
+    }
+
+    // undo the last instruction, false at the start of the recording
+    pub fn reverse_step(&mut self, cpu: &mut CPU) -> bool {
+        if self.icount == 0 {
+            return false;
+        }
+        self.seek(cpu, self.icount - 1);
+        true
+    }
+
+    // run backwards until pc is on one of `breakpoints`, false when the start
+    // of the recording is reached first
+    pub fn reverse_continue(&mut self, cpu: &mut CPU, breakpoints: &[u32]) -> bool {
+        let end = self.icount;
+        match self.search_back(cpu, end, |cpu| breakpoints.contains(&cpu.pc)) {
+            Some(n) => {
+                self.seek(cpu, n);
+                true
+            }
+            None => {
+                self.seek(cpu, 0);
+                false
+            }
+        }
+    }
+
+    // (instruction number, pc) of the most recent instruction that wrote
+    // `addr`, directly or through a device's DMA or a host call it made; the
+    // machine is left where it was
+    pub fn last_write(&mut self, cpu: &mut CPU, addr: u32) -> Option<(u64, u32)> {
+        let end = self.icount;
+        cpu.bus.watch(Some(addr));
+        let found = self.search_back(cpu, end, |_| false);
+        cpu.bus.watch(None);
+        let pc = found.map(|n| {
+            self.seek(cpu, n);
+            cpu.pc
+        });
+        self.seek(cpu, end);
+        found.zip(pc)
+    }
+
+    // latest n < end whose state satisfies `pred` or whose instruction wrote
+    // the address the bus watches, searched one checkpoint interval at a time
+    // from the end, leaves the machine anywhere
+    fn search_back<F: FnMut(&CPU) -> bool>(
+        &mut self,
+        cpu: &mut CPU,
+        end: u64,
+        mut pred: F,
+    ) -> Option<u64> {
+        if end == 0 {
+            return None;
+        }
+        for idx in (0..=self.checkpoint_before(end - 1)).rev() {
+            let stop = self
+                .checkpoints
+                .get(idx + 1)
+                .map_or(end, |(n, _)| (*n).min(end));
+            self.restore(cpu, idx);
+            let mut found = None;
+            quietly(cpu, |cpu| {
+                while self.icount < stop {
+                    let (n, hits) = (self.icount, cpu.bus.watch_hits());
+                    let hit = pred(cpu);
+                    self.step(cpu);
+                    if hit || cpu.bus.watch_hits() != hits {
+                        found = Some(n);
+                    }
+                }
+            });
+            if found.is_some() {
+                return found;
+            }
+        }
+        None
+    }
+
+    fn checkpoint(&mut self, cpu: &CPU) {
+        let mut data = Vec::new();
+        snapshot::save(cpu, &mut data).expect("writing to memory cannot fail");
+        self.checkpoints.push((self.icount, data));
+        if self.checkpoints.len() > MAX_CHECKPOINTS {
+            // the first and the newest stay
+            let mut i = 0;
+            self.checkpoints.retain(|_| {
+                i += 1;
+                i % 2 == 1
+            });
+            self.interval *= 2;
+        }
+    }
+
+    fn checkpoint_before(&self, target: u64) -> usize {
+        self.checkpoints.partition_point(|(n, _)| *n <= target) - 1
+    }
+
+    fn restore(&mut self, cpu: &mut CPU, idx: usize) {
+        let (n, data) = &self.checkpoints[idx];
+        let n = *n;
+        snapshot::restore(cpu, &mut data.as_slice()).expect("corrupt checkpoint");
+        self.icount = n;
+    }
+}
+
+// re-executed instructions are not traced nor counted twice by the analyses
+fn quietly<F: FnOnce(&mut CPU)>(cpu: &mut CPU, f: F) {
+    let trace = std::mem::replace(&mut cpu.trace, false);
+    let profiler = cpu.profiler.take();
+    let coverage = cpu.coverage.take();
+    f(cpu);
+    cpu.trace = trace;
+    cpu.profiler = profiler;
+    cpu.coverage = coverage;
+}
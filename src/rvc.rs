@@ -0,0 +1,181 @@
+// The C extension. A compressed instruction is 16 bits, told apart from the
+// full ones by its two low bits not being 11, and is carried out as the full
+// instruction it stands for (RISC-V unprivileged spec, chapter "C"). Blocks
+// and traps keep the 16-bit form, so lengths and mtval stay right. The
+// floating point loads and stores expand to nothing, there is no F or D.
+use crate::opcode::*;
+
+pub fn is_compressed(instr: u32) -> bool {
+    instr & 0x3 != 0x3
+}
+
+// length in bytes of the instruction that starts with `instr`
+pub fn len(instr: u32) -> u32 {
+    match is_compressed(instr) {
+        true => 2,
+        false => 4,
+    }
+}
+
+// the full instruction for a compressed one, 0 (illegal either way) for the
+// reserved and unsupported encodings; full instructions come back as they are
+pub fn expand(instr: u32) -> u32 {
+    if !is_compressed(instr) {
+        return instr;
+    }
+    let c = instr & 0xffff;
+    let bit = |n: u32| (c >> n) & 1;
+    let bits = |hi: u32, lo: u32| (c >> lo) & ((1 << (hi - lo + 1)) - 1);
+    // registers x8-x15 of the 3-bit fields
+    let rd_ = bits(4, 2) + 8;
+    let rs1_ = bits(9, 7) + 8;
+    let rd = bits(11, 7);
+    let rs2 = bits(6, 2);
+    // 6-bit immediate of c.addi, c.li, c.andi, sign extended
+    let imm6 = ((bit(12) << 5 | bits(6, 2)) as i32) << 26 >> 26;
+    let shamt = bits(6, 2);
+    // offsets of c.lw and c.sw
+    let offset_w = bits(12, 10) << 3 | bit(6) << 2 | bit(5) << 6;
+
+    match (c & 0x3, bits(15, 13)) {
+        // c.addi4spn
+        (0, 0) => {
+            let imm = bits(12, 11) << 4 | bits(10, 7) << 6 | bit(6) << 2 | bit(5) << 3;
+            match imm {
+                0 => 0,
+                _ => i_type(imm as i32, 2, ADDI, rd_, I_TYPE),
+            }
+        }
+        (0, 2) => i_type(offset_w as i32, rs1_, LW, rd_, LOAD),
+        (0, 6) => s_type(offset_w as i32, rd_, rs1_, SW),
+        // c.addi, c.nop
+        (1, 0) => i_type(imm6, rd, ADDI, rd, I_TYPE),
+        (1, 1) => j_type(offset_j(c), 1),
+        // c.li
+        (1, 2) => i_type(imm6, 0, ADDI, rd, I_TYPE),
+        (1, 3) if rd == 2 => {
+            let imm = bit(12) << 9 | bit(6) << 4 | bit(5) << 6 | bits(4, 3) << 7 | bit(2) << 5;
+            match imm {
+                0 => 0,
+                _ => i_type((imm as i32) << 22 >> 22, 2, ADDI, 2, I_TYPE),
+            }
+        }
+        // c.lui
+        (1, 3) => match imm6 {
+            0 => 0,
+            _ => (imm6 as u32) << 12 | rd << 7 | LUI,
+        },
+        (1, 4) => match (bits(11, 10), bit(12)) {
+            // shifts by 32 and more are for RV64
+            (0 | 1, 1) => 0,
+            (0, _) => i_type(shamt as i32, rs1_, SRI, rs1_, I_TYPE),
+            (1, _) => i_type((SRAI << 5 | shamt) as i32, rs1_, SRI, rs1_, I_TYPE),
+            (2, _) => i_type(imm6, rs1_, ANDI, rs1_, I_TYPE),
+            (_, 1) => 0,
+            _ => {
+                let (funct7, funct3) = match bits(6, 5) {
+                    0 => (SUB, ADDSUB),
+                    1 => (0, XOR),
+                    2 => (0, OR),
+                    _ => (0, AND),
+                };
+                r_type(funct7, rd_, rs1_, funct3, rs1_)
+            }
+        },
+        // c.j
+        (1, 5) => j_type(offset_j(c), 0),
+        (1, 6) => b_type(offset_b(c), 0, rs1_, BEQ),
+        (1, 7) => b_type(offset_b(c), 0, rs1_, BNE),
+        (2, 0) => match bit(12) {
+            0 => i_type(shamt as i32, rd, SLLI, rd, I_TYPE),
+            _ => 0,
+        },
+        // c.lwsp
+        (2, 2) => match rd {
+            0 => 0,
+            _ => i_type(
+                (bit(12) << 5 | bits(6, 4) << 2 | bits(3, 2) << 6) as i32,
+                2,
+                LW,
+                rd,
+                LOAD,
+            ),
+        },
+        (2, 4) => match (bit(12), rd, rs2) {
+            // c.jr
+            (0, 0, 0) => 0,
+            (0, _, 0) => i_type(0, rd, 0, 0, JALR),
+            // c.mv
+            (0, _, _) => r_type(ADD, rs2, 0, ADDSUB, rd),
+            // c.ebreak
+            (_, 0, 0) => 0x00100073,
+            // c.jalr
+            (_, _, 0) => i_type(0, rd, 0, 1, JALR),
+            // c.add
+            _ => r_type(ADD, rs2, rd, ADDSUB, rd),
+        },
+        // c.swsp
+        (2, 6) => s_type((bits(12, 9) << 2 | bits(8, 7) << 6) as i32, rs2, 2, SW),
+        _ => 0,
+    }
+}
+
+// offset of c.j and c.jal
+fn offset_j(c: u32) -> i32 {
+    let bit = |n: u32| (c >> n) & 1;
+    let offset = bit(12) << 11
+        | bit(11) << 4
+        | (c >> 9 & 0x3) << 8
+        | bit(8) << 10
+        | bit(7) << 6
+        | bit(6) << 7
+        | (c >> 3 & 0x7) << 1
+        | bit(2) << 5;
+    (offset as i32) << 20 >> 20
+}
+
+// offset of c.beqz and c.bnez
+fn offset_b(c: u32) -> i32 {
+    let bit = |n: u32| (c >> n) & 1;
+    let offset = bit(12) << 8
+        | (c >> 10 & 0x3) << 3
+        | (c >> 5 & 0x3) << 6
+        | (c >> 3 & 0x3) << 1
+        | bit(2) << 5;
+    (offset as i32) << 23 >> 23
+}
+
+fn i_type(imm: i32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (imm as u32 & 0xfff) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn s_type(imm: i32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    let imm = imm as u32;
+    (imm >> 5 & 0x7f) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm & 0x1f) << 7 | S_TYPE
+}
+
+fn b_type(imm: i32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    let imm = imm as u32;
+    (imm >> 12 & 1) << 31
+        | (imm >> 5 & 0x3f) << 25
+        | rs2 << 20
+        | rs1 << 15
+        | funct3 << 12
+        | (imm >> 1 & 0xf) << 8
+        | (imm >> 11 & 1) << 7
+        | B_TYPE
+}
+
+fn j_type(imm: i32, rd: u32) -> u32 {
+    let imm = imm as u32;
+    (imm >> 20 & 1) << 31
+        | (imm >> 1 & 0x3ff) << 21
+        | (imm >> 11 & 1) << 20
+        | (imm >> 12 & 0xff) << 12
+        | rd << 7
+        | JAL
+}
+
+fn r_type(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | R_TYPE
+}
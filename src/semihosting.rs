@@ -0,0 +1,302 @@
+// RISC-V semihosting, the ARM semihosting operations reached through the
+// `slli x0, x0, 0x1f; ebreak; srai x0, x0, 7` sequence. a0 holds the
+// operation, a1 the parameter block and the result goes back to a0.
+use std::path::{Component, Path, PathBuf};
+use std::time::Instant;
+
+use crate::cpu::CPU;
+use crate::host::{self, FileTable, OpenFlags};
+
+pub const SLLI_X0_X0_0X1F: u32 = 0x01f01013;
+pub const EBREAK_INSTR: u32 = 0x00100073;
+pub const SRAI_X0_X0_7: u32 = 0x40705013;
+
+pub const SYS_OPEN: u32 = 0x01;
+pub const SYS_CLOSE: u32 = 0x02;
+pub const SYS_WRITEC: u32 = 0x03;
+pub const SYS_WRITE0: u32 = 0x04;
+pub const SYS_WRITE: u32 = 0x05;
+pub const SYS_READ: u32 = 0x06;
+pub const SYS_READC: u32 = 0x07;
+pub const SYS_ISERROR: u32 = 0x08;
+pub const SYS_ISTTY: u32 = 0x09;
+pub const SYS_SEEK: u32 = 0x0a;
+pub const SYS_FLEN: u32 = 0x0c;
+pub const SYS_REMOVE: u32 = 0x0e;
+pub const SYS_CLOCK: u32 = 0x10;
+pub const SYS_TIME: u32 = 0x11;
+pub const SYS_ERRNO: u32 = 0x13;
+pub const SYS_GET_CMDLINE: u32 = 0x15;
+pub const SYS_EXIT: u32 = 0x18;
+pub const SYS_EXIT_EXTENDED: u32 = 0x20;
+pub const SYS_ELAPSED: u32 = 0x30;
+pub const SYS_TICKFREQ: u32 = 0x31;
+
+// reason code of a normal program termination
+pub const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+
+// SYS_ELAPSED ticks per second
+const TICK_FREQ: u64 = 1_000_000;
+
+#[derive(Debug, Clone)]
+pub struct Semihosting {
+    files: FileTable,
+    cmdline: String,
+    // when set, guest paths are resolved inside this directory only
+    root: Option<PathBuf>,
+    start: Instant,
+    errno: i32,
+    pub exit_code: Option<i32>,
+}
+
+impl Semihosting {
+    pub fn new(cmdline: &str, root: Option<PathBuf>) -> Self {
+        Semihosting {
+            files: FileTable::new(),
+            cmdline: cmdline.to_string(),
+            root,
+            start: Instant::now(),
+            errno: 0,
+            exit_code: None,
+        }
+    }
+
+    // serve the request in a0/a1 and write the result to a0
+    pub fn call(&mut self, cpu: &mut CPU) {
+        let op = cpu.xregs.regs[10];
+        let arg = cpu.xregs.regs[11];
+        let ret = match self.dispatch(cpu, op, arg) {
+            Ok(val) => val,
+            Err(errno) => {
+                self.errno = errno;
+                u32::MAX
+            }
+        };
+        cpu.xregs.regs[10] = ret;
+    }
+
+    fn dispatch(&mut self, cpu: &mut CPU, op: u32, arg: u32) -> Result<u32, i32> {
+        // a bad parameter block fails the call instead of the emulator
+        let param = |i: u32| {
+            arg.checked_add(i * 4)
+                .and_then(|addr| cpu.bus.try_load(addr, 32))
+                .ok_or(host::EFAULT)
+        };
+        match op {
+            SYS_OPEN => {
+                let (name, mode, len) = (param(0)?, param(1)?, param(2)?);
+                let name = read_string(cpu, name, len)?;
+                // ":tt" is the console, the mode picks stdin, stdout or stderr
+                if name == ":tt" {
+                    return Ok(match mode {
+                        0..=3 => 0,
+                        4..=7 => 1,
+                        _ => 2,
+                    });
+                }
+                let path = self.resolve(&name)?;
+                self.files.open(&path, open_flags(mode)?, 0o644)
+            }
+            SYS_CLOSE => {
+                let fd = param(0)?;
+                if fd <= 2 {
+                    return Ok(0);
+                }
+                self.files.close(fd).map(|_| 0)
+            }
+            SYS_WRITEC => {
+                let c = cpu.bus.try_load(arg, 8).ok_or(host::EFAULT)? as u8;
+                self.files.write(1, &[c])?;
+                Ok(0)
+            }
+            SYS_WRITE0 => {
+                let s = read_cstr(cpu, arg)?;
+                self.files.write(1, &s)?;
+                Ok(0)
+            }
+            // both report the number of bytes *not* transferred
+            SYS_WRITE => {
+                let (fd, buf, len) = (param(0)?, param(1)?, param(2)?);
+                let data = read_bytes(cpu, buf, len)?;
+                let n = self.files.write(fd, &data)?;
+                Ok(len - n as u32)
+            }
+            SYS_READ => {
+                let (fd, buf, len) = (param(0)?, param(1)?, param(2)?);
+                read_bytes(cpu, buf, len)?;
+                let data = self.files.read(fd, len as usize)?;
+                cpu.bus.store_bytes(buf, &data).ok_or(host::EFAULT)?;
+                Ok(len - data.len() as u32)
+            }
+            SYS_READC => {
+                let c = self.files.read(0, 1)?;
+                Ok(c.first().copied().unwrap_or(0) as u32)
+            }
+            SYS_ISERROR => Ok(((param(0)? as i32) < 0) as u32),
+            SYS_ISTTY => {
+                let fd = param(0)?;
+                if !self.files.is_open(fd) {
+                    return Err(host::EBADF);
+                }
+                Ok(self.files.is_tty(fd) as u32)
+            }
+            SYS_SEEK => {
+                let (fd, pos) = (param(0)?, param(1)?);
+                self.files.seek(fd, pos as i64, host::SEEK_SET).map(|_| 0)
+            }
+            SYS_FLEN => self.files.stat(param(0)?).map(|st| st.size as u32),
+            SYS_REMOVE => {
+                let name = read_string(cpu, param(0)?, param(1)?)?;
+                let path = self.resolve(&name)?;
+                std::fs::remove_file(path).map_err(host::errno)?;
+                Ok(0)
+            }
+            // centiseconds since the program started
+            SYS_CLOCK => Ok((self.start.elapsed().as_millis() / 10) as u32),
+            SYS_TIME => Ok(host::now().0 as u32),
+            SYS_ERRNO => Ok(self.errno as u32),
+            SYS_GET_CMDLINE => {
+                let (buf, size) = (param(0)?, param(1)?);
+                let mut cmdline = self.cmdline.as_bytes().to_vec();
+                if cmdline.len() + 1 > size as usize {
+                    return Err(host::EINVAL);
+                }
+                cmdline.push(0);
+                cpu.bus.store_bytes(buf, &cmdline).ok_or(host::EFAULT)?;
+                cpu.bus
+                    .try_store(arg + 4, 32, cmdline.len() as u32 - 1)
+                    .ok_or(host::EFAULT)?;
+                Ok(0)
+            }
+            // on RV32 the reason is passed in a1 itself rather than a block
+            SYS_EXIT => {
+                self.exit_code = Some(match arg {
+                    ADP_STOPPED_APPLICATION_EXIT => 0,
+                    _ => 1,
+                });
+                Ok(0)
+            }
+            SYS_EXIT_EXTENDED => {
+                let (reason, code) = (param(0)?, param(1)?);
+                self.exit_code = Some(match reason {
+                    ADP_STOPPED_APPLICATION_EXIT => code as i32,
+                    _ => 1,
+                });
+                Ok(0)
+            }
+            SYS_ELAPSED => {
+                let ticks = self.start.elapsed().as_micros() as u64;
+                cpu.bus
+                    .store_bytes(arg, &ticks.to_le_bytes())
+                    .ok_or(host::EFAULT)?;
+                Ok(0)
+            }
+            SYS_TICKFREQ => Ok(TICK_FREQ as u32),
+            _ => Err(host::ENOSYS),
+        }
+    }
+
+    // keep the guest inside the sandbox root, if there is one; symlinks are
+    // followed, so one pointing out of the root is refused as well
+    fn resolve(&self, name: &str) -> Result<String, i32> {
+        let Some(root) = &self.root else {
+            return Ok(name.to_string());
+        };
+        let root = root.canonicalize().map_err(host::errno)?;
+        let mut path = root.clone();
+        for comp in Path::new(name).components() {
+            match comp {
+                Component::Normal(c) => path.push(c),
+                Component::RootDir | Component::CurDir => (),
+                Component::ParentDir | Component::Prefix(_) => return Err(host::EACCES),
+            }
+        }
+        // the part of the path that exists, with its links resolved, and the
+        // names that are still to be created below it
+        let mut missing = Vec::new();
+        let mut existing = path.as_path();
+        let real = loop {
+            if let Ok(real) = existing.canonicalize() {
+                break real;
+            }
+            // a dangling link would be followed by a create
+            if existing.symlink_metadata().is_ok() {
+                return Err(host::EACCES);
+            }
+            missing.push(existing.file_name().ok_or(host::EACCES)?);
+            existing = existing.parent().ok_or(host::EACCES)?;
+        };
+        if !real.starts_with(&root) {
+            return Err(host::EACCES);
+        }
+        let path = missing
+            .iter()
+            .rev()
+            .fold(real, |path, name| path.join(name));
+        Ok(path.to_string_lossy().into_owned())
+    }
+}
+
+// the instruction at pc is an ebreak wrapped in the semihosting marker nops
+pub fn is_semihosting_call(cpu: &CPU) -> bool {
+    let word = |addr: u32| {
+        cpu.bus
+            .load_bytes(addr, 4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    };
+    cpu.pc >= 4
+        && cpu.pc <= u32::MAX - 8
+        && word(cpu.pc - 4) == Some(SLLI_X0_X0_0X1F)
+        && word(cpu.pc) == Some(EBREAK_INSTR)
+        && word(cpu.pc + 4) == Some(SRAI_X0_X0_7)
+}
+
+// fopen() modes "r", "rb", "r+", "r+b", "w", ... "a+b" in that order
+fn open_flags(mode: u32) -> Result<OpenFlags, i32> {
+    let plus = mode & 0x2 != 0;
+    Ok(match mode >> 2 {
+        0 => OpenFlags {
+            read: true,
+            write: plus,
+            ..Default::default()
+        },
+        1 => OpenFlags {
+            read: plus,
+            write: true,
+            create: true,
+            truncate: true,
+            ..Default::default()
+        },
+        2 => OpenFlags {
+            read: plus,
+            write: true,
+            create: true,
+            append: true,
+            ..Default::default()
+        },
+        _ => return Err(host::EINVAL),
+    })
+}
+
+fn read_bytes(cpu: &CPU, addr: u32, len: u32) -> Result<Vec<u8>, i32> {
+    cpu.bus
+        .load_bytes(addr, len as usize)
+        .map(|b| b.to_vec())
+        .ok_or(host::EFAULT)
+}
+
+fn read_string(cpu: &CPU, addr: u32, len: u32) -> Result<String, i32> {
+    Ok(String::from_utf8_lossy(&read_bytes(cpu, addr, len)?).into_owned())
+}
+
+fn read_cstr(cpu: &CPU, addr: u32) -> Result<Vec<u8>, i32> {
+    let mut bytes = Vec::new();
+    loop {
+        let addr = addr.checked_add(bytes.len() as u32).ok_or(host::EFAULT)?;
+        let b = read_bytes(cpu, addr, 1)?[0];
+        if b == 0 {
+            return Ok(bytes);
+        }
+        bytes.push(b);
+    }
+}
@@ -0,0 +1,221 @@
+// Several harts sharing one bus. Every hart is a CPU of its own with its own
+// registers, pc, CSRs and block cache; the bus is handed to whichever hart
+// needs it, and a hart has memory to itself for as long as it holds it.
+// That is what makes the AMOs atomic; sc.w only stores while the word lr.w
+// read is still there.
+//
+// run() takes the harts round robin, a quantum of instructions each with the
+// bus, so the same guest and inputs always interleave the same way; a
+// reservation is dropped when the hart gives the bus away. run_threaded()
+// gives each hart a host thread and leaves the order to the host scheduler,
+// which shakes out interleavings a fixed schedule never produces. There the
+// bus sits behind a lock that a hart takes to enter a block and for each
+// instruction that reaches memory or devices, the others run on its
+// registers alone.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::cpu::{Fault, CPU};
+use crate::csr::MHARTID;
+use crate::device::Power;
+use crate::memory::BUS;
+use crate::opcode::{AUIPC, B_TYPE, I_TYPE, JAL, JALR, LUI, R_TYPE};
+use crate::rvc;
+
+pub const DEFAULT_QUANTUM: u64 = 1000;
+
+pub struct SMP {
+    pub harts: Vec<CPU>,
+    pub bus: BUS,
+    // instructions a hart runs before the next one gets the bus
+    pub quantum: u64,
+}
+
+impl SMP {
+    // `n` copies of `cpu` with mhartid 0 to n-1 and their hartid in a0,
+    // sharing its bus
+    pub fn new(mut cpu: CPU, n: usize) -> Self {
+        let bus = std::mem::replace(&mut cpu.bus, BUS::new());
+        let harts = (0..n as u32)
+            .map(|hartid| {
+                let mut hart = cpu.clone();
+                hart.csrs.csrs[MHARTID as usize] = hartid;
+                hart.xregs.regs[10] = hartid;
+                hart
+            })
+            .collect();
+        SMP {
+            harts,
+            bus,
+            quantum: DEFAULT_QUANTUM,
+        }
+    }
+
+    // exit code of the first hart that asked to stop the machine, or of a
+    // power-off device
+    pub fn exit_code(&self) -> Option<i32> {
+        self.harts
+            .iter()
+            .find_map(exit_code)
+            .or(match self.bus.power() {
+                Some(Power::Off(code)) => Some(code),
+                _ => None,
+            })
+    }
+
+    // the first hart stuck on an instruction it cannot carry out
+    pub fn fault(&self) -> Option<(u32, Fault)> {
+        self.harts
+            .iter()
+            .find_map(|hart| Some((hart.hartid(), hart.fault?)))
+    }
+
+    // run every hart for up to `max` instructions, round robin, or until
+    // one exits or faults, and return the instructions retired by all of them
+    pub fn run(&mut self, max: u64) -> u64 {
+        let mut done = vec![0; self.harts.len()];
+        while !self.stopped() && done.iter().any(|d| *d < max) {
+            for (hart, done) in self.harts.iter_mut().zip(done.iter_mut()) {
+                if *done >= max {
+                    continue;
+                }
+                *done += run_quantum(hart, &mut self.bus, self.quantum.min(max - *done));
+                if stopped(hart) || self.bus.power().is_some() {
+                    break;
+                }
+            }
+        }
+        done.iter().sum()
+    }
+
+    // a hart exited or faulted, or a device powered the machine off or
+    // asked for a reset
+    fn stopped(&self) -> bool {
+        self.harts.iter().any(stopped) || self.bus.power().is_some()
+    }
+
+    // like run(), but with every hart on its own host thread
+    pub fn run_threaded(&mut self, max: u64) -> u64 {
+        let bus = Mutex::new(std::mem::replace(&mut self.bus, BUS::new()));
+        let stop = AtomicBool::new(false);
+        let quantum = self.quantum;
+        let total = thread::scope(|s| {
+            let threads: Vec<_> = self
+                .harts
+                .iter_mut()
+                .map(|hart| {
+                    let (bus, stop) = (&bus, &stop);
+                    s.spawn(move || {
+                        let mut done = 0;
+                        while done < max && !stop.load(Ordering::Relaxed) {
+                            done += run_quantum_shared(hart, bus, quantum.min(max - done));
+                            if stopped(hart) || bus.lock().unwrap().power().is_some() {
+                                stop.store(true, Ordering::Relaxed);
+                            }
+                        }
+                        done
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).sum()
+        });
+        self.bus = bus.into_inner().unwrap();
+        total
+    }
+}
+
+// let `hart` have the bus for about `quantum` instructions, whole blocks at a
+// time, then poll the devices, and return how many it retired
+fn run_quantum(hart: &mut CPU, bus: &mut BUS, quantum: u64) -> u64 {
+    std::mem::swap(&mut hart.bus, bus);
+    let mut retired = 0;
+    while retired < quantum && !stopped(hart) && hart.bus.power().is_none() {
+        retired += hart.run_block();
+    }
+    std::mem::swap(&mut hart.bus, bus);
+    hart.reservation = None;
+    bus.poll();
+    retired
+}
+
+// run_quantum() for a hart on a thread of its own, which holds the lock on
+// the bus only to enter a block and for instructions that need the bus
+fn run_quantum_shared(hart: &mut CPU, bus: &Mutex<BUS>, quantum: u64) -> u64 {
+    let mut retired = 0;
+    while retired < quantum && !stopped(hart) {
+        let entered = lease(hart, &mut bus.lock().unwrap(), |hart| {
+            if hart.take_interrupt() {
+                return None;
+            }
+            let block = hart.enter_block();
+            Some((block, hart.bus.code_writes(), hart.bus.power().is_some()))
+        });
+        let Some((block, generation, off)) = entered else {
+            retired += 1;
+            continue;
+        };
+        if off {
+            break;
+        }
+        let Some(block) = block else {
+            retired += lease(hart, &mut bus.lock().unwrap(), CPU::step);
+            continue;
+        };
+        for &(instr, exec) in block.instrs.iter() {
+            let pc = hart.pc;
+            let mut stale = false;
+            if needs_bus(instr) {
+                let mut bus = bus.lock().unwrap();
+                lease(hart, &mut bus, |hart| hart.execute_decoded(instr, exec));
+                stale = bus.code_writes() != generation || bus.power().is_some();
+            } else {
+                hart.execute_decoded(instr, exec);
+            }
+            if hart.trapped {
+                retired += 1;
+                break;
+            }
+            if hart.fault.is_some() {
+                break;
+            }
+            hart.retire(pc, instr);
+            retired += 1;
+            // as in CPU::run_block(), but code may also have changed under
+            // the other harts
+            if hart.pc != pc.wrapping_add(rvc::len(instr)) || stale {
+                break;
+            }
+        }
+    }
+    lease(hart, &mut bus.lock().unwrap(), |hart| hart.bus.poll());
+    retired
+}
+
+// run `f` with the shared bus swapped into `hart`; the instructions the hart
+// retired on its own since it last had the bus count on it now
+fn lease<T>(hart: &mut CPU, bus: &mut BUS, f: impl FnOnce(&mut CPU) -> T) -> T {
+    bus.count_retired(hart.bus.instret());
+    hart.bus.set_instret(0);
+    std::mem::swap(&mut hart.bus, bus);
+    let result = f(hart);
+    std::mem::swap(&mut hart.bus, bus);
+    result
+}
+
+// everything but the instructions that only work on registers and pc
+fn needs_bus(instr: u32) -> bool {
+    !matches!(
+        rvc::expand(instr) & 0x7f,
+        LUI | AUIPC | JAL | JALR | B_TYPE | I_TYPE | R_TYPE
+    )
+}
+
+fn exit_code(hart: &CPU) -> Option<i32> {
+    hart.semihosting.as_ref().and_then(|sh| sh.exit_code)
+}
+
+// exited or faulted, either stops the whole machine
+fn stopped(hart: &CPU) -> bool {
+    exit_code(hart).is_some() || hart.fault.is_some()
+}
@@ -0,0 +1,317 @@
+// Machine state snapshots. A snapshot is a magic and version followed by
+// tagged sections, so newer sections can be added without breaking old files:
+//
+//   "RISCLAND" version:u32
+//   { tag:[u8; 4] len:u64 payload }*   ends with the "END\0" section
+//
+// All integers are little endian. Sections are read straight from the file,
+// so a length gone wrong costs a truncated read and not an allocation.
+// Memories and devices are restored into a machine made from the same board:
+// what the configuration says, e.g. a disk image or the size of a ROM, is
+// not saved, and neither is host side state such as open semihosting files.
+// Version 2 added the memories besides the main RAM, the devices and the
+// privilege level.
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+use crate::cpu::{Privilege, CPU};
+use crate::csr::{CSRS, MISA, NUM_CSRS};
+use crate::memory::{self, Backing};
+
+pub const MAGIC: &[u8; 8] = b"RISCLAND";
+pub const VERSION: u32 = 2;
+
+// pc followed by x0-x31
+const TAG_REGS: &[u8; 4] = b"REGS";
+// (number, value) pairs of the CSRs that are not zero
+const TAG_CSRS: &[u8; 4] = b"CSR\0";
+// RAM base and size, then (page index, page) records of non-zero pages,
+// terminated by a u32::MAX index
+const TAG_RAM: &[u8; 4] = b"RAM\0";
+// the same for every other RAM and ROM, after their index on the bus
+const TAG_MEMORY: &[u8; 4] = b"MEM\0";
+// base and name length:u8 + name of a device, then what its save() wrote
+const TAG_DEVICE: &[u8; 4] = b"DEV\0";
+// privilege level the hart runs at, a byte
+const TAG_PRIVILEGE: &[u8; 4] = b"PRIV";
+// instructions retired on the bus, the clock of virtual time devices
+const TAG_TIME: &[u8; 4] = b"TIME";
+// address and word of the lr.w reservation, only there while one is held
+const TAG_RESERVATION: &[u8; 4] = b"LRSC";
+const TAG_END: &[u8; 4] = b"END\0";
+
+const PAGE_SIZE: usize = 4096;
+const NO_PAGE: u32 = u32::MAX;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u32),
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "snapshot i/o error: {}", e),
+            SnapshotError::BadMagic => write!(f, "not a riscland snapshot"),
+            SnapshotError::UnsupportedVersion(v) => {
+                write!(f, "snapshot version {} is newer than {}", v, VERSION)
+            }
+            SnapshotError::Corrupt(what) => write!(f, "corrupt snapshot: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => SnapshotError::Corrupt("truncated"),
+            _ => SnapshotError::Io(e),
+        }
+    }
+}
+
+pub fn save<W: Write>(cpu: &CPU, w: &mut W) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_all(&VERSION.to_le_bytes())?;
+
+    let mut regs = cpu.pc.to_le_bytes().to_vec();
+    for reg in cpu.xregs.regs {
+        regs.extend(reg.to_le_bytes());
+    }
+    write_section(w, TAG_REGS, &regs)?;
+
+    let mut csrs = Vec::new();
+    for (i, csr) in cpu.csrs.csrs.iter().enumerate() {
+        if *csr != 0 {
+            csrs.extend((i as u32).to_le_bytes());
+            csrs.extend(csr.to_le_bytes());
+        }
+    }
+    write_section(w, TAG_CSRS, &csrs)?;
+
+    write_section(w, TAG_PRIVILEGE, &[cpu.privilege as u8])?;
+
+    let (base, ram) = cpu.bus.ram();
+    write_section(w, TAG_RAM, &pages(Vec::new(), base, ram))?;
+    for index in 1..cpu.bus.memories().count() {
+        let (base, mem) = cpu.bus.memory(index);
+        let payload = pages((index as u32).to_le_bytes().to_vec(), base, mem);
+        write_section(w, TAG_MEMORY, &payload)?;
+    }
+
+    write_section(w, TAG_TIME, &cpu.bus.instret().to_le_bytes())?;
+
+    // after the time, so devices on virtual time restore at their clock
+    for dev in cpu.bus.devices() {
+        let name = dev.device.fdt_name();
+        let mut payload = dev.base.to_le_bytes().to_vec();
+        payload.push(name.len() as u8);
+        payload.extend(name.as_bytes());
+        dev.device.save(&mut payload)?;
+        write_section(w, TAG_DEVICE, &payload)?;
+    }
+
+    if let Some((addr, word)) = cpu.reservation {
+        let mut payload = addr.to_le_bytes().to_vec();
+        payload.extend(word.to_le_bytes());
+        write_section(w, TAG_RESERVATION, &payload)?;
+    }
+
+    write_section(w, TAG_END, &[])
+}
+
+// overwrite the architectural state of `cpu` with the snapshot, anything the
+// snapshot does not cover (tracing, attached analyses) is left alone
+pub fn restore<R: Read>(cpu: &mut CPU, r: &mut R) -> Result<(), SnapshotError> {
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+    let version = read_u32(r)?;
+    if version > VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+    loop {
+        let mut tag = [0; 4];
+        r.read_exact(&mut tag)?;
+        let len = read_u64(r)?;
+        let mut p = r.by_ref().take(len);
+        match &tag {
+            TAG_REGS => {
+                cpu.pc = read_u32(&mut p)?;
+                cpu.reservation = None;
+                // snapshots from before TAG_PRIVILEGE are all machine mode
+                cpu.privilege = Privilege::Machine;
+                for reg in cpu.xregs.regs.iter_mut() {
+                    *reg = read_u32(&mut p)?;
+                }
+            }
+            TAG_CSRS => {
+                let mut csrs = CSRS::new(0);
+                while p.limit() > 0 {
+                    let csr = read_u32(&mut p)? as usize;
+                    if csr >= NUM_CSRS {
+                        return Err(SnapshotError::Corrupt("bad CSR number"));
+                    }
+                    csrs.csrs[csr] = read_u32(&mut p)?;
+                }
+                // the ISA is part of the configuration, not of the state
+                csrs.csrs[MISA as usize] = cpu.isa().misa();
+                cpu.csrs = csrs;
+                // satp may name another address space now
+                cpu.tlb.flush();
+            }
+            TAG_PRIVILEGE => cpu.privilege = Privilege::from_bits(read_u8(&mut p)? as u32),
+            TAG_RAM | TAG_MEMORY => {
+                let index = match &tag {
+                    TAG_RAM => 0,
+                    _ => read_u32(&mut p)? as usize,
+                };
+                let base = read_u32(&mut p)?;
+                let size = read_u32(&mut p)?;
+                let mem = cpu
+                    .bus
+                    .zeroed_memory(index, base, size)
+                    .ok_or(SnapshotError::Corrupt("memory not on the bus"))?;
+                restore_pages(&mut p, mem)?;
+            }
+            TAG_TIME => cpu.bus.set_instret(read_u64(&mut p)?),
+            TAG_DEVICE => {
+                let base = read_u32(&mut p)?;
+                let mut name = vec![0; read_u8(&mut p)? as usize];
+                p.read_exact(&mut name)?;
+                let instret = cpu.bus.instret();
+                let dev = cpu
+                    .bus
+                    .device_mut(base)
+                    .filter(|dev| dev.device.fdt_name().as_bytes() == name)
+                    .ok_or(SnapshotError::Corrupt("device not on the bus"))?;
+                dev.device.clock(instret);
+                dev.device.restore(&mut p).map_err(|e| match e.kind() {
+                    io::ErrorKind::InvalidData => SnapshotError::Corrupt("bad device state"),
+                    _ => e.into(),
+                })?;
+            }
+            TAG_RESERVATION => cpu.reservation = Some((read_u32(&mut p)?, read_u32(&mut p)?)),
+            TAG_END => {
+                // the restored devices decide the interrupt lines
+                cpu.bus.route_irqs();
+                return Ok(());
+            }
+            // sections from newer writers that this version knows nothing of
+            _ => (),
+        }
+        // whatever of the section is left, which must all be there
+        io::copy(&mut p, &mut io::sink())?;
+        if p.limit() > 0 {
+            return Err(SnapshotError::Corrupt("truncated"));
+        }
+    }
+}
+
+pub fn save_file(cpu: &CPU, path: &str) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    save(cpu, &mut w)?;
+    w.flush()
+}
+
+pub fn restore_file(cpu: &mut CPU, path: &str) -> Result<(), SnapshotError> {
+    restore(cpu, &mut BufReader::new(File::open(path)?))
+}
+
+fn write_section<W: Write>(w: &mut W, tag: &[u8; 4], payload: &[u8]) -> io::Result<()> {
+    w.write_all(tag)?;
+    w.write_all(&(payload.len() as u64).to_le_bytes())?;
+    w.write_all(payload)
+}
+
+// `prefix`, then base, size and the pages of `mem` that are not all zeros
+fn pages(mut payload: Vec<u8>, base: u32, mem: &[u8]) -> Vec<u8> {
+    payload.extend(base.to_le_bytes());
+    payload.extend((mem.len() as u32).to_le_bytes());
+    for (idx, page) in mem.chunks(PAGE_SIZE).enumerate() {
+        if !memory::is_zero(page) {
+            payload.extend((idx as u32).to_le_bytes());
+            payload.extend(page);
+        }
+    }
+    payload.extend(NO_PAGE.to_le_bytes());
+    payload
+}
+
+fn restore_pages<R: Read>(r: &mut R, mem: &mut Backing) -> Result<(), SnapshotError> {
+    loop {
+        let idx = read_u32(r)?;
+        if idx == NO_PAGE {
+            return Ok(());
+        }
+        let start = idx as usize * PAGE_SIZE;
+        let end = (start + PAGE_SIZE).min(mem.len());
+        if start >= end {
+            return Err(SnapshotError::Corrupt("page outside of memory"));
+        }
+        r.read_exact(&mut mem[start..end])?;
+        mem.touch(start, end - start);
+    }
+}
+
+// helpers for Device::save() and restore()
+
+pub fn read_u8<R: Read + ?Sized>(r: &mut R) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+pub fn read_u32<R: Read + ?Sized>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub fn read_u64<R: Read + ?Sized>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+pub fn read_bool<R: Read + ?Sized>(r: &mut R) -> io::Result<bool> {
+    match read_u8(r)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(bad_state("not a bool")),
+    }
+}
+
+// the error for device state that cannot be
+pub fn bad_state(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+// registers kept by offset, ordered so the same state saves the same
+pub fn write_map<W: Write + ?Sized>(w: &mut W, map: &HashMap<u32, u32>) -> io::Result<()> {
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort();
+    w.write_all(&(entries.len() as u32).to_le_bytes())?;
+    for (key, value) in entries {
+        w.write_all(&key.to_le_bytes())?;
+        w.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+pub fn read_map<R: Read + ?Sized>(r: &mut R) -> io::Result<HashMap<u32, u32>> {
+    let mut map = HashMap::new();
+    for _ in 0..read_u32(r)? {
+        map.insert(read_u32(r)?, read_u32(r)?);
+    }
+    Ok(map)
+}
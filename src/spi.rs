@@ -0,0 +1,256 @@
+// SiFive SPI controller. A frame written to txdata goes out at once and the
+// byte shifted back lands in the receive FIFO. What sits on the bus is a
+// SpiBus: a Rust implementation, e.g. a fake sensor in a test, or a Script
+// of reply bytes from the board file. Chip select follows csmode: AUTO
+// selects around every frame, HOLD from the first frame until csmode or csid
+// change, OFF never. Only 8-bit frames, MSB or LSB first is up to the
+// guest and the device on the other end.
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::device::Device;
+use crate::fdt::Fdt;
+use crate::snapshot::{bad_state, read_bool, read_u32, read_u8};
+
+pub const SPI_SIZE: u32 = 0x1000;
+
+const FIFO_DEPTH: usize = 8;
+
+const SCKDIV: u32 = 0x00;
+const CSID: u32 = 0x10;
+const CSMODE: u32 = 0x18;
+const FMT: u32 = 0x40;
+const TXDATA: u32 = 0x48;
+const RXDATA: u32 = 0x4c;
+const TXMARK: u32 = 0x50;
+const RXMARK: u32 = 0x54;
+const IP: u32 = 0x74;
+const LAST_REG: u32 = IP;
+
+const CSMODE_AUTO: u32 = 0;
+const CSMODE_HOLD: u32 = 2;
+
+// fmt.dir: transmit only, nothing goes into the receive FIFO
+const FMT_DIR: u32 = 1 << 3;
+
+const FIFO_EMPTY: u32 = 1 << 31;
+
+const IP_TXWM: u32 = 1 << 0;
+const IP_RXWM: u32 = 1 << 1;
+
+// the devices on the bus, told apart by chip select
+pub trait SpiBus: Send + fmt::Debug {
+    fn select(&mut self, _cs: u32) {}
+
+    // the byte the selected device shifts out while `byte` goes in
+    fn transfer(&mut self, cs: u32, byte: u8) -> u8;
+
+    fn deselect(&mut self, _cs: u32) {}
+
+    // state of the devices for a snapshot, as Device::save() and restore()
+    fn save(&self, _w: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn restore(&mut self, _r: &mut dyn Read) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn SpiBus>;
+}
+
+impl Clone for Box<dyn SpiBus> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+// a device that answers every frame with the next of `replies`, and with
+// 0xff, an undriven line, once they run out
+#[derive(Debug, Clone, Default)]
+pub struct Script {
+    replies: VecDeque<u8>,
+}
+
+impl Script {
+    pub fn new(replies: &[u8]) -> Self {
+        Script {
+            replies: replies.iter().copied().collect(),
+        }
+    }
+}
+
+impl SpiBus for Script {
+    fn transfer(&mut self, _cs: u32, _byte: u8) -> u8 {
+        self.replies.pop_front().unwrap_or(0xff)
+    }
+
+    // the replies still to come
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(&(self.replies.len() as u32).to_le_bytes())?;
+        w.write_all(&self.replies.iter().copied().collect::<Vec<_>>())
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.replies.clear();
+        for _ in 0..read_u32(r)? {
+            self.replies.push_back(read_u8(r)?);
+        }
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn SpiBus> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Spi {
+    bus: Box<dyn SpiBus>,
+    regs: [u32; LAST_REG as usize / 4 + 1],
+    rx: VecDeque<u8>,
+    // chip select held in HOLD mode
+    held: Option<u32>,
+}
+
+impl Spi {
+    pub fn new(bus: Box<dyn SpiBus>) -> Self {
+        let mut regs = [0; LAST_REG as usize / 4 + 1];
+        // the reset values
+        regs[SCKDIV as usize / 4] = 3;
+        regs[FMT as usize / 4] = 8 << 16;
+        regs[TXMARK as usize / 4] = 1;
+        Spi {
+            bus,
+            regs,
+            rx: VecDeque::new(),
+            held: None,
+        }
+    }
+
+    fn reg(&self, offset: u32) -> u32 {
+        self.regs[offset as usize / 4]
+    }
+
+    fn release(&mut self) {
+        if let Some(cs) = self.held.take() {
+            self.bus.deselect(cs);
+        }
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        let cs = self.reg(CSID);
+        let received = match self.reg(CSMODE) {
+            CSMODE_AUTO => {
+                self.bus.select(cs);
+                let received = self.bus.transfer(cs, byte);
+                self.bus.deselect(cs);
+                received
+            }
+            CSMODE_HOLD => {
+                if self.held.is_none() {
+                    self.bus.select(cs);
+                    self.held = Some(cs);
+                }
+                self.bus.transfer(cs, byte)
+            }
+            _ => self.bus.transfer(cs, byte),
+        };
+        if self.reg(FMT) & FMT_DIR == 0 && self.rx.len() < FIFO_DEPTH {
+            self.rx.push_back(received);
+        }
+    }
+
+    // the transmit FIFO is always empty, frames leave at once
+    fn pending(&self) -> u32 {
+        let mut ip = 0;
+        if self.reg(TXMARK) > 0 {
+            ip |= IP_TXWM;
+        }
+        if self.rx.len() as u32 > self.reg(RXMARK) {
+            ip |= IP_RXWM;
+        }
+        ip
+    }
+}
+
+impl Device for Spi {
+    fn load(&mut self, offset: u32, _size: u32) -> u32 {
+        if offset > LAST_REG || !offset.is_multiple_of(4) {
+            return 0;
+        }
+        match offset {
+            TXDATA => 0,
+            RXDATA => self.rx.pop_front().map_or(FIFO_EMPTY, |byte| byte as u32),
+            IP => self.pending(),
+            _ => self.reg(offset),
+        }
+    }
+
+    fn store(&mut self, offset: u32, _size: u32, value: u32) {
+        if offset > LAST_REG || !offset.is_multiple_of(4) {
+            return;
+        }
+        match offset {
+            TXDATA => self.transmit(value as u8),
+            RXDATA | IP => (),
+            _ => {
+                if (offset == CSMODE || offset == CSID) && value != self.reg(offset) {
+                    self.release();
+                }
+                self.regs[offset as usize / 4] = value;
+            }
+        }
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        for reg in self.regs {
+            w.write_all(&reg.to_le_bytes())?;
+        }
+        w.write_all(&[self.rx.len() as u8])?;
+        w.write_all(&self.rx.iter().copied().collect::<Vec<_>>())?;
+        w.write_all(&[self.held.is_some() as u8])?;
+        w.write_all(&self.held.unwrap_or(0).to_le_bytes())?;
+        self.bus.save(w)
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        for reg in self.regs.iter_mut() {
+            *reg = read_u32(r)?;
+        }
+        let len = read_u8(r)? as usize;
+        if len > FIFO_DEPTH {
+            return Err(bad_state("receive FIFO overflow"));
+        }
+        self.rx.clear();
+        for _ in 0..len {
+            self.rx.push_back(read_u8(r)?);
+        }
+        let held = read_bool(r)?;
+        let cs = read_u32(r)?;
+        self.bus.restore(r)?;
+        // the device on the other end is selected again
+        self.held = held.then_some(cs);
+        if let Some(cs) = self.held {
+            self.bus.select(cs);
+        }
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+
+    fn fdt_name(&self) -> &'static str {
+        "spi"
+    }
+
+    fn fdt_props(&self, fdt: &mut Fdt, _harts: u32) {
+        fdt.prop_str("compatible", "sifive,spi0");
+        fdt.prop_u32("#address-cells", 1);
+        fdt.prop_u32("#size-cells", 0);
+        fdt.prop_u32("sifive,fifo-depth", FIFO_DEPTH as u32);
+        fdt.prop_u32("sifive,max-bits-per-word", 8);
+    }
+}
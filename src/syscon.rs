@@ -0,0 +1,70 @@
+// SiFive test device, the syscon QEMU's virt machine uses to power off and
+// reboot. The low half of a store is the command: FINISHER_PASS powers off
+// with exit code 0, FINISHER_FAIL with the code in the high half, and
+// FINISHER_RESET reboots. Linux finds it through the syscon-poweroff and
+// syscon-reboot nodes below it.
+use crate::device::{Device, Power};
+use crate::fdt::{Fdt, SYSCON_PHANDLE};
+
+pub const SYSCON_SIZE: u32 = 0x1000;
+
+pub const FINISHER_FAIL: u32 = 0x3333;
+pub const FINISHER_PASS: u32 = 0x5555;
+pub const FINISHER_RESET: u32 = 0x7777;
+
+#[derive(Debug, Clone, Default)]
+pub struct Syscon {
+    power: Option<Power>,
+}
+
+impl Syscon {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Device for Syscon {
+    fn load(&mut self, _offset: u32, _size: u32) -> u32 {
+        0
+    }
+
+    fn store(&mut self, offset: u32, _size: u32, value: u32) {
+        if offset != 0 {
+            return;
+        }
+        self.power = match value & 0xffff {
+            FINISHER_PASS => Some(Power::Off(0)),
+            FINISHER_FAIL => Some(Power::Off((value >> 16) as i32)),
+            FINISHER_RESET => Some(Power::Reset),
+            _ => None,
+        };
+    }
+
+    fn power(&mut self) -> Option<Power> {
+        self.power.take()
+    }
+
+    fn clone_box(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+
+    fn fdt_name(&self) -> &'static str {
+        "test"
+    }
+
+    fn fdt_props(&self, fdt: &mut Fdt, _harts: u32) {
+        fdt.prop_strs(
+            "compatible",
+            &["sifive,test1", "sifive,test0", "syscon", "simple-mfd"],
+        );
+        fdt.prop_u32("phandle", SYSCON_PHANDLE);
+        for (name, value) in [("poweroff", FINISHER_PASS), ("reboot", FINISHER_RESET)] {
+            fdt.begin_node(name);
+            fdt.prop_str("compatible", &format!("syscon-{}", name));
+            fdt.prop_u32("regmap", SYSCON_PHANDLE);
+            fdt.prop_u32("offset", 0);
+            fdt.prop_u32("value", value);
+            fdt.end_node();
+        }
+    }
+}
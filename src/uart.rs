@@ -0,0 +1,153 @@
+// NS16550A compatible UART, transmit side only: bytes the guest sends go to
+// the host's stdout, or into a buffer when captured. Nothing is ever
+// received and the transmitter is always ready, so LSR reads THRE | TEMT.
+// With ETBEI set in IER, every byte sent and enabling ETBEI itself raise the
+// transmitter empty interrupt, until IIR has reported it.
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::device::Device;
+use crate::fdt::Fdt;
+use crate::snapshot::{read_bool, read_u8};
+
+pub const UART_SIZE: u32 = 0x100;
+
+const RBR_THR_DLL: u32 = 0;
+const IER_DLM: u32 = 1;
+const IIR_FCR: u32 = 2;
+const LCR: u32 = 3;
+const MCR: u32 = 4;
+const LSR: u32 = 5;
+const SCR: u32 = 7;
+
+// divisor latch access bit in LCR
+const LCR_DLAB: u8 = 0x80;
+const LSR_THRE_TEMT: u32 = 0x60;
+// no interrupt pending
+const IIR_NONE: u32 = 0x01;
+// the transmit holding register is empty
+const IIR_THRE: u32 = 0x02;
+// transmitter empty interrupt enable in IER
+const IER_ETBEI: u8 = 0x02;
+
+#[derive(Debug, Clone, Default)]
+pub struct Uart {
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    divisor: u16,
+    // the transmitter empty interrupt is up
+    thre: bool,
+    // where transmitted bytes go, stdout when None
+    captured: Option<Arc<Mutex<Vec<u8>>>>,
+}
+
+impl Uart {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // a UART whose output is collected in the returned buffer
+    pub fn captured() -> (Self, Arc<Mutex<Vec<u8>>>) {
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let uart = Uart {
+            captured: Some(out.clone()),
+            ..Self::default()
+        };
+        (uart, out)
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        match &self.captured {
+            Some(out) => out.lock().unwrap().push(byte),
+            None => {
+                let mut stdout = std::io::stdout();
+                let _ = stdout.write_all(&[byte]);
+                let _ = stdout.flush();
+            }
+        }
+    }
+}
+
+impl Device for Uart {
+    fn load(&mut self, offset: u32, _size: u32) -> u32 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR_DLL if dlab => self.divisor as u32 & 0xff,
+            IER_DLM if dlab => self.divisor as u32 >> 8,
+            IER_DLM => self.ier as u32,
+            IIR_FCR if self.irq() => {
+                self.thre = false;
+                IIR_THRE
+            }
+            IIR_FCR => IIR_NONE,
+            LCR => self.lcr as u32,
+            MCR => self.mcr as u32,
+            LSR => LSR_THRE_TEMT,
+            SCR => self.scr as u32,
+            _ => 0,
+        }
+    }
+
+    fn store(&mut self, offset: u32, _size: u32, value: u32) {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let byte = value as u8;
+        match offset {
+            RBR_THR_DLL if dlab => self.divisor = self.divisor & 0xff00 | byte as u16,
+            RBR_THR_DLL => {
+                self.transmit(byte);
+                self.thre = true;
+            }
+            IER_DLM if dlab => self.divisor = self.divisor & 0xff | (byte as u16) << 8,
+            IER_DLM => {
+                self.thre |= byte & IER_ETBEI != 0 && self.ier & IER_ETBEI == 0;
+                self.ier = byte & 0x0f;
+            }
+            LCR => self.lcr = byte,
+            MCR => self.mcr = byte,
+            SCR => self.scr = byte,
+            _ => (),
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.ier & IER_ETBEI != 0 && self.thre
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        let [dll, dlm] = self.divisor.to_le_bytes();
+        w.write_all(&[
+            self.ier,
+            self.lcr,
+            self.mcr,
+            self.scr,
+            dll,
+            dlm,
+            self.thre as u8,
+        ])
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.ier = read_u8(r)?;
+        self.lcr = read_u8(r)?;
+        self.mcr = read_u8(r)?;
+        self.scr = read_u8(r)?;
+        self.divisor = u16::from_le_bytes([read_u8(r)?, read_u8(r)?]);
+        self.thre = read_bool(r)?;
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+
+    fn fdt_name(&self) -> &'static str {
+        "serial"
+    }
+
+    fn fdt_props(&self, fdt: &mut Fdt, _harts: u32) {
+        fdt.prop_str("compatible", "ns16550a");
+        fdt.prop_u32("clock-frequency", 3_686_400);
+    }
+}
@@ -0,0 +1,482 @@
+// virtio over MMIO (virtio 1.2, section 4.2) with split virtqueues, in both
+// flavours guests still use: the legacy interface (version 1, a queue is
+// found through its page frame number) and the modern one (version 2, the
+// three parts of a queue are given as 64-bit addresses). The transport does
+// the registers and walks descriptor chains when the driver notifies a
+// queue; a Backend says what kind of device it is and answers the requests.
+// A request a backend cannot answer yet, e.g. a console read before any
+// input, stays on its queue and is tried again whenever the bus polls.
+// Used buffers raise InterruptStatus, and the device's interrupt line
+// stays up until the driver acknowledges every bit of it.
+use core::fmt;
+use std::io::{self, Read, Write};
+
+use crate::device::Device;
+use crate::fdt::Fdt;
+use crate::memory::BUS;
+use crate::snapshot::{bad_state, read_bool, read_u32, read_u64, read_u8};
+
+pub const VIRTIO_SIZE: u32 = 0x1000;
+
+const MAGIC_VALUE: u32 = 0x000;
+const VERSION: u32 = 0x004;
+const DEVICE_ID: u32 = 0x008;
+const VENDOR_ID: u32 = 0x00c;
+const DEVICE_FEATURES: u32 = 0x010;
+const DEVICE_FEATURES_SEL: u32 = 0x014;
+const DRIVER_FEATURES: u32 = 0x020;
+const DRIVER_FEATURES_SEL: u32 = 0x024;
+const GUEST_PAGE_SIZE: u32 = 0x028;
+const QUEUE_SEL: u32 = 0x030;
+const QUEUE_NUM_MAX: u32 = 0x034;
+const QUEUE_NUM: u32 = 0x038;
+const QUEUE_ALIGN: u32 = 0x03c;
+const QUEUE_PFN: u32 = 0x040;
+const QUEUE_READY: u32 = 0x044;
+const QUEUE_NOTIFY: u32 = 0x050;
+const INTERRUPT_STATUS: u32 = 0x060;
+const INTERRUPT_ACK: u32 = 0x064;
+const STATUS: u32 = 0x070;
+const QUEUE_DESC_LOW: u32 = 0x080;
+const QUEUE_DESC_HIGH: u32 = 0x084;
+const QUEUE_DRIVER_LOW: u32 = 0x090;
+const QUEUE_DRIVER_HIGH: u32 = 0x094;
+const QUEUE_DEVICE_LOW: u32 = 0x0a0;
+const QUEUE_DEVICE_HIGH: u32 = 0x0a4;
+const CONFIG_GENERATION: u32 = 0x0fc;
+const CONFIG: u32 = 0x100;
+
+// "virt"
+const MAGIC: u32 = 0x74726976;
+// "QEMU", what guests expect to see
+const VENDOR: u32 = 0x554d4551;
+const QUEUE_MAX: u32 = 256;
+
+pub const F_VERSION_1: u64 = 1 << 32;
+
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_NEEDS_RESET: u32 = 0x40;
+const INTERRUPT_USED_BUFFER: u32 = 1;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+// most bytes a chain may carry each way, well above the largest request
+// drivers make; the lengths come from the guest and backends allocate room
+// for them
+const MAX_CHAIN: usize = 16 << 20;
+
+// what makes a virtio device a block device, a console, ...
+pub trait Backend: Send + fmt::Debug {
+    // 2 for block, 3 for console, ...
+    fn device_id(&self) -> u32;
+
+    // device specific feature bits, the transport adds its own
+    fn features(&self) -> u64;
+
+    // what the driver took of features() and the transport's features
+    fn negotiated(&mut self, _features: u64) {}
+
+    fn num_queues(&self) -> usize;
+
+    // the device specific configuration space
+    fn config(&self) -> Vec<u8>;
+
+    // answer the request the driver put on `queue`: `input` is everything
+    // it wrote, the answer has at most `room` bytes; None to be asked again
+    // later
+    fn request(&mut self, queue: usize, input: &[u8], room: usize) -> Option<Vec<u8>>;
+
+    // device state of the backend for a snapshot, as Device::save() and
+    // restore()
+    fn save(&self, _w: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn restore(&mut self, _r: &mut dyn Read) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn Backend>;
+}
+
+impl Clone for Box<dyn Backend> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Queue {
+    num: u32,
+    ready: bool,
+    // legacy layout
+    align: u32,
+    pfn: u32,
+    // descriptor table, available (driver) and used (device) rings
+    desc: u64,
+    avail: u64,
+    used: u64,
+    last_avail: u16,
+}
+
+#[derive(Debug, Clone)]
+pub struct VirtioMmio {
+    backend: Box<dyn Backend>,
+    legacy: bool,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    page_size: u32,
+    queue_sel: u32,
+    queues: Vec<Queue>,
+    interrupt_status: u32,
+    status: u32,
+    // bit per queue notified since the last dma()
+    notified: u64,
+    // bit per queue with a request the backend put off
+    waiting: u64,
+}
+
+// a descriptor chain: what the driver wrote, and where the answer goes
+struct Chain {
+    head: u16,
+    input: Vec<u8>,
+    output: Vec<(u32, u32)>,
+}
+
+impl VirtioMmio {
+    pub fn new(backend: Box<dyn Backend>, legacy: bool) -> Self {
+        let queues = vec![Queue::default(); backend.num_queues()];
+        VirtioMmio {
+            backend,
+            legacy,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            page_size: 0,
+            queue_sel: 0,
+            queues,
+            interrupt_status: 0,
+            status: 0,
+            notified: 0,
+            waiting: 0,
+        }
+    }
+
+    pub fn backend(&self) -> &dyn Backend {
+        &*self.backend
+    }
+
+    // the features the driver took
+    pub fn driver_features(&self) -> u64 {
+        self.driver_features
+    }
+
+    fn features(&self) -> u64 {
+        match self.legacy {
+            true => self.backend.features(),
+            false => self.backend.features() | F_VERSION_1,
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = VirtioMmio::new(self.backend.clone(), self.legacy);
+    }
+
+    fn queue(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    // where the legacy layout puts the rings behind the descriptor table
+    fn legacy_layout(queue: &mut Queue, page_size: u32) {
+        let num = queue.num as u64;
+        let align = queue.align.max(1) as u64;
+        queue.desc = queue.pfn as u64 * page_size as u64;
+        queue.avail = queue.desc + 16 * num;
+        queue.used = (queue.avail + 6 + 2 * num).div_ceil(align) * align;
+        queue.ready = queue.pfn != 0;
+    }
+
+    // every chain the driver made available on queue `i`
+    fn process(&mut self, i: usize, bus: &mut BUS) -> Option<()> {
+        self.waiting &= !(1 << i);
+        let queue = self.queues[i];
+        if !queue.ready || queue.num == 0 {
+            return Some(());
+        }
+        loop {
+            let avail_idx = load(bus, queue.avail + 2, 16)? as u16;
+            let last = self.queues[i].last_avail;
+            if last == avail_idx {
+                return Some(());
+            }
+            let slot = queue.avail + 4 + 2 * (last as u32 % queue.num) as u64;
+            let head = load(bus, slot, 16)? as u16;
+            let chain = chain(bus, &queue, head)?;
+            let room = chain.output.iter().map(|(_, len)| *len as usize).sum();
+            let Some(answer) = self.backend.request(i, &chain.input, room) else {
+                self.waiting |= 1 << i;
+                return Some(());
+            };
+
+            let mut rest = &answer[..answer.len().min(room)];
+            for (addr, len) in &chain.output {
+                let n = rest.len().min(*len as usize);
+                bus.store_bytes(*addr, &rest[..n])?;
+                rest = &rest[n..];
+            }
+            let used_idx = load(bus, queue.used + 2, 16)? as u16;
+            let elem = queue.used + 4 + 8 * (used_idx as u32 % queue.num) as u64;
+            store(bus, elem, chain.head as u32)?;
+            store(bus, elem + 4, answer.len().min(room) as u32)?;
+            bus.try_store(addr(queue.used + 2)?, 16, used_idx.wrapping_add(1) as u32)?;
+            self.queues[i].last_avail = last.wrapping_add(1);
+            self.interrupt_status |= INTERRUPT_USED_BUFFER;
+        }
+    }
+}
+
+fn addr(addr: u64) -> Option<u32> {
+    u32::try_from(addr).ok()
+}
+
+fn load(bus: &BUS, at: u64, size: u32) -> Option<u32> {
+    bus.try_load(addr(at)?, size)
+}
+
+fn store(bus: &mut BUS, at: u64, value: u32) -> Option<()> {
+    bus.try_store(addr(at)?, 32, value)
+}
+
+// follow the descriptors from `head`, None for a chain that leaves memory,
+// loops, carries more than MAX_CHAIN bytes either way or uses indirect
+// descriptors, which are never offered
+fn chain(bus: &BUS, queue: &Queue, head: u16) -> Option<Chain> {
+    let mut chain = Chain {
+        head,
+        input: Vec::new(),
+        output: Vec::new(),
+    };
+    let mut room = 0;
+    let mut i = head as u32;
+    for _ in 0..queue.num {
+        if i >= queue.num {
+            return None;
+        }
+        let desc = queue.desc + 16 * i as u64;
+        let buf = addr(load(bus, desc, 32)? as u64 | (load(bus, desc + 4, 32)? as u64) << 32)?;
+        let len = load(bus, desc + 8, 32)?;
+        let flags = load(bus, desc + 12, 16)? as u16;
+        let next = load(bus, desc + 14, 16)?;
+        if flags & !(DESC_F_NEXT | DESC_F_WRITE) != 0 {
+            return None;
+        }
+        let bytes = bus.load_bytes(buf, len as usize)?;
+        match flags & DESC_F_WRITE {
+            0 if chain.input.len() + bytes.len() <= MAX_CHAIN => chain.input.extend(bytes),
+            0 => return None,
+            _ => {
+                room += len as usize;
+                if room > MAX_CHAIN {
+                    return None;
+                }
+                chain.output.push((buf, len));
+            }
+        }
+        if flags & DESC_F_NEXT == 0 {
+            return Some(chain);
+        }
+        i = next;
+    }
+    None
+}
+
+impl Device for VirtioMmio {
+    fn load(&mut self, offset: u32, size: u32) -> u32 {
+        if offset >= CONFIG {
+            let config = self.backend.config();
+            let start = (offset - CONFIG) as usize;
+            let mut bytes = [0; 4];
+            for (i, byte) in bytes.iter_mut().take(size as usize / 8).enumerate() {
+                *byte = config.get(start + i).copied().unwrap_or(0);
+            }
+            return u32::from_le_bytes(bytes);
+        }
+        let queue = self.queues.get(self.queue_sel as usize).copied();
+        match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION if self.legacy => 1,
+            VERSION => 2,
+            DEVICE_ID => self.backend.device_id(),
+            VENDOR_ID => VENDOR,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.features() as u32,
+                1 => (self.features() >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX => queue.map_or(0, |_| QUEUE_MAX),
+            QUEUE_PFN => queue.map_or(0, |q| q.pfn),
+            QUEUE_READY => queue.map_or(0, |q| q.ready as u32),
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            CONFIG_GENERATION => 0,
+            _ => 0,
+        }
+    }
+
+    fn store(&mut self, offset: u32, _size: u32, value: u32) {
+        let page_size = self.page_size;
+        let legacy = self.legacy;
+        // the high or low half of a 64-bit queue address
+        let set = |addr: &mut u64, high: bool| {
+            *addr = match high {
+                true => *addr & 0xffff_ffff | (value as u64) << 32,
+                false => *addr & !0xffff_ffff | value as u64,
+            };
+        };
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            DRIVER_FEATURES => {
+                let shift = 32 * self.driver_features_sel as u64;
+                if shift < 64 {
+                    self.driver_features =
+                        self.driver_features & !(0xffff_ffff << shift) | (value as u64) << shift;
+                    let features = self.driver_features & self.features();
+                    self.backend.negotiated(features);
+                }
+            }
+            DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            GUEST_PAGE_SIZE => self.page_size = value,
+            QUEUE_SEL => self.queue_sel = value,
+            QUEUE_NUM if value <= QUEUE_MAX => {
+                if let Some(q) = self.queue() {
+                    q.num = value;
+                    if legacy {
+                        Self::legacy_layout(q, page_size);
+                    }
+                }
+            }
+            QUEUE_ALIGN => {
+                if let Some(q) = self.queue() {
+                    q.align = value;
+                }
+            }
+            QUEUE_PFN => {
+                if let Some(q) = self.queue() {
+                    q.pfn = value;
+                    Self::legacy_layout(q, page_size);
+                }
+            }
+            QUEUE_READY => {
+                if let Some(q) = self.queue() {
+                    q.ready = value & 1 != 0;
+                }
+            }
+            QUEUE_DESC_LOW | QUEUE_DESC_HIGH => {
+                if let Some(q) = self.queue() {
+                    set(&mut q.desc, offset == QUEUE_DESC_HIGH);
+                }
+            }
+            QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH => {
+                if let Some(q) = self.queue() {
+                    set(&mut q.avail, offset == QUEUE_DRIVER_HIGH);
+                }
+            }
+            QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH => {
+                if let Some(q) = self.queue() {
+                    set(&mut q.used, offset == QUEUE_DEVICE_HIGH);
+                }
+            }
+            QUEUE_NOTIFY if (value as usize) < self.queues.len() => self.notified |= 1 << value,
+            INTERRUPT_ACK => self.interrupt_status &= !value,
+            STATUS if value == 0 => self.reset(),
+            STATUS => self.status = value,
+            _ => (),
+        }
+    }
+
+    fn dma(&mut self, bus: &mut BUS) {
+        let due = std::mem::take(&mut self.notified) | self.waiting;
+        if self.status & STATUS_DRIVER_OK == 0 {
+            return;
+        }
+        for i in 0..self.queues.len() {
+            if due & 1 << i != 0 && self.process(i, bus).is_none() {
+                self.status |= STATUS_NEEDS_RESET;
+            }
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        let words = [
+            self.device_features_sel,
+            self.driver_features_sel,
+            self.page_size,
+            self.queue_sel,
+            self.interrupt_status,
+            self.status,
+        ];
+        for word in words {
+            w.write_all(&word.to_le_bytes())?;
+        }
+        for bits in [self.driver_features, self.notified, self.waiting] {
+            w.write_all(&bits.to_le_bytes())?;
+        }
+        for q in &self.queues {
+            for word in [q.num, q.align, q.pfn] {
+                w.write_all(&word.to_le_bytes())?;
+            }
+            for addr in [q.desc, q.avail, q.used] {
+                w.write_all(&addr.to_le_bytes())?;
+            }
+            w.write_all(&q.last_avail.to_le_bytes())?;
+            w.write_all(&[q.ready as u8])?;
+        }
+        self.backend.save(w)
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.device_features_sel = read_u32(r)?;
+        self.driver_features_sel = read_u32(r)?;
+        self.page_size = read_u32(r)?;
+        self.queue_sel = read_u32(r)?;
+        self.interrupt_status = read_u32(r)?;
+        self.status = read_u32(r)?;
+        self.driver_features = read_u64(r)?;
+        self.notified = read_u64(r)?;
+        self.waiting = read_u64(r)?;
+        for q in &mut self.queues {
+            q.num = read_u32(r)?;
+            q.align = read_u32(r)?;
+            q.pfn = read_u32(r)?;
+            q.desc = read_u64(r)?;
+            q.avail = read_u64(r)?;
+            q.used = read_u64(r)?;
+            q.last_avail = u16::from_le_bytes([read_u8(r)?, read_u8(r)?]);
+            q.ready = read_bool(r)?;
+            if q.num > QUEUE_MAX {
+                return Err(bad_state("queue too long"));
+            }
+        }
+        let features = self.driver_features & self.features();
+        self.backend.negotiated(features);
+        self.backend.restore(r)
+    }
+
+    fn clone_box(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+
+    fn fdt_name(&self) -> &'static str {
+        "virtio_mmio"
+    }
+
+    fn fdt_props(&self, fdt: &mut Fdt, _harts: u32) {
+        fdt.prop_str("compatible", "virtio,mmio");
+    }
+}
@@ -0,0 +1,198 @@
+// virtio block device on a host disk image. How the image is used is the
+// DiskMode: read-write writes through to the file, read-only tells the driver
+// so (VIRTIO_BLK_F_RO) and fails writes, and overlay keeps written sectors
+// in memory, so a test run sees its own writes while the image itself never
+// changes.
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::virtio::Backend;
+
+pub const SECTOR_SIZE: u64 = 512;
+
+const DEVICE_ID: u32 = 2;
+
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+const T_GET_ID: u32 = 8;
+
+const S_OK: u8 = 0;
+const S_IOERR: u8 = 1;
+const S_UNSUPP: u8 = 2;
+
+// type, reserved, sector
+const HEADER_SIZE: usize = 16;
+const ID_SIZE: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiskMode {
+    #[default]
+    ReadWrite,
+    ReadOnly,
+    Overlay,
+}
+
+impl DiskMode {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "read-write" => Ok(DiskMode::ReadWrite),
+            "read-only" => Ok(DiskMode::ReadOnly),
+            "overlay" => Ok(DiskMode::Overlay),
+            _ => Err(format!(
+                "`{}` is none of read-write, read-only and overlay",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Disk {
+    file: Arc<File>,
+    sectors: u64,
+    mode: DiskMode,
+    // sectors written in overlay mode
+    overlay: HashMap<u64, Vec<u8>>,
+}
+
+impl Disk {
+    pub fn open(path: &Path, mode: DiskMode) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(mode == DiskMode::ReadWrite)
+            .open(path)?;
+        let sectors = file.metadata()?.len() / SECTOR_SIZE;
+        Ok(Disk {
+            file: Arc::new(file),
+            sectors,
+            mode,
+            overlay: HashMap::new(),
+        })
+    }
+
+    pub fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    // whether `len` bytes from `sector` on are on the disk
+    fn contains(&self, sector: u64, len: usize) -> bool {
+        sector
+            .checked_mul(SECTOR_SIZE)
+            .and_then(|start| start.checked_add(len as u64))
+            .is_some_and(|end| end <= self.sectors * SECTOR_SIZE)
+    }
+
+    pub fn read(&self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        for (i, chunk) in buf.chunks_mut(SECTOR_SIZE as usize).enumerate() {
+            let sector = sector + i as u64;
+            match self.overlay.get(&sector) {
+                Some(data) => chunk.copy_from_slice(&data[..chunk.len()]),
+                None => self.file.read_exact_at(chunk, sector * SECTOR_SIZE)?,
+            }
+        }
+        Ok(())
+    }
+
+    // whole sectors only
+    pub fn write(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        match self.mode {
+            DiskMode::ReadWrite => self.file.write_all_at(data, sector * SECTOR_SIZE),
+            DiskMode::ReadOnly => Err(io::ErrorKind::PermissionDenied.into()),
+            DiskMode::Overlay => {
+                for (i, chunk) in data.chunks(SECTOR_SIZE as usize).enumerate() {
+                    self.overlay.insert(sector + i as u64, chunk.to_vec());
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        match self.mode {
+            DiskMode::ReadWrite => self.file.sync_data(),
+            _ => Ok(()),
+        }
+    }
+
+    // data and status of a request, the status goes last
+    fn serve(&mut self, input: &[u8], room: usize) -> (Vec<u8>, u8) {
+        let word = |at: usize| u32::from_le_bytes(input[at..at + 4].try_into().unwrap());
+        let kind = word(0);
+        let sector = word(8) as u64 | (word(12) as u64) << 32;
+        let mut data = vec![0; room - 1];
+        let status = match kind {
+            T_IN if self.contains(sector, data.len()) => match self.read(sector, &mut data) {
+                Ok(()) => S_OK,
+                Err(_) => S_IOERR,
+            },
+            T_OUT => {
+                let out = &input[HEADER_SIZE..];
+                let whole = (out.len() as u64).is_multiple_of(SECTOR_SIZE);
+                match whole && self.contains(sector, out.len()) && self.write(sector, out).is_ok() {
+                    true => S_OK,
+                    false => S_IOERR,
+                }
+            }
+            T_FLUSH => match self.flush() {
+                Ok(()) => S_OK,
+                Err(_) => S_IOERR,
+            },
+            T_GET_ID => {
+                let id = b"riscland";
+                let n = id.len().min(data.len()).min(ID_SIZE);
+                data[..n].copy_from_slice(&id[..n]);
+                S_OK
+            }
+            T_IN => S_IOERR,
+            _ => S_UNSUPP,
+        };
+        (data, status)
+    }
+}
+
+impl Backend for Disk {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        match self.mode {
+            DiskMode::ReadOnly => F_RO | F_FLUSH,
+            _ => F_FLUSH,
+        }
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    // capacity in sectors
+    fn config(&self) -> Vec<u8> {
+        self.sectors.to_le_bytes().to_vec()
+    }
+
+    fn request(&mut self, _queue: usize, input: &[u8], room: usize) -> Option<Vec<u8>> {
+        // a request without header or room for the status is no request
+        if input.len() < HEADER_SIZE || room == 0 {
+            return Some(Vec::new());
+        }
+        let (mut answer, status) = self.serve(input, room);
+        answer.push(status);
+        Some(answer)
+    }
+
+    fn clone_box(&self) -> Box<dyn Backend> {
+        Box::new(self.clone())
+    }
+}
@@ -0,0 +1,132 @@
+// virtio console with a single port: queue 0 takes what the host sends the
+// guest, queue 1 what the guest writes. It is wired to the host's stdio, to
+// a Unix socket it connects to, or to buffers for tests. Host input is
+// read by a thread of its own and handed to the guest when the bus polls.
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::virtio::Backend;
+
+const DEVICE_ID: u32 = 3;
+
+const RECEIVEQ: usize = 0;
+const TRANSMITQ: usize = 1;
+
+// cols, rows, max_nr_ports, emerg_wr; none of them offered
+const CONFIG_SIZE: usize = 12;
+
+type Input = Arc<Mutex<VecDeque<u8>>>;
+
+#[derive(Debug, Clone)]
+enum Output {
+    Stdout,
+    Socket(Arc<UnixStream>),
+    Buffer(Arc<Mutex<Vec<u8>>>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Console {
+    input: Input,
+    output: Output,
+}
+
+// copy everything `from` produces into `input` until it ends
+fn feed(mut from: impl Read + Send + 'static, input: Input) {
+    thread::spawn(move || {
+        let mut buf = [0; 256];
+        while let Ok(n @ 1..) = from.read(&mut buf) {
+            input.lock().unwrap().extend(&buf[..n]);
+        }
+    });
+}
+
+impl Console {
+    pub fn stdio() -> Self {
+        let input = Input::default();
+        feed(io::stdin(), input.clone());
+        Console {
+            input,
+            output: Output::Stdout,
+        }
+    }
+
+    // talk through the Unix socket a server listens on at `path`
+    pub fn socket(path: &Path) -> io::Result<Self> {
+        let stream = UnixStream::connect(path)?;
+        let input = Input::default();
+        feed(stream.try_clone()?, input.clone());
+        Ok(Console {
+            input,
+            output: Output::Socket(Arc::new(stream)),
+        })
+    }
+
+    // a console whose input the caller pushes and whose output it collects
+    pub fn captured() -> (Self, Input, Arc<Mutex<Vec<u8>>>) {
+        let input = Input::default();
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let console = Console {
+            input: input.clone(),
+            output: Output::Buffer(output.clone()),
+        };
+        (console, input, output)
+    }
+
+    fn transmit(&self, data: &[u8]) {
+        match &self.output {
+            Output::Stdout => {
+                let mut stdout = io::stdout();
+                let _ = stdout.write_all(data);
+                let _ = stdout.flush();
+            }
+            Output::Socket(stream) => {
+                let _ = (&**stream).write_all(data);
+            }
+            Output::Buffer(out) => out.lock().unwrap().extend(data),
+        }
+    }
+}
+
+impl Backend for Console {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    fn config(&self) -> Vec<u8> {
+        vec![0; CONFIG_SIZE]
+    }
+
+    fn request(&mut self, queue: usize, input: &[u8], room: usize) -> Option<Vec<u8>> {
+        match queue {
+            RECEIVEQ => {
+                let mut pending = self.input.lock().unwrap();
+                if pending.is_empty() {
+                    return None;
+                }
+                let n = pending.len().min(room);
+                Some(pending.drain(..n).collect())
+            }
+            TRANSMITQ => {
+                self.transmit(input);
+                Some(Vec::new())
+            }
+            _ => Some(Vec::new()),
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn Backend> {
+        Box::new(self.clone())
+    }
+}
@@ -0,0 +1,195 @@
+// virtio network device on a local link, no host network involved. Frames
+// go over a Unix datagram socket, either one end of a socketpair or a
+// socket bound to a path that sends to a peer's path (so two emulators on
+// one host can talk), or through an in-process queue pair a test harness
+// holds the other end of. Frames sent while nobody listens are dropped, as
+// on an unplugged cable.
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::virtio::{Backend, F_VERSION_1};
+
+const DEVICE_ID: u32 = 1;
+
+const F_MAC: u64 = 1 << 5;
+
+const RECEIVEQ: usize = 0;
+const TRANSMITQ: usize = 1;
+
+// virtio_net_hdr, num_buffers is only there with VIRTIO_F_VERSION_1
+const HEADER_SIZE_LEGACY: usize = 10;
+const HEADER_SIZE: usize = 12;
+// largest Ethernet frame without FCS, plus a VLAN tag
+const MAX_FRAME: usize = 1518;
+
+pub const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+type Frames = Arc<Mutex<VecDeque<Vec<u8>>>>;
+
+#[derive(Debug, Clone)]
+enum Link {
+    Socket {
+        socket: Arc<UnixDatagram>,
+        // where frames go, None for a connected socket
+        peer: Option<PathBuf>,
+    },
+    Queue {
+        inbox: Frames,
+        outbox: Frames,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Net {
+    link: Link,
+    mac: [u8; 6],
+    header_size: usize,
+}
+
+// "52:54:00:12:34:56"
+pub fn parse_mac(s: &str) -> Result<[u8; 6], String> {
+    let bytes: Vec<u8> = s
+        .split(':')
+        .map(|b| u8::from_str_radix(b, 16))
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("`{}` is no MAC address", s))?;
+    bytes
+        .try_into()
+        .map_err(|_| format!("`{}` is no MAC address", s))
+}
+
+impl Net {
+    fn new(link: Link) -> Self {
+        Net {
+            link,
+            mac: DEFAULT_MAC,
+            header_size: HEADER_SIZE_LEGACY,
+        }
+    }
+
+    // the two ends of a Unix datagram socketpair
+    pub fn socket_pair() -> io::Result<(Self, Self)> {
+        let (a, b) = UnixDatagram::pair()?;
+        let end = |socket: UnixDatagram| -> io::Result<Self> {
+            socket.set_nonblocking(true)?;
+            Ok(Net::new(Link::Socket {
+                socket: Arc::new(socket),
+                peer: None,
+            }))
+        };
+        Ok((end(a)?, end(b)?))
+    }
+
+    // receive on a socket bound at `path`, send to the one at `peer`
+    pub fn bind(path: &Path, peer: &Path) -> io::Result<Self> {
+        let _ = std::fs::remove_file(path);
+        let socket = UnixDatagram::bind(path)?;
+        socket.set_nonblocking(true)?;
+        Ok(Net::new(Link::Socket {
+            socket: Arc::new(socket),
+            peer: Some(peer.to_path_buf()),
+        }))
+    }
+
+    // two ends of an in-process link
+    pub fn queue_pair() -> (Self, Self) {
+        let (a, b) = (Frames::default(), Frames::default());
+        let end = |inbox: &Frames, outbox: &Frames| {
+            Net::new(Link::Queue {
+                inbox: inbox.clone(),
+                outbox: outbox.clone(),
+            })
+        };
+        (end(&a, &b), end(&b, &a))
+    }
+
+    pub fn with_mac(mut self, mac: [u8; 6]) -> Self {
+        self.mac = mac;
+        self
+    }
+
+    pub fn send(&self, frame: &[u8]) {
+        match &self.link {
+            Link::Socket { socket, peer } => {
+                let _ = match peer {
+                    Some(peer) => socket.send_to(frame, peer),
+                    None => socket.send(frame),
+                };
+            }
+            Link::Queue { outbox, .. } => outbox.lock().unwrap().push_back(frame.to_vec()),
+        }
+    }
+
+    pub fn recv(&self) -> Option<Vec<u8>> {
+        match &self.link {
+            Link::Socket { socket, .. } => {
+                let mut buf = vec![0; MAX_FRAME];
+                let n = socket.recv(&mut buf).ok()?;
+                buf.truncate(n);
+                Some(buf)
+            }
+            Link::Queue { inbox, .. } => inbox.lock().unwrap().pop_front(),
+        }
+    }
+}
+
+impl Backend for Net {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        F_MAC
+    }
+
+    fn negotiated(&mut self, features: u64) {
+        self.header_size = match features & F_VERSION_1 {
+            0 => HEADER_SIZE_LEGACY,
+            _ => HEADER_SIZE,
+        };
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    // mac, then a status nobody was offered
+    fn config(&self) -> Vec<u8> {
+        let mut config = self.mac.to_vec();
+        config.extend([0, 0]);
+        config
+    }
+
+    fn request(&mut self, queue: usize, input: &[u8], room: usize) -> Option<Vec<u8>> {
+        match queue {
+            RECEIVEQ => loop {
+                let frame = self.recv()?;
+                // a frame too big for the buffer is lost
+                if self.header_size + frame.len() > room {
+                    continue;
+                }
+                let mut answer = vec![0; self.header_size];
+                if self.header_size == HEADER_SIZE {
+                    // num_buffers
+                    answer[10] = 1;
+                }
+                answer.extend(frame);
+                return Some(answer);
+            },
+            TRANSMITQ => {
+                if input.len() > self.header_size {
+                    self.send(&input[self.header_size..]);
+                }
+                Some(Vec::new())
+            }
+            _ => Some(Vec::new()),
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn Backend> {
+        Box::new(self.clone())
+    }
+}
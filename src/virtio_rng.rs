@@ -0,0 +1,83 @@
+// virtio entropy device. The bytes come from a splitmix64 generator, so a
+// run with a fixed seed gets the same "entropy" every time; without a seed
+// it starts from the host clock.
+use std::io::{self, Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::snapshot::read_u64;
+use crate::virtio::Backend;
+
+const DEVICE_ID: u32 = 4;
+
+// most bytes one request gets, the used length tells the driver; Linux asks
+// for far less
+const MAX_ENTROPY: usize = 64 << 10;
+
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    pub fn from_host_time() -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Rng::new(now.as_nanos() as u64)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl Backend for Rng {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn config(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    // buffers come back full up to MAX_ENTROPY bytes
+    fn request(&mut self, _queue: usize, _input: &[u8], room: usize) -> Option<Vec<u8>> {
+        let len = room.min(MAX_ENTROPY);
+        let mut bytes = Vec::with_capacity(len.next_multiple_of(8));
+        while bytes.len() < len {
+            bytes.extend(self.next_u64().to_le_bytes());
+        }
+        bytes.truncate(len);
+        Some(bytes)
+    }
+
+    // the generator goes on with the same bytes
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(&self.state.to_le_bytes())
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        self.state = read_u64(r)?;
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn Backend> {
+        Box::new(self.clone())
+    }
+}
@@ -0,0 +1,85 @@
+mod helper;
+
+#[cfg(test)]
+mod tests {
+    use crate::helper::hart as machine;
+    use riscland::memory;
+
+    #[test]
+    fn test_self_modifying_code() {
+        let mut cpu_test = machine(&[
+            0x80000337, // lui t1, 0x80000
+            0x04032383, // lw t2, 0x40(t1)
+            0x00732823, // sw t2, 0x10(t1)
+            0x00000013, // nop
+            0x00100293, // addi t0, zero, 1, patched to addi t0, zero, 2
+            0x00000063, // beq zero, zero, 0
+        ]);
+        cpu_test.bus.store(memory::MEM_BASE + 0x40, 32, 0x00200293);
+        // the whole block was translated before the store patched it
+        for _ in 0..5 {
+            cpu_test.step();
+        }
+        assert_eq!(cpu_test.xregs.regs[5], 2);
+        assert_eq!(cpu_test.pc, memory::MEM_BASE + 0x14);
+    }
+
+    #[test]
+    fn test_blocks_are_reused_and_flushed() {
+        let mut cpu_test = machine(&[
+            0x00128293, // addi t0, t0, 1
+            0x00128293, // addi t0, t0, 1
+            0xfe000ce3, // beq zero, zero, -8
+        ]);
+        for _ in 0..30 {
+            cpu_test.step();
+        }
+        assert_eq!(cpu_test.xregs.regs[5], 20);
+        // the loop is a single block translated once
+        assert_eq!(cpu_test.blocks.len(), 1);
+
+        // code written by the host, e.g. a loader
+        cpu_test
+            .bus
+            .store_bytes(memory::MEM_BASE + 4, &0x00a28293u32.to_le_bytes())
+            .unwrap();
+        cpu_test.pc = memory::MEM_BASE;
+        for _ in 0..3 {
+            cpu_test.step();
+        }
+        assert_eq!(cpu_test.xregs.regs[5], 31);
+
+        // fence.i
+        cpu_test.execute(0x0000100f);
+        assert!(cpu_test.blocks.is_empty());
+    }
+
+    #[test]
+    fn test_instruction_across_pages() {
+        let mut cpu_test = machine(&[]);
+        let code = [
+            0x93, 0x82, 0x12, 0x00, // addi t0, t0, 1
+            0x01, 0x00, // c.nop
+            0x93, 0x82, 0x12, 0x00, // addi t0, t0, 1 across the page
+            0xdd, 0xbf, // c.j -10
+        ];
+        let start = memory::MEM_BASE + 0xff8;
+        cpu_test.bus.store_bytes(start, &code).unwrap();
+        cpu_test.pc = start;
+        for _ in 0..12 {
+            cpu_test.step();
+        }
+        assert_eq!(cpu_test.xregs.regs[5], 6);
+        assert_eq!(cpu_test.pc, start);
+
+        // patching the half on the second page is seen, addi t0, t0, 5
+        cpu_test
+            .bus
+            .store_bytes(memory::MEM_BASE + 0x1000, &[0x52, 0x00])
+            .unwrap();
+        for _ in 0..4 {
+            cpu_test.step();
+        }
+        assert_eq!(cpu_test.xregs.regs[5], 12);
+    }
+}
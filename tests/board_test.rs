@@ -0,0 +1,102 @@
+#[cfg(test)]
+mod tests {
+    use riscland::board::{Board, BoardError};
+    use riscland::cpu::Fault;
+    use riscland::machine::{HaltReason, Machine};
+
+    const BOARD: &str = r#"
+        harts = 2
+        reset_vector = 0x1000
+
+        [[rom]]
+        base = 0x1000
+        size = 0x100
+
+        [[ram]]
+        base = 0x80000000
+        size = 0x4000
+
+        [[ram]]
+        base = 0x20000000
+        size = 0x1000
+    "#;
+
+    fn error(text: &str) -> String {
+        Board::parse(text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_board() {
+        let board = Board::parse(BOARD).unwrap();
+        assert_eq!(board.harts, 2);
+        assert_eq!(board.isa, "rv32imac_zicsr_zifencei");
+
+        let mut cpu = board.cpu().unwrap();
+        assert_eq!(cpu.pc, 0x1000);
+        // the device tree sits at the top of RAM, the stack right below it
+        let dtb = cpu.xregs.regs[11];
+        assert!(dtb > 0x80003000 && dtb < 0x80004000 && dtb.is_multiple_of(8));
+        assert_eq!(
+            cpu.bus.load_bytes(dtb, 4),
+            Some(&[0xd0, 0x0d, 0xfe, 0xed][..])
+        );
+        assert_eq!(cpu.xregs.regs[2], dtb);
+        assert_eq!(cpu.bus.ram().0, 0x80000000);
+        // lui t0, 0x20000; sw t0, 0(t0); lw t1, 0(t0); sw t0, 0(zero)
+        let code: [u32; 4] = [0x200002b7, 0x0052a023, 0x0002a303, 0x00502023];
+        for (i, instr) in code.iter().enumerate() {
+            cpu.bus.store(0x80000000 + i as u32 * 4, 32, *instr);
+        }
+        // the ROM holds zeros, which is no instruction, and takes no stores
+        assert_eq!(cpu.bus.try_store(0x1000, 32, 1), None);
+        assert_eq!(cpu.bus.try_load(0x10fc, 32), Some(0));
+
+        cpu.pc = 0x80000000;
+        let mut m = Machine::new(cpu);
+        assert_eq!(m.run(10), HaltReason::Fault(Fault::StoreAccess(0)));
+        assert_eq!(m.cpu.xregs.regs[6], 0x20000000);
+    }
+
+    #[test]
+    fn test_errors_name_the_key() {
+        // the TOML parser points at keys it does not know or cannot use
+        let e = error("ram = [{ base = 0x80000000, size = 0x1000, colour = 1 }]");
+        assert!(e.contains("unknown field `colour`"), "{}", e);
+        let e = error("ram = [{ base = -1, size = 0x1000 }]");
+        assert!(e.contains("ram"), "{}", e);
+        assert!(matches!(
+            Board::parse("harts = 1"),
+            Err(BoardError::Parse(_))
+        ));
+
+        let e = error(&format!(
+            "{}\n[[ram]]\nbase = 0x80002000\nsize = 0x10",
+            BOARD
+        ));
+        assert_eq!(e, "ram[2].base: overlaps ram[0]");
+        let e = error(&BOARD.replace("0x100\n", "0\n"));
+        assert_eq!(e, "rom[0].size: must not be 0");
+        let e = error(&BOARD.replace("reset_vector = 0x1000", "reset_vector = 0x2000"));
+        assert_eq!(e, "reset_vector: 0x2000 is neither in RAM nor in ROM");
+        let e = error(&BOARD.replace("harts = 2", "isa = \"rv64gc\""));
+        assert_eq!(e, "isa: `rv64gc` is not an rv32 ISA");
+        let e = error(&format!("{}\n[[device]]\ntype = \"nope\"\nbase = 0", BOARD));
+        assert_eq!(e, "device[0].type: unknown device `nope`");
+
+        let board = format!(
+            "{}\n[[device]]\ntype = \"virtio-blk\"\nbase = 0x10001000",
+            BOARD
+        );
+        let e = Board::parse(&board).unwrap().cpu().unwrap_err().to_string();
+        assert_eq!(e, "device[0].image: a disk needs an image");
+
+        let mut board = Board::parse(BOARD).unwrap();
+        board.rom[0].image = Some("/nonexistent/boot.bin".into());
+        let e = board.cpu().unwrap_err().to_string();
+        assert!(
+            e.starts_with("rom[0].image: /nonexistent/boot.bin: "),
+            "{}",
+            e
+        );
+    }
+}
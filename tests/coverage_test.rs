@@ -0,0 +1,70 @@
+#[cfg(test)]
+mod tests {
+    use riscland::{coverage::Coverage, cpu, elf, memory};
+
+    // tests/loop-dwarf.o is tests/loop-dwarf.s (built as loop.s) assembled with
+    // `llvm-mc -triple=riscv32 -mattr=-relax -filetype=obj -g`
+    const OBJ: &str = "./tests/loop-dwarf.o";
+
+    fn run_loop() -> (cpu::CPU, Coverage) {
+        let code = [
+            0x00300293, // addi t0, zero, 3
+            0xfff28293, // addi t0, t0, -1
+            0xfe029ee3, // bnez t0, -4
+            0x00628463, // beq t0, t1, +8
+            0x00100313, // addi t1, zero, 1
+        ];
+        let mut cpu_test = cpu::CPU::new();
+        cpu_test.trace = false;
+        cpu_test.bus = memory::BUS::with_memory(0, 0x100);
+        for (i, instr) in code.iter().enumerate() {
+            cpu_test.bus.store(i as u32 * 4, 32, *instr);
+        }
+        cpu_test.pc = 0;
+        cpu_test.coverage = Some(Coverage::new());
+        while cpu_test.pc != 0x14 {
+            let pc = cpu_test.pc;
+            let instr = cpu_test.fetch().unwrap();
+            cpu_test.execute(instr);
+            cpu_test.retire(pc, instr);
+        }
+        let coverage = cpu_test.coverage.take().unwrap();
+        (cpu_test, coverage)
+    }
+
+    #[test]
+    fn test_hits_and_branches() {
+        let (_, coverage) = run_loop();
+        assert_eq!(coverage.hits(0x0), 1);
+        assert_eq!(coverage.hits(0x4), 3);
+        assert_eq!(coverage.hits(0x10), 0);
+        let bnez = coverage.branch(0x8).unwrap();
+        assert_eq!((bnez.taken, bnez.not_taken), (2, 1));
+        let beq = coverage.branch(0xc).unwrap();
+        assert_eq!((beq.taken, beq.not_taken), (1, 0));
+        let report = coverage.report(&elf::Symbols::default());
+        assert!(report.contains("0x00000008 <0x8> 3 taken 2 not-taken 1\n"));
+    }
+
+    #[test]
+    fn test_lcov_from_dwarf() {
+        let lines = elf::ELF::new(OBJ).read_line_table();
+        let (file, line) = lines.lookup(0x8).unwrap();
+        assert!(file.ends_with("loop.s"));
+        assert_eq!(line, 7);
+
+        let (cpu_test, coverage) = run_loop();
+        let lcov = coverage.lcov(&lines, &cpu_test.bus);
+        for record in [
+            "DA:4,1\n",
+            "DA:6,3\n",
+            "DA:9,0\n",
+            "BRDA:7,0,0,2\nBRDA:7,0,1,1\n",
+            "BRDA:8,0,0,1\nBRDA:8,0,1,0\n",
+            "BRF:4\nBRH:3\n",
+            "LF:6\nLH:4\nend_of_record\n",
+        ] {
+            assert!(lcov.contains(record), "missing {:?} in\n{}", record, lcov);
+        }
+    }
+}
@@ -77,14 +77,14 @@ mod tests {
         // beq x8, x7, 12
         let instr: u32 = helper::set_b_type_instruction(12, 7, 8, BEQ as u8);
         cpu::exec_beq(&mut cpu_test, instr);
-        assert_eq!(cpu_test.pc as i32, (ori_pc as i32) + 12 - 4);
+        assert_eq!(cpu_test.pc as i32, (ori_pc as i32) + 12);
 
         // set x9=4
         helper::set_register_val(&mut cpu_test, 9, 4);
         // beq x9, x7, 12
         let instr: u32 = helper::set_b_type_instruction(12, 7, 9, BEQ as u8);
         cpu::exec_beq(&mut cpu_test, instr);
-        assert_eq!(cpu_test.pc as i32, (ori_pc as i32) + 12 - 4);
+        assert_eq!(cpu_test.pc as i32, (ori_pc as i32) + 12);
     }
     #[test]
     fn test_exec_bne() {
@@ -107,7 +107,7 @@ mod tests {
         // bne x9, x7, 12
         let instr: u32 = helper::set_b_type_instruction(12, 7, 9, BNE as u8);
         cpu::exec_bne(&mut cpu_test, instr);
-        assert_eq!(cpu_test.pc as i32, (ori_pc as i32) + 12 - 4);
+        assert_eq!(cpu_test.pc as i32, (ori_pc as i32) + 12);
     }
     #[test]
     fn test_exec_blt() {
@@ -130,7 +130,7 @@ mod tests {
         // blt x9, x7, 12
         let instr: u32 = helper::set_b_type_instruction(12, 7, 9, BLT as u8);
         cpu::exec_blt(&mut cpu_test, instr);
-        assert_eq!(cpu_test.pc as i32, (ori_pc as i32) + 12 - 4);
+        assert_eq!(cpu_test.pc as i32, (ori_pc as i32) + 12);
     }
     #[test]
     fn test_exec_bge() {
@@ -153,7 +153,7 @@ mod tests {
         // bge x9, x7, 12
         let instr: u32 = helper::set_b_type_instruction(12, 7, 9, BGE as u8);
         cpu::exec_bge(&mut cpu_test, instr);
-        assert_eq!(cpu_test.pc as i32, (ori_pc as i32) + 12 - 4);
+        assert_eq!(cpu_test.pc as i32, (ori_pc as i32) + 12);
     }
     #[test]
     fn test_exec_bltu() {
@@ -176,7 +176,7 @@ mod tests {
         // bltu x9, x7, 12
         let instr: u32 = helper::set_b_type_instruction(12, 7, 9, BLTU as u8);
         cpu::exec_bltu(&mut cpu_test, instr);
-        assert_eq!(cpu_test.pc as i32, (ori_pc as i32) + 12 - 4);
+        assert_eq!(cpu_test.pc as i32, (ori_pc as i32) + 12);
     }
     #[test]
     fn test_exec_bgeu() {
@@ -199,7 +199,7 @@ mod tests {
         // bgeu x9, x7, 12
         let instr: u32 = helper::set_b_type_instruction(12, 7, 9, BGEU as u8);
         cpu::exec_bgeu(&mut cpu_test, instr);
-        assert_eq!(cpu_test.pc as i32, (ori_pc as i32) + 12 - 4);
+        assert_eq!(cpu_test.pc as i32, (ori_pc as i32) + 12);
     }
     #[test]
     fn test_exec_lb() {
@@ -0,0 +1,253 @@
+#[cfg(test)]
+mod tests {
+    use riscland::board::{Board, Boot, KERNEL_OFFSET};
+    use riscland::device::Mapped;
+    use riscland::fdt;
+    use riscland::machine::{HaltReason, Machine};
+    use riscland::memory::{self, MEM_BASE};
+    use riscland::uart::{Uart, UART_SIZE};
+    use riscland::{cpu, isa};
+
+    const BOARD: &str = r#"
+        harts = 2
+        bootargs = "console=ttyS0"
+
+        [[ram]]
+        base = 0x80000000
+        size = 0x100000
+
+        [[device]]
+        type = "clint"
+        base = 0x2000000
+
+        [[device]]
+        type = "plic"
+        base = 0xc000000
+
+        [[device]]
+        type = "uart"
+        base = 0x10000000
+        irq = 10
+    "#;
+
+    fn be32(blob: &[u8], off: usize) -> u32 {
+        u32::from_be_bytes(blob[off..off + 4].try_into().unwrap())
+    }
+
+    fn cstr(blob: &[u8], off: usize) -> &str {
+        let end = off + blob[off..].iter().position(|b| *b == 0).unwrap();
+        std::str::from_utf8(&blob[off..end]).unwrap()
+    }
+
+    // every property as "/path/to/node:name" and its raw value
+    fn props(blob: &[u8]) -> Vec<(String, Vec<u8>)> {
+        assert_eq!(be32(blob, 0), 0xd00dfeed);
+        assert_eq!(be32(blob, 4) as usize, blob.len());
+        let strings = be32(blob, 12) as usize;
+        let mut p = be32(blob, 8) as usize;
+        let mut path: Vec<String> = Vec::new();
+        let mut props = Vec::new();
+        loop {
+            let token = be32(blob, p);
+            p += 4;
+            match token {
+                1 => {
+                    let name = cstr(blob, p);
+                    p = (p + name.len() + 4) & !3;
+                    path.push(name.to_string());
+                }
+                2 => {
+                    path.pop();
+                }
+                3 => {
+                    let len = be32(blob, p) as usize;
+                    let name = cstr(blob, strings + be32(blob, p + 4) as usize);
+                    let value = blob[p + 8..p + 8 + len].to_vec();
+                    p = (p + 8 + len + 3) & !3;
+                    props.push((format!("{}:{}", path.join("/"), name), value));
+                }
+                9 => break,
+                _ => panic!("bad token {}", token),
+            }
+        }
+        assert!(path.is_empty());
+        props
+    }
+
+    fn prop<'a>(props: &'a [(String, Vec<u8>)], key: &str) -> &'a [u8] {
+        &props.iter().find(|(k, _)| k == key).unwrap().1
+    }
+
+    fn cells(value: &[u8]) -> Vec<u32> {
+        value
+            .chunks(4)
+            .map(|c| u32::from_be_bytes(c.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_machine_tree() {
+        let cpu = Board::parse(BOARD).unwrap().cpu().unwrap();
+        let chosen = fdt::Chosen {
+            bootargs: "console=ttyS0",
+            initrd: None,
+        };
+        let blob = fdt::machine(&cpu.bus, 2, cpu.isa(), &chosen);
+        // the same tree the board put into memory for the guest
+        let addr = cpu.xregs.regs[11];
+        assert_eq!(cpu.bus.load_bytes(addr, blob.len()), Some(&blob[..]));
+
+        let props = props(&blob);
+        assert_eq!(prop(&props, "/chosen:bootargs"), b"console=ttyS0\0");
+        assert_eq!(
+            prop(&props, "/chosen:stdout-path"),
+            b"/soc/serial@10000000\0"
+        );
+        assert_eq!(
+            cells(prop(&props, "/memory@80000000:reg")),
+            [0x80000000, 0x100000]
+        );
+        let riscv_isa = format!("{}\0", isa::DEFAULT);
+        assert_eq!(prop(&props, "/cpus/cpu@1:riscv,isa"), riscv_isa.as_bytes());
+        assert_eq!(
+            cells(prop(&props, "/cpus/cpu@1/interrupt-controller:phandle")),
+            [fdt::intc_phandle(1)]
+        );
+        assert_eq!(
+            cells(prop(&props, "/soc/clint@2000000:interrupts-extended")),
+            [1, 3, 1, 7, 2, 3, 2, 7]
+        );
+        assert_eq!(
+            cells(prop(&props, "/soc/plic@c000000:phandle")),
+            [fdt::PLIC_PHANDLE]
+        );
+        assert_eq!(
+            cells(prop(&props, "/soc/serial@10000000:reg")),
+            [0x10000000, UART_SIZE]
+        );
+        assert_eq!(cells(prop(&props, "/soc/serial@10000000:interrupts")), [10]);
+        assert_eq!(
+            prop(&props, "/soc/serial@10000000:compatible"),
+            b"ns16550a\0"
+        );
+    }
+
+    #[test]
+    fn test_virt_boot() {
+        let dir = std::env::temp_dir().join(format!("riscland-virt-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let kernel = dir.join("Image");
+        let initrd = dir.join("rootfs.cpio");
+        std::fs::write(&kernel, [0x13, 0, 0, 0]).unwrap();
+        std::fs::write(&initrd, vec![0x5a; 0x1800]).unwrap();
+        let boot = Boot {
+            bios: Some("tests/rv32ui-p-auipc".into()),
+            kernel: Some(kernel),
+            initrd: Some(initrd),
+            append: Some("console=ttyS0 rdinit=/bin/sh".into()),
+        };
+        let (cpu, blob) = Board::virt().boot(&boot).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // the firmware starts, the kernel waits where fw_jump jumps to
+        assert_eq!(cpu.pc, 0x80000000);
+        assert_eq!(cpu.bus.load(0x80000000 + KERNEL_OFFSET, 32), 0x13);
+        assert_eq!(cpu.xregs.regs[10], 0);
+        let addr = cpu.xregs.regs[11];
+        assert_eq!(cpu.bus.load_bytes(addr, blob.len()), Some(&blob[..]));
+
+        let props = props(&blob);
+        assert_eq!(
+            prop(&props, "/chosen:bootargs"),
+            b"console=ttyS0 rdinit=/bin/sh\0"
+        );
+        let start = cells(prop(&props, "/chosen:linux,initrd-start"))[0];
+        let end = cells(prop(&props, "/chosen:linux,initrd-end"))[0];
+        assert_eq!(end - start, 0x1800);
+        assert!(start.is_multiple_of(0x1000) && end <= addr);
+        assert_eq!(cpu.bus.load_bytes(start, 0x1800), Some(&[0x5a; 0x1800][..]));
+        assert_eq!(
+            cells(prop(&props, "/memory@80000000:reg")),
+            [0x80000000, 0x8000000]
+        );
+        assert_eq!(cells(prop(&props, "/soc/serial@10000000:interrupts")), [10]);
+        assert_eq!(
+            prop(&props, "/soc/test@100000/poweroff:compatible"),
+            b"syscon-poweroff\0"
+        );
+        assert_eq!(
+            cells(prop(&props, "/soc/test@100000/reboot:value")),
+            [0x7777]
+        );
+
+        // without firmware the kernel starts at the start of RAM
+        let boot = Boot {
+            kernel: Some("tests/rv32ui-p-auipc".into()),
+            ..Boot::default()
+        };
+        let (cpu, _) = Board::virt().boot(&boot).unwrap();
+        assert_eq!(cpu.pc, 0x80000000);
+        let e = Board::virt()
+            .boot(&Boot {
+                kernel: Some("/nonexistent/Image".into()),
+                ..Boot::default()
+            })
+            .unwrap_err()
+            .to_string();
+        assert!(e.starts_with("kernel: /nonexistent/Image: "), "{}", e);
+
+        // images that run past the end of RAM or of the address space are
+        // errors, not panics
+        let board =
+            Board::parse("reset_vector = 0xfff00000\n[[ram]]\nbase = 0xfff00000\nsize = 0x100000")
+                .unwrap();
+        let e = board
+            .boot(&Boot {
+                bios: Some("tests/rv32ui-p-add.bin".into()),
+                kernel: Some("tests/rv32ui-p-add.bin".into()),
+                ..Boot::default()
+            })
+            .unwrap_err()
+            .to_string();
+        assert_eq!(e, "kernel: does not fit into RAM");
+        let e = board
+            .boot(&Boot {
+                kernel: Some("tests/rv32ui-p-auipc".into()),
+                ..Boot::default()
+            })
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("does not fit into RAM"), "{}", e);
+    }
+
+    #[test]
+    fn test_uart_output() {
+        let mut cpu_test = cpu::CPU::new();
+        cpu_test.trace = false;
+        cpu_test.bus = memory::BUS::with_memory(MEM_BASE, 0x1000);
+        let (uart, out) = Uart::captured();
+        cpu_test.bus.add_device(Mapped {
+            base: 0x10000000,
+            size: UART_SIZE,
+            irq: None,
+            device: Box::new(uart),
+        });
+        let code = [
+            0x100002b7, // lui t0, 0x10000
+            0x06800313, // addi t1, zero, 'h'
+            0x00628023, // sb t1, 0(t0)
+            0x06900313, // addi t1, zero, 'i'
+            0x00628023, // sb t1, 0(t0)
+            0x0052c383, // lbu t2, 5(t0)
+        ];
+        for (i, instr) in code.iter().enumerate() {
+            cpu_test.bus.store(MEM_BASE + i as u32 * 4, 32, *instr);
+        }
+        cpu_test.pc = MEM_BASE;
+        let mut m = Machine::new(cpu_test);
+        assert_eq!(m.run(6), HaltReason::Limit);
+        assert_eq!(*out.lock().unwrap(), b"hi");
+        // transmitter empty
+        assert_eq!(m.cpu.xregs.regs[7], 0x60);
+    }
+}
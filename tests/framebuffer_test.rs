@@ -0,0 +1,133 @@
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use riscland::board::{Board, Boot};
+    use riscland::device::Device;
+    use riscland::framebuffer::{Framebuffer, Mode, PixelFormat};
+    use riscland::machine::{HaltReason, Machine};
+
+    fn decode_png(path: &std::path::Path) -> (u32, u32, Vec<u8>) {
+        let decoder = png::Decoder::new(File::open(path).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf).unwrap();
+        buf.truncate(info.buffer_size());
+        (info.width, info.height, buf)
+    }
+
+    #[test]
+    fn test_pixel_formats() {
+        assert_eq!(
+            Mode::parse("320x240:r5g6b5"),
+            Ok(Mode {
+                width: 320,
+                height: 240,
+                format: PixelFormat::Rgb565,
+            })
+        );
+        assert!(Mode::parse("320x0").is_err());
+        assert!(Mode::parse("320x240:yuv").is_err());
+
+        let mut fb = Framebuffer::new(Mode::parse("2x1:r5g6b5").unwrap());
+        fb.store(0, 16, 0xf800);
+        fb.store(2, 16, 0x001f);
+        assert_eq!(fb.load(0, 32), 0x001ff800);
+        assert_eq!(fb.rgb(), [255, 0, 0, 0, 0, 255]);
+
+        let mut fb = Framebuffer::new(Mode::parse("2x1:x8b8g8r8").unwrap());
+        fb.store(4, 32, 0x00ff8040);
+        assert_eq!(fb.rgb(), [0, 0, 0, 0x40, 0x80, 0xff]);
+        let mut ppm = Vec::new();
+        fb.write_ppm(&mut ppm).unwrap();
+        assert_eq!(ppm, b"P6\n2 1\n255\n\0\0\0\x40\x80\xff");
+    }
+
+    #[test]
+    fn test_dumps() {
+        let dir = std::env::temp_dir().join(format!("riscland-fb-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let board = format!(
+            r#"
+            [[ram]]
+            base = 0x80000000
+            size = 0x10000
+
+            [[device]]
+            type = "framebuffer"
+            base = 0x40000000
+            width = 4
+            height = 2
+            dump = "{}"
+            dump_every = 1000
+            "#,
+            dir.join("fb.png").display()
+        );
+        let mut cpu = Board::parse(&board).unwrap().cpu().unwrap();
+        cpu.trace = false;
+        // lui t0, 0x40000; lui t1, 0xff0; sw t1, 0(t0); lui t2, 1;
+        // add t2, t2, t0; sw zero, 0(t2); j .
+        let code = [
+            0x400002b7, 0x00ff0337, 0x0062a023, 0x000013b7, 0x005383b3, 0x0003a023, 0x0000006f,
+        ];
+        for (i, instr) in code.iter().enumerate() {
+            cpu.bus.store(cpu.pc + i as u32 * 4, 32, *instr);
+        }
+        let mut m = Machine::new(cpu);
+        assert_eq!(m.run(100), HaltReason::Limit);
+        // the frame the guest asked for
+        let (width, height, rgb) = decode_png(&dir.join("fb-0000.png"));
+        assert_eq!((width, height), (4, 2));
+        assert_eq!(&rgb[..6], [255, 0, 0, 0, 0, 0]);
+        assert!(!dir.join("fb-0001.png").exists());
+
+        // and one at each poll after another 1000 instructions
+        m.run(25_000);
+        assert!(dir.join("fb-0001.png").exists());
+        assert!(dir.join("fb-0002.png").exists());
+        assert!(!dir.join("fb-0003.png").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_device_tree_and_errors() {
+        let board = r#"
+            [[ram]]
+            base = 0x80000000
+            size = 0x10000
+
+            [[device]]
+            type = "framebuffer"
+            base = 0x40000000
+            width = 800
+            height = 600
+            format = "r5g6b5"
+        "#;
+        let board = Board::parse(board).unwrap();
+        let bus = board.bus().unwrap();
+        // the pixels and the control page
+        assert_eq!(bus.devices()[0].size, 0xec000);
+        let (_, dtb) = board.boot(&Boot::default()).unwrap();
+        let find = |needle: &[u8]| dtb.windows(needle.len()).any(|w| w == needle);
+        assert!(find(b"framebuffer@40000000\0"));
+        assert!(find(b"simple-framebuffer\0"));
+        assert!(find(b"r5g6b5\0"));
+
+        let zero = board_error("width = 0");
+        assert_eq!(zero, "device[0].width: must not be 0");
+        let huge = board_error("width = 100000\nheight = 100000");
+        assert_eq!(
+            huge,
+            "device[0].width: the frame does not fit into the address space"
+        );
+    }
+
+    fn board_error(keys: &str) -> String {
+        let board = format!(
+            "[[ram]]\nbase = 0x80000000\nsize = 0x10000\n\n\
+             [[device]]\ntype = \"framebuffer\"\nbase = 0x40000000\n{}\n",
+            keys
+        );
+        Board::parse(&board).unwrap_err().to_string()
+    }
+}
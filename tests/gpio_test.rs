@@ -0,0 +1,108 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use riscland::board::Board;
+    use riscland::device::Device;
+    use riscland::gpio::{Gpio, Pins};
+    use riscland::machine::Machine;
+
+    // a button on pin 3 and what the guest drove
+    #[derive(Debug, Clone, Default)]
+    struct Bench {
+        button: Arc<Mutex<bool>>,
+        driven: Arc<Mutex<Vec<(u32, u32)>>>,
+    }
+
+    impl Pins for Bench {
+        fn input(&mut self, _instret: u64) -> u32 {
+            (*self.button.lock().unwrap() as u32) << 3
+        }
+
+        fn output(&mut self, _instret: u64, value: u32, enabled: u32) {
+            self.driven.lock().unwrap().push((value, enabled));
+        }
+
+        fn clone_box(&self) -> Box<dyn Pins> {
+            Box::new(self.clone())
+        }
+    }
+
+    #[test]
+    fn test_pins() {
+        let bench = Bench::default();
+        let mut gpio = Gpio::new(Box::new(bench.clone()));
+        // input_en on pin 3, nothing reads without it
+        assert_eq!(gpio.load(0x00, 32), 0);
+        gpio.store(0x04, 32, 1 << 3);
+        *bench.button.lock().unwrap() = true;
+        assert_eq!(gpio.load(0x00, 32), 1 << 3);
+        // rise_ip latched, a 1 clears it
+        assert_eq!(gpio.load(0x1c, 32), 1 << 3);
+        gpio.store(0x1c, 32, 1 << 3);
+        assert_eq!(gpio.load(0x1c, 32), 0);
+        *bench.button.lock().unwrap() = false;
+        assert_eq!(gpio.load(0x00, 32), 0);
+        assert_eq!(gpio.load(0x24, 32), 1 << 3);
+
+        // pin 0 driven high, inverted by out_xor
+        gpio.store(0x0c, 32, 1);
+        gpio.store(0x08, 32, 1);
+        gpio.store(0x40, 32, 1);
+        assert_eq!(*bench.driven.lock().unwrap(), [(1, 1), (0, 1)]);
+        // a driven pin reads back what is driven
+        gpio.store(0x04, 32, 1);
+        gpio.store(0x40, 32, 0);
+        assert_eq!(gpio.load(0x00, 32), 1);
+    }
+
+    // the guest copies pin 3 to pin 0:
+    //   lui t0, 0x10012; addi t1, zero, 8; sw t1, 4(t0); addi t1, zero, 1
+    //   sw t1, 8(t0)
+    //   loop: lw t2, 0(t0); srli t2, t2, 3; sw t2, 12(t0); j loop
+    fn follow_button(log: &std::path::Path) -> String {
+        let board = format!(
+            r#"
+            [[ram]]
+            base = 0x80000000
+            size = 0x10000
+
+            [[device]]
+            type = "sifive-gpio"
+            base = 0x10012000
+            input = [{{ at = 120, pin = 3, level = false }}, {{ at = 50, pin = 3, level = true }}]
+            log = "{}"
+            "#,
+            log.display()
+        );
+        let mut cpu = Board::parse(&board).unwrap().cpu().unwrap();
+        cpu.trace = false;
+        let code = [
+            0x100122b7, 0x00800313, 0x0062a223, 0x00100313, 0x0062a423, 0x0002a383, 0x0033d393,
+            0x0072a623, 0xff5ff06f,
+        ];
+        for (i, instr) in code.iter().enumerate() {
+            cpu.bus.store(cpu.pc + i as u32 * 4, 32, *instr);
+        }
+        let mut m = Machine::new(cpu);
+        m.run(200);
+        drop(m);
+        std::fs::read_to_string(log).unwrap()
+    }
+
+    #[test]
+    fn test_script() {
+        let log = std::env::temp_dir().join(format!("riscland-gpio-{}.log", std::process::id()));
+        let first = follow_button(&log);
+        let lines: Vec<&str> = first.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with(" 00000000 00000001"));
+        let (at, rest) = lines[1].split_once(' ').unwrap();
+        assert!((50..60).contains(&at.parse::<u64>().unwrap()));
+        assert_eq!(rest, "00000001 00000001");
+        assert!(lines[2].ends_with(" 00000000 00000001"));
+        // the same on every run
+        assert_eq!(follow_button(&log), first);
+        std::fs::remove_file(&log).unwrap();
+    }
+}
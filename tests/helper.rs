@@ -1,5 +1,8 @@
+// shared by the test crates, none of which uses all of it
+#![allow(dead_code)]
+
 use riscland::{
-    cpu,
+    cpu, memory,
     opcode::{ADDI, B_TYPE, I_TYPE, LOAD, LUI, R_TYPE, S_TYPE},
 };
 
@@ -80,3 +83,14 @@ pub fn set_register_val(cpu: &mut cpu::CPU, rd: u8, val: i32) {
     let instr: u32 = set_u_type_instruction((val as u32 & 0xfffff000) as i32, rd, LUI as u8);
     cpu::exec_lui(cpu, instr);
 }
+
+// a hart at MEM_BASE, where `code` starts 16 KiB of otherwise zeroed RAM
+pub fn hart(code: &[u32]) -> cpu::CPU {
+    let mut cpu = cpu::CPU::new();
+    cpu.bus = memory::BUS::with_memory(memory::MEM_BASE, 0x4000);
+    for (i, instr) in code.iter().enumerate() {
+        cpu.bus.store(memory::MEM_BASE + i as u32 * 4, 32, *instr);
+    }
+    cpu.pc = memory::MEM_BASE;
+    cpu
+}
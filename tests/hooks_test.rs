@@ -0,0 +1,163 @@
+mod helper;
+
+#[cfg(test)]
+mod tests {
+    use crate::helper;
+    use std::sync::{Arc, Mutex};
+
+    use riscland::cpu::Fault;
+    use riscland::csr;
+    use riscland::hooks::{Hooks, Privilege, Trap};
+    use riscland::machine::{HaltReason, Machine};
+    use riscland::memory::MEM_BASE;
+    use riscland::smp::SMP;
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Retire(u32),
+        Read(u32, u32, u32),
+        Write(u32, u32, u32),
+        Trap(u32, Trap),
+        Csr(u32, u32, Option<u32>),
+        Privilege(Privilege, Privilege),
+    }
+
+    // records into a log the test keeps a handle on
+    struct Recorder(Arc<Mutex<Vec<Event>>>);
+
+    impl Hooks for Recorder {
+        fn retire(&mut self, pc: u32, _instr: u32) {
+            self.0.lock().unwrap().push(Event::Retire(pc));
+        }
+        fn mem_read(&mut self, addr: u32, size: u32, value: u32) {
+            self.0.lock().unwrap().push(Event::Read(addr, size, value));
+        }
+        fn mem_write(&mut self, addr: u32, size: u32, value: u32) {
+            self.0.lock().unwrap().push(Event::Write(addr, size, value));
+        }
+        fn trap(&mut self, pc: u32, trap: Trap) {
+            self.0.lock().unwrap().push(Event::Trap(pc, trap));
+        }
+        fn csr(&mut self, csr: u32, old: u32, new: Option<u32>) {
+            self.0.lock().unwrap().push(Event::Csr(csr, old, new));
+        }
+        fn privilege(&mut self, from: Privilege, to: Privilege) {
+            self.0.lock().unwrap().push(Event::Privilege(from, to));
+        }
+    }
+
+    // counts retired instructions and nothing else
+    struct Counter(Arc<Mutex<u64>>);
+
+    impl Hooks for Counter {
+        fn retire(&mut self, _pc: u32, _instr: u32) {
+            *self.0.lock().unwrap() += 1;
+        }
+    }
+
+    fn machine(code: &[u32]) -> Machine {
+        Machine::new(helper::hart(code))
+    }
+
+    #[test]
+    fn test_events() {
+        let mut m = machine(&[
+            0x80001337, // lui t1, 0x80001
+            0x02a00393, // addi t2, zero, 42
+            0x00732223, // sw t2, 4(t1)
+            0x00432e03, // lw t3, 4(t1)
+            0x340e1ef3, // csrrw t4, mscratch, t3
+            0x34002f73, // csrr t5, mscratch
+            0x00000073, // ecall
+            0x00002303, // lw t1, 0(zero)
+        ]);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let count = Arc::new(Mutex::new(0));
+        m.add_hooks(Recorder(log.clone()));
+        m.add_hooks(Counter(count.clone()));
+
+        assert_eq!(m.run(100), HaltReason::Ecall);
+        // the ecall traps once it runs
+        assert_eq!(m.run(100), HaltReason::Fault(Fault::LoadAccess(0)));
+
+        let data = MEM_BASE + 0x1004;
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                Event::Retire(MEM_BASE),
+                Event::Retire(MEM_BASE + 4),
+                Event::Write(data, 32, 42),
+                Event::Retire(MEM_BASE + 8),
+                Event::Read(data, 32, 42),
+                Event::Retire(MEM_BASE + 12),
+                Event::Csr(csr::MSCRATCH, 0, Some(42)),
+                Event::Retire(MEM_BASE + 16),
+                Event::Csr(csr::MSCRATCH, 42, None),
+                Event::Retire(MEM_BASE + 20),
+                Event::Trap(MEM_BASE + 24, Trap::Ecall),
+                Event::Retire(MEM_BASE + 24),
+                Event::Trap(MEM_BASE + 28, Trap::Fault(Fault::LoadAccess(0))),
+            ]
+        );
+        assert_eq!(*count.lock().unwrap(), 7);
+    }
+
+    #[test]
+    fn test_hooks_see_every_block_instruction() {
+        let mut m = machine(&[
+            0x00128293, // addi t0, t0, 1
+            0xffdff06f, // jal zero, -4
+        ]);
+        let count = Arc::new(Mutex::new(0));
+        m.add_hooks(Counter(count.clone()));
+        assert_eq!(m.run(1000), HaltReason::Limit);
+        assert_eq!(*count.lock().unwrap(), 1000);
+        assert_eq!(m.cpu.xregs.regs[5], 500);
+
+        // the harts of a machine made from it report to the same hooks
+        let mut smp = SMP::new(m.cpu, 2);
+        let retired = smp.run(100);
+        assert_eq!(retired, 200);
+        assert_eq!(*count.lock().unwrap(), 1200);
+    }
+
+    #[test]
+    fn test_privilege_changes() {
+        let handler = MEM_BASE + 0x100;
+        let mut m = machine(&[
+            0x30200073, // mret, to user mode
+            0x00000013, // nop
+            0x00000073, // ecall
+            0x00000063, // beq zero, zero, 0
+        ]);
+        for (i, instr) in [0x341022f3, 0x00428293, 0x34129073, 0x30200073]
+            .iter()
+            .enumerate()
+        {
+            // csrr t0, mepc; addi t0, t0, 4; csrw mepc, t0; mret
+            m.cpu.bus.store(handler + i as u32 * 4, 32, *instr);
+        }
+        m.cpu.csrs.write(csr::MTVEC, handler);
+        m.cpu.csrs.write(csr::MEPC, MEM_BASE + 8);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        m.add_hooks(Recorder(log.clone()));
+
+        assert_eq!(m.run(7), HaltReason::Limit);
+        assert_eq!(m.cpu.privilege, Privilege::User);
+        assert_eq!(m.cpu.pc, MEM_BASE + 12);
+        let log = log.lock().unwrap();
+        let changes: Vec<_> = log
+            .iter()
+            .filter(|e| matches!(e, Event::Privilege(..) | Event::Trap(..)))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                &Event::Privilege(Privilege::Machine, Privilege::User),
+                &Event::Trap(MEM_BASE + 8, Trap::Ecall),
+                &Event::Privilege(Privilege::User, Privilege::Machine),
+                &Event::Privilege(Privilege::Machine, Privilege::User),
+            ]
+        );
+    }
+}
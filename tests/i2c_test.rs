@@ -0,0 +1,82 @@
+#[cfg(test)]
+mod tests {
+    use riscland::board::Board;
+    use riscland::device::Device;
+    use riscland::i2c::{I2c, Registers, Target};
+
+    const TXR: u32 = 0x0c;
+    const CR: u32 = 0x10;
+    const STA: u32 = 0x80;
+    const STO: u32 = 0x40;
+    const RD: u32 = 0x20;
+    const WR: u32 = 0x10;
+    const ACK: u32 = 0x08;
+    const RXACK: u32 = 0x80;
+
+    fn sensor() -> I2c {
+        let mut i2c = I2c::new(Box::new(Registers::new(vec![Target {
+            address: 0x48,
+            registers: vec![0x19, 0x80, 0x00, 0x00],
+        }])));
+        // enable
+        i2c.store(0x08, 32, 0x80);
+        i2c
+    }
+
+    fn write(i2c: &mut I2c, byte: u32, cr: u32) -> bool {
+        i2c.store(TXR, 32, byte);
+        i2c.store(CR, 32, cr | WR);
+        i2c.load(CR, 32) & RXACK == 0
+    }
+
+    #[test]
+    fn test_register_reads_and_writes() {
+        let mut i2c = sensor();
+        // point at register 0 and read two bytes after a repeated start
+        assert!(write(&mut i2c, 0x48 << 1, STA));
+        assert_eq!(i2c.load(CR, 32) & 0x41, 0x41);
+        assert!(write(&mut i2c, 0, 0));
+        assert!(write(&mut i2c, 0x48 << 1 | 1, STA));
+        i2c.store(CR, 32, RD);
+        assert_eq!(i2c.load(TXR, 32), 0x19);
+        i2c.store(CR, 32, RD | ACK | STO);
+        assert_eq!(i2c.load(TXR, 32), 0x80);
+        // not busy any more, IF until acknowledged
+        assert_eq!(i2c.load(CR, 32) & 0x41, 0x01);
+        i2c.store(CR, 32, 1);
+        assert_eq!(i2c.load(CR, 32), 0);
+
+        // write register 2 and read it back
+        assert!(write(&mut i2c, 0x48 << 1, STA));
+        assert!(write(&mut i2c, 2, 0));
+        assert!(write(&mut i2c, 0x5a, STO));
+        assert!(write(&mut i2c, 0x48 << 1, STA));
+        assert!(write(&mut i2c, 2, 0));
+        assert!(write(&mut i2c, 0x48 << 1 | 1, STA));
+        i2c.store(CR, 32, RD | ACK | STO);
+        assert_eq!(i2c.load(TXR, 32), 0x5a);
+
+        // nobody at 0x50
+        assert!(!write(&mut i2c, 0x50 << 1, STA));
+        assert!(!write(&mut i2c, 0, STO));
+    }
+
+    #[test]
+    fn test_board() {
+        let board = r#"
+            [[ram]]
+            base = 0x80000000
+            size = 0x10000
+
+            [[device]]
+            type = "sifive-i2c"
+            base = 0x10016000
+            target = [{ address = 0x98, registers = [] }]
+        "#;
+        let err = Board::parse(board).unwrap().bus().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "device[0].target: 0x98 is no 7-bit address"
+        );
+    }
+}
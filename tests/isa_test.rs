@@ -0,0 +1,158 @@
+mod helper;
+
+#[cfg(test)]
+mod tests {
+    use crate::helper;
+    use riscland::cpu::Fault;
+    use riscland::csr;
+    use riscland::isa::{self, Isa};
+    use riscland::machine::{HaltReason, Machine};
+    use riscland::memory::MEM_BASE;
+
+    fn machine(isa: &str, code: &[u32]) -> Machine {
+        let mut cpu_test = helper::hart(code);
+        cpu_test.set_isa(Isa::parse(isa).unwrap());
+        Machine::new(cpu_test)
+    }
+
+    #[test]
+    fn test_parse() {
+        let isa = Isa::parse("RV32I2p1A_Zicsr2p0").unwrap();
+        assert!(isa.has('i') && isa.has('a') && isa.zicsr && !isa.zifencei);
+        assert!(!isa.has('m'));
+        assert_eq!(isa.to_string(), "rv32ia_zicsr");
+        assert_eq!(isa.misa(), 0x40140101);
+        assert_eq!(Isa::default().to_string(), isa::DEFAULT);
+
+        let err = |s: &str| Isa::parse(s).unwrap_err().to_string();
+        assert_eq!(err("rv64i"), "`rv64i` is not an rv32 ISA");
+        assert_eq!(err("rv32a"), "`rv32a` has no i, e or g base");
+        assert_eq!(err("rv32e"), "the rv32e base is not implemented");
+        assert_eq!(
+            Isa::parse("rv32imc_zicsr").unwrap().to_string(),
+            "rv32imc_zicsr"
+        );
+        assert_eq!(Isa::default().misa(), 0x40141105);
+        assert_eq!(
+            err("rv32gc"),
+            "`rv32gc`: g includes the f and d extensions, which are not implemented, \
+             use rv32ima_zicsr_zifencei"
+        );
+        assert_eq!(err("rv32imf"), "extension `f` is not implemented");
+        assert_eq!(err("rv32iaa"), "extension `a` given twice");
+        assert_eq!(err("rv32i_zba"), "extension `zba` is not implemented");
+        assert_eq!(err("rv32i_zicsr_zicsr"), "extension `zicsr` given twice");
+        assert_eq!(err("rv32i+a"), "unexpected `+` in `rv32i+a`");
+    }
+
+    #[test]
+    fn test_disabled_extensions_are_illegal() {
+        let code = [
+            0xf1202573, // csrr a0, marchid
+            0x0000100f, // fence.i
+            0x08b5252f, // amoswap.w a0, a1, (a0)
+        ];
+        let mut m = machine("rv32i", &code);
+        assert_eq!(
+            m.step(),
+            HaltReason::Fault(Fault::IllegalInstruction(code[0]))
+        );
+        m.cpu.pc += 4;
+        assert_eq!(
+            m.step(),
+            HaltReason::Fault(Fault::IllegalInstruction(code[1]))
+        );
+        m.cpu.pc += 4;
+        assert_eq!(
+            m.step(),
+            HaltReason::Fault(Fault::IllegalInstruction(code[2]))
+        );
+
+        // the same code runs once the extensions are there
+        let mut m = machine(isa::DEFAULT, &code);
+        m.cpu.xregs.regs[11] = 7;
+        assert_eq!(m.run(2), HaltReason::Limit);
+        m.cpu.xregs.regs[10] = MEM_BASE + 0x100;
+        assert_eq!(m.step(), HaltReason::Limit);
+        assert_eq!(m.cpu.bus.load(MEM_BASE + 0x100, 32), 7);
+    }
+
+    #[test]
+    fn test_misa() {
+        let mut m = machine(
+            "rv32i_zicsr",
+            &[
+                0x30102573, // csrr a0, misa
+                0x30159073, // csrw misa, a1
+                0x301025f3, // csrr a1, misa
+            ],
+        );
+        m.cpu.xregs.regs[11] = 0xffffffff;
+        assert_eq!(m.run(3), HaltReason::Limit);
+        assert_eq!(m.cpu.xregs.regs[10], 0x40140100);
+        assert_eq!(m.cpu.xregs.regs[11], 0x40140100);
+        assert_eq!(m.cpu.csrs.load(csr::MISA), m.cpu.isa().misa());
+    }
+
+    #[test]
+    fn test_muldiv() {
+        let op = |funct3: u32, a: u32, b: u32| {
+            // mul a2, a0, a1 and friends
+            let mut m = machine(isa::DEFAULT, &[0x02b50633 | funct3 << 12]);
+            m.cpu.xregs.regs[10] = a;
+            m.cpu.xregs.regs[11] = b;
+            assert_eq!(m.step(), HaltReason::Limit);
+            m.cpu.xregs.regs[12]
+        };
+        assert_eq!(op(0, 7, -3i32 as u32), -21i32 as u32);
+        assert_eq!(op(1, -2i32 as u32, 0x80000000), 1);
+        assert_eq!(op(2, -2i32 as u32, 0x80000000), 0xffffffff);
+        assert_eq!(op(3, 0xffffffff, 0xffffffff), 0xfffffffe);
+        assert_eq!(op(4, -7i32 as u32, 2), -3i32 as u32);
+        assert_eq!(op(5, -7i32 as u32, 2), 0x7ffffffc);
+        assert_eq!(op(6, -7i32 as u32, 2), -1i32 as u32);
+        assert_eq!(op(7, 7, 4), 3);
+        // division by zero and overflow do not trap
+        assert_eq!(op(4, 5, 0), 0xffffffff);
+        assert_eq!(op(5, 5, 0), 0xffffffff);
+        assert_eq!(op(6, 5, 0), 5);
+        assert_eq!(op(7, 5, 0), 5);
+        assert_eq!(op(4, 0x80000000, -1i32 as u32), 0x80000000);
+        assert_eq!(op(6, 0x80000000, -1i32 as u32), 0);
+
+        let mut m = machine("rv32ia_zicsr", &[0x02b50633]);
+        assert_eq!(
+            m.step(),
+            HaltReason::Fault(Fault::IllegalInstruction(0x02b50633))
+        );
+    }
+
+    #[test]
+    fn test_compressed() {
+        let code = [
+            0x050d4515, // c.li a0, 5; c.addi a0, 3
+            0x45a2c42a, // c.swsp a0, 8(sp); c.lwsp a1, 8(sp)
+            0xc1912019, // c.jal 6; c.beqz a1, 4
+            0x86ae0001, // c.nop; c.mv a3, a1
+            0x06330001, // c.nop; mul a2, a0, a1 across the word
+            0x000002b5,
+        ];
+        let mut m = machine(isa::DEFAULT, &code);
+        m.cpu.xregs.regs[2] = MEM_BASE + 0x800;
+        assert_eq!(m.run(8), HaltReason::Limit);
+        assert_eq!(m.cpu.xregs.regs[10], 8);
+        assert_eq!(m.cpu.xregs.regs[11], 8);
+        assert_eq!(m.cpu.xregs.regs[12], 64);
+        assert_eq!(m.cpu.xregs.regs[13], 8);
+        assert_eq!(m.cpu.xregs.regs[1], MEM_BASE + 10);
+        assert_eq!(m.cpu.pc, MEM_BASE + 22);
+        // the all zero halfword is defined to be illegal
+        assert_eq!(m.step(), HaltReason::Fault(Fault::IllegalInstruction(0)));
+
+        let mut m = machine("rv32ima_zicsr", &code);
+        assert_eq!(
+            m.step(),
+            HaltReason::Fault(Fault::IllegalInstruction(code[0]))
+        );
+    }
+}
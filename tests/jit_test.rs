@@ -0,0 +1,109 @@
+#![cfg(feature = "jit")]
+
+mod helper;
+
+#[cfg(test)]
+mod tests {
+    use crate::helper::hart as machine;
+    use riscland::{cpu, jit::Jit, memory};
+
+    #[test]
+    fn test_cross_check_loop() {
+        let code = [
+            0x80001337, // lui t1, 0x80001
+            0x06400393, // addi t2, zero, 100
+            0x00328293, // addi t0, t0, 3
+            0x00529e13, // slli t3, t0, 5
+            0x402e5e93, // srai t4, t3, 2
+            0x005ecf33, // xor t5, t4, t0
+            0x01e32023, // sw t5, 0(t1)
+            0x00130f83, // lb t6, 1(t1)
+            0x01f31323, // sh t6, 6(t1)
+            0x00435503, // lhu a0, 4(t1)
+            0x005535b3, // sltu a1, a0, t0
+            0x00b60633, // add a2, a2, a1
+            0x41e686b3, // sub a3, a3, t5
+            0xfff38393, // addi t2, t2, -1
+            0xfc0398e3, // bne t2, zero, -48
+            0x00700713, // addi a4, zero, 7
+            0x00000063, // beq zero, zero, 0
+        ];
+        let mut jitted = machine(&code);
+        let mut jit = Jit::with_cross_check();
+        let mut retired = 0;
+        while retired < 1500 {
+            retired += jit.run(&mut jitted);
+        }
+        assert!(jit.compiled() >= 2);
+        assert_eq!(jitted.xregs.regs[14], 7);
+
+        let mut interp = machine(&code);
+        for _ in 0..retired {
+            interp.step();
+        }
+        assert_eq!(jitted.pc, interp.pc);
+        assert_eq!(jitted.xregs.regs, interp.xregs.regs);
+        assert_eq!(jitted.bus.ram(), interp.bus.ram());
+    }
+
+    #[test]
+    fn test_store_to_code_leaves_jit() {
+        let mut cpu_test = machine(&[
+            0x80000337, // lui t1, 0x80000
+            0x800013b7, // lui t2, 0x80001
+            0x0003a283, // lw t0, 0(t2)
+            0x00532a23, // sw t0, 20(t1)
+            0x00500593, // addi a1, zero, 5
+            0x00100513, // addi a0, zero, 1, patched to addi a0, zero, 2
+            0x00000063, // beq zero, zero, 0
+        ]);
+        cpu_test
+            .bus
+            .store(memory::MEM_BASE + 0x1000, 32, 0x00200513);
+        let mut jit = Jit::with_cross_check();
+        // the store runs in the interpreter, which drops the stale block
+        assert_eq!(jit.run(&mut cpu_test), 3);
+        assert_eq!(jit.run(&mut cpu_test), 1);
+        assert_eq!(cpu_test.pc, memory::MEM_BASE + 0x10);
+        for _ in 0..4 {
+            jit.run(&mut cpu_test);
+        }
+        assert_eq!(cpu_test.xregs.regs[11], 5);
+        assert_eq!(cpu_test.xregs.regs[10], 2);
+        assert_eq!(cpu_test.pc, memory::MEM_BASE + 0x18);
+    }
+
+    #[test]
+    fn test_misaligned_leaves_jit() {
+        let code = [
+            0x80001337, // lui t1, 0x80001
+            0x00132503, // lw a0, 1(t1)
+            0x00a585b3, // add a1, a1, a0
+            0xff9ff06f, // jal zero, -8
+        ];
+        // emulated in the interpreter, the same as without the jit
+        let mut cpu_test = machine(&code);
+        cpu_test.misaligned = cpu::Misaligned::Emulate;
+        cpu_test
+            .bus
+            .store(memory::MEM_BASE + 0x1000, 32, 0x44332211);
+        cpu_test
+            .bus
+            .store(memory::MEM_BASE + 0x1004, 32, 0x88776655);
+        let mut jit = Jit::with_cross_check();
+        let mut retired = 0;
+        while retired < 30 {
+            retired += jit.run(&mut cpu_test);
+        }
+        assert_eq!(cpu_test.xregs.regs[10], 0x55443322);
+
+        let mut cpu_test = machine(&code);
+        cpu_test.misaligned = cpu::Misaligned::Trap;
+        assert_eq!(jit.run(&mut cpu_test), 1);
+        assert_eq!(jit.run(&mut cpu_test), 0);
+        assert_eq!(
+            cpu_test.fault,
+            Some(cpu::Fault::LoadMisaligned(memory::MEM_BASE + 0x1001))
+        );
+    }
+}
@@ -0,0 +1,178 @@
+mod helper;
+
+#[cfg(test)]
+mod tests {
+    use crate::helper;
+    use riscland::{
+        elf::{Image, Segment},
+        linux::{self, Abi, Process},
+        opcode::*,
+    };
+
+    const ENTRY: u32 = 0x10000;
+
+    fn image(code: &[u32], data: &[u8]) -> Image {
+        let mut bytes: Vec<u8> = code.iter().flat_map(|i| i.to_le_bytes()).collect();
+        bytes.resize(0x100, 0);
+        bytes.extend(data);
+        Image {
+            entry: ENTRY,
+            segments: vec![Segment {
+                vaddr: ENTRY,
+                memsz: bytes.len() as u32 + 0x1000,
+                data: bytes,
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn args(argv: &[&str]) -> Vec<String> {
+        argv.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_write_and_exit_status() {
+        let msg = b"hello from riscland\n";
+        let code = [
+            // write(1, msg, len)
+            helper::set_i_type_instruction(linux::SYS_WRITE as i16, 0, ADDI as u8, 17),
+            helper::set_i_type_instruction(1, 0, ADDI as u8, 10),
+            helper::set_u_type_instruction(ENTRY as i32, 11, LUI as u8),
+            helper::set_i_type_instruction(0x100, 11, ADDI as u8, 11),
+            helper::set_i_type_instruction(msg.len() as i16, 0, ADDI as u8, 12),
+            linux::ECALL_INSTR,
+            // exit(argc + 40)
+            helper::set_load_type_instruction(0, 2, LW as u8, 10),
+            helper::set_i_type_instruction(40, 10, ADDI as u8, 10),
+            helper::set_i_type_instruction(linux::SYS_EXIT as i16, 0, ADDI as u8, 17),
+            linux::ECALL_INSTR,
+        ];
+        let mut process =
+            Process::new(&image(&code, msg), &args(&["prog", "arg"]), &[], Abi::Linux).unwrap();
+        assert_eq!(process.run(), 42);
+        assert_eq!(process.exit_code(), Some(42));
+    }
+
+    #[test]
+    fn test_initial_stack() {
+        let process = Process::new(
+            &image(&[], &[]),
+            &args(&["prog", "-v"]),
+            &args(&["HOME=/"]),
+            Abi::Linux,
+        )
+        .unwrap();
+        let bus = &process.cpu.bus;
+        let sp = process.cpu.xregs.regs[2];
+        assert_eq!(sp % 16, 0);
+        assert_eq!(process.cpu.pc, ENTRY);
+        // argc, argv[0], argv[1], NULL, envp[0], NULL
+        assert_eq!(bus.load(sp, 32), 2);
+        assert_eq!(bus.load_bytes(bus.load(sp + 8, 32), 3), Some(&b"-v\0"[..]));
+        assert_eq!(bus.load(sp + 12, 32), 0);
+        assert_eq!(
+            bus.load_bytes(bus.load(sp + 16, 32), 7),
+            Some(&b"HOME=/\0"[..])
+        );
+        assert_eq!(bus.load(sp + 20, 32), 0);
+        // auxv ends with AT_NULL
+        let mut auxv = sp + 24;
+        while bus.load(auxv, 32) != 0 {
+            auxv += 8;
+        }
+        assert!(auxv > sp + 24);
+    }
+
+    #[test]
+    fn test_brk_and_mmap() {
+        let mut process =
+            Process::new(&image(&[], &[]), &args(&["prog"]), &[], Abi::Linux).unwrap();
+        let mut call = |nr: u32, args: &[u32]| {
+            process.cpu.xregs.regs[17] = nr;
+            for (i, a) in args.iter().enumerate() {
+                process.cpu.xregs.regs[10 + i] = *a;
+            }
+            process.syscall();
+            process.cpu.xregs.regs[10]
+        };
+        let start = call(linux::SYS_BRK, &[0]);
+        assert_eq!(start % linux::PAGE_SIZE, 0);
+        assert_eq!(call(linux::SYS_BRK, &[start + 0x2000]), start + 0x2000);
+        // out of range requests leave the break untouched
+        assert_eq!(call(linux::SYS_BRK, &[linux::STACK_TOP]), start + 0x2000);
+
+        // mmap2(NULL, 8192, PROT_READ|PROT_WRITE, MAP_PRIVATE|MAP_ANONYMOUS, -1, 0)
+        let addr = call(linux::SYS_MMAP, &[0, 0x2000, 0x3, 0x22, u32::MAX, 0]);
+        assert_eq!(addr, linux::MMAP_BASE);
+        let next = call(linux::SYS_MMAP, &[0, 0x10, 0x3, 0x22, u32::MAX, 0]);
+        assert_eq!(next, linux::MMAP_BASE + 0x2000);
+        // unknown syscalls fail with -ENOSYS
+        assert_eq!(call(0xfff, &[]) as i32, -38);
+    }
+
+    #[test]
+    fn test_bad_pointers() {
+        let mut process =
+            Process::new(&image(&[], &[]), &args(&["prog"]), &[], Abi::Linux).unwrap();
+        let mut call = |nr: u32, args: &[u32]| {
+            process.cpu.xregs.regs[17] = nr;
+            for (i, a) in args.iter().enumerate() {
+                process.cpu.xregs.regs[10 + i] = *a;
+            }
+            process.syscall();
+            process.cpu.xregs.regs[10] as i32
+        };
+        // iovecs past the end of memory and wrapping around it
+        assert_eq!(call(linux::SYS_WRITEV, &[1, 0xfffffff8, 2]), -14);
+        assert_eq!(call(linux::SYS_READV, &[0, 0xffffff00, 32]), -14);
+        assert_eq!(call(linux::SYS_READV, &[0, ENTRY, 0x2000_0001]), -22);
+        // buffers the guest does not have
+        assert_eq!(call(linux::SYS_READ, &[0, 0xffff0000, 0x10000]), -14);
+        assert_eq!(call(linux::SYS_GETRANDOM, &[ENTRY, u32::MAX, 0]), -14);
+        // lengths that round up past 4 GiB, fixed mappings outside memory
+        assert_eq!(
+            call(linux::SYS_MMAP, &[0, u32::MAX - 10, 3, 0x22, u32::MAX, 0]),
+            -12
+        );
+        assert_eq!(
+            call(
+                linux::SYS_MMAP,
+                &[0x2000_0000, 0x1000, 3, 0x32, u32::MAX, 0]
+            ),
+            -14
+        );
+    }
+
+    #[test]
+    fn test_image_errors() {
+        let dir = std::env::temp_dir();
+        // an RV64 ELF header: class 2, little endian, EM_RISCV
+        let mut header = vec![0x7f, b'E', b'L', b'F', 2, 1, 1];
+        header.resize(64, 0);
+        header[16] = 2;
+        header[18] = 243;
+        header[20] = 1;
+        header[52] = 64;
+        let path = dir.join(format!("riscland-rv64-{}", std::process::id()));
+        std::fs::write(&path, &header).unwrap();
+        let e = riscland::elf::ELF::new(path.to_str().unwrap())
+            .read_image()
+            .unwrap_err();
+        assert!(e.to_string().ends_with(
+            "an RV64 program, riscland runs RV32 only: build it with -march=rv32gc -mabi=ilp32d"
+        ));
+        std::fs::remove_file(&path).unwrap();
+
+        // a segment beyond the user address space
+        let mut bad = image(&[], &[]);
+        bad.segments[0].vaddr = 0xfffff000;
+        assert!(Process::new(&bad, &args(&["prog"]), &[], Abi::Linux).is_err());
+
+        // more arguments than the stack holds
+        let huge = "x".repeat(linux::STACK_SIZE as usize);
+        let e = Process::new(&image(&[], &[]), &args(&["prog", &huge]), &[], Abi::Linux)
+            .err()
+            .unwrap();
+        assert_eq!(e, "arguments and environment do not fit on the stack");
+    }
+}
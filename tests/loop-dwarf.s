@@ -0,0 +1,11 @@
+    .text
+    .globl _start
+_start:
+    addi t0, zero, 3
+loop:
+    addi t0, t0, -1
+    bne t0, zero, loop
+    beq t0, t1, done
+    addi t1, zero, 1
+done:
+    ebreak
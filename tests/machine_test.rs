@@ -0,0 +1,84 @@
+mod helper;
+
+#[cfg(test)]
+mod tests {
+    use crate::helper;
+    use riscland::cpu::Fault;
+    use riscland::machine::{HaltReason, Machine};
+    use riscland::memory::MEM_BASE;
+
+    fn machine(code: &[u32]) -> Machine {
+        Machine::new(helper::hart(code))
+    }
+
+    #[test]
+    fn test_ecall_breakpoint_and_limits() {
+        let mut m = machine(&[
+            0x00500513, // addi a0, zero, 5
+            0x00000073, // ecall
+            0x00150513, // addi a0, a0, 1
+            0x008000ef, // jal ra, 8
+            0x00100073, // ebreak
+            0x00128293, // addi t0, t0, 1
+            0xffdff06f, // jal zero, -4
+        ]);
+        assert_eq!(m.run(100), HaltReason::Ecall);
+        assert_eq!(m.cpu.pc, MEM_BASE + 4);
+        assert_eq!(m.retired, 1);
+
+        // the host serves the ecall and carries on after it
+        m.cpu.xregs.regs[10] = 10;
+        assert_eq!(m.run(3), HaltReason::Limit);
+        assert_eq!(m.retired, 4);
+        assert_eq!(m.cpu.xregs.regs[10], 11);
+        assert_eq!(m.cpu.xregs.regs[1], MEM_BASE + 0x10);
+        assert_eq!(m.cpu.pc, MEM_BASE + 0x14);
+
+        m.breakpoints.push(MEM_BASE + 0x18);
+        assert_eq!(m.run(100), HaltReason::Breakpoint);
+        assert_eq!(m.cpu.pc, MEM_BASE + 0x18);
+        assert_eq!(m.cpu.xregs.regs[5], 1);
+        m.breakpoints.clear();
+
+        assert_eq!(
+            m.run_until(|cpu| cpu.xregs.regs[5] == 10),
+            HaltReason::Condition
+        );
+        assert_eq!(m.cpu.pc, MEM_BASE + 0x18);
+
+        // block at a time, but never past the budget
+        let retired = m.retired;
+        assert_eq!(m.run(1001), HaltReason::Limit);
+        assert_eq!(m.retired, retired + 1001);
+        assert_eq!(m.step(), HaltReason::Limit);
+        assert_eq!(m.retired, retired + 1002);
+    }
+
+    #[test]
+    fn test_ebreak_and_faults() {
+        let mut m = machine(&[
+            0x00128293, // addi t0, t0, 1
+            0x00100073, // ebreak
+            0x00002303, // lw t1, 0(zero)
+            0xffffffff, // illegal
+        ]);
+        m.cpu.xregs.regs[6] = 7;
+        assert_eq!(m.run(10), HaltReason::Breakpoint);
+        assert_eq!(m.cpu.pc, MEM_BASE + 4);
+
+        // the load neither retires nor writes t1, pc stays on it
+        assert_eq!(m.run(10), HaltReason::Fault(Fault::LoadAccess(0)));
+        assert_eq!(m.cpu.pc, MEM_BASE + 8);
+        assert_eq!(m.cpu.xregs.regs[6], 7);
+        assert_eq!(m.retired, 2);
+
+        m.cpu.pc = MEM_BASE + 12;
+        assert_eq!(
+            m.step(),
+            HaltReason::Fault(Fault::IllegalInstruction(0xffffffff))
+        );
+        m.cpu.pc = 0x1000;
+        assert_eq!(m.step(), HaltReason::Fault(Fault::FetchAccess(0x1000)));
+        assert_eq!(m.retired, 2);
+    }
+}
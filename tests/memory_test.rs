@@ -0,0 +1,104 @@
+#[cfg(test)]
+mod tests {
+    use riscland::board::Board;
+    use riscland::snapshot;
+
+    fn board(extra: &str) -> Board {
+        let text = format!(
+            r#"
+            [[ram]]
+            base = 0x80000000
+            size = 0x10000000
+            sparse = true
+            {}
+            "#,
+            extra
+        );
+        Board::parse(&text).unwrap()
+    }
+
+    #[test]
+    fn test_sparse() {
+        // 256 MiB of main RAM and another GiB, the host only sees a few pages
+        let board = board("[[ram]]\nbase = 0x40000000\nsize = 0x40000000\nsparse = true");
+        let mut cpu = board.cpu().unwrap();
+        cpu.trace = false;
+        cpu.bus.store(0x7ffffffc, 32, 0x12345678);
+        cpu.bus.store(0x80000000, 32, 0xdeadbeef);
+        assert_eq!(cpu.bus.load(0x7ffffffc, 32), 0x12345678);
+        assert_eq!(cpu.bus.load(0x40000000, 32), 0);
+
+        // a copy has the same contents and goes its own way
+        let mut copy = cpu.clone();
+        assert_eq!(copy.bus.load(0x7ffffffc, 32), 0x12345678);
+        copy.bus.store(0x7ffffffc, 32, 0);
+        assert_eq!(cpu.bus.load(0x7ffffffc, 32), 0x12345678);
+
+        // a snapshot restores into RAM of the same shape
+        let mut buf = Vec::new();
+        snapshot::save(&cpu, &mut buf).unwrap();
+        copy.bus.store(0x80001000, 32, 1);
+        snapshot::restore(&mut copy, &mut buf.as_slice()).unwrap();
+        assert_eq!(copy.bus.load(0x80000000, 32), 0xdeadbeef);
+        assert_eq!(copy.bus.load(0x80001000, 32), 0);
+        assert_eq!(copy.bus.ram(), cpu.bus.ram());
+        // and copies of the restored RAM have every page of it
+        assert_eq!(copy.clone().bus.ram(), cpu.bus.ram());
+    }
+
+    #[test]
+    fn test_sparse_image() {
+        let path = std::env::temp_dir().join(format!("riscland-img-{}", std::process::id()));
+        std::fs::write(&path, [0x13, 0, 0, 0, 0x73, 0, 0x10, 0]).unwrap();
+        let board = board(&format!(
+            "[[ram]]\nbase = 0x40000000\nsize = 0x10000\nsparse = true\nimage = \"{}\"",
+            path.display()
+        ));
+        let mut bus = board.bus().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        bus.store_bytes(0x4000fffe, &[1, 2]).unwrap();
+        // an image loaded before the machine ran is copied like any write
+        let copy = bus.clone();
+        assert_eq!(copy.load(0x40000004, 32), 0x00100073);
+        assert_eq!(copy.load(0x4000fffe, 16), 0x0201);
+    }
+
+    #[test]
+    fn test_file_backed() {
+        let path = std::env::temp_dir().join(format!("riscland-ram-{}", std::process::id()));
+        std::fs::write(&path, [1, 2, 3, 4]).unwrap();
+        let board = board(&format!(
+            "[[ram]]\nbase = 0x20000000\nsize = 0x2000\nfile = \"{}\"",
+            path.display()
+        ));
+        let mut bus = board.bus().unwrap();
+        // what the file held, grown to the size of the region
+        assert_eq!(bus.load(0x20000000, 32), 0x04030201);
+        bus.store(0x20001ffc, 32, 0xcafef00d);
+        // a copy is private
+        let mut copy = bus.clone();
+        copy.store(0x20000000, 32, 0);
+        drop(copy);
+        drop(bus);
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(data.len(), 0x2000);
+        assert_eq!(data[..4], [1, 2, 3, 4]);
+        assert_eq!(data[0x1ffc..], [0x0d, 0xf0, 0xfe, 0xca]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_errors() {
+        let rom = "[[rom]]\nbase = 0x1000\nsize = 0x1000\nsparse = true";
+        let text = format!("[[ram]]\nbase = 0x80000000\nsize = 0x1000\n{}", rom);
+        assert_eq!(
+            Board::parse(&text).unwrap_err().to_string(),
+            "rom[0]: only RAM can be sparse or backed by a file"
+        );
+        let text = "[[ram]]\nbase = 0x80000000\nsize = 0x1000\nsparse = true\nfile = \"x\"";
+        assert_eq!(
+            Board::parse(text).unwrap_err().to_string(),
+            "ram[0].sparse: a file backed region is sparse already"
+        );
+    }
+}
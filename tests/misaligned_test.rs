@@ -0,0 +1,114 @@
+mod helper;
+
+#[cfg(test)]
+mod tests {
+    use crate::helper;
+    use riscland::board::Board;
+    use riscland::cpu::{Fault, Misaligned, CPU};
+    use riscland::isa::Isa;
+    use riscland::{csr, memory};
+
+    const DATA: u32 = memory::MEM_BASE + 0x1000;
+
+    // t1 points at DATA, which holds 0x44332211 0x88776655
+    fn machine(code: &[u32], misaligned: Misaligned) -> CPU {
+        let mut cpu = helper::hart(&[&[0x80001337], code].concat()); // lui t1, 0x80001
+        cpu.misaligned = misaligned;
+        cpu.bus.store(DATA, 32, 0x44332211);
+        cpu.bus.store(DATA + 4, 32, 0x88776655);
+        cpu.step();
+        cpu
+    }
+
+    #[test]
+    fn test_loads_and_stores() {
+        // lw a0, 1(t1)
+        for policy in [Misaligned::Allow, Misaligned::Emulate] {
+            let mut cpu = machine(&[0x00132503], policy);
+            assert_eq!(cpu.step(), 1);
+            assert_eq!(cpu.xregs.regs[10], 0x55443322);
+        }
+        let mut cpu = machine(&[0x00132503], Misaligned::Trap);
+        assert_eq!(cpu.step(), 0);
+        assert_eq!(cpu.fault, Some(Fault::LoadMisaligned(DATA + 1)));
+        assert_eq!(cpu.pc, memory::MEM_BASE + 4);
+        assert_eq!(cpu.csrs.load(csr::MEPC), memory::MEM_BASE + 4);
+        assert_eq!(cpu.csrs.load(csr::MCAUSE), 4);
+        assert_eq!(cpu.csrs.load(csr::MTVAL), DATA + 1);
+        assert_eq!(cpu.xregs.regs[10], 0);
+
+        // sw a1, 3(t1)
+        for policy in [Misaligned::Allow, Misaligned::Emulate] {
+            let mut cpu = machine(&[0x00b321a3], policy);
+            cpu.xregs.regs[11] = 0xaabbccdd;
+            assert_eq!(cpu.step(), 1);
+            assert_eq!(cpu.bus.load(DATA, 32), 0xdd332211);
+            assert_eq!(cpu.bus.load(DATA + 4, 32), 0x88aabbcc);
+        }
+        let mut cpu = machine(&[0x00b321a3], Misaligned::Trap);
+        assert_eq!(cpu.step(), 0);
+        assert_eq!(cpu.fault, Some(Fault::StoreMisaligned(DATA + 3)));
+        assert_eq!(cpu.csrs.load(csr::MCAUSE), 6);
+        assert_eq!(cpu.bus.load(DATA, 32), 0x44332211);
+
+        // aligned accesses are the same under every policy
+        let mut cpu = machine(&[0x00432503], Misaligned::Trap); // lw a0, 4(t1)
+        assert_eq!(cpu.step(), 1);
+        assert_eq!(cpu.xregs.regs[10], 0x88776655);
+    }
+
+    #[test]
+    fn test_atomics() {
+        // addi t1, t1, 1; amoadd.w a0, a1, (t1)
+        let code = [0x00130313, 0x00b3252f];
+        let mut cpu = machine(&code, Misaligned::Allow);
+        cpu.xregs.regs[11] = 1;
+        assert_eq!(cpu.step() + cpu.step(), 2);
+        assert_eq!(cpu.xregs.regs[10], 0x55443322);
+        // firmware does not emulate AMOs
+        for policy in [Misaligned::Trap, Misaligned::Emulate] {
+            let mut cpu = machine(&code, policy);
+            cpu.step();
+            assert_eq!(cpu.step(), 0);
+            assert_eq!(cpu.fault, Some(Fault::StoreMisaligned(DATA + 1)));
+        }
+    }
+
+    #[test]
+    fn test_jump_targets() {
+        // jalr ra, 2(t1), jal ra, 6 and beq zero, zero, 6 trap at the jump
+        // without C, whatever the policy for data
+        for instr in [0x002300e7, 0x006000ef, 0x00000363] {
+            let mut cpu = machine(&[instr], Misaligned::Allow);
+            cpu.set_isa(Isa::parse("rv32ima_zicsr").unwrap());
+            let target = match instr {
+                0x002300e7 => DATA + 2,
+                _ => memory::MEM_BASE + 10,
+            };
+            assert_eq!(cpu.step(), 0);
+            assert_eq!(cpu.fault, Some(Fault::FetchMisaligned(target)));
+            assert_eq!(cpu.pc, memory::MEM_BASE + 4);
+            assert_eq!(cpu.csrs.load(csr::MCAUSE), 0);
+            assert_eq!(cpu.csrs.load(csr::MTVAL), target);
+            assert_eq!(cpu.xregs.regs[1], 0);
+        }
+        // with C they are fine
+        let mut cpu = machine(&[0x006000ef], Misaligned::Trap);
+        assert_eq!(cpu.step(), 1);
+        assert_eq!(cpu.pc, memory::MEM_BASE + 10);
+        assert_eq!(cpu.xregs.regs[1], memory::MEM_BASE + 8);
+        // a branch not taken does not care
+        let mut cpu = machine(&[0x00001363], Misaligned::Trap); // bne zero, zero, 6
+        assert_eq!(cpu.step(), 1);
+        assert_eq!(cpu.pc, memory::MEM_BASE + 8);
+    }
+
+    #[test]
+    fn test_board() {
+        let board = "misaligned = \"emulate\"\n[[ram]]\nbase = 0x80000000\nsize = 0x1000";
+        let cpu = Board::parse(board).unwrap().cpu().unwrap();
+        assert_eq!(cpu.misaligned, Misaligned::Emulate);
+        let board = "misaligned = \"fix\"\n[[ram]]\nbase = 0x80000000\nsize = 0x1000";
+        assert!(Board::parse(board).is_err());
+    }
+}
@@ -0,0 +1,148 @@
+mod helper;
+
+#[cfg(test)]
+mod tests {
+    use crate::helper;
+    use riscland::cpu::{Fault, Privilege, CPU};
+    use riscland::csr;
+    use riscland::memory::MEM_BASE;
+    use riscland::mmu::Access;
+
+    const ROOT: u32 = MEM_BASE + 0x1000;
+    const TABLE: u32 = MEM_BASE + 0x2000;
+    const PAGE: u32 = MEM_BASE + 0x3000;
+
+    const V: u32 = 1 << 0;
+    const R: u32 = 1 << 1;
+    const W: u32 = 1 << 2;
+    const X: u32 = 1 << 3;
+    const U: u32 = 1 << 4;
+    const A: u32 = 1 << 6;
+    const D: u32 = 1 << 7;
+
+    fn pte(addr: u32, flags: u32) -> u32 {
+        addr >> 12 << 10 | flags
+    }
+
+    // a hart in supervisor mode with Sv32 on: virtual page 0 maps PAGE with
+    // `flags`, the 4 MiB from MEM_BASE map themselves
+    fn paged(code: &[u32], flags: u32) -> CPU {
+        let mut cpu = helper::hart(code);
+        cpu.bus.store(ROOT, 32, pte(TABLE, V));
+        cpu.bus
+            .store(ROOT + 0x200 * 4, 32, pte(MEM_BASE, V | R | W | X | A | D));
+        cpu.bus.store(TABLE, 32, pte(PAGE, flags));
+        cpu.csrs.write(csr::SATP, csr::SATP_MODE | ROOT >> 12);
+        cpu.privilege = Privilege::Supervisor;
+        cpu
+    }
+
+    #[test]
+    fn test_loads_and_stores() {
+        let mut cpu = paged(&[], V | R | W);
+        cpu.bus.store(PAGE + 8, 32, 0x1234);
+        assert_eq!(cpu.load(8, 32), Some(0x1234));
+        assert_eq!(cpu.bus.load(TABLE, 32) & (A | D), A);
+        assert_eq!(cpu.store(12, 32, 0x5678), Some(()));
+        assert_eq!(cpu.bus.load(PAGE + 12, 32), 0x5678);
+        assert_eq!(cpu.bus.load(TABLE, 32) & (A | D), A | D);
+
+        // a misaligned word across into page 1, which is not mapped
+        assert_eq!(cpu.load(0xffe, 32), None);
+        assert_eq!(cpu.fault, Some(Fault::LoadPage(0x1000)));
+
+        // machine mode uses physical addresses, but for loads and stores
+        // under MPRV
+        cpu.privilege = Privilege::Machine;
+        assert_eq!(cpu.translate(8, Access::Load), Ok(8));
+        cpu.csrs.write(
+            csr::MSTATUS,
+            csr::MSTATUS_MPRV | 1 << csr::MSTATUS_MPP_SHIFT,
+        );
+        assert_eq!(cpu.translate(8, Access::Load), Ok(PAGE + 8));
+        assert_eq!(cpu.translate(8, Access::Fetch), Ok(8));
+    }
+
+    #[test]
+    fn test_permissions() {
+        let mut cpu = paged(&[], V | R | U);
+        // supervisor mode reads user pages only with SUM, and never runs
+        // code from them
+        assert_eq!(cpu.load(0, 32), None);
+        assert_eq!(cpu.fault, Some(Fault::LoadPage(0)));
+        cpu.csrs.write(csr::SSTATUS, csr::MSTATUS_SUM);
+        assert_eq!(cpu.load(0, 32), Some(0));
+        assert_eq!(cpu.store(4, 32, 1), None);
+        assert_eq!(cpu.fault, Some(Fault::StorePage(4)));
+        assert_eq!(cpu.translate(0, Access::Fetch), Err(Fault::FetchPage(0)));
+
+        // user mode reaches user pages only
+        cpu.privilege = Privilege::User;
+        assert_eq!(cpu.translate(4, Access::Load), Ok(PAGE + 4));
+        assert_eq!(
+            cpu.translate(MEM_BASE, Access::Load),
+            Err(Fault::LoadPage(MEM_BASE))
+        );
+
+        // a megapage that is not aligned to 4 MiB
+        cpu.privilege = Privilege::Supervisor;
+        cpu.bus.store(ROOT + 4, 32, pte(MEM_BASE + 0x1000, V | R));
+        assert_eq!(
+            cpu.translate(0x400000, Access::Load),
+            Err(Fault::LoadPage(0x400000))
+        );
+    }
+
+    #[test]
+    fn test_page_fault_delegated_to_supervisor() {
+        let mut cpu = paged(
+            &[
+                0x00400537, // lui a0, 0x400, which is not mapped
+                0x00052583, // lw a1, 0(a0)
+            ],
+            V | R,
+        );
+        cpu.csrs.write(csr::MEDELEG, 1 << 13);
+        cpu.csrs.write(csr::STVEC, MEM_BASE + 0x100);
+        assert_eq!(cpu.step(), 1);
+        assert_eq!(cpu.step(), 1);
+        assert_eq!(cpu.fault, None);
+        assert_eq!(cpu.privilege, Privilege::Supervisor);
+        assert_eq!(cpu.pc, MEM_BASE + 0x100);
+        assert_eq!(cpu.csrs.load(csr::SCAUSE), 13);
+        assert_eq!(cpu.csrs.load(csr::STVAL), 0x400000);
+        assert_eq!(cpu.csrs.load(csr::SEPC), MEM_BASE + 4);
+    }
+
+    #[test]
+    fn test_code_at_its_virtual_address() {
+        let mut cpu = paged(
+            &[
+                0x01050513, // addi a0, a0, 16
+                0x0000006f, // j .
+            ],
+            V | R | X,
+        );
+        cpu.bus.store(PAGE, 32, 0x00150513); // addi a0, a0, 1
+        cpu.bus.store(PAGE + 4, 32, 0x00150513); // addi a0, a0, 1
+        cpu.bus.store(PAGE + 8, 32, 0x0000006f); // j .
+        cpu.pc = 0;
+        assert_eq!(cpu.run_block(), 3);
+        assert_eq!(cpu.xregs.regs[10], 2);
+        assert_eq!(cpu.pc, 8);
+
+        // page 0 maps the code at MEM_BASE once sfence.vma drops what the
+        // TLB holds
+        cpu.bus.store(TABLE, 32, pte(MEM_BASE, V | R | X));
+        cpu.execute(0x12000073); // sfence.vma
+        cpu.pc = 0;
+        assert_eq!(cpu.run_block(), 2);
+        assert_eq!(cpu.xregs.regs[10], 18);
+        assert_eq!(cpu.pc, 4);
+
+        // which mstatus.TVM keeps from supervisor mode
+        cpu.csrs.write(csr::MSTATUS, csr::MSTATUS_TVM);
+        cpu.execute(0x12000073);
+        assert_eq!(cpu.fault, Some(Fault::IllegalInstruction(0x12000073)));
+    }
+}
@@ -0,0 +1,73 @@
+#[cfg(test)]
+mod tests {
+    use riscland::{
+        elf::{Image, Segment},
+        linux::{Abi, Process},
+        pk,
+    };
+
+    const DATA: u32 = 0x10000;
+
+    fn process() -> Process {
+        let image = Image {
+            entry: DATA,
+            segments: vec![Segment {
+                vaddr: DATA,
+                memsz: 0x1000,
+                data: vec![],
+            }],
+            ..Default::default()
+        };
+        Process::new(&image, &["prog".to_string()], &[], Abi::ProxyKernel).unwrap()
+    }
+
+    fn call(process: &mut Process, nr: u32, args: &[u32]) -> u32 {
+        process.cpu.xregs.regs[17] = nr;
+        for (i, a) in args.iter().enumerate() {
+            process.cpu.xregs.regs[10 + i] = *a;
+        }
+        process.syscall();
+        process.cpu.xregs.regs[10]
+    }
+
+    #[test]
+    fn test_open_write_close() {
+        let mut process = process();
+        let path = std::env::temp_dir().join(format!("riscland-pk-{}", std::process::id()));
+        let mut cpath = path.to_str().unwrap().as_bytes().to_vec();
+        cpath.push(0);
+        process.cpu.bus.store_bytes(DATA, &cpath).unwrap();
+        process
+            .cpu
+            .bus
+            .store_bytes(DATA + 0x800, b"newlib")
+            .unwrap();
+
+        // open(path, O_WRONLY | O_CREAT | O_TRUNC, 0644) with newlib's flag values
+        let fd = call(&mut process, pk::SYS_OPEN, &[DATA, 0x601, 0o644]);
+        assert_eq!(fd, 3);
+        assert_eq!(call(&mut process, pk::SYS_WRITE, &[fd, DATA + 0x800, 6]), 6);
+        assert_eq!(call(&mut process, pk::SYS_CLOSE, &[fd]), 0);
+        assert_eq!(std::fs::read(&path).unwrap(), b"newlib");
+
+        // stat reports the size at offset 48 of struct kernel_stat
+        assert_eq!(call(&mut process, pk::SYS_STAT, &[DATA, DATA + 0x400]), 0);
+        assert_eq!(process.cpu.bus.load(DATA + 0x400 + 48, 32), 6);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            call(&mut process, pk::SYS_STAT, &[DATA, DATA + 0x400]) as i32,
+            -2
+        );
+    }
+
+    #[test]
+    fn test_exit_and_brk() {
+        let mut process = process();
+        let start = call(&mut process, pk::SYS_BRK, &[0]);
+        assert_eq!(call(&mut process, pk::SYS_BRK, &[start + 64]), start + 64);
+        assert_eq!(call(&mut process, pk::SYS_GETTIMEOFDAY, &[DATA, 0]), 0);
+        assert_ne!(process.cpu.bus.load(DATA, 32), 0);
+        call(&mut process, pk::SYS_EXIT, &[3]);
+        assert_eq!(process.exit_code(), Some(3));
+    }
+}
@@ -0,0 +1,73 @@
+#[cfg(test)]
+mod tests {
+    use riscland::{
+        elf::{Symbol, Symbols},
+        opcode::{JAL, JALR},
+        profile::Profiler,
+    };
+
+    const NOP: u32 = 0x13;
+    // jal ra, <anywhere>
+    const CALL: u32 = JAL | (1 << 7);
+    // jalr x0, 0(ra)
+    const RET: u32 = JALR | (1 << 15);
+
+    fn symbols() -> Symbols {
+        let sym = |name: &str, addr, size| Symbol {
+            name: name.to_string(),
+            addr,
+            size,
+        };
+        Symbols::new(vec![sym("foo", 0x200, 0x10), sym("main", 0x100, 0x20)])
+    }
+
+    fn run(profiler: &mut Profiler, trace: &[(u32, u32, u32)]) {
+        for (pc, instr, next) in trace {
+            profiler.retire(*pc, *instr, *next);
+        }
+    }
+
+    #[test]
+    fn test_call_stacks() {
+        let mut profiler = Profiler::new(symbols());
+        run(
+            &mut profiler,
+            &[
+                (0x100, NOP, 0x104),
+                (0x104, CALL, 0x200),
+                (0x200, NOP, 0x204),
+                (0x204, RET, 0x108),
+                (0x108, CALL, 0x200),
+                (0x200, NOP, 0x204),
+                (0x204, RET, 0x10c),
+                (0x10c, NOP, 0x110),
+            ],
+        );
+        assert_eq!(profiler.total(), 8);
+        assert_eq!(profiler.pc_count(0x200), 2);
+        assert_eq!(profiler.collapsed(), "main 4\nmain;foo 4\n");
+        assert_eq!(
+            profiler.function_counts(),
+            vec![("foo".to_string(), 4), ("main".to_string(), 4)]
+        );
+    }
+
+    #[test]
+    fn test_report_and_unknown_code() {
+        let mut profiler = Profiler::new(symbols());
+        run(
+            &mut profiler,
+            &[
+                (0x100, NOP, 0x104),
+                (0x104, CALL, 0x300),
+                (0x300, NOP, 0x304),
+                (0x304, NOP, 0x308),
+            ],
+        );
+        assert_eq!(profiler.collapsed(), "main 2\nmain;0x300 2\n");
+        let report = profiler.report(1);
+        assert!(report.starts_with("instructions retired: 4\n"));
+        assert!(report.contains("50.00%            2  main"));
+        assert!(report.contains("0x100 <main>"));
+    }
+}
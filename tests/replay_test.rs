@@ -0,0 +1,238 @@
+mod helper;
+
+#[cfg(test)]
+mod tests {
+    use crate::helper;
+    use riscland::device::Mapped;
+    use riscland::semihosting::Semihosting;
+    use riscland::virtio::{VirtioMmio, VIRTIO_SIZE};
+    use riscland::virtio_rng::Rng;
+    use riscland::{cpu, debugger::Debugger, debugger::Stop, gdb, memory};
+
+    const DATA: u32 = memory::MEM_BASE + 0x1000;
+
+    // stores 1, 2, 3 ... to DATA forever
+    fn machine() -> cpu::CPU {
+        helper::hart(&[
+            0x80001337, // lui t1, 0x80001
+            0x00128293, // addi t0, t0, 1
+            0x00532023, // sw t0, 0(t1)
+            0xfe000ce3, // beq zero, zero, -8
+        ])
+    }
+
+    #[test]
+    fn test_reverse_step_and_continue() {
+        let mut dbg = Debugger::new(machine(), 4);
+        for _ in 0..10 {
+            dbg.step();
+        }
+        assert_eq!(dbg.cpu.xregs.regs[5], 3);
+        let pc = dbg.cpu.pc;
+
+        assert_eq!(dbg.reverse_step(), Stop::Step);
+        assert_eq!(dbg.replay.icount(), 9);
+        assert_eq!(dbg.step(), Stop::Step);
+        assert_eq!(dbg.cpu.pc, pc);
+
+        // back to the previous increment
+        dbg.add_breakpoint(memory::MEM_BASE + 4);
+        assert_eq!(dbg.reverse_cont(), Stop::Breakpoint);
+        assert_eq!(dbg.replay.icount(), 7);
+        assert_eq!(dbg.cpu.xregs.regs[5], 2);
+        assert_eq!(dbg.cpu.bus.load(DATA, 32), 2);
+
+        dbg.remove_breakpoint(memory::MEM_BASE + 4);
+        assert_eq!(dbg.reverse_cont(), Stop::Start);
+        assert_eq!(dbg.replay.icount(), 0);
+        assert_eq!(dbg.cpu.pc, memory::MEM_BASE);
+        assert_eq!(dbg.reverse_step(), Stop::Start);
+    }
+
+    #[test]
+    fn test_last_write() {
+        let mut dbg = Debugger::new(machine(), 3);
+        let out = dbg.last_write(DATA);
+        assert!(out.contains("not written"));
+        for _ in 0..12 {
+            dbg.step();
+        }
+        // the fourth store is instruction 11
+        let out = dbg.last_write(DATA + 2);
+        assert_eq!(
+            out,
+            format!(
+                "{:#x} last written by instruction 11 at pc {:#x}\n",
+                DATA + 2,
+                memory::MEM_BASE + 8
+            )
+        );
+        assert_eq!(dbg.replay.icount(), 12);
+        assert_eq!(dbg.cpu.xregs.regs[5], 4);
+
+        let out = dbg.command(&format!("lw {:#x}", DATA)).unwrap();
+        assert!(out.contains("instruction 11"));
+        assert!(dbg.command("q").is_none());
+    }
+
+    #[test]
+    fn test_last_write_atomics() {
+        let mut cpu = machine();
+        let code = [
+            0x80001337, // lui t1, 0x80001
+            0x100322af, // lr.w t0, (t1)
+            0x00128293, // addi t0, t0, 1
+            0x185323af, // sc.w t2, t0, (t1)
+            0x185323af, // sc.w t2, t0, (t1), no reservation left
+            0x00532e2f, // amoadd.w t3, t0, (t1)
+            0x00000063, // beq zero, zero, 0
+        ];
+        for (i, instr) in code.iter().enumerate() {
+            cpu.bus.store(memory::MEM_BASE + i as u32 * 4, 32, *instr);
+        }
+        let mut dbg = Debugger::new(cpu, 2);
+        let written_by = |dbg: &mut Debugger| {
+            let out = dbg.last_write(DATA);
+            out.split_whitespace().nth(5).map(str::to_string)
+        };
+        for _ in 0..5 {
+            dbg.step();
+        }
+        assert_eq!(dbg.cpu.xregs.regs[7], 1);
+        assert_eq!(written_by(&mut dbg).as_deref(), Some("3"));
+        dbg.step();
+        assert_eq!(dbg.cpu.bus.load(DATA, 32), 2);
+        assert_eq!(written_by(&mut dbg).as_deref(), Some("5"));
+    }
+
+    #[test]
+    fn test_checkpoints_are_bounded() {
+        let mut dbg = Debugger::new(machine(), 1);
+        let mut at_500 = None;
+        for _ in 0..1000 {
+            dbg.step();
+            if dbg.replay.icount() == 500 {
+                at_500 = Some((dbg.cpu.xregs.regs[5], dbg.cpu.bus.load(DATA, 32)));
+            }
+        }
+        assert!(dbg.replay.checkpoints() <= 32);
+        dbg.replay.seek(&mut dbg.cpu, 500);
+        assert_eq!(
+            Some((dbg.cpu.xregs.regs[5], dbg.cpu.bus.load(DATA, 32))),
+            at_500
+        );
+        dbg.replay.seek(&mut dbg.cpu, 1000);
+        assert_eq!(dbg.cpu.xregs.regs[5], 333);
+    }
+
+    #[test]
+    fn test_last_write_by_host_call() {
+        let mut cpu = machine();
+        let code = [
+            0x01500513, // addi a0, zero, SYS_GET_CMDLINE
+            0x800015b7, // lui a1, 0x80001
+            0x10058593, // addi a1, a1, 0x100
+            0x01f01013, // slli zero, zero, 0x1f
+            0x00100073, // ebreak
+            0x40705013, // srai zero, zero, 7
+            0x00000063, // beq zero, zero, 0
+        ];
+        for (i, instr) in code.iter().enumerate() {
+            cpu.bus.store(memory::MEM_BASE + i as u32 * 4, 32, *instr);
+        }
+        // the command line goes to DATA, which has room for 16 bytes
+        cpu.bus.store(DATA + 0x100, 32, DATA);
+        cpu.bus.store(DATA + 0x104, 32, 16);
+        cpu.semihosting = Some(Semihosting::new("hello", None));
+        let mut dbg = Debugger::new(cpu, 2);
+        for _ in 0..7 {
+            dbg.step();
+        }
+        assert_eq!(dbg.cpu.bus.load(DATA, 8), b'h' as u32);
+        let out = dbg.last_write(DATA + 4);
+        assert!(out.contains("instruction 4 at pc 0x80000010"), "{}", out);
+        assert_eq!(dbg.replay.icount(), 7);
+    }
+
+    #[test]
+    fn test_last_write_by_dma() {
+        const DEV: u32 = 0x10001000;
+        const QUEUE: u32 = memory::MEM_BASE + 0x2000;
+        let mut cpu = helper::hart(&[
+            0x100012b7, // lui t0, 0x10001
+            0x0402a823, // sw zero, 0x50(t0), queue notify
+            0x00000063, // beq zero, zero, 0
+        ]);
+        cpu.bus.add_device(Mapped {
+            base: DEV,
+            size: VIRTIO_SIZE,
+            irq: None,
+            device: Box::new(VirtioMmio::new(Box::new(Rng::new(1)), true)),
+        });
+        // a legacy queue of 8 with one buffer of 8 bytes at DATA for the
+        // device to fill
+        for (offset, value) in [
+            (0x070, 1 | 2),
+            (0x028, 0x1000),
+            (0x030, 0),
+            (0x038, 8),
+            (0x03c, 0x1000),
+            (0x040, QUEUE / 0x1000),
+            (0x070, 1 | 2 | 4),
+        ] {
+            cpu.bus.write(DEV + offset, 32, value).unwrap();
+        }
+        cpu.bus.store(QUEUE, 32, DATA);
+        cpu.bus.store(QUEUE + 8, 32, 8);
+        cpu.bus.store(QUEUE + 12, 16, 2);
+        cpu.bus.store(QUEUE + 16 * 8 + 2, 16, 1);
+        let mut dbg = Debugger::new(cpu, 1);
+        for _ in 0..3 {
+            dbg.step();
+        }
+        assert_ne!(dbg.cpu.bus.load(DATA, 32), 0);
+        let out = dbg.last_write(DATA + 4);
+        assert!(out.contains("instruction 1 at pc 0x80000004"), "{}", out);
+    }
+
+    #[test]
+    fn test_gdb_packets() {
+        let mut dbg = Debugger::new(machine(), 2);
+        let mut never = || false;
+        let mut send = |dbg: &mut Debugger, packet: &str| gdb::handle(dbg, packet, &mut never);
+
+        assert!(send(&mut dbg, "qSupported:multiprocess+")
+            .unwrap()
+            .contains("ReverseStep+;ReverseContinue+"));
+        assert_eq!(send(&mut dbg, "Z0,80000008,4").unwrap(), "OK");
+        assert_eq!(send(&mut dbg, "c").unwrap(), "S05");
+        assert_eq!(dbg.cpu.pc, memory::MEM_BASE + 8);
+        assert_eq!(send(&mut dbg, "c").unwrap(), "S05");
+        assert_eq!(send(&mut dbg, "p20").unwrap(), "08000080");
+        assert_eq!(send(&mut dbg, "p5").unwrap(), "02000000");
+
+        assert_eq!(send(&mut dbg, "bc").unwrap(), "S05");
+        assert_eq!(send(&mut dbg, "p5").unwrap(), "01000000");
+        assert_eq!(send(&mut dbg, "bs").unwrap(), "S05");
+        assert_eq!(dbg.cpu.pc, memory::MEM_BASE + 4);
+        assert_eq!(send(&mut dbg, "bc").unwrap(), "T05replaylog:begin;");
+
+        let g = send(&mut dbg, "g").unwrap();
+        assert_eq!(g.len(), 33 * 8);
+        assert!(g.ends_with("00000080"));
+        assert_eq!(send(&mut dbg, "m80000000,4").unwrap(), "37130080");
+
+        // "last-write 80001000" hex encoded
+        let cmd: String = "last-write 80001000"
+            .bytes()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(send(&mut dbg, &format!("qRcmd,{}", cmd)).unwrap(), {
+            let out = "0x80001000 not written since recording started\n";
+            out.bytes()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+        });
+        assert!(send(&mut dbg, "k").is_none());
+    }
+}
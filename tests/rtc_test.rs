@@ -0,0 +1,95 @@
+#[cfg(test)]
+mod tests {
+    use riscland::board::Board;
+    use riscland::clint::Clint;
+    use riscland::cpu::CPU;
+    use riscland::device::Device;
+    use riscland::goldfish_rtc::{Rtc, NS_PER_INSTR};
+    use riscland::machine::Machine;
+    use riscland::snapshot;
+
+    const EPOCH: u64 = 1_700_000_000;
+
+    // lui t0, 0x101; lw t1, 0(t0); lw t2, 4(t0); j .
+    fn read_rtc() -> CPU {
+        let mut board = Board::virt();
+        for device in &mut board.device {
+            match device.kind.as_str() {
+                "goldfish-rtc" => device.epoch = Some(EPOCH),
+                "clint" => device.virtual_time = true,
+                _ => (),
+            }
+        }
+        let mut cpu = board.cpu().unwrap();
+        cpu.trace = false;
+        let code = [0x001012b7, 0x0002a303, 0x0042a383, 0x0000006f];
+        for (i, instr) in code.iter().enumerate() {
+            cpu.bus.store(cpu.pc + i as u32 * 4, 32, *instr);
+        }
+        cpu
+    }
+
+    #[test]
+    fn test_virtual_time() {
+        let mut m = Machine::new(read_rtc());
+        m.run(10);
+        // TIME_LOW is read after the lui retired
+        let now = EPOCH * 1_000_000_000 + NS_PER_INSTR;
+        assert_eq!(m.cpu.xregs.regs[6], now as u32);
+        assert_eq!(m.cpu.xregs.regs[7], (now >> 32) as u32);
+        assert_eq!(m.cpu.bus.instret(), 10);
+
+        // a restored snapshot carries on with the same clock
+        let mut buf = Vec::new();
+        snapshot::save(&m.cpu, &mut buf).unwrap();
+        let mut restored = read_rtc();
+        snapshot::restore(&mut restored, &mut buf.as_slice()).unwrap();
+        assert_eq!(restored.bus.instret(), 10);
+
+        // mtime follows the instruction count too, a tick every 100 ns
+        m.run(990);
+        assert_eq!(m.cpu.bus.read(0x200bff8, 32), Some(100));
+        assert_eq!(m.cpu.bus.read(0x200bffc, 32), Some(0));
+    }
+
+    #[test]
+    fn test_set_time_and_alarm() {
+        let mut rtc = Rtc::virtual_time(0);
+        rtc.clock(0);
+        rtc.store(0x04, 32, 1);
+        rtc.store(0x00, 32, 0);
+        rtc.clock(100);
+        assert_eq!(rtc.now(), (1 << 32) + 100 * NS_PER_INSTR);
+        assert_eq!(rtc.load(0x00, 32), 100 * NS_PER_INSTR as u32);
+        assert_eq!(rtc.load(0x04, 32), 1);
+
+        rtc.store(0x10, 32, 1);
+        rtc.store(0x0c, 32, 1);
+        rtc.store(0x08, 32, 2000);
+        assert_eq!(rtc.load(0x18, 32), 1);
+        assert!(!rtc.irq_pending());
+        rtc.clock(200);
+        assert_eq!(rtc.load(0x18, 32), 0);
+        assert!(rtc.irq_pending());
+        rtc.store(0x1c, 32, 1);
+        assert!(!rtc.irq_pending());
+    }
+
+    #[test]
+    fn test_virtual_mtime() {
+        let mut clint = Clint::virtual_time();
+        clint.clock(25);
+        assert_eq!(clint.mtime(), 2);
+        // the guest sets mtime, which keeps counting from there
+        clint.store(0xbff8, 32, 1000);
+        clint.clock(125);
+        assert_eq!(clint.mtime(), 1010);
+        // mtimecmp of hart 1 raises its timer interrupt once reached
+        clint.store(0x4008, 32, 1020);
+        clint.store(0x400c, 32, 0);
+        assert_eq!(clint.interrupts(1), 0);
+        clint.clock(225);
+        assert_eq!(clint.interrupts(1), 1 << 7);
+        assert_eq!(clint.interrupts(0), 0);
+    }
+}
@@ -0,0 +1,142 @@
+#[cfg(test)]
+mod tests {
+    use riscland::{cpu, memory, semihosting::*};
+
+    const PARAMS: u32 = memory::MEM_BASE + 0x100;
+    const BUF: u32 = memory::MEM_BASE + 0x200;
+
+    // a cpu stopped on the ebreak of a semihosting sequence at MEM_BASE
+    fn cpu_with(root: Option<std::path::PathBuf>) -> cpu::CPU {
+        let mut cpu_test = cpu::CPU::new();
+        cpu_test.bus = memory::BUS::with_memory(memory::MEM_BASE, 0x1000);
+        for (i, instr) in [SLLI_X0_X0_0X1F, EBREAK_INSTR, SRAI_X0_X0_7]
+            .iter()
+            .enumerate()
+        {
+            cpu_test
+                .bus
+                .store(memory::MEM_BASE + i as u32 * 4, 32, *instr);
+        }
+        cpu_test.pc = memory::MEM_BASE + 4;
+        cpu_test.semihosting = Some(Semihosting::new("prog -x 1", root));
+        cpu_test
+    }
+
+    fn call(cpu_test: &mut cpu::CPU, op: u32, params: &[u32]) -> u32 {
+        for (i, p) in params.iter().enumerate() {
+            cpu_test.bus.store(PARAMS + i as u32 * 4, 32, *p);
+        }
+        cpu_test.xregs.regs[10] = op;
+        cpu_test.xregs.regs[11] = PARAMS;
+        // execute() moves past the ebreak, every call is made from it again
+        cpu_test.pc = memory::MEM_BASE + 4;
+        cpu_test.execute(EBREAK_INSTR);
+        cpu_test.xregs.regs[10]
+    }
+
+    #[test]
+    fn test_console_write() {
+        let mut cpu_test = cpu_with(None);
+        cpu_test.bus.store_bytes(BUF, b":tt").unwrap();
+        // ":tt" opened for writing is stdout
+        let fd = call(&mut cpu_test, SYS_OPEN, &[BUF, 4, 3]);
+        assert_eq!(fd, 1);
+        cpu_test.bus.store_bytes(BUF, b"semihosting\n").unwrap();
+        assert_eq!(call(&mut cpu_test, SYS_WRITE, &[fd, BUF, 12]), 0);
+        assert_eq!(call(&mut cpu_test, SYS_ISTTY, &[fd]), 1);
+    }
+
+    #[test]
+    fn test_sandboxed_files() {
+        let root = std::env::temp_dir().join(format!("riscland-sh-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let mut cpu_test = cpu_with(Some(root.clone()));
+
+        cpu_test.bus.store_bytes(BUF, b"/out.txt").unwrap();
+        let fd = call(&mut cpu_test, SYS_OPEN, &[BUF, 6, 8]);
+        assert_ne!(fd, u32::MAX);
+        cpu_test.bus.store_bytes(BUF, b"data").unwrap();
+        assert_eq!(call(&mut cpu_test, SYS_WRITE, &[fd, BUF, 4]), 0);
+        assert_eq!(call(&mut cpu_test, SYS_FLEN, &[fd]), 4);
+        assert_eq!(call(&mut cpu_test, SYS_SEEK, &[fd, 1]), 0);
+        assert_eq!(call(&mut cpu_test, SYS_READ, &[fd, BUF + 0x10, 8]), 5);
+        assert_eq!(cpu_test.bus.load_bytes(BUF + 0x10, 3), Some(&b"ata"[..]));
+        assert_eq!(call(&mut cpu_test, SYS_CLOSE, &[fd]), 0);
+        assert_eq!(std::fs::read(root.join("out.txt")).unwrap(), b"data");
+
+        // escaping the root is refused
+        cpu_test.bus.store_bytes(BUF, b"../passwd").unwrap();
+        assert_eq!(call(&mut cpu_test, SYS_OPEN, &[BUF, 0, 9]), u32::MAX);
+        assert_eq!(call(&mut cpu_test, SYS_ERRNO, &[]), 13);
+
+        // and so is following a link out of it, or creating through one
+        let outside = root.with_extension("outside");
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+        std::os::unix::fs::symlink(outside.join("new"), root.join("dangling")).unwrap();
+        for name in [&b"link/new"[..], b"dangling"] {
+            cpu_test.bus.store_bytes(BUF, name).unwrap();
+            let len = name.len() as u32;
+            assert_eq!(call(&mut cpu_test, SYS_OPEN, &[BUF, 4, len]), u32::MAX);
+            assert_eq!(call(&mut cpu_test, SYS_ERRNO, &[]), 13);
+        }
+        assert!(!outside.join("new").exists());
+        // links that stay inside are fine
+        std::os::unix::fs::symlink(root.join("out.txt"), root.join("alias")).unwrap();
+        cpu_test.bus.store_bytes(BUF, b"alias").unwrap();
+        let fd = call(&mut cpu_test, SYS_OPEN, &[BUF, 0, 5]);
+        assert_eq!(call(&mut cpu_test, SYS_FLEN, &[fd]), 4);
+        std::fs::remove_dir_all(root).unwrap();
+        std::fs::remove_dir_all(outside).unwrap();
+    }
+
+    #[test]
+    fn test_cmdline_clock_and_exit() {
+        let mut cpu_test = cpu_with(None);
+        assert_eq!(call(&mut cpu_test, SYS_GET_CMDLINE, &[BUF, 64]), 0);
+        assert_eq!(cpu_test.bus.load_bytes(BUF, 10), Some(&b"prog -x 1\0"[..]));
+        assert_eq!(cpu_test.bus.load(PARAMS + 4, 32), 9);
+        // too small a buffer fails
+        assert_eq!(call(&mut cpu_test, SYS_GET_CMDLINE, &[BUF, 4]), u32::MAX);
+        assert!(call(&mut cpu_test, SYS_CLOCK, &[]) < 100);
+
+        call(
+            &mut cpu_test,
+            SYS_EXIT_EXTENDED,
+            &[ADP_STOPPED_APPLICATION_EXIT, 7],
+        );
+        assert_eq!(cpu_test.semihosting.as_ref().unwrap().exit_code, Some(7));
+    }
+
+    #[test]
+    fn test_plain_ebreak_is_ignored() {
+        let mut cpu_test = cpu_with(None);
+        // break the marker before the ebreak
+        cpu_test.bus.store(memory::MEM_BASE, 32, 0x13);
+        assert_eq!(call(&mut cpu_test, SYS_TIME, &[]), SYS_TIME);
+    }
+
+    #[test]
+    fn test_bad_parameters() {
+        let mut cpu_test = cpu_with(None);
+        // parameter blocks and buffers outside memory fail with EFAULT
+        for (op, arg) in [
+            (SYS_WRITE, 0xfffffffc),
+            (SYS_WRITEC, 0),
+            (SYS_ELAPSED, 0x10),
+            (SYS_GET_CMDLINE, u32::MAX),
+        ] {
+            cpu_test.xregs.regs[10] = op;
+            cpu_test.xregs.regs[11] = arg;
+            cpu_test.pc = memory::MEM_BASE + 4;
+            cpu_test.execute(EBREAK_INSTR);
+            assert_eq!(cpu_test.xregs.regs[10], u32::MAX);
+            assert_eq!(call(&mut cpu_test, SYS_ERRNO, &[]), 14);
+        }
+        assert_eq!(
+            call(&mut cpu_test, SYS_READ, &[0, 0x1000, 0x10000]),
+            u32::MAX
+        );
+        assert_eq!(call(&mut cpu_test, SYS_GET_CMDLINE, &[0x10, 64]), u32::MAX);
+    }
+}
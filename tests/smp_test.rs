@@ -0,0 +1,121 @@
+mod helper;
+
+#[cfg(test)]
+mod tests {
+    use crate::helper;
+    use riscland::cpu::Fault;
+    use riscland::{csr, memory, smp::SMP};
+
+    const DATA: u32 = memory::MEM_BASE + 0x1000;
+
+    // every hart adds 100 to a counter behind an amoswap spinlock and 100 to
+    // another with lr/sc, then sets its flag at DATA + 16 + 4 * mhartid
+    const CODE: [u32; 22] = [
+        0xf1402573, // csrr a0, mhartid
+        0x80001337, // lui t1, 0x80001
+        0x00830613, // addi a2, t1, 8
+        0x06400f13, // addi t5, zero, 100
+        0x00100393, // addi t2, zero, 1
+        0x08732e2f, // amoswap.w t3, t2, (t1)
+        0xfe0e1ee3, // bne t3, zero, -4
+        0x00432e83, // lw t4, 4(t1)
+        0x001e8e93, // addi t4, t4, 1
+        0x01d32223, // sw t4, 4(t1)
+        0x00032023, // sw zero, 0(t1)
+        0x10062eaf, // lr.w t4, (a2)
+        0x001e8e93, // addi t4, t4, 1
+        0x19d62faf, // sc.w t6, t4, (a2)
+        0xfe0f9ae3, // bne t6, zero, -12
+        0x00128293, // addi t0, t0, 1
+        0xfde2c8e3, // blt t0, t5, -48
+        0x00251593, // slli a1, a0, 2
+        0x006585b3, // add a1, a1, t1
+        0x00150693, // addi a3, a0, 1
+        0x00d5a823, // sw a3, 16(a1)
+        0x00000063, // beq zero, zero, 0
+    ];
+
+    fn machine(code: &[u32], harts: usize, quantum: u64) -> SMP {
+        let mut smp = SMP::new(helper::hart(code), harts);
+        smp.quantum = quantum;
+        smp
+    }
+
+    fn check_counters(smp: &SMP, harts: u32) {
+        assert_eq!(smp.bus.load(DATA + 4, 32), 100 * harts);
+        assert_eq!(smp.bus.load(DATA + 8, 32), 100 * harts);
+        for hart in 0..harts {
+            assert_eq!(smp.bus.load(DATA + 16 + hart * 4, 32), hart + 1);
+        }
+    }
+
+    #[test]
+    fn test_round_robin_is_deterministic() {
+        let mut first = machine(&CODE, 4, 7);
+        assert_eq!(first.run(20_000), 80_000);
+        check_counters(&first, 4);
+        for (i, hart) in first.harts.iter().enumerate() {
+            assert_eq!(hart.hartid(), i as u32);
+            assert_eq!(hart.xregs.regs[10], i as u32);
+        }
+
+        let mut second = machine(&CODE, 4, 7);
+        second.run(20_000);
+        assert_eq!(first.bus.ram(), second.bus.ram());
+        for (a, b) in first.harts.iter().zip(&second.harts) {
+            assert_eq!(a.pc, b.pc);
+            assert_eq!(a.xregs.regs, b.xregs.regs);
+        }
+    }
+
+    #[test]
+    fn test_threaded() {
+        let mut smp = machine(&CODE, 3, 5);
+        assert_eq!(smp.run_threaded(20_000), 60_000);
+        check_counters(&smp, 3);
+    }
+
+    #[test]
+    fn test_failed_store_keeps_rd() {
+        // lr.w t0, (a0); sc.w t2, t0, (a0); amoadd.w t3, t0, (a0) on ROM
+        let mut smp = machine(&[0x100522af, 0x185523af, 0x00552e2f], 1, 10);
+        smp.bus.add_region(0x1000, vec![0; 0x1000], true);
+        let hart = &mut smp.harts[0];
+        hart.xregs.regs[10] = 0x1000;
+        hart.xregs.regs[7] = 7;
+        hart.xregs.regs[28] = 28;
+        smp.run(3);
+        let hart = &mut smp.harts[0];
+        assert_eq!(hart.fault, Some(Fault::StoreAccess(0x1000)));
+        assert_eq!(hart.xregs.regs[7], 7);
+        hart.pc += 4;
+        hart.fault = None;
+        smp.run(1);
+        let hart = &smp.harts[0];
+        assert_eq!(hart.fault, Some(Fault::StoreAccess(0x1000)));
+        assert_eq!(hart.xregs.regs[28], 28);
+    }
+
+    #[test]
+    fn test_csr_instructions() {
+        let mut smp = machine(
+            &[
+                0x00500393, // addi t2, zero, 5
+                0x34039e73, // csrrw t3, mscratch, t2
+                0x3402eef3, // csrrsi t4, mscratch, 5
+                0xf1439ff3, // csrrw t6, mhartid, t2
+                0x00000063, // beq zero, zero, 0
+            ],
+            2,
+            10,
+        );
+        smp.run(5);
+        let hart = &smp.harts[1];
+        assert_eq!(hart.xregs.regs[28], 0);
+        assert_eq!(hart.xregs.regs[29], 5);
+        assert_eq!(hart.csrs.load(csr::MSCRATCH), 5);
+        // mhartid is read only
+        assert_eq!(hart.xregs.regs[31], 1);
+        assert_eq!(hart.hartid(), 1);
+    }
+}
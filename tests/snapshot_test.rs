@@ -0,0 +1,227 @@
+mod helper;
+
+#[cfg(test)]
+mod tests {
+    use crate::helper;
+    use riscland::board::Board;
+    use riscland::cpu::Privilege;
+    use riscland::{cpu, csr, memory, snapshot};
+
+    const BOARD: &str = r#"
+        [[rom]]
+        base = 0x1000
+        size = 0x1000
+
+        [[ram]]
+        base = 0x80000000
+        size = 0x10000
+
+        [[ram]]
+        base = 0x20000000
+        size = 0x2000
+
+        [[device]]
+        type = "clint"
+        base = 0x2000000
+        virtual_time = true
+
+        [[device]]
+        type = "plic"
+        base = 0xc000000
+
+        [[device]]
+        type = "uart"
+        base = 0x10000000
+        irq = 10
+
+        [[device]]
+        type = "goldfish-rtc"
+        base = 0x101000
+        epoch = 0
+
+        [[device]]
+        type = "sifive-spi"
+        base = 0x10014000
+        replies = [1, 2, 3]
+
+        [[device]]
+        type = "sifive-i2c"
+        base = 0x10016000
+        target = [{ address = 0x48, registers = [0x19, 0x80] }]
+
+        [[device]]
+        type = "framebuffer"
+        base = 0x30000000
+        width = 4
+        height = 4
+    "#;
+
+    const I2C: u32 = 0x10016000;
+
+    fn machine() -> cpu::CPU {
+        helper::hart(&[
+            0x00100293, // addi t0, zero, 1
+            0x00128293, // addi t0, t0, 1
+            0xfe000ee3, // beq zero, zero, -4
+        ])
+    }
+
+    fn step(cpu_test: &mut cpu::CPU, n: usize) {
+        for _ in 0..n {
+            let instr = cpu_test.fetch().unwrap();
+            cpu_test.execute(instr);
+        }
+    }
+
+    #[test]
+    fn test_save_restore_round_trip() {
+        let mut original = machine();
+        step(&mut original, 7);
+        original
+            .bus
+            .store(memory::MEM_BASE + 0x2ffc, 32, 0xdeadbeef);
+        original.csrs.store(csr::MSCRATCH, 0x1234);
+        let mut buf = Vec::new();
+        snapshot::save(&original, &mut buf).unwrap();
+        // only the two pages with data are stored
+        assert!(buf.len() < 2 * 4096 + 512);
+
+        let mut restored = helper::hart(&[]);
+        snapshot::restore(&mut restored, &mut buf.as_slice()).unwrap();
+        assert_eq!(restored.pc, original.pc);
+        assert_eq!(restored.xregs.regs, original.xregs.regs);
+        assert_eq!(restored.csrs.load(csr::MSCRATCH), 0x1234);
+        assert_eq!(restored.bus.ram(), original.bus.ram());
+
+        // both continue identically from the checkpoint
+        step(&mut original, 5);
+        step(&mut restored, 5);
+        assert_eq!(restored.pc, original.pc);
+        assert_eq!(restored.xregs.regs[5], original.xregs.regs[5]);
+    }
+
+    #[test]
+    fn test_rejects_bad_files() {
+        let mut cpu_test = machine();
+        let err = snapshot::restore(&mut cpu_test, &mut &b"NOTASNAP\x01\0\0\0"[..]).unwrap_err();
+        assert!(matches!(err, snapshot::SnapshotError::BadMagic));
+
+        let mut buf = Vec::new();
+        snapshot::save(&cpu_test, &mut buf).unwrap();
+        buf[8..12].copy_from_slice(&(snapshot::VERSION + 1).to_le_bytes());
+        let err = snapshot::restore(&mut cpu_test, &mut buf.as_slice()).unwrap_err();
+        assert!(matches!(
+            err,
+            snapshot::SnapshotError::UnsupportedVersion(_)
+        ));
+
+        buf[8..12].copy_from_slice(&snapshot::VERSION.to_le_bytes());
+        buf.truncate(buf.len() - 20);
+        let err = snapshot::restore(&mut cpu_test, &mut buf.as_slice()).unwrap_err();
+        assert!(matches!(err, snapshot::SnapshotError::Corrupt(_)));
+    }
+
+    // register `register` of the I2C target at 0x48
+    fn i2c_register(cpu_test: &mut cpu::CPU, register: u32) -> u32 {
+        // start, pointer, repeated start for a read, read with a NACK, stop
+        for (data, command) in [(0x90, 0x90), (register, 0x10), (0x91, 0x90)] {
+            cpu_test.bus.write(I2C + 0x0c, 32, data).unwrap();
+            cpu_test.bus.write(I2C + 0x10, 32, command).unwrap();
+        }
+        cpu_test.bus.write(I2C + 0x10, 32, 0x28).unwrap();
+        cpu_test.bus.write(I2C + 0x10, 32, 0x40).unwrap();
+        cpu_test.bus.read(I2C + 0x0c, 32).unwrap()
+    }
+
+    #[test]
+    fn test_memories_and_devices() {
+        let board = Board::parse(BOARD).unwrap();
+        let mut original = board.cpu().unwrap();
+        let bus = &mut original.bus;
+        bus.store(0x20001000, 32, 0xdeadbeef);
+        // msip and mtimecmp of hart 0
+        bus.write(0x2000000, 32, 1).unwrap();
+        bus.write(0x2004000, 32, 1234).unwrap();
+        // the UART's interrupt, pending at the PLIC
+        bus.write(0xc000028, 32, 3).unwrap();
+        bus.write(0xc002000, 32, 1 << 10).unwrap();
+        bus.write(0x10000001, 8, 0x02).unwrap();
+        bus.write(0x10000007, 8, 0x5a).unwrap();
+        // an RTC alarm far away
+        bus.write(0x10100c, 32, 1).unwrap();
+        bus.write(0x101008, 32, 0).unwrap();
+        // the SPI device's first reply waits in the receive FIFO
+        bus.write(0x10014048, 32, 0xaa).unwrap();
+        // register 1 of the I2C target becomes 0x42
+        bus.write(I2C + 0x08, 32, 0x80).unwrap();
+        for (data, command) in [(0x90, 0x90), (1, 0x10), (0x42, 0x10)] {
+            bus.write(I2C + 0x0c, 32, data).unwrap();
+            bus.write(I2C + 0x10, 32, command).unwrap();
+        }
+        bus.write(I2C + 0x10, 32, 0x40).unwrap();
+        bus.write(0x30000000, 32, 0x00ff00ff).unwrap();
+        original.privilege = Privilege::Supervisor;
+
+        let mut buf = Vec::new();
+        snapshot::save(&original, &mut buf).unwrap();
+        let mut restored = board.cpu().unwrap();
+        snapshot::restore(&mut restored, &mut buf.as_slice()).unwrap();
+
+        assert_eq!(restored.privilege, Privilege::Supervisor);
+        let bus = &mut restored.bus;
+        assert_eq!(bus.load(0x20001000, 32), 0xdeadbeef);
+        assert_eq!(bus.read(0x2004000, 32), Some(1234));
+        assert_eq!(bus.read(0xc001000, 32), Some(1 << 10));
+        assert_eq!(
+            bus.interrupts(0),
+            1 << csr::IRQ_M_SOFT | 1 << csr::IRQ_M_EXT
+        );
+        assert_eq!(bus.read(0x10000007, 8), Some(0x5a));
+        assert_eq!(bus.read(0x101018, 32), Some(1));
+        assert_eq!(bus.read(0x1001404c, 32), Some(1));
+        bus.write(0x10014048, 32, 0xaa).unwrap();
+        assert_eq!(bus.read(0x1001404c, 32), Some(2));
+        assert_eq!(bus.read(0x30000000, 32), Some(0x00ff00ff));
+        assert_eq!(i2c_register(&mut restored, 1), 0x42);
+
+        // a machine without the memory or a device is not the one saved
+        let mut other = Board::parse(&BOARD.replace("0x20000000", "0x20002000"))
+            .unwrap()
+            .cpu()
+            .unwrap();
+        let err = snapshot::restore(&mut other, &mut buf.as_slice()).unwrap_err();
+        assert_eq!(err.to_string(), "corrupt snapshot: memory not on the bus");
+        let mut other = Board::parse(&BOARD.replace("0x10016000", "0x10017000"))
+            .unwrap()
+            .cpu()
+            .unwrap();
+        let err = snapshot::restore(&mut other, &mut buf.as_slice()).unwrap_err();
+        assert_eq!(err.to_string(), "corrupt snapshot: device not on the bus");
+    }
+
+    #[test]
+    fn test_section_length_is_not_trusted() {
+        let mut cpu_test = machine();
+        let mut buf = snapshot::MAGIC.to_vec();
+        buf.extend(snapshot::VERSION.to_le_bytes());
+        buf.extend(b"RAM\0");
+        buf.extend(u64::MAX.to_le_bytes());
+        buf.extend(0x80000000u32.to_le_bytes());
+        let err = snapshot::restore(&mut cpu_test, &mut buf.as_slice()).unwrap_err();
+        assert!(matches!(err, snapshot::SnapshotError::Corrupt("truncated")));
+
+        // nor is the size of a RAM unlike the machine's
+        buf.extend(u32::MAX.to_le_bytes());
+        let err = snapshot::restore(&mut cpu_test, &mut buf.as_slice()).unwrap_err();
+        assert!(matches!(
+            err,
+            snapshot::SnapshotError::Corrupt("memory not on the bus")
+        ));
+        let mut smaller = cpu::CPU::new();
+        smaller.bus = memory::BUS::with_memory(memory::MEM_BASE, 0x1000);
+        let mut saved = Vec::new();
+        snapshot::save(&cpu_test, &mut saved).unwrap();
+        assert!(snapshot::restore(&mut smaller, &mut saved.as_slice()).is_err());
+        assert_eq!(smaller.bus.ram().1.len(), 0x1000);
+    }
+}
@@ -0,0 +1,94 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use riscland::device::Device;
+    use riscland::spi::{Script, Spi, SpiBus};
+
+    // a flash answering its JEDEC ID command, logging the bus
+    #[derive(Debug, Clone, Default)]
+    struct Flash {
+        log: Arc<Mutex<Vec<String>>>,
+        command: Option<u8>,
+        index: usize,
+    }
+
+    impl SpiBus for Flash {
+        fn select(&mut self, cs: u32) {
+            self.log.lock().unwrap().push(format!("select {}", cs));
+            self.command = None;
+            self.index = 0;
+        }
+
+        fn transfer(&mut self, _cs: u32, byte: u8) -> u8 {
+            self.log.lock().unwrap().push(format!("{:02x}", byte));
+            if self.command.is_none() {
+                self.command = Some(byte);
+                return 0xff;
+            }
+            let id = [0xef, 0x40, 0x18];
+            self.index += 1;
+            match self.command {
+                Some(0x9f) => id.get(self.index - 1).copied().unwrap_or(0),
+                _ => 0xff,
+            }
+        }
+
+        fn deselect(&mut self, cs: u32) {
+            self.log.lock().unwrap().push(format!("deselect {}", cs));
+        }
+
+        fn clone_box(&self) -> Box<dyn SpiBus> {
+            Box::new(self.clone())
+        }
+    }
+
+    #[test]
+    fn test_hold_and_auto() {
+        let flash = Flash::default();
+        let mut spi = Spi::new(Box::new(flash.clone()));
+        // csmode HOLD, chip select 1
+        spi.store(0x10, 32, 1);
+        spi.store(0x18, 32, 2);
+        for byte in [0x9f, 0, 0, 0] {
+            spi.store(0x48, 32, byte);
+        }
+        let rx: Vec<u32> = (0..5).map(|_| spi.load(0x4c, 32)).collect();
+        assert_eq!(rx, [0xff, 0xef, 0x40, 0x18, 1 << 31]);
+        // back to AUTO lets go of the chip select
+        spi.store(0x18, 32, 0);
+        spi.store(0x48, 32, 0x05);
+        assert_eq!(
+            *flash.log.lock().unwrap(),
+            [
+                "select 1",
+                "9f",
+                "00",
+                "00",
+                "00",
+                "deselect 1",
+                "select 1",
+                "05",
+                "deselect 1"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_script_and_fifo() {
+        let mut spi = Spi::new(Box::new(Script::new(&[1, 2])));
+        for byte in 0..10 {
+            spi.store(0x48, 32, byte);
+        }
+        // rxwm: more than rxmark (0) bytes waiting
+        assert_eq!(spi.load(0x74, 32) & 2, 2);
+        let rx: Vec<u32> = (0..9).map(|_| spi.load(0x4c, 32)).collect();
+        // eight fit into the FIFO, the line floats high once the script ends
+        assert_eq!(rx, [1, 2, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 1 << 31]);
+
+        // transmit only
+        spi.store(0x40, 32, 8 << 16 | 1 << 3);
+        spi.store(0x48, 32, 0);
+        assert_eq!(spi.load(0x4c, 32), 1 << 31);
+    }
+}
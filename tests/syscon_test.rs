@@ -0,0 +1,47 @@
+#[cfg(test)]
+mod tests {
+    use riscland::board::Board;
+    use riscland::cpu::CPU;
+    use riscland::machine::{HaltReason, Machine};
+    use riscland::smp::SMP;
+
+    // lui t0, 0x100; lui t1, hi; addi t1, t1, lo; sw t1, 0(t0); j .
+    fn syscon_store(hi: u32, lo: u32) -> CPU {
+        let mut cpu = Board::virt().cpu().unwrap();
+        cpu.trace = false;
+        let code = [
+            0x001002b7,
+            hi << 12 | 6 << 7 | 0x37,
+            lo << 20 | 6 << 15 | 6 << 7 | 0x13,
+            0x0062a023,
+            0x0000006f,
+        ];
+        for (i, instr) in code.iter().enumerate() {
+            cpu.bus.store(cpu.pc + i as u32 * 4, 32, *instr);
+        }
+        cpu
+    }
+
+    #[test]
+    fn test_power_off_and_reset() {
+        let mut m = Machine::new(syscon_store(0x5, 0x555));
+        assert_eq!(m.run(100), HaltReason::Exited(0));
+        assert_eq!(m.retired, 4);
+        // FINISHER_FAIL, the exit code in the high half
+        let mut m = Machine::new(syscon_store(0x33, 0x333));
+        assert_eq!(m.run(100), HaltReason::Exited(3));
+        let mut m = Machine::new(syscon_store(0x7, 0x777));
+        assert_eq!(m.run(100), HaltReason::Reset);
+        // anything else is no command
+        let mut m = Machine::new(syscon_store(0x1, 0x234));
+        assert_eq!(m.run(100), HaltReason::Limit);
+    }
+
+    #[test]
+    fn test_smp_power_off() {
+        let mut smp = SMP::new(syscon_store(0x2a3, 0x333), 2);
+        smp.run(1_000_000);
+        assert_eq!(smp.exit_code(), Some(0x2a));
+        assert!(smp.harts.iter().all(|hart| hart.fault.is_none()));
+    }
+}
@@ -0,0 +1,242 @@
+mod helper;
+
+#[cfg(test)]
+mod tests {
+    use crate::helper;
+    use riscland::clint::{Clint, CLINT_SIZE};
+    use riscland::cpu::{Privilege, CPU};
+    use riscland::csr;
+    use riscland::device::{Device, Mapped};
+    use riscland::machine::{HaltReason, Machine};
+    use riscland::memory::MEM_BASE;
+    use riscland::plic::{Plic, PLIC_SIZE};
+    use riscland::uart::{Uart, UART_SIZE};
+
+    const CLINT: u32 = 0x2000000;
+    const PLIC: u32 = 0xc000000;
+    const UART: u32 = 0x10000000;
+
+    // `code` at every address given, the CLINT, the PLIC and a UART on
+    // source 10 on the bus
+    fn machine(code: &[(u32, &[u32])]) -> CPU {
+        let mut cpu = helper::hart(&[]);
+        for (at, code) in code {
+            for (i, instr) in code.iter().enumerate() {
+                cpu.bus.store(at + i as u32 * 4, 32, *instr);
+            }
+        }
+        let mut add = |base, size, irq, device: Box<dyn Device>| {
+            cpu.bus.add_device(Mapped {
+                base,
+                size,
+                irq,
+                device,
+            })
+        };
+        add(CLINT, CLINT_SIZE, None, Box::new(Clint::new()));
+        add(PLIC, PLIC_SIZE, None, Box::new(Plic::new()));
+        add(UART, UART_SIZE, Some(10), Box::new(Uart::captured().0));
+        cpu.pc = MEM_BASE;
+        cpu
+    }
+
+    #[test]
+    fn test_exceptions_and_returns() {
+        let s_code = MEM_BASE + 0x100;
+        let m_handler = MEM_BASE + 0x200;
+        let s_handler = MEM_BASE + 0x300;
+        let mut cpu = machine(&[
+            (MEM_BASE, &[0x30200073]), // mret
+            (
+                s_code,
+                &[
+                    0x10002573, // csrr a0, sstatus
+                    0x300025f3, // csrr a1, mstatus, which S-mode may not
+                    0x00000297, // auipc t0, 0
+                    0x01028293, // addi t0, t0, 16
+                    0x14129073, // csrw sepc, t0
+                    0x10200073, // sret
+                    0x00000073, // ecall, in U-mode
+                ],
+            ),
+            (
+                m_handler,
+                &[
+                    0x341022f3, // csrr t0, mepc
+                    0x00428293, // addi t0, t0, 4
+                    0x34129073, // csrw mepc, t0
+                    0x30200073, // mret
+                ],
+            ),
+            (
+                s_handler,
+                &[
+                    0x10500073, // wfi
+                    0x12000073, // sfence.vma
+                    0x30200073, // mret, which S-mode may not
+                ],
+            ),
+        ]);
+        cpu.csrs.write(csr::MTVEC, m_handler);
+        cpu.csrs.write(csr::STVEC, s_handler);
+        cpu.csrs.write(csr::MEDELEG, 1 << 8);
+        cpu.csrs.write(csr::MSTATUS, 1 << csr::MSTATUS_MPP_SHIFT);
+        cpu.csrs.write(csr::MEPC, s_code);
+
+        // down to S-mode, which reads sstatus
+        assert_eq!(cpu.step() + cpu.step(), 2);
+        assert_eq!(cpu.privilege, Privilege::Supervisor);
+        assert_eq!(cpu.pc, s_code + 4);
+        assert_eq!(cpu.csrs.load(csr::MSTATUS), csr::MSTATUS_MPIE);
+
+        // the illegal csrr goes to machine mode, which skips it
+        cpu.xregs.regs[11] = 0x55;
+        assert_eq!(cpu.step(), 1);
+        assert_eq!(cpu.fault, None);
+        assert_eq!(cpu.privilege, Privilege::Machine);
+        assert_eq!(cpu.pc, m_handler);
+        assert_eq!(cpu.xregs.regs[11], 0x55);
+        assert_eq!(cpu.csrs.load(csr::MCAUSE), 2);
+        assert_eq!(cpu.csrs.load(csr::MTVAL), 0x300025f3);
+        assert_eq!(cpu.csrs.load(csr::MEPC), s_code + 4);
+        assert_eq!(
+            cpu.csrs.load(csr::MSTATUS) & csr::MSTATUS_MPP,
+            1 << csr::MSTATUS_MPP_SHIFT
+        );
+        for _ in 0..4 {
+            cpu.step();
+        }
+        assert_eq!(cpu.privilege, Privilege::Supervisor);
+        assert_eq!(cpu.pc, s_code + 8);
+
+        // sret to user mode, whose ecall is delegated to S-mode
+        for _ in 0..4 {
+            cpu.step();
+        }
+        assert_eq!(cpu.privilege, Privilege::User);
+        assert_eq!(cpu.pc, s_code + 0x18);
+        assert_eq!(cpu.step(), 1);
+        assert_eq!(cpu.privilege, Privilege::Supervisor);
+        assert_eq!(cpu.pc, s_handler);
+        assert_eq!(cpu.csrs.load(csr::SCAUSE), 8);
+        assert_eq!(cpu.csrs.load(csr::SEPC), s_code + 0x18);
+        assert_eq!(cpu.csrs.read(csr::SSTATUS) & csr::MSTATUS_SPP, 0);
+        assert_eq!(cpu.csrs.load(csr::MCAUSE), 2);
+
+        // wfi and sfence.vma do nothing here, mret is illegal
+        assert_eq!(cpu.step() + cpu.step(), 2);
+        assert_eq!(cpu.pc, s_handler + 8);
+        assert_eq!(cpu.step(), 1);
+        assert_eq!(cpu.privilege, Privilege::Machine);
+        assert_eq!(cpu.pc, m_handler);
+        assert_eq!(cpu.csrs.load(csr::MTVAL), 0x30200073);
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        let handler = MEM_BASE + 0x100;
+        let code = [
+            0x020042b7, // lui t0, 0x2004, mtimecmp of hart 0
+            0x0002a023, // sw zero, 0(t0)
+            0x0002a223, // sw zero, 4(t0)
+            0x00150513, // addi a0, a0, 1
+            0xffdff06f, // j -4
+        ];
+        let vector = [
+            0x342025f3, // csrr a1, mcause
+            0x00700613, // addi a2, zero, 7
+            0x00000013, // nop
+        ];
+        let timer = handler + 4 * csr::IRQ_M_TIMER;
+        let mut m = Machine::new(machine(&[(MEM_BASE, &code), (timer, &vector)]));
+        // vectored mode
+        m.cpu.csrs.write(csr::MTVEC, handler | 1);
+        m.cpu.csrs.write(csr::MIE, 1 << csr::IRQ_M_TIMER);
+
+        // masked while mstatus.MIE is clear
+        assert_eq!(m.run(50), HaltReason::Limit);
+        assert!(m.cpu.xregs.regs[10] > 10);
+        assert_ne!(m.cpu.csrs.read(csr::MIP) & 1 << csr::IRQ_M_TIMER, 0);
+
+        m.cpu.csrs.write(csr::MSTATUS, csr::MSTATUS_MIE);
+        m.breakpoints.push(timer + 8);
+        assert_eq!(m.run(50), HaltReason::Breakpoint);
+        assert_eq!(m.cpu.xregs.regs[11], 1 << 31 | csr::IRQ_M_TIMER);
+        assert_eq!(m.cpu.xregs.regs[12], 7);
+        let mepc = m.cpu.csrs.load(csr::MEPC);
+        assert!(mepc == MEM_BASE + 12 || mepc == MEM_BASE + 16);
+        assert_eq!(
+            m.cpu.csrs.load(csr::MSTATUS),
+            csr::MSTATUS_MPIE | csr::MSTATUS_MPP
+        );
+        assert_eq!(m.cpu.csrs.load(csr::MCAUSE), 1 << 31 | csr::IRQ_M_TIMER);
+    }
+
+    #[test]
+    fn test_plic_routes_device_interrupts() {
+        let mut cpu = machine(&[]);
+        let claim = |context: u32| PLIC + 0x200004 + 0x1000 * context;
+        cpu.csrs.write(csr::MTVEC, MEM_BASE + 0x100);
+        cpu.csrs
+            .write(csr::MIE, 1 << csr::IRQ_M_EXT | 1 << csr::IRQ_S_EXT);
+        cpu.csrs.write(csr::MSTATUS, csr::MSTATUS_MIE);
+        cpu.bus.write(PLIC + 4 * 10, 32, 1).unwrap();
+        cpu.bus.write(PLIC + 0x2000, 32, 1 << 10).unwrap();
+        assert!(!cpu.take_interrupt());
+
+        // the UART raises its transmitter empty interrupt
+        cpu.bus.write(UART + 1, 8, 0x02).unwrap();
+        assert_eq!(cpu.bus.read(PLIC + 0x1000, 32), Some(1 << 10));
+        assert!(cpu.take_interrupt());
+        assert_eq!(cpu.pc, MEM_BASE + 0x100);
+        assert_eq!(cpu.csrs.load(csr::MCAUSE), 1 << 31 | csr::IRQ_M_EXT);
+        assert_eq!(cpu.bus.read(claim(0), 32), Some(10));
+        assert_eq!(cpu.bus.read(claim(0), 32), Some(0));
+        // IIR reports it, which lowers the line
+        assert_eq!(cpu.bus.read(UART + 2, 8), Some(0x02));
+        cpu.bus.write(claim(0), 32, 10).unwrap();
+        assert!(!cpu.take_interrupt());
+        assert_eq!(cpu.csrs.read(csr::MIP), 0);
+
+        // the supervisor context alone raises SEIP, which machine mode does
+        // not take once it is delegated
+        cpu.csrs.write(csr::MIDELEG, 1 << csr::IRQ_S_EXT);
+        cpu.csrs.write(csr::MSTATUS, csr::MSTATUS_MIE);
+        cpu.bus.write(PLIC + 0x2000, 32, 0).unwrap();
+        cpu.bus.write(PLIC + 0x2080, 32, 1 << 10).unwrap();
+        cpu.bus.write(UART, 8, b'x' as u32).unwrap();
+        assert!(!cpu.take_interrupt());
+        assert_eq!(cpu.csrs.read(csr::MIP), 1 << csr::IRQ_S_EXT);
+        assert_eq!(cpu.csrs.read(csr::SIP), 1 << csr::IRQ_S_EXT);
+        // a threshold as high as the priority masks it
+        cpu.bus.write(PLIC + 0x201000, 32, 1).unwrap();
+        cpu.take_interrupt();
+        assert_eq!(cpu.csrs.read(csr::MIP), 0);
+    }
+
+    #[test]
+    fn test_csr_views() {
+        let mut cpu = machine(&[]);
+        cpu.csrs.write(csr::MSTATUS, 0xffffffff);
+        // MPP 3, and no bits that are not there
+        assert_eq!(cpu.csrs.load(csr::MSTATUS), 0x007e19aa);
+        assert_eq!(cpu.csrs.read(csr::SSTATUS), 0x000c0122);
+        cpu.csrs.write(csr::SSTATUS, 0);
+        assert_eq!(cpu.csrs.load(csr::MSTATUS), 0x00721888);
+        // MPP 2 is no privilege level
+        cpu.csrs.write(csr::MSTATUS, 2 << csr::MSTATUS_MPP_SHIFT);
+        assert_eq!(cpu.csrs.load(csr::MSTATUS), 0);
+
+        cpu.csrs.write(csr::MIDELEG, 0xffffffff);
+        assert_eq!(cpu.csrs.load(csr::MIDELEG), 0x222);
+        cpu.csrs.write(csr::SIE, 0xffffffff);
+        assert_eq!(cpu.csrs.load(csr::MIE), 0x222);
+        cpu.csrs.write(csr::SIP, 0xffffffff);
+        assert_eq!(cpu.csrs.load(csr::MIP), 0x2);
+        cpu.csrs.write(csr::MEDELEG, 0xffffffff);
+        assert_eq!(cpu.csrs.load(csr::MEDELEG), !(1 << 11));
+        // Sv32 and Bare, without ASIDs
+        cpu.csrs.write(csr::SATP, 0x80400123);
+        assert_eq!(cpu.csrs.load(csr::SATP), 0x80000123);
+    }
+}
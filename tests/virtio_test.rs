@@ -0,0 +1,386 @@
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use riscland::csr;
+    use riscland::device::Mapped;
+    use riscland::memory::{self, MEM_BASE};
+    use riscland::plic::{Plic, PLIC_SIZE};
+    use riscland::virtio::{Backend, VirtioMmio, F_VERSION_1, VIRTIO_SIZE};
+    use riscland::virtio_blk::{Disk, DiskMode};
+    use riscland::virtio_console::Console;
+    use riscland::virtio_net::{self, Net};
+    use riscland::virtio_rng::Rng;
+
+    const DEV: u32 = 0x10001000;
+    const PLIC: u32 = 0xc000000;
+    // every queue has 8 entries and three pages from queue_base() on
+    const HEADER: u32 = MEM_BASE + 0x8000;
+    const DATA: u32 = MEM_BASE + 0x9000;
+    const STATUS: u32 = MEM_BASE + 0xa000;
+    const MEMORY: u32 = 0x40000;
+
+    fn queue_base(queue: u32) -> u32 {
+        MEM_BASE + queue * 0x3000
+    }
+
+    // a guest driving the device through its registers
+    struct Driver {
+        bus: memory::BUS,
+        legacy: bool,
+        submitted: Vec<u16>,
+    }
+
+    impl Driver {
+        fn new(backend: Box<dyn Backend>, legacy: bool) -> Self {
+            let queues = backend.num_queues();
+            let mut bus = memory::BUS::with_memory(MEM_BASE, MEMORY);
+            bus.add_device(Mapped {
+                base: DEV,
+                size: VIRTIO_SIZE,
+                irq: Some(1),
+                device: Box::new(VirtioMmio::new(backend, legacy)),
+            });
+            let mut driver = Driver {
+                bus,
+                legacy,
+                submitted: vec![0; queues],
+            };
+            assert_eq!(driver.reg(0x000), 0x74726976);
+            assert_eq!(driver.reg(0x004), if legacy { 1 } else { 2 });
+            // acknowledge, driver, features ok
+            driver.set(0x070, 1 | 2);
+            let high = driver.features();
+            driver.set(0x024, 1);
+            driver.set(0x020, high);
+            driver.set(0x070, 1 | 2 | 8);
+            driver.set(0x028, 0x1000);
+            for queue in 0..queues as u32 {
+                let base = queue_base(queue);
+                driver.set(0x030, queue);
+                assert_eq!(driver.reg(0x034), 256);
+                driver.set(0x038, 8);
+                if legacy {
+                    driver.set(0x03c, 0x1000);
+                    driver.set(0x040, base / 0x1000);
+                } else {
+                    driver.set(0x080, base);
+                    driver.set(0x090, base + 0x1000);
+                    driver.set(0x0a0, base + 0x2000);
+                    driver.set(0x044, 1);
+                }
+            }
+            driver.set(0x070, 1 | 2 | 8 | 4);
+            driver
+        }
+
+        fn reg(&mut self, offset: u32) -> u32 {
+            self.bus.read(DEV + offset, 32).unwrap()
+        }
+
+        fn set(&mut self, offset: u32, value: u32) {
+            self.bus.write(DEV + offset, 32, value).unwrap();
+        }
+
+        fn features(&mut self) -> u32 {
+            self.set(0x014, 1);
+            let high = self.reg(0x010);
+            self.set(0x014, 0);
+            high
+        }
+
+        // legacy: the available ring behind the table, the used ring on the
+        // next page
+        fn rings(&self, queue: u32) -> (u32, u32) {
+            let base = queue_base(queue);
+            match self.legacy {
+                true => (base + 16 * 8, base + 0x1000),
+                false => (base + 0x1000, base + 0x2000),
+            }
+        }
+
+        // make a chain of (address, length, device writes) buffers available
+        // on `queue` and notify
+        fn submit(&mut self, queue: u32, bufs: &[(u32, u32, bool)]) {
+            for (i, (addr, len, write)) in bufs.iter().enumerate() {
+                let at = queue_base(queue) + 16 * i as u32;
+                let next = (i + 1 < bufs.len()) as u32;
+                self.bus.store(at, 32, *addr);
+                self.bus.store(at + 4, 32, 0);
+                self.bus.store(at + 8, 32, *len);
+                self.bus.store(at + 12, 16, next | (*write as u32) << 1);
+                self.bus.store(at + 14, 16, i as u32 + 1);
+            }
+            let (avail, _) = self.rings(queue);
+            let submitted = &mut self.submitted[queue as usize];
+            self.bus
+                .store(avail + 4 + 2 * (*submitted as u32 % 8), 16, 0);
+            *submitted += 1;
+            self.bus.store(avail + 2, 16, *submitted as u32);
+            self.set(0x050, queue);
+        }
+
+        // length the device wrote for the last chain on `queue`, None while
+        // it has not used it
+        fn used(&mut self, queue: u32) -> Option<u32> {
+            let (_, used) = self.rings(queue);
+            let submitted = self.submitted[queue as usize];
+            if self.bus.load(used + 2, 16) != submitted as u32 {
+                return None;
+            }
+            let elem = used + 4 + 8 * ((submitted - 1) as u32 % 8);
+            assert_eq!(self.bus.load(elem, 32), 0);
+            assert_eq!(self.reg(0x060) & 1, 1);
+            self.set(0x064, 1);
+            Some(self.bus.load(elem + 4, 32))
+        }
+
+        // a block request of `len` data bytes, the status byte it got back
+        fn request(&mut self, kind: u32, sector: u32, len: u32) -> u8 {
+            self.bus.store(HEADER, 32, kind);
+            self.bus.store(HEADER + 8, 32, sector);
+            self.bus.store(HEADER + 12, 32, 0);
+            let bufs = [
+                (HEADER, 16, false),
+                (DATA, len, kind == 0),
+                (STATUS, 1, true),
+            ];
+            self.submit(0, &bufs);
+            assert!(self.used(0).is_some());
+            self.bus.load(STATUS, 8) as u8
+        }
+    }
+
+    fn image(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("riscland-{}-{}", name, std::process::id()));
+        let data: Vec<u8> = (0..4 * 512).map(|i| (i / 512) as u8 + 1).collect();
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_read_and_write() {
+        for legacy in [false, true] {
+            let path = image(&format!("blk-{}", legacy));
+            let mut driver = Driver::new(
+                Box::new(Disk::open(&path, DiskMode::ReadWrite).unwrap()),
+                legacy,
+            );
+            let version_1 = (F_VERSION_1 >> 32) as u32;
+            assert_eq!(driver.features() & version_1 != 0, !legacy);
+            // capacity in sectors
+            assert_eq!(driver.reg(0x100), 4);
+
+            assert_eq!(driver.request(0, 2, 512), 0);
+            assert_eq!(driver.bus.load_bytes(DATA, 512), Some(&[3; 512][..]));
+            driver.bus.store_bytes(DATA, &[0xee; 1024]).unwrap();
+            assert_eq!(driver.request(1, 1, 1024), 0);
+            // past the end of the disk
+            assert_eq!(driver.request(0, 4, 512), 1);
+
+            let data = std::fs::read(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(&data[512..1536], &[0xee; 1024][..]);
+            assert_eq!(data[0], 1);
+        }
+    }
+
+    #[test]
+    fn test_read_only_and_overlay() {
+        let path = image("blk-modes");
+        let golden = std::fs::read(&path).unwrap();
+
+        let mut driver = Driver::new(
+            Box::new(Disk::open(&path, DiskMode::ReadOnly).unwrap()),
+            false,
+        );
+        // VIRTIO_BLK_F_RO
+        assert_eq!(driver.reg(0x010) & 1 << 5, 1 << 5);
+        assert_eq!(driver.request(1, 0, 512), 1);
+
+        let mut driver = Driver::new(
+            Box::new(Disk::open(&path, DiskMode::Overlay).unwrap()),
+            false,
+        );
+        assert_eq!(driver.reg(0x010) & 1 << 5, 0);
+        driver.bus.store_bytes(DATA, &[0x77; 512]).unwrap();
+        assert_eq!(driver.request(1, 3, 512), 0);
+        driver.bus.store_bytes(DATA, &[0; 512]).unwrap();
+        // the guest reads its own write, the image stays as it was
+        assert_eq!(driver.request(0, 3, 512), 0);
+        assert_eq!(driver.bus.load_bytes(DATA, 512), Some(&[0x77; 512][..]));
+        assert_eq!(std::fs::read(&path).unwrap(), golden);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(DiskMode::parse("overlay"), Ok(DiskMode::Overlay));
+        assert!(DiskMode::parse("cow").is_err());
+    }
+
+    #[test]
+    fn test_console() {
+        let (console, input, output) = Console::captured();
+        let mut driver = Driver::new(Box::new(console), false);
+        assert_eq!(driver.reg(0x008), 3);
+
+        driver.bus.store_bytes(DATA, b"$ ").unwrap();
+        driver.submit(1, &[(DATA, 2, false)]);
+        assert_eq!(driver.used(1), Some(0));
+        assert_eq!(*output.lock().unwrap(), b"$ ");
+
+        // a read waits for input, which the next poll hands on
+        driver.submit(0, &[(DATA, 16, true)]);
+        assert_eq!(driver.used(0), None);
+        driver.bus.poll();
+        assert_eq!(driver.used(0), None);
+        input.lock().unwrap().extend(b"ls\n");
+        driver.bus.poll();
+        assert_eq!(driver.used(0), Some(3));
+        assert_eq!(driver.bus.load_bytes(DATA, 3), Some(&b"ls\n"[..]));
+    }
+
+    #[test]
+    fn test_console_socket() {
+        use std::io::{Read, Write};
+        use std::os::unix::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!("riscland-con-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let console = Console::socket(&path).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut driver = Driver::new(Box::new(console), true);
+
+        driver.bus.store_bytes(DATA, b"ping").unwrap();
+        driver.submit(1, &[(DATA, 4, false)]);
+        let mut got = [0; 4];
+        peer.read_exact(&mut got).unwrap();
+        assert_eq!(&got, b"ping");
+
+        peer.write_all(b"pong").unwrap();
+        driver.submit(0, &[(DATA, 4, true)]);
+        // the reader thread takes its time
+        let mut used = None;
+        for _ in 0..1000 {
+            driver.bus.poll();
+            used = driver.used(0);
+            if used == Some(4) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert_eq!(used, Some(4));
+        assert_eq!(driver.bus.load_bytes(DATA, 4), Some(&b"pong"[..]));
+    }
+
+    #[test]
+    fn test_net() {
+        let frame: Vec<u8> = (0..60).collect();
+        let (guest, harness) = Net::queue_pair();
+        let (legacy_guest, legacy_peer) = Net::socket_pair().unwrap();
+        for (guest, peer, legacy) in [(guest, harness, false), (legacy_guest, legacy_peer, true)] {
+            // the header grows num_buffers with VIRTIO_F_VERSION_1
+            let header = if legacy { 10 } else { 12 };
+            let mut driver = Driver::new(Box::new(guest.with_mac([2, 0, 0, 0, 0, 1])), legacy);
+            assert_eq!(driver.reg(0x008), 1);
+            assert_eq!(driver.reg(0x100), 0x02);
+            assert_eq!(driver.bus.read(DEV + 0x105, 8), Some(1));
+
+            driver.bus.store_bytes(HEADER, &[0; 12]).unwrap();
+            driver.bus.store_bytes(DATA, &frame).unwrap();
+            driver.submit(1, &[(HEADER, header, false), (DATA, 60, false)]);
+            assert_eq!(driver.used(1), Some(0));
+            assert_eq!(peer.recv(), Some(frame.clone()));
+            assert_eq!(peer.recv(), None);
+
+            driver.submit(0, &[(DATA, 2048, true)]);
+            driver.bus.poll();
+            assert_eq!(driver.used(0), None);
+            peer.send(&frame);
+            driver.bus.poll();
+            assert_eq!(driver.used(0), Some(header + 60));
+            let got = driver.bus.load_bytes(DATA + header, 60).unwrap();
+            assert_eq!(got, &frame[..]);
+        }
+
+        // two ends bound to paths, as two emulators would use them
+        let dir = std::env::temp_dir();
+        let a = dir.join(format!("riscland-net-a-{}", std::process::id()));
+        let b = dir.join(format!("riscland-net-b-{}", std::process::id()));
+        let end_a = Net::bind(&a, &b).unwrap();
+        let end_b = Net::bind(&b, &a).unwrap();
+        end_a.send(b"hello");
+        assert_eq!(end_b.recv(), Some(b"hello".to_vec()));
+        std::fs::remove_file(&a).unwrap();
+        std::fs::remove_file(&b).unwrap();
+
+        assert_eq!(
+            virtio_net::parse_mac("52:54:00:12:34:56"),
+            Ok(virtio_net::DEFAULT_MAC)
+        );
+        assert!(virtio_net::parse_mac("52:54:00").is_err());
+    }
+
+    #[test]
+    fn test_rng() {
+        let mut bytes = Vec::new();
+        for _ in 0..2 {
+            let mut driver = Driver::new(Box::new(Rng::new(42)), true);
+            assert_eq!(driver.reg(0x008), 4);
+            driver.submit(0, &[(DATA, 13, true)]);
+            assert_eq!(driver.used(0), Some(13));
+            bytes.push(driver.bus.load_bytes(DATA, 13).unwrap().to_vec());
+        }
+        // the same seed, the same bytes
+        assert_eq!(bytes[0], bytes[1]);
+        assert!(bytes[0].iter().any(|b| *b != 0));
+        let mut other = Rng::new(43);
+        assert_ne!(other.next_u64().to_le_bytes()[..], bytes[0][..8]);
+
+        // a large buffer gets 64 KiB
+        let mut driver = Driver::new(Box::new(Rng::new(42)), false);
+        driver.submit(0, &[(DATA, 0x20000, true)]);
+        assert_eq!(driver.used(0), Some(0x10000));
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut driver = Driver::new(Box::new(Rng::new(42)), false);
+        driver.bus.add_device(Mapped {
+            base: PLIC,
+            size: PLIC_SIZE,
+            irq: None,
+            device: Box::new(Plic::new()),
+        });
+        // source 1 at priority 1, enabled for hart 0's machine mode
+        driver.bus.write(PLIC + 4, 32, 1).unwrap();
+        driver.bus.write(PLIC + 0x2000, 32, 1 << 1).unwrap();
+        assert_eq!(driver.bus.interrupts(0), 0);
+
+        driver.submit(0, &[(DATA, 13, true)]);
+        assert_eq!(driver.bus.interrupts(0), 1 << csr::IRQ_M_EXT);
+        assert_eq!(driver.bus.read(PLIC + 0x200004, 32), Some(1));
+        // acknowledged before completion, so the line is down by then
+        assert_eq!(driver.used(0), Some(13));
+        assert_eq!(driver.reg(0x060), 0);
+        driver.bus.write(PLIC + 0x200004, 32, 1).unwrap();
+        assert_eq!(driver.bus.interrupts(0), 0);
+    }
+
+    #[test]
+    fn test_bad_chains() {
+        // a write buffer running out of memory, one claiming 2 GiB, and
+        // read buffers past the end; each breaks the device instead of
+        // being answered
+        for bufs in [
+            [(DATA, MEMORY, true)],
+            [(DATA, 0x8000_0000, true)],
+            [(MEM_BASE + MEMORY - 0x100, 0x200, false)],
+        ] {
+            let mut driver = Driver::new(Box::new(Rng::new(42)), false);
+            driver.submit(0, &bufs);
+            assert_eq!(driver.used(0), None);
+            assert_eq!(driver.reg(0x070) & 0x40, 0x40);
+        }
+    }
+}